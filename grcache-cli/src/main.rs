@@ -42,14 +42,14 @@ fn main() {
         Commands::Validate { .. } => todo!(),
         Commands::UploadDescriptors { .. } => todo!(),
         Commands::GenerateSchemas { out_dir } => {
-            //fn write_json<T: Serialize>(out_dir: &Path, name: &str, value: T) {
-            //    let mut path = PathBuf::new();
-            //    path.push(out_dir);
-            //    path.push(name);
+            fn write_json<T: Serialize>(out_dir: &Path, name: &str, value: T) {
+                let mut path = PathBuf::new();
+                path.push(out_dir);
+                path.push(name);
 
-            //    let file = File::create(&path).unwrap();
-            //    serde_json::to_writer(BufWriter::new(file), &value).unwrap();
-            //}
+                let file = File::create(&path).unwrap();
+                serde_json::to_writer_pretty(BufWriter::new(file), &value).unwrap();
+            }
 
             fn write_yaml<T: Serialize>(out_dir: &Path, name: &str, value: T) {
                 let mut path = PathBuf::new();
@@ -65,6 +65,11 @@ fn main() {
                 "grcache_service_crd.yaml",
                 grcache_shared::config::crd::GrcacheService::crd(),
             );
+            write_json(
+                &out_dir,
+                "grcache_config_schema.json",
+                grcache_shared::config::loader::schema(),
+            );
         }
     }
 
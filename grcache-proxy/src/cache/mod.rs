@@ -1,9 +1,118 @@
+use std::{
+    future::Future,
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use async_trait::async_trait;
 use pingora::cache::Storage;
+use tokio::task;
 
 //pub mod local;
 pub mod redis_cluster;
 pub mod redis_replicas;
 
+#[async_trait]
 pub trait GrcacheStorage: Sync {
     fn as_storage(&self) -> &(dyn Storage + Sync);
+
+    /// Takes a token from the token bucket at `key`, shared by all
+    /// proxies using the backend, or returns how long until there is
+    /// one. Buckets hold up to `burst` tokens, refilled at `rate` per
+    /// second.
+    async fn take_shared_token(
+        &self,
+        _key: &[u8],
+        _rate: u32,
+        _burst: u32,
+    ) -> anyhow::Result<Option<Duration>> {
+        bail!("the cache backend has no shared rate limits")
+    }
+}
+
+/// Deadlines of cache lookups, by the task serving the request.
+/// Pingora hands storages nothing from the request context, but runs
+/// their lookups in the task of the request.
+static LOOKUP_DEADLINES: LazyLock<papaya::HashMap<task::Id, Instant>> =
+    LazyLock::new(papaya::HashMap::new);
+
+/// Makes cache lookups of the current request give up at `deadline`,
+/// until the returned guard is dropped.
+pub fn set_lookup_deadline(deadline: Instant) -> LookupDeadline {
+    let task = task::try_id();
+    if let Some(task) = task {
+        LOOKUP_DEADLINES.pin().insert(task, deadline);
+    }
+    LookupDeadline { task }
+}
+
+/// Removes the lookup deadline of a request when dropped.
+pub struct LookupDeadline {
+    task: Option<task::Id>,
+}
+
+impl Drop for LookupDeadline {
+    fn drop(&mut self) {
+        if let Some(task) = self.task {
+            LOOKUP_DEADLINES.pin().remove(&task);
+        }
+    }
+}
+
+/// The deadline set with `set_lookup_deadline` in the current task.
+fn lookup_deadline() -> Option<Instant> {
+    LOOKUP_DEADLINES.pin().get(&task::try_id()?).copied()
+}
+
+/// Runs a lookup until the deadline of the request. Lookups which run
+/// out of time are misses.
+pub async fn lookup_until_deadline<T>(
+    lookup: impl Future<Output = pingora::Result<Option<T>>>,
+) -> pingora::Result<Option<T>> {
+    let Some(deadline) = lookup_deadline() else {
+        return lookup.await;
+    };
+    match tokio::time::timeout_at(deadline.into(), lookup).await {
+        Ok(result) => result,
+        Err(_) => {
+            log::warn!("cache lookup exceeded the request deadline, treating as a miss");
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn slow_lookup() -> pingora::Result<Option<()>> {
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        Ok(Some(()))
+    }
+
+    #[tokio::test]
+    async fn lookup_stops_at_deadline() {
+        let lookup = tokio::spawn(async {
+            assert_eq!(
+                lookup_until_deadline(async { Ok(Some(())) }).await.unwrap(),
+                Some(())
+            );
+
+            let deadline = set_lookup_deadline(Instant::now() + Duration::from_millis(10));
+            assert_eq!(lookup_until_deadline(slow_lookup()).await.unwrap(), None);
+
+            drop(deadline);
+            assert!(lookup_deadline().is_none());
+        });
+
+        // Deadlines are per task.
+        let other = tokio::spawn(async {
+            let _deadline = set_lookup_deadline(Instant::now());
+            assert!(lookup_deadline().is_some());
+            tokio::spawn(async { lookup_deadline() }).await.unwrap()
+        });
+        assert!(other.await.unwrap().is_none());
+        lookup.await.unwrap();
+    }
 }
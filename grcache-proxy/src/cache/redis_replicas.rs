@@ -1,9 +1,17 @@
-use std::{any::Any, sync::Arc};
+use std::{
+    any::Any,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
+use anyhow::Context;
 use bb8::Pool;
 use bb8_redis::{redis::AsyncCommands, RedisConnectionManager};
 use bytes::BufMut;
-use grcache_shared::health::HealthEndpoint;
+use grcache_shared::{
+    config::{context::ConfigContext, crd::AddressFamily, CacheBackend, ConfigFile},
+    health::HealthEndpoint,
+};
 use pingora::{
     cache::{
         key::{CacheHashKey, CompactCacheKey},
@@ -18,23 +26,62 @@ use pingora_load_balancing::Backend;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
-use crate::discovery::{self, ServiceBackendsHandle};
+use crate::discovery::{self, outlier::OutlierDetector, ServiceBackendsHandle};
 
-use super::GrcacheStorage;
+use super::{lookup_until_deadline, GrcacheStorage};
 
 pub struct RedisReplicasCacheBackend {
     pools: Arc<RedisPools>,
 }
 
+/// Hostname, port and address family to discover `redis` instances
+/// through.
+fn redis_target(config: &ConfigFile) -> (String, u16, AddressFamily) {
+    match &config.cache_backend {
+        CacheBackend::RedisReplicas {
+            hostname,
+            port,
+            address_family,
+            ..
+        } => (hostname.clone(), *port, *address_family),
+    }
+}
+
+fn redis_outlier_detector(config: &ConfigFile) -> Option<Arc<OutlierDetector>> {
+    match &config.cache_backend {
+        CacheBackend::RedisReplicas {
+            outlier_detection, ..
+        } => outlier_detection
+            .clone()
+            .map(|config| Arc::new(OutlierDetector::new("redis".into(), config))),
+    }
+}
+
 impl RedisReplicasCacheBackend {
+    /// Discovers `redis` instances as configured in the
+    /// `cacheBackend`, following changes to it when the config is
+    /// reloaded.
     pub fn new(
-        discovery: Arc<ServiceBackendsHandle>,
+        dns_discovery: discovery::dns::Handle,
+        config: ConfigContext,
         mut health: HealthEndpoint,
     ) -> (Service, Self) {
         health.name("redis backend service discovery");
 
+        let (hostname, port, family) = redis_target(&config.borrow().config);
+        // The discovery service only stops handling commands once it
+        // has been started and shut down.
+        let discovery = dns_discovery
+            .backends_for_hostname(hostname, port, family)
+            .expect("DNS discovery service stopped before startup");
+        let outliers = redis_outlier_detector(&config.borrow().config);
+        let mut discovery_ready = discovery.ready.clone();
+
         let pools = Arc::new(RedisPools {
-            backends: discovery,
+            backends: RwLock::new(discovery),
+            outliers: RwLock::new(outliers),
+            dns_discovery,
+            config,
             pools: Default::default(),
             health,
         });
@@ -49,7 +96,6 @@ impl RedisReplicasCacheBackend {
                 // Because this is readiness, we never timeout
                 // and mark as ready unless we successfully
                 // complete discovery.
-                let mut discovery_ready = pools.backends.ready.clone();
                 discovery_ready.wait_for(|v| *v).await.unwrap();
 
                 pools.health.ready();
@@ -114,7 +160,7 @@ impl HandleMiss for RedisCacheMiss {
         let miss_data = *self;
 
         // Fetch connection pool
-        let pool = match miss_data.pools.pool_for_hash(&miss_data.hash).await {
+        let (backend, pool) = match miss_data.pools.pool_for_hash(&miss_data.hash).await {
             Some(pool) => pool,
             None => {
                 // No pool available, do not fetch.
@@ -127,6 +173,7 @@ impl HandleMiss for RedisCacheMiss {
             Ok(conn) => conn,
             Err(error) => {
                 log::error!("error getting cache connection from pool! {}", error);
+                miss_data.pools.record(&backend, false);
                 return Ok(0);
             }
         };
@@ -141,9 +188,10 @@ impl HandleMiss for RedisCacheMiss {
         bincode::serialize_into(&mut data, &data_struct).unwrap();
 
         let ret: Result<(), _> = conn.set(&miss_data.hash, &data).await;
-        if let Err(error) = ret {
+        if let Err(error) = &ret {
             log::error!("failed to set cache value! {}", error);
         }
+        miss_data.pools.record(&backend, ret.is_ok());
 
         // todo
         Ok(data.len())
@@ -156,23 +204,74 @@ struct CacheData {
     data: bytes::Bytes,
 }
 
+/// Token bucket in a hash with the `tokens` left and the time they were
+/// `updated` at, in milliseconds. Returns the milliseconds to wait for a
+/// token, zero if one was taken.
+const TAKE_TOKEN_SCRIPT: &str = r#"
+local rate = tonumber(ARGV[1])
+local burst = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = time[1] * 1000 + math.floor(time[2] / 1000)
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(bucket[1]) or burst
+local updated = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - updated) * rate / 1000)
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = math.ceil((1 - tokens) * 1000 / rate)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', now)
+redis.call('PEXPIRE', KEYS[1], math.ceil(burst * 1000 / rate) + 1000)
+return wait
+"#;
+
+#[async_trait::async_trait]
 impl GrcacheStorage for RedisReplicasCacheBackend {
     fn as_storage(&self) -> &(dyn Storage + Sync) {
         self
     }
+
+    async fn take_shared_token(
+        &self,
+        key: &[u8],
+        rate: u32,
+        burst: u32,
+    ) -> anyhow::Result<Option<Duration>> {
+        let (backend, pool) = self
+            .pools
+            .pool_for_hash(key)
+            .await
+            .context("no available redis pool")?;
+        let mut conn = match pool.get().await {
+            Ok(conn) => conn,
+            Err(error) => {
+                self.pools.record(&backend, false);
+                return Err(error).context("failed to get redis connection from pool");
+            }
+        };
+
+        let result = bb8_redis::redis::cmd("EVAL")
+            .arg(TAKE_TOKEN_SCRIPT)
+            .arg(1)
+            .arg(key)
+            .arg(rate)
+            .arg(burst)
+            .query_async::<u64>(&mut *conn)
+            .await;
+        self.pools.record(&backend, result.is_ok());
+        let wait = result.context("failed to run redis rate limit script")?;
+        Ok((wait > 0).then(|| Duration::from_millis(wait)))
+    }
 }
 
-#[async_trait::async_trait]
-impl Storage for RedisReplicasCacheBackend {
-    async fn lookup(
-        &'static self,
-        key: &CacheKey,
-        _trace: &SpanHandle,
-    ) -> pingora::Result<Option<(CacheMeta, HitHandler)>> {
+impl RedisReplicasCacheBackend {
+    async fn fetch(&self, key: &CacheKey) -> pingora::Result<Option<(CacheMeta, HitHandler)>> {
         let hash = key.primary_bin();
 
         // Fetch connection pool
-        let pool = match self.pools.pool_for_hash(&hash[..]).await {
+        let (backend, pool) = match self.pools.pool_for_hash(&hash[..]).await {
             Some(pool) => pool,
             None => {
                 log::warn!("not caching, no available pool!");
@@ -186,12 +285,15 @@ impl Storage for RedisReplicasCacheBackend {
             Ok(conn) => conn,
             Err(error) => {
                 log::error!("error getting cache connection from pool! {}", error);
+                self.pools.record(&backend, false);
                 return Ok(None);
             }
         };
 
         // Get value from connection
-        let data_opt: Option<bytes::Bytes> = conn.get(&hash[..]).await.unwrap_or_else(|error| {
+        let result = conn.get(&hash[..]).await;
+        self.pools.record(&backend, result.is_ok());
+        let data_opt: Option<bytes::Bytes> = result.unwrap_or_else(|error| {
             log::error!("error running redis cache get! {}", error);
             None
         });
@@ -231,6 +333,17 @@ impl Storage for RedisReplicasCacheBackend {
             }
         }
     }
+}
+
+#[async_trait::async_trait]
+impl Storage for RedisReplicasCacheBackend {
+    async fn lookup(
+        &'static self,
+        key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> pingora::Result<Option<(CacheMeta, HitHandler)>> {
+        lookup_until_deadline(self.fetch(key)).await
+    }
 
     async fn get_miss_handler(
         &'static self,
@@ -277,8 +390,13 @@ pub struct Service {
 }
 
 pub struct RedisPools {
-    /// Service discovery handle for `redis` backend.
-    backends: Arc<discovery::ServiceBackendsHandle>,
+    /// Service discovery handle for `redis` backend. Replaced when the
+    /// `cacheBackend` config changes.
+    backends: RwLock<Arc<ServiceBackendsHandle>>,
+    /// Ejects failing instances, if enabled in the `cacheBackend`.
+    outliers: RwLock<Option<Arc<OutlierDetector>>>,
+    dns_discovery: discovery::dns::Handle,
+    config: ConfigContext,
     /// Connection pools maintained by dedicated task.
     pools: papaya::HashMap<Backend, bb8::Pool<bb8_redis::RedisConnectionManager>>,
     health: HealthEndpoint,
@@ -286,28 +404,37 @@ pub struct RedisPools {
 
 impl RedisPools {
     pub fn select_backend(&self, key: &[u8]) -> Option<Backend> {
+        let outliers = self.outliers.read().unwrap().clone();
         self.backends
+            .read()
+            .unwrap()
             .load_balancer_consistent
             .select_with(key, 255, |backend, healthy| {
-                if !healthy {
+                if !healthy || outliers.as_ref().is_some_and(|o| o.is_ejected(backend)) {
                     return false;
                 }
                 self.pools.pin().contains_key(backend)
             })
     }
 
-    pub async fn pool_for_hash(&self, hash: &[u8]) -> Option<Pool<RedisConnectionManager>> {
-        if let Some(backend) = self.select_backend(hash) {
-            let pool_opt = self.pools.pin().get(&backend).cloned();
-            if let Some(pool) = pool_opt {
-                Some(pool)
-            } else {
-                // This can happen if we raced with pool construction/destruction.
-                // Should be exceptionally rare, we handle as miss.
-                None
-            }
-        } else {
-            None
+    pub async fn pool_for_hash(
+        &self,
+        hash: &[u8],
+    ) -> Option<(Backend, Pool<RedisConnectionManager>)> {
+        // `None` can also happen if we raced with pool
+        // construction/destruction. Should be exceptionally rare, we
+        // handle as miss.
+        self.select_backend(hash).and_then(|backend| {
+            let pool = self.pools.pin().get(&backend).cloned()?;
+            Some((backend, pool))
+        })
+    }
+
+    /// Records the outcome of a command for outlier detection.
+    fn record(&self, backend: &Backend, success: bool) {
+        if let Some(outliers) = self.outliers.read().unwrap().as_ref() {
+            let handle = self.backends.read().unwrap();
+            outliers.record(backend, success, &handle.backends.borrow());
         }
     }
 }
@@ -315,7 +442,9 @@ impl RedisPools {
 #[async_trait::async_trait]
 impl BackgroundService for Service {
     async fn start(&self, _shutdown: ShutdownWatch) {
-        let mut changed_handler = self.pools.backends.backends.clone();
+        let mut config = self.pools.config.clone();
+        let mut target = redis_target(&config.borrow_and_update().config);
+        let mut changed_handler = self.pools.backends.read().unwrap().backends.clone();
         changed_handler.mark_changed();
 
         // This loops and propagates our `pools` map whenever the set of
@@ -325,9 +454,49 @@ impl BackgroundService for Service {
         // pool being created should not be a big issue except for a few
         // potential cache misses.
         loop {
-            // If service discovery crashes we want to propagate.
-            changed_handler.changed().await.unwrap();
-            let backends = changed_handler.borrow().clone();
+            tokio::select! {
+                // If service discovery crashes we want to propagate.
+                result = changed_handler.changed() => result.unwrap(),
+                Ok(()) = config.changed() => {
+                    let new_config = config.borrow_and_update().config.clone();
+
+                    let new_outliers = redis_outlier_detector(&new_config);
+                    let mut outliers = self.pools.outliers.write().unwrap();
+                    if outliers.as_ref().map(|o| o.config()) != new_outliers.as_ref().map(|o| o.config()) {
+                        *outliers = new_outliers;
+                    }
+                    drop(outliers);
+
+                    let new_target = redis_target(&new_config);
+                    if new_target == target {
+                        continue;
+                    }
+                    log::info!(
+                        "redis cache backend changed to `{}:{}` ({:?})",
+                        new_target.0,
+                        new_target.1,
+                        new_target.2
+                    );
+
+                    // Pools for replicas which are not part of the new
+                    // set of backends are removed below.
+                    let discovery = match self.pools.dns_discovery.backends_for_hostname(
+                        new_target.0.clone(),
+                        new_target.1,
+                        new_target.2,
+                    ) {
+                        Ok(discovery) => discovery,
+                        Err(error) => {
+                            log::warn!("redis cache backend discovery stopped: {}", error);
+                            return;
+                        }
+                    };
+                    changed_handler = discovery.backends.clone();
+                    *self.pools.backends.write().unwrap() = discovery;
+                    target = new_target;
+                }
+            }
+            let backends = changed_handler.borrow_and_update().clone();
 
             self.pools.pools.pin().retain(|k, _v| {
                 let retain = backends.contains(k);
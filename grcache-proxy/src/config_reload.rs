@@ -0,0 +1,163 @@
+//! Reloading of the config file.
+//!
+//! The config file is checked for changes every `RELOAD_INTERVAL`, and
+//! reloaded immediately on `SIGHUP`. A new config is only applied if
+//! all of its changes are reloadable, see
+//! `ConfigFile::restart_required_changes`.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use grcache_shared::config::{
+    context::{ConfigContext, ConfigContextData},
+    ConfigFile,
+};
+use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Background service which reloads the config file.
+pub struct Service {
+    path: PathBuf,
+    sender: watch::Sender<ConfigContextData>,
+    last_modified: Mutex<Option<SystemTime>>,
+}
+
+impl Service {
+    pub fn new(path: PathBuf, config: ConfigFile) -> (Service, ConfigContext) {
+        let last_modified = modified(&path);
+        let (sender, receiver) = watch::channel(ConfigContextData::new(config));
+        let service = Service {
+            path,
+            sender,
+            last_modified: Mutex::new(last_modified),
+        };
+        (service, receiver)
+    }
+
+    fn reload_if_changed(&self) {
+        let modified = modified(&self.path);
+        let mut last_modified = self.last_modified.lock().unwrap();
+        if modified != *last_modified {
+            *last_modified = modified;
+            self.reload();
+        }
+    }
+
+    fn reload(&self) {
+        let new = match ConfigFile::load(&self.path) {
+            Ok(config) => config,
+            Err(error) => {
+                log::error!(
+                    "failed to reload config file `{}`, keeping current config: {}",
+                    self.path.display(),
+                    error
+                );
+                return;
+            }
+        };
+
+        let current = self.sender.borrow().config.clone();
+        if *current == new {
+            return;
+        }
+
+        let changes = current.restart_required_changes(&new);
+        if !changes.is_empty() {
+            log::error!(
+                "config file `{}` changed `{}`, which requires a restart, keeping current config",
+                self.path.display(),
+                changes.join("`, `")
+            );
+            return;
+        }
+
+        apply_global(&new);
+        self.sender.send_replace(ConfigContextData::new(new));
+        log::info!("reloaded config file `{}`", self.path.display());
+    }
+}
+
+/// Applies the reloadable settings which are process global. The rest
+/// is picked up from the `ConfigContext` by their users.
+fn apply_global(config: &ConfigFile) {
+    crate::logging::set_level(config.log_level);
+    if let Some(tracing) = &config.tracing {
+        crate::tracing::set_propagators(tracing);
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[async_trait::async_trait]
+impl BackgroundService for Service {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut hangup = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = hangup.recv() => {
+                    log::info!("received SIGHUP, reloading config file");
+                    self.reload();
+                }
+                _ = tokio::time::sleep(RELOAD_INTERVAL) => self.reload_if_changed(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "\
+kubernetes:
+  enable: true
+cacheBackend:
+  redisReplicas:
+    hostname: redis
+proxy:
+  propagationHeaders: []
+";
+
+    #[test]
+    fn reloads_only_reloadable_changes() {
+        let path =
+            std::env::temp_dir().join(format!("grcache-config-test-{}.yaml", std::process::id()));
+        std::fs::write(&path, CONFIG).unwrap();
+
+        let config = ConfigFile::load(&path).unwrap();
+        let (service, mut context) = Service::new(path.clone(), config);
+
+        // Invalid configs are not applied.
+        std::fs::write(&path, "kubernetes: {}").unwrap();
+        service.reload();
+        assert!(!context.has_changed().unwrap());
+
+        let reloadable = CONFIG.replace("[]", "[x-request-id]");
+        std::fs::write(&path, &reloadable).unwrap();
+        service.reload();
+        assert!(context.has_changed().unwrap());
+        assert!(context
+            .borrow_and_update()
+            .config
+            .proxy
+            .propagation_headers
+            .contains("x-request-id"));
+
+        std::fs::write(&path, format!("{}  jsonTranscoding: true\n", reloadable)).unwrap();
+        service.reload();
+        assert!(!context.has_changed().unwrap());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,378 @@
+//! Selection of an upstream for each request to a service, with the
+//! load balancing policy of its `GrcacheService`.
+//!
+//! Weighted round robin and ketama use the load balancers of the
+//! `ServiceBackendsHandle`. The other policies select from the backends
+//! loaded into them, so health is taken into account the same way.
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use blake2::{digest::consts::U8, Blake2b, Digest};
+use grcache_shared::config::crd::{HashOn, LoadBalancing, LoadBalancingPolicy};
+use pingora_load_balancing::Backend;
+use rand::Rng;
+
+use super::ServiceBackendsHandle;
+
+/// Iterations for the pingora load balancers to find an acceptable
+/// backend.
+const MAX_ITERATIONS: usize = 256;
+
+/// Entries in a Maglev lookup table. Must be prime, and much larger
+/// than the number of backends.
+const MAGLEV_TABLE_SIZE: usize = 65537;
+
+pub struct Balancer {
+    policy: LoadBalancingPolicy,
+    hash_on: Option<HashOn>,
+    /// Turn for round robin, also used to break ties between backends
+    /// with as many requests in flight.
+    next: AtomicUsize,
+    /// Requests in flight per backend, only counted for the policies
+    /// which use them.
+    in_flight: Mutex<HashMap<Backend, usize>>,
+    /// Rebuilt when the backends change.
+    maglev: Mutex<Option<Arc<Maglev>>>,
+}
+
+impl Default for Balancer {
+    fn default() -> Self {
+        Balancer::new(&LoadBalancing::default())
+    }
+}
+
+impl Balancer {
+    pub fn new(spec: &LoadBalancing) -> Self {
+        Balancer {
+            policy: spec.policy,
+            hash_on: spec.hash_on.clone(),
+            next: AtomicUsize::new(0),
+            in_flight: Mutex::new(HashMap::new()),
+            maglev: Mutex::new(None),
+        }
+    }
+
+    /// What requests are hashed on, if the policy hashes them.
+    pub fn hash_on(&self) -> Option<&HashOn> {
+        match self.policy {
+            LoadBalancingPolicy::Ketama | LoadBalancingPolicy::Maglev => self.hash_on.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Selects a ready backend of `handle` which is accepted by
+    /// `accept`. `key` is the hash key of the request, requests
+    /// without one are sent round robin by the hashing policies.
+    pub fn select(
+        &self,
+        handle: &ServiceBackendsHandle,
+        key: Option<&[u8]>,
+        accept: impl Fn(&Backend) -> bool,
+    ) -> Option<Backend> {
+        let load_balancer = &handle.load_balancer_round_robin;
+        let backends = load_balancer.backends();
+        let all = backends.get_backend();
+        let usable = |backend: &Backend| backends.ready(backend) && accept(backend);
+
+        let candidates = || all.iter().filter(|b| usable(b)).collect::<Vec<_>>();
+        match (self.policy, key) {
+            (LoadBalancingPolicy::WeightedRoundRobin, _)
+            | (LoadBalancingPolicy::Ketama | LoadBalancingPolicy::Maglev, None) => load_balancer
+                .select_with(b"", MAX_ITERATIONS, |backend, healthy| {
+                    healthy && accept(backend)
+                }),
+            (LoadBalancingPolicy::Ketama, Some(key)) => handle
+                .load_balancer_consistent
+                .select_with(key, MAX_ITERATIONS, |backend, healthy| {
+                    healthy && accept(backend)
+                }),
+            (LoadBalancingPolicy::Maglev, Some(key)) => {
+                self.maglev_for(&all).select(key, usable).cloned()
+            }
+            (LoadBalancingPolicy::RoundRobin, _) => {
+                let candidates = candidates();
+                let turn = self.next.fetch_add(1, Ordering::Relaxed);
+                (!candidates.is_empty()).then(|| candidates[turn % candidates.len()].clone())
+            }
+            (LoadBalancingPolicy::Random, _) => random_weighted(&candidates()).cloned(),
+            (LoadBalancingPolicy::LeastRequests, _) => {
+                let candidates = candidates();
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                let in_flight = self.in_flight.lock().unwrap();
+                (0..candidates.len())
+                    .map(|i| candidates[(start + i) % candidates.len()])
+                    .min_by_key(|backend| in_flight.get(*backend).copied().unwrap_or(0))
+                    .cloned()
+            }
+            (LoadBalancingPolicy::PowerOfTwoChoices, _) => {
+                let candidates = candidates();
+                let picks = rand::seq::index::sample(
+                    &mut rand::thread_rng(),
+                    candidates.len(),
+                    candidates.len().min(2),
+                );
+                let in_flight = self.in_flight.lock().unwrap();
+                picks
+                    .into_iter()
+                    .map(|i| candidates[i])
+                    .min_by_key(|backend| in_flight.get(*backend).copied().unwrap_or(0))
+                    .cloned()
+            }
+        }
+    }
+
+    /// Counts a request to `backend` as in flight until the returned
+    /// guard is dropped, for the policies which use it.
+    pub fn start_request(self: &Arc<Self>, backend: &Backend) -> Option<InFlight> {
+        if !matches!(
+            self.policy,
+            LoadBalancingPolicy::LeastRequests | LoadBalancingPolicy::PowerOfTwoChoices
+        ) {
+            return None;
+        }
+        *self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(backend.clone())
+            .or_default() += 1;
+        Some(InFlight {
+            balancer: self.clone(),
+            backend: backend.clone(),
+        })
+    }
+
+    fn maglev_for(&self, backends: &Arc<BTreeSet<Backend>>) -> Arc<Maglev> {
+        let mut maglev = self.maglev.lock().unwrap();
+        match &*maglev {
+            Some(table) if Arc::ptr_eq(&table.source, backends) => table.clone(),
+            _ => {
+                let table = Arc::new(Maglev::new(backends.clone()));
+                *maglev = Some(table.clone());
+                table
+            }
+        }
+    }
+}
+
+/// A request in flight to a backend.
+pub struct InFlight {
+    balancer: Arc<Balancer>,
+    backend: Backend,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut in_flight = self.balancer.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.backend) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.backend);
+            }
+        }
+    }
+}
+
+fn random_weighted<'a>(candidates: &[&'a Backend]) -> Option<&'a Backend> {
+    let total: usize = candidates.iter().map(|b| b.weight.max(1)).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut point = rand::thread_rng().gen_range(0..total);
+    candidates.iter().copied().find(|backend| {
+        let weight = backend.weight.max(1);
+        if point < weight {
+            true
+        } else {
+            point -= weight;
+            false
+        }
+    })
+}
+
+/// Stable across proxy instances, so they agree on where keys go.
+fn hash(seed: &[u8], data: &[u8]) -> u64 {
+    let mut hasher = Blake2b::<U8>::new();
+    hasher.update(seed);
+    hasher.update(data);
+    u64::from_le_bytes(hasher.finalize().into())
+}
+
+/// Maglev lookup table, see "Maglev: A Fast and Reliable Software
+/// Network Load Balancer". Each backend fills entries in the order of
+/// its own permutation of the table, in proportion to its weight.
+struct Maglev {
+    /// Backends the table was built from.
+    source: Arc<BTreeSet<Backend>>,
+    backends: Vec<Backend>,
+    /// Index into `backends` per entry.
+    table: Vec<u32>,
+}
+
+impl Maglev {
+    fn new(source: Arc<BTreeSet<Backend>>) -> Self {
+        let backends: Vec<Backend> = source.iter().cloned().collect();
+        let mut table = vec![u32::MAX; MAGLEV_TABLE_SIZE];
+
+        let permutations: Vec<(usize, usize)> = backends
+            .iter()
+            .map(|backend| {
+                let name = backend.addr.to_string();
+                let offset = hash(b"offset", name.as_bytes()) as usize % MAGLEV_TABLE_SIZE;
+                let skip = hash(b"skip", name.as_bytes()) as usize % (MAGLEV_TABLE_SIZE - 1) + 1;
+                (offset, skip)
+            })
+            .collect();
+        let mut next = vec![0; backends.len()];
+        let mut filled = 0;
+
+        'fill: while !backends.is_empty() {
+            for (index, (offset, skip)) in permutations.iter().enumerate() {
+                for _ in 0..backends[index].weight.max(1) {
+                    let mut entry = (offset + next[index] * skip) % MAGLEV_TABLE_SIZE;
+                    while table[entry] != u32::MAX {
+                        next[index] += 1;
+                        entry = (offset + next[index] * skip) % MAGLEV_TABLE_SIZE;
+                    }
+                    table[entry] = index as u32;
+                    next[index] += 1;
+                    filled += 1;
+                    if filled == MAGLEV_TABLE_SIZE {
+                        break 'fill;
+                    }
+                }
+            }
+        }
+
+        Maglev {
+            source,
+            backends,
+            table,
+        }
+    }
+
+    /// Returns the backend of `key`, or if it is not usable, the next
+    /// usable backend in the table.
+    fn select(&self, key: &[u8], usable: impl Fn(&Backend) -> bool) -> Option<&Backend> {
+        let start = hash(b"key", key) as usize % MAGLEV_TABLE_SIZE;
+        let mut tried = HashSet::new();
+        for step in 0..MAGLEV_TABLE_SIZE {
+            if tried.len() == self.backends.len() {
+                break;
+            }
+            let index = self.table[(start + step) % MAGLEV_TABLE_SIZE] as usize;
+            if tried.insert(index) && usable(&self.backends[index]) {
+                return Some(&self.backends[index]);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn handle_with(count: usize) -> (Arc<ServiceBackendsHandle>, Vec<Backend>) {
+        let backends: Vec<_> = (1..=count)
+            .map(|i| Backend::new(&format!("10.0.0.{}:80", i)).unwrap())
+            .collect();
+        let (handle, _ready_sender, backends_sender) = ServiceBackendsHandle::new_test();
+        backends_sender
+            .send(backends.iter().cloned().collect())
+            .unwrap();
+        handle.update_load_balancers().await;
+        (handle, backends)
+    }
+
+    fn balancer(policy: LoadBalancingPolicy) -> Arc<Balancer> {
+        Arc::new(Balancer::new(&LoadBalancing {
+            policy,
+            hash_on: None,
+        }))
+    }
+
+    #[tokio::test]
+    async fn round_robin_skips_unavailable() {
+        let (handle, backends) = handle_with(3).await;
+        let balancer = balancer(LoadBalancingPolicy::RoundRobin);
+        handle.set_available(&backends[1], false);
+
+        let selected: Vec<_> = (0..4)
+            .map(|_| balancer.select(&handle, None, |b| b != &backends[2]))
+            .collect();
+        assert_eq!(selected, vec![Some(backends[0].clone()); 4]);
+
+        handle.set_available(&backends[0], false);
+        assert_eq!(balancer.select(&handle, None, |b| b != &backends[2]), None);
+    }
+
+    #[tokio::test]
+    async fn least_requests_prefers_idle_backends() {
+        let (handle, backends) = handle_with(3).await;
+        let balancer = balancer(LoadBalancingPolicy::LeastRequests);
+
+        let first = balancer.start_request(&backends[0]);
+        let _second = balancer.start_request(&backends[1]);
+        for _ in 0..3 {
+            assert_eq!(
+                balancer.select(&handle, None, |_| true),
+                Some(backends[2].clone())
+            );
+        }
+
+        // Finished requests no longer count.
+        let _third = balancer.start_request(&backends[2]);
+        drop(first);
+        assert_eq!(
+            balancer.select(&handle, None, |_| true),
+            Some(backends[0].clone())
+        );
+    }
+
+    #[test]
+    fn maglev_spreads_keys_consistently() {
+        let backends: BTreeSet<_> = (1..=4)
+            .map(|i| Backend::new(&format!("10.0.0.{}:80", i)).unwrap())
+            .collect();
+        let maglev = Maglev::new(Arc::new(backends.clone()));
+        let keys: Vec<_> = (0..4000).map(|i| format!("user-{}", i)).collect();
+        let before: Vec<_> = keys
+            .iter()
+            .map(|key| maglev.select(key.as_bytes(), |_| true).unwrap().clone())
+            .collect();
+
+        for backend in &backends {
+            let share = before.iter().filter(|b| *b == backend).count();
+            assert!((800..1200).contains(&share), "uneven share {}", share);
+        }
+
+        // Keys of the remaining backends mostly stay where they were
+        // when one is removed, or is not usable.
+        let removed = backends.first().unwrap().clone();
+        let mut remaining = backends.clone();
+        remaining.remove(&removed);
+        let maglev = Maglev::new(Arc::new(remaining));
+        let kept = keys
+            .iter()
+            .zip(&before)
+            .filter(|(_, b)| **b != removed)
+            .filter(|(key, b)| maglev.select(key.as_bytes(), |_| true).unwrap() == *b)
+            .count();
+        assert!(kept > 2700, "only {} keys kept", kept);
+
+        let maglev = Maglev::new(Arc::new(backends));
+        for (key, backend) in keys.iter().zip(&before) {
+            let selected = maglev.select(key.as_bytes(), |b| b != &removed).unwrap();
+            if *backend != removed {
+                assert_eq!(selected, backend);
+            }
+        }
+    }
+}
@@ -1,34 +1,144 @@
 use std::{
     collections::{BTreeSet, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     sync::{Arc, Mutex, Weak},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
-use hickory_resolver::TokioAsyncResolver;
+use grcache_shared::config::{crd::AddressFamily, DnsConfig};
+use hickory_resolver::{
+    error::{ResolveError, ResolveErrorKind},
+    TokioAsyncResolver,
+};
 use pingora::{
     protocols::l4::socket::SocketAddr, server::ShutdownWatch,
     services::background::BackgroundService,
 };
-use pingora_load_balancing::{
-    prelude::RoundRobin, selection::Consistent, Backend, Extensions, LoadBalancer,
-};
+use pingora_load_balancing::{Backend, Extensions};
 use tokio::sync::{
     mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
     watch,
 };
 
-use super::{ServiceBackendsHandle, WatchServiceDiscovery};
+use super::{ServiceBackendsHandle, MAX_BACKEND_WEIGHT};
+
+/// How long a name without records is considered empty, if the
+/// response does not say.
+const NEGATIVE_TTL: Duration = Duration::from_secs(30);
+
+/// Records of one type for a name, and when they expire.
+pub struct Records<T> {
+    pub records: Vec<T>,
+    pub valid_until: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct SrvTarget {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// The DNS lookups used for discovery. Implemented by
+/// `TokioAsyncResolver`, and by stubs in tests.
+///
+/// A name without records of the requested type resolves to no
+/// records rather than an error.
+#[async_trait::async_trait]
+pub trait Resolve: Send + Sync + 'static {
+    async fn ipv4(&self, name: &str) -> Result<Records<Ipv4Addr>, ResolveError>;
+    async fn ipv6(&self, name: &str) -> Result<Records<Ipv6Addr>, ResolveError>;
+    async fn srv(&self, name: &str) -> Result<Records<SrvTarget>, ResolveError>;
+}
+
+fn records<T>(result: Result<(Vec<T>, Instant), ResolveError>) -> Result<Records<T>, ResolveError> {
+    match result {
+        Ok((records, valid_until)) => Ok(Records {
+            records,
+            valid_until,
+        }),
+        Err(error) => match error.kind() {
+            ResolveErrorKind::NoRecordsFound { negative_ttl, .. } => Ok(Records {
+                records: Vec::new(),
+                valid_until: Instant::now()
+                    + negative_ttl.map_or(NEGATIVE_TTL, |ttl| Duration::from_secs(ttl.into())),
+            }),
+            _ => Err(error),
+        },
+    }
+}
+
+#[async_trait::async_trait]
+impl Resolve for TokioAsyncResolver {
+    async fn ipv4(&self, name: &str) -> Result<Records<Ipv4Addr>, ResolveError> {
+        records(
+            self.ipv4_lookup(name)
+                .await
+                .map(|r| (r.iter().map(|a| a.0).collect(), r.valid_until())),
+        )
+    }
+
+    async fn ipv6(&self, name: &str) -> Result<Records<Ipv6Addr>, ResolveError> {
+        records(
+            self.ipv6_lookup(name)
+                .await
+                .map(|r| (r.iter().map(|a| a.0).collect(), r.valid_until())),
+        )
+    }
+
+    async fn srv(&self, name: &str) -> Result<Records<SrvTarget>, ResolveError> {
+        records(self.srv_lookup(name).await.map(|r| {
+            let targets = r
+                .iter()
+                .map(|srv| SrvTarget {
+                    priority: srv.priority(),
+                    weight: srv.weight(),
+                    port: srv.port(),
+                    target: srv.target().to_utf8(),
+                })
+                .collect();
+            (targets, r.as_lookup().valid_until())
+        }))
+    }
+}
+
+/// What a discovery loop resolves.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Query {
+    /// Addresses of the hostname, with a fixed port.
+    Host(String, u16, AddressFamily),
+    /// SRV records, with the port and weight of each target.
+    Srv(String, AddressFamily),
+}
+
+impl std::fmt::Display for Query {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Query::Host(hostname, port, family) => {
+                write!(f, "{}:{} ({:?})", hostname, port, family)
+            }
+            Query::Srv(name, family) => write!(f, "SRV {} ({:?})", name, family),
+        }
+    }
+}
+
+/// The discovery service no longer starts discovery loops, after
+/// shutdown.
+#[derive(Debug, thiserror::Error)]
+#[error("DNS discovery service has stopped")]
+pub struct ServiceStopped;
 
 enum Command {
     StartDiscoveryLoop {
-        host_port: (String, u16),
+        query: Query,
         ready_send: watch::Sender<bool>,
         backends_sender: watch::Sender<BTreeSet<Backend>>,
     },
 }
 
 struct HandleState {
-    service_backends: HashMap<(String, u16), Weak<ServiceBackendsHandle>>,
+    service_backends: HashMap<Query, Weak<ServiceBackendsHandle>>,
 }
 
 struct HandleInner {
@@ -49,48 +159,55 @@ impl Handle {
         }))
     }
 
-    pub fn backends_for_hostname(&self, hostname: String, port: u16) -> Arc<ServiceBackendsHandle> {
-        let mut state = self.0.state.lock().unwrap();
+    pub fn backends_for_hostname(
+        &self,
+        hostname: String,
+        port: u16,
+        family: AddressFamily,
+    ) -> Result<Arc<ServiceBackendsHandle>, ServiceStopped> {
+        self.backends_for_query(Query::Host(hostname, port, family))
+    }
+
+    /// Backends for the targets of the SRV records `name`.
+    pub fn backends_for_srv(
+        &self,
+        name: String,
+        family: AddressFamily,
+    ) -> Result<Arc<ServiceBackendsHandle>, ServiceStopped> {
+        self.backends_for_query(Query::Srv(name, family))
+    }
 
-        let host_port = (hostname, port);
+    fn backends_for_query(
+        &self,
+        query: Query,
+    ) -> Result<Arc<ServiceBackendsHandle>, ServiceStopped> {
+        let mut state = self.0.state.lock().unwrap();
 
         if let Some(lb) = state
             .service_backends
-            .get(&host_port)
+            .get(&query)
             .and_then(|lb| lb.upgrade())
         {
-            lb
+            Ok(lb)
         } else {
             let (ready_send, ready_recv) = watch::channel(false);
             let (backends_sender, backends_receiver) = watch::channel(BTreeSet::new());
 
-            let discovery = WatchServiceDiscovery::new(backends_receiver.clone());
-
-            let load_balancer_rr: LoadBalancer<RoundRobin> =
-                LoadBalancer::from_backends(discovery.clone().into_backends());
-            let load_balancer_cons: LoadBalancer<Consistent> =
-                LoadBalancer::from_backends(discovery.clone().into_backends());
-
-            let data = Arc::new(ServiceBackendsHandle {
-                backends: backends_receiver,
-                load_balancer_round_robin: load_balancer_rr,
-                load_balancer_consistent: load_balancer_cons,
-                ready: ready_recv,
-            });
+            let data = Arc::new(ServiceBackendsHandle::new(backends_receiver, ready_recv));
 
-            state
-                .service_backends
-                .insert(host_port.clone(), Arc::downgrade(&data));
+            // The service looks the handle up under the lock, so it
+            // is inserted before the command is handled.
             self.0
                 .service_commands
                 .send(Command::StartDiscoveryLoop {
-                    host_port,
+                    query: query.clone(),
                     ready_send,
                     backends_sender,
                 })
-                .unwrap();
+                .map_err(|_| ServiceStopped)?;
+            state.service_backends.insert(query, Arc::downgrade(&data));
 
-            data
+            Ok(data)
         }
     }
 }
@@ -98,11 +215,12 @@ impl Handle {
 pub struct Service {
     handle: Handle,
     commands: std::sync::Mutex<Option<UnboundedReceiver<Command>>>,
-    resolver: Arc<TokioAsyncResolver>,
+    resolver: Arc<dyn Resolve>,
+    config: DnsConfig,
 }
 
 impl Service {
-    pub fn new(resolver: Arc<TokioAsyncResolver>) -> (Service, Handle) {
+    pub fn new(resolver: Arc<dyn Resolve>, config: DnsConfig) -> (Service, Handle) {
         let (commands_sender, commands_receiver) = unbounded_channel();
         let handle = Handle::new(commands_sender);
         (
@@ -110,67 +228,238 @@ impl Service {
                 handle: handle.clone(),
                 commands: std::sync::Mutex::new(Some(commands_receiver)),
                 resolver,
+                config,
             },
             handle.clone(),
         )
     }
 }
 
-async fn dns_discovery_loop(
-    resolver: Arc<TokioAsyncResolver>,
-    handle: Handle,
-    backends_sender: watch::Sender<BTreeSet<Backend>>,
-    ready_send: watch::Sender<bool>,
-    host_port: (String, u16),
-) {
-    log::info!(
-        "started DNS discovery loop for `{}:{}`",
-        host_port.0,
-        host_port.1
-    );
-
-    let handle_weak = {
-        let state = handle.0.state.lock().unwrap();
-        state.service_backends.get(&host_port).unwrap().clone()
-    };
+fn backend(addr: IpAddr, port: u16, weight: usize) -> Backend {
+    Backend {
+        addr: SocketAddr::Inet(std::net::SocketAddr::new(addr, port)),
+        weight,
+        ext: Extensions::new(),
+    }
+}
 
-    loop {
-        // TODO ready timeout?
-        // This can also be done in receiver.
+/// Looks up the addresses of `name` in `family`, along with the time
+/// the result expires.
+async fn lookup_addrs(
+    resolver: &dyn Resolve,
+    name: &str,
+    family: AddressFamily,
+) -> Result<(Vec<IpAddr>, Instant), ResolveError> {
+    fn v4(records: Records<Ipv4Addr>) -> (Vec<IpAddr>, Instant) {
+        let addrs = records.records.into_iter().map(IpAddr::from).collect();
+        (addrs, records.valid_until)
+    }
+    fn v6(records: Records<Ipv6Addr>) -> (Vec<IpAddr>, Instant) {
+        let addrs = records.records.into_iter().map(IpAddr::from).collect();
+        (addrs, records.valid_until)
+    }
 
-        let response = match resolver.ipv4_lookup(&host_port.0).await {
-            Ok(response) => response,
-            Err(error) => {
-                log::error!(
-                    "error \"{}\" while looking up DNS for host `{}:{}`, retrying..",
-                    error,
-                    host_port.0,
-                    host_port.1
-                );
+    match family {
+        AddressFamily::V4 => Ok(v4(resolver.ipv4(name).await?)),
+        AddressFamily::V6 => Ok(v6(resolver.ipv6(name).await?)),
+        AddressFamily::Both => {
+            let (records_v4, records_v6) = tokio::join!(resolver.ipv4(name), resolver.ipv6(name));
+            let (mut addrs, valid_until_v4) = v4(records_v4?);
+            let (addrs_v6, valid_until_v6) = v6(records_v6?);
+            addrs.extend(addrs_v6);
+            Ok((addrs, valid_until_v4.min(valid_until_v6)))
+        }
+        AddressFamily::PreferV6 => {
+            let (addrs, valid_until_v6) = v6(resolver.ipv6(name).await?);
+            if !addrs.is_empty() {
+                return Ok((addrs, valid_until_v6));
+            }
+            // Checked again when the AAAA result expires, so upstreams
+            // move to IPv6 once it is available.
+            let (addrs, valid_until_v4) = v4(resolver.ipv4(name).await?);
+            Ok((addrs, valid_until_v4.min(valid_until_v6)))
+        }
+    }
+}
+
+/// Resolves `query` into backends, along with the time the result
+/// expires.
+async fn lookup(
+    resolver: &dyn Resolve,
+    query: &Query,
+) -> Result<(BTreeSet<Backend>, Instant), ResolveError> {
+    match query {
+        Query::Host(hostname, port, family) => {
+            let (addrs, valid_until) = lookup_addrs(resolver, hostname, *family).await?;
+            let backends = addrs
+                .into_iter()
+                .map(|addr| backend(addr, *port, 1))
+                .collect();
+            Ok((backends, valid_until))
+        }
+        Query::Srv(name, family) => {
+            let response = resolver.srv(name).await?;
+            let mut valid_until = response.valid_until;
+
+            // Lower priority values are preferred, higher ones are only
+            // for when those are unavailable.
+            let Some(priority) = response.records.iter().map(|srv| srv.priority).min() else {
+                return Ok((BTreeSet::new(), valid_until));
+            };
+            let records: Vec<_> = response
+                .records
+                .iter()
+                .filter(|srv| srv.priority == priority)
+                .collect();
+            let weights = srv_weights(records.iter().map(|srv| srv.weight));
+
+            let mut backends = BTreeSet::new();
+            let mut last_error = None;
+            for (srv, weight) in records.iter().zip(weights) {
+                // Targets are usually in the additional section, and
+                // resolved from the cache.
+                let (addrs, addrs_valid_until) =
+                    match lookup_addrs(resolver, &srv.target, *family).await {
+                        Ok(result) => result,
+                        Err(error) => {
+                            log::warn!(
+                                "skipping SRV target `{}` of `{}`: {}",
+                                srv.target,
+                                name,
+                                error
+                            );
+                            last_error = Some(error);
+                            continue;
+                        }
+                    };
+                valid_until = valid_until.min(addrs_valid_until);
+                backends.extend(addrs.into_iter().map(|ip| backend(ip, srv.port, weight)));
+            }
+            // Keep the previous backends when no target resolves.
+            match last_error {
+                Some(error) if backends.is_empty() => Err(error),
+                _ => Ok((backends, valid_until)),
+            }
+        }
+    }
+}
+
+/// Maps SRV weights onto backend weights. Backend weights are expanded
+/// by the load balancers, so they are scaled down to at most
+/// `MAX_BACKEND_WEIGHT`. A weight of 0 gets the smallest share.
+fn srv_weights(weights: impl Iterator<Item = u16> + Clone) -> impl Iterator<Item = usize> {
+    let max = weights.clone().max().unwrap_or(0) as usize;
+    let divisor = max.max(MAX_BACKEND_WEIGHT);
+    weights.map(move |weight| {
+        (weight as usize * MAX_BACKEND_WEIGHT)
+            .div_ceil(divisor)
+            .max(1)
+    })
+}
+
+/// Exponential backoff with jitter, for retrying failed lookups.
+struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    const INITIAL: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(30);
 
-                if handle_weak.strong_count() == 0 {
-                    // All references to lb gone, we stop refresh loop.
-                    break;
+    fn new() -> Self {
+        Backoff {
+            next: Self::INITIAL,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.next = Self::INITIAL;
+    }
+
+    /// Returns between half and all of the current backoff, and doubles
+    /// it for the next call.
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(Self::MAX);
+        delay / 2 + tokio_retry::strategy::jitter(delay / 2)
+    }
+}
+
+/// Keeps the backends of one query up to date.
+struct DiscoveryLoop {
+    resolver: Arc<dyn Resolve>,
+    config: DnsConfig,
+    query: Query,
+    handle_weak: Weak<ServiceBackendsHandle>,
+    backends_sender: watch::Sender<BTreeSet<Backend>>,
+    ready_send: watch::Sender<bool>,
+}
+
+impl DiscoveryLoop {
+    /// Runs until the backends are no longer used, or on shutdown.
+    async fn run(self: Arc<Self>, mut shutdown: ShutdownWatch) {
+        log::info!("started DNS discovery loop for `{}`", self.query);
+
+        let min_refresh = Duration::from_secs(self.config.min_refresh_seconds);
+        let max_refresh = Duration::from_secs(self.config.max_refresh_seconds);
+        let stale = Duration::from_secs(self.config.stale_seconds);
+
+        let mut backoff = Backoff::new();
+        let mut last_success: Option<tokio::time::Instant> = None;
+
+        while self.handle_weak.strong_count() > 0 {
+            let delay = match lookup(&*self.resolver, &self.query).await {
+                Ok((backends, valid_until)) => {
+                    backoff.reset();
+                    last_success = Some(tokio::time::Instant::now());
+                    self.publish(backends).await;
+                    let _ = self.ready_send.send(true);
+
+                    let ttl = valid_until.saturating_duration_since(Instant::now());
+                    log::debug!(
+                        "got result for DNS `{}` valid for {}s",
+                        self.query,
+                        ttl.as_secs()
+                    );
+
+                    // Zero TTLs would otherwise cause a tight loop.
+                    ttl.clamp(min_refresh, max_refresh)
                 }
+                Err(error) => {
+                    log::error!(
+                        "error \"{}\" while looking up DNS for `{}`, retrying..",
+                        error,
+                        self.query
+                    );
+
+                    // The last good backends are kept through short
+                    // outages of the DNS servers.
+                    if last_success.is_some_and(|t| t.elapsed() > stale) {
+                        log::error!(
+                            "DNS for `{}` failed for over {}s, removing backends",
+                            self.query,
+                            stale.as_secs()
+                        );
+                        self.publish(BTreeSet::new()).await;
+                        last_success = None;
+                    }
+
+                    backoff.next_delay()
+                }
+            };
 
-                // TODO backoff?
-                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-                continue;
+            tokio::select! {
+                _ = shutdown.changed() => break,
+                _ = tokio::time::sleep(delay) => {}
             }
-        };
-
-        let mut backends = BTreeSet::new();
-        for addr in response.iter() {
-            backends.insert(Backend {
-                // TODO port addr
-                addr: SocketAddr::Inet(std::net::SocketAddr::new(addr.0.into(), host_port.1)),
-                weight: 1,
-                ext: Extensions::new(),
-            });
         }
 
+        log::info!("stopped DNS discovery loop for `{}`", self.query);
+    }
+
+    async fn publish(&self, backends: BTreeSet<Backend>) {
         // Send updated backends to watch
-        let has_changed = backends_sender.send_if_modified(|val| {
+        let has_changed = self.backends_sender.send_if_modified(|val| {
             if val != &backends {
                 *val = backends;
                 true
@@ -180,74 +469,422 @@ async fn dns_discovery_loop(
         });
 
         if has_changed {
-            if let Some(lb) = handle_weak.upgrade() {
-                let _ = lb.load_balancer_round_robin.update().await;
-                let _ = lb.load_balancer_consistent.update().await;
+            if let Some(lb) = self.handle_weak.upgrade() {
+                lb.update_load_balancers().await;
             }
         }
+    }
+}
 
-        if handle_weak.strong_count() == 0 {
-            // All references to lb gone, we stop refresh loop.
-            break;
+/// Runs `discovery`, restarting it if it panics.
+async fn supervise(discovery: Arc<DiscoveryLoop>, mut shutdown: ShutdownWatch) {
+    let mut backoff = Backoff::new();
+    loop {
+        match tokio::spawn(discovery.clone().run(shutdown.clone())).await {
+            Ok(()) => return,
+            Err(error) => {
+                log::error!(
+                    "DNS discovery loop for `{}` crashed, restarting: {}",
+                    discovery.query,
+                    error
+                );
+            }
         }
 
-        let _ = ready_send.send(true);
-
-        let valid_sec = response
-            .valid_until()
-            .duration_since(Instant::now())
-            .as_secs();
-        log::debug!(
-            "got result for DNS `{}:{}` valid for {}s",
-            host_port.0,
-            host_port.1,
-            valid_sec
-        );
-
-        // Sleep until TTL expire
-        tokio::time::sleep_until(response.valid_until().into()).await;
+        tokio::select! {
+            _ = shutdown.changed() => return,
+            _ = tokio::time::sleep(backoff.next_delay()) => {}
+        }
     }
-
-    log::info!(
-        "stopped DNS discovery loop for `{}:{}`",
-        host_port.0,
-        host_port.1
-    );
 }
 
 #[async_trait::async_trait]
 impl BackgroundService for Service {
-    async fn start(&self, _shutdown: ShutdownWatch) {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
         let mut commands = self.commands.try_lock().unwrap().take().unwrap();
 
         log::info!("DNS discovery service started");
 
-        // TODO select on shutdown in loop
-        // TODO handle discovery loop panic/shutdown
         loop {
-            // Unwrap is safe since sender is held from handle, will
-            // never go out of scope since it is referenced from `self`.
-            let command = commands.recv().await.unwrap();
+            let command = tokio::select! {
+                _ = shutdown.changed() => break,
+                // Unwrap is safe since sender is held from handle, will
+                // never go out of scope since it is referenced from `self`.
+                command = commands.recv() => command.unwrap(),
+            };
 
             match command {
                 Command::StartDiscoveryLoop {
-                    host_port,
+                    query,
                     ready_send,
                     backends_sender,
                 } => {
-                    let resolver = self.resolver.clone();
-                    let handle = self.handle.clone();
-
-                    // Spawn task to handle the discovery loop
-                    tokio::spawn(dns_discovery_loop(
-                        resolver,
-                        handle,
+                    let handle_weak = {
+                        let state = self.handle.0.state.lock().unwrap();
+                        state.service_backends.get(&query).unwrap().clone()
+                    };
+
+                    let discovery = Arc::new(DiscoveryLoop {
+                        resolver: self.resolver.clone(),
+                        config: self.config.clone(),
+                        query,
+                        handle_weak,
                         backends_sender,
                         ready_send,
-                        host_port,
-                    ));
+                    });
+
+                    // Spawn task to handle the discovery loop
+                    tokio::spawn(supervise(discovery, shutdown.clone()));
+                }
+            }
+        }
+
+        log::info!("DNS discovery service stopped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Resolves from fixed records. Names without records resolve to
+    /// no records, `fail` resolves to an error.
+    #[derive(Default)]
+    struct StubResolver {
+        ipv4: HashMap<&'static str, Vec<Ipv4Addr>>,
+        ipv6: HashMap<&'static str, Vec<Ipv6Addr>>,
+        srv: HashMap<&'static str, Vec<SrvTarget>>,
+    }
+
+    fn stub_records<T: Clone>(
+        records: &HashMap<&'static str, Vec<T>>,
+        name: &str,
+    ) -> Result<Records<T>, ResolveError> {
+        if name == "fail" {
+            return Err("lookup failed".into());
+        }
+        Ok(Records {
+            records: records.get(name).cloned().unwrap_or_default(),
+            valid_until: Instant::now() + Duration::from_secs(60),
+        })
+    }
+
+    #[async_trait::async_trait]
+    impl Resolve for StubResolver {
+        async fn ipv4(&self, name: &str) -> Result<Records<Ipv4Addr>, ResolveError> {
+            stub_records(&self.ipv4, name)
+        }
+
+        async fn ipv6(&self, name: &str) -> Result<Records<Ipv6Addr>, ResolveError> {
+            stub_records(&self.ipv6, name)
+        }
+
+        async fn srv(&self, name: &str) -> Result<Records<SrvTarget>, ResolveError> {
+            stub_records(&self.srv, name)
+        }
+    }
+
+    fn stub_resolver() -> StubResolver {
+        let mut resolver = StubResolver::default();
+        resolver
+            .ipv4
+            .insert("dual", vec![Ipv4Addr::new(10, 0, 0, 1)]);
+        resolver
+            .ipv6
+            .insert("dual", vec!["fd00::1".parse().unwrap()]);
+        resolver
+            .ipv4
+            .insert("v4-only", vec![Ipv4Addr::new(10, 0, 0, 2)]);
+        resolver
+            .ipv6
+            .insert("v6-only", vec!["fd00::2".parse().unwrap()]);
+        resolver.srv.insert(
+            "_grpc._tcp.service",
+            vec![
+                SrvTarget {
+                    priority: 1,
+                    weight: 3,
+                    port: 8001,
+                    target: "dual".into(),
+                },
+                SrvTarget {
+                    priority: 2,
+                    weight: 1,
+                    port: 8002,
+                    target: "v4-only".into(),
+                },
+            ],
+        );
+        resolver.srv.insert(
+            "_grpc._tcp.partial",
+            vec![
+                SrvTarget {
+                    priority: 1,
+                    weight: 1,
+                    port: 8001,
+                    target: "v4-only".into(),
+                },
+                SrvTarget {
+                    priority: 1,
+                    weight: 1,
+                    port: 8002,
+                    target: "fail".into(),
+                },
+            ],
+        );
+        resolver.srv.insert(
+            "_grpc._tcp.broken",
+            vec![SrvTarget {
+                priority: 1,
+                weight: 1,
+                port: 8001,
+                target: "fail".into(),
+            }],
+        );
+        resolver
+    }
+
+    async fn lookup_addrs(query: Query) -> Vec<String> {
+        let (backends, _valid_until) = lookup(&stub_resolver(), &query).await.unwrap();
+        backends
+            .iter()
+            .map(|b| format!("{} {}", b.addr, b.weight))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn looks_up_address_families() {
+        let host = |name: &str, family| Query::Host(name.into(), 80, family);
+
+        assert_eq!(
+            lookup_addrs(host("dual", AddressFamily::V4)).await,
+            vec!["10.0.0.1:80 1"]
+        );
+        assert_eq!(
+            lookup_addrs(host("dual", AddressFamily::V6)).await,
+            vec!["[fd00::1]:80 1"]
+        );
+        assert_eq!(
+            lookup_addrs(host("dual", AddressFamily::Both)).await,
+            vec!["10.0.0.1:80 1", "[fd00::1]:80 1"]
+        );
+        assert_eq!(
+            lookup_addrs(host("dual", AddressFamily::PreferV6)).await,
+            vec!["[fd00::1]:80 1"]
+        );
+        assert_eq!(
+            lookup_addrs(host("v4-only", AddressFamily::PreferV6)).await,
+            vec!["10.0.0.2:80 1"]
+        );
+        assert!(lookup_addrs(host("v6-only", AddressFamily::V4))
+            .await
+            .is_empty());
+
+        let result = lookup(&stub_resolver(), &host("fail", AddressFamily::Both)).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn looks_up_srv_targets() {
+        // Only the lowest priority value is used.
+        assert_eq!(
+            lookup_addrs(Query::Srv("_grpc._tcp.service".into(), AddressFamily::Both)).await,
+            vec!["10.0.0.1:8001 3", "[fd00::1]:8001 3"]
+        );
+
+        // Targets which fail to resolve are skipped.
+        assert_eq!(
+            lookup_addrs(Query::Srv("_grpc._tcp.partial".into(), AddressFamily::V4)).await,
+            vec!["10.0.0.2:8001 1"]
+        );
+
+        // Unless none resolves, then the lookup fails.
+        let query = Query::Srv("_grpc._tcp.broken".into(), AddressFamily::V4);
+        assert!(lookup(&stub_resolver(), &query).await.is_err());
+    }
+
+    #[test]
+    fn fails_after_service_stopped() {
+        let (service, handle) = Service::new(Arc::new(stub_resolver()), DnsConfig::default());
+        assert!(handle
+            .backends_for_hostname("dual".into(), 80, AddressFamily::V4)
+            .is_ok());
+
+        drop(service);
+        assert!(handle
+            .backends_for_hostname("v4-only".into(), 80, AddressFamily::V4)
+            .is_err());
+    }
+
+    #[derive(Clone)]
+    enum Step {
+        /// Resolves to one address, valid for the given seconds.
+        Records(u64),
+        Fail,
+        Panic,
+    }
+
+    /// Resolves IPv4 lookups by following a script. The last step is
+    /// repeated.
+    struct ScriptedResolver {
+        steps: Mutex<Vec<Step>>,
+        calls: AtomicUsize,
+    }
+
+    impl ScriptedResolver {
+        fn new(mut steps: Vec<Step>) -> Arc<Self> {
+            steps.reverse();
+            Arc::new(ScriptedResolver {
+                steps: Mutex::new(steps),
+                calls: AtomicUsize::new(0),
+            })
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Resolve for ScriptedResolver {
+        async fn ipv4(&self, _name: &str) -> Result<Records<Ipv4Addr>, ResolveError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let step = {
+                let mut steps = self.steps.lock().unwrap();
+                if steps.len() > 1 {
+                    steps.pop().unwrap()
+                } else {
+                    steps[0].clone()
                 }
+            };
+            match step {
+                Step::Records(ttl) => Ok(Records {
+                    records: vec![Ipv4Addr::new(10, 0, 0, 1)],
+                    valid_until: Instant::now() + Duration::from_secs(ttl),
+                }),
+                Step::Fail => Err("lookup failed".into()),
+                Step::Panic => panic!("resolver crashed"),
             }
         }
+
+        async fn ipv6(&self, _name: &str) -> Result<Records<Ipv6Addr>, ResolveError> {
+            unimplemented!()
+        }
+
+        async fn srv(&self, _name: &str) -> Result<Records<SrvTarget>, ResolveError> {
+            unimplemented!()
+        }
+    }
+
+    struct LoopTest {
+        resolver: Arc<ScriptedResolver>,
+        handle: Arc<ServiceBackendsHandle>,
+        backends: watch::Receiver<BTreeSet<Backend>>,
+        shutdown: watch::Sender<bool>,
+        task: tokio::task::JoinHandle<()>,
+    }
+
+    fn start_loop(steps: Vec<Step>) -> LoopTest {
+        let resolver = ScriptedResolver::new(steps);
+        let (ready_send, ready_recv) = watch::channel(false);
+        let (backends_sender, backends_recv) = watch::channel(BTreeSet::new());
+        let backends = backends_sender.subscribe();
+        let handle = Arc::new(ServiceBackendsHandle::new(backends_recv, ready_recv));
+        let (shutdown, shutdown_recv) = watch::channel(false);
+
+        let discovery = Arc::new(DiscoveryLoop {
+            resolver: resolver.clone(),
+            config: DnsConfig {
+                min_refresh_seconds: 5,
+                max_refresh_seconds: 300,
+                stale_seconds: 60,
+            },
+            query: Query::Host("scripted".into(), 80, AddressFamily::V4),
+            handle_weak: Arc::downgrade(&handle),
+            backends_sender,
+            ready_send,
+        });
+        let task = tokio::spawn(supervise(discovery, shutdown_recv));
+
+        LoopTest {
+            resolver,
+            handle,
+            backends,
+            shutdown,
+            task,
+        }
+    }
+
+    async fn advance(seconds: u64) {
+        tokio::time::sleep(Duration::from_secs(seconds)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bounds_refresh_interval() {
+        // Zero TTLs are refreshed after the minimum interval.
+        let test = start_loop(vec![Step::Records(0)]);
+        advance(22).await;
+        assert_eq!(test.resolver.calls(), 5);
+
+        // Long TTLs are refreshed after the maximum interval.
+        let test = start_loop(vec![Step::Records(86400)]);
+        advance(301).await;
+        assert_eq!(test.resolver.calls(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn backs_off_on_errors() {
+        let test = start_loop(vec![Step::Fail]);
+        advance(20).await;
+        // Delays of up to 1, 2, 4, 8 and 16 seconds, with jitter.
+        assert!((5..=6).contains(&test.resolver.calls()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_backends_during_outages() {
+        let test = start_loop(vec![Step::Records(10), Step::Fail]);
+        advance(1).await;
+        assert_eq!(test.backends.borrow().len(), 1);
+        assert!(*test.handle.ready.borrow());
+
+        advance(45).await;
+        assert_eq!(test.backends.borrow().len(), 1);
+
+        // Removed after failing for longer than `staleSeconds`.
+        advance(60).await;
+        assert!(test.backends.borrow().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_on_shutdown_and_when_unused() {
+        let test = start_loop(vec![Step::Records(0)]);
+        advance(1).await;
+        test.shutdown.send(true).unwrap();
+        test.task.await.unwrap();
+        assert_eq!(test.resolver.calls(), 1);
+
+        let test = start_loop(vec![Step::Records(0)]);
+        advance(1).await;
+        drop(test.handle);
+        advance(10).await;
+        assert!(test.task.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_crashed_loop() {
+        let test = start_loop(vec![Step::Panic, Step::Records(60)]);
+        advance(2).await;
+        assert_eq!(test.resolver.calls(), 2);
+        assert_eq!(test.backends.borrow().len(), 1);
+    }
+
+    #[test]
+    fn scales_srv_weights() {
+        let weights = |w: &[u16]| srv_weights(w.iter().copied()).collect::<Vec<_>>();
+        assert_eq!(weights(&[0, 0]), vec![1, 1]);
+        assert_eq!(weights(&[10, 30, 0]), vec![10, 30, 1]);
+        assert_eq!(weights(&[65535, 6554, 1]), vec![100, 11, 1]);
     }
 }
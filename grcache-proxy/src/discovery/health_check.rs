@@ -0,0 +1,315 @@
+//! Active health checks of upstreams with the gRPC health checking
+//! protocol.
+//!
+//! Each health checked service gets a handle derived from the handle of
+//! its discovered backends, so services sharing backends keep their own
+//! health state. Unhealthy backends are disabled in the load balancers
+//! of the derived handle, and skipped during selection.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use bytes::{BufMut, Bytes, BytesMut};
+use grcache_shared::config::crd::HealthCheck as HealthCheckSpec;
+use pingora::{
+    connectors::http::Connector, http::RequestHeader, protocols::http::client::HttpSession,
+};
+use pingora_load_balancing::Backend;
+use protobuf::{rt::WireType, CodedInputStream, CodedOutputStream};
+
+use super::ServiceBackendsHandle;
+use crate::{
+    grpc::status::GrpcStatus,
+    tls::{self, UpstreamTls},
+};
+
+const CHECK_PATH: &[u8] = b"/grpc.health.v1.Health/Check";
+
+/// `HealthCheckResponse.ServingStatus.SERVING`
+const SERVING: i32 = 1;
+
+/// Starts health checking the backends of `source` for one service.
+/// The checks stop when the returned handle is dropped.
+pub fn health_checked(
+    source: Arc<ServiceBackendsHandle>,
+    spec: &HealthCheckSpec,
+    upstream_tls: Option<Arc<UpstreamTls>>,
+) -> Arc<ServiceBackendsHandle> {
+    let handle = Arc::new(ServiceBackendsHandle::derived(source));
+    let check = GrpcHealthCheck {
+        service: spec.service.clone(),
+        timeout: Duration::from_millis(spec.timeout_millis),
+        upstream_tls,
+        connector: Connector::new(None),
+    };
+    tokio::spawn(check_loop(Arc::downgrade(&handle), check, spec.clone()));
+    handle
+}
+
+struct GrpcHealthCheck {
+    service: String,
+    timeout: Duration,
+    upstream_tls: Option<Arc<UpstreamTls>>,
+    connector: Connector,
+}
+
+impl GrpcHealthCheck {
+    async fn check(&self, backend: &Backend) -> anyhow::Result<()> {
+        tokio::time::timeout(self.timeout, self.call(backend))
+            .await
+            .context("timed out")?
+    }
+
+    async fn call(&self, backend: &Backend) -> anyhow::Result<()> {
+        let addr = backend.addr.as_inet().context("not an inet address")?;
+        let peer = tls::upstream_peer(*addr, self.upstream_tls.as_deref());
+
+        let (session, _reused) = self.connector.get_http_session(&peer).await?;
+        let HttpSession::H2(mut session) = session else {
+            bail!("upstream does not support HTTP/2");
+        };
+
+        let mut request = RequestHeader::build("POST", CHECK_PATH, None)?;
+        request.insert_header("host", addr.to_string())?;
+        request.insert_header("content-type", "application/grpc")?;
+        request.insert_header("te", "trailers")?;
+        session.write_request_header(Box::new(request), false)?;
+        session.write_request_body(encode_request(&self.service), true)?;
+
+        session.read_response_header().await?;
+        let mut body = BytesMut::new();
+        while let Some(chunk) = session.read_response_body().await? {
+            body.put(chunk);
+        }
+
+        // Errors are usually sent as trailers-only responses.
+        let status = match session.read_trailers().await? {
+            Some(trailers) => GrpcStatus::parse(&trailers),
+            None => GrpcStatus::parse(
+                &session
+                    .response_header()
+                    .context("no response header")?
+                    .headers,
+            ),
+        };
+        // The exchange is complete, the connection is reused by the
+        // next check.
+        self.connector
+            .release_http_session(HttpSession::H2(session), &peer, None)
+            .await;
+        let status = status?;
+        if !status.is_ok() {
+            bail!("health check failed with gRPC status {:?}", status.code());
+        }
+
+        let serving_status = decode_response(&body)?;
+        if serving_status != SERVING {
+            bail!("serving status is {}", serving_status);
+        }
+        Ok(())
+    }
+}
+
+/// Encodes a `HealthCheckRequest` as a gRPC message.
+fn encode_request(service: &str) -> Bytes {
+    let mut message = Vec::new();
+    let mut os = CodedOutputStream::vec(&mut message);
+    os.write_string(1, service).unwrap();
+    os.flush().unwrap();
+    drop(os);
+
+    let mut body = BytesMut::with_capacity(5 + message.len());
+    body.put_u8(0);
+    body.put_u32(message.len() as u32);
+    body.put_slice(&message);
+    body.freeze()
+}
+
+/// Decodes the serving status from a `HealthCheckResponse` message.
+fn decode_response(body: &[u8]) -> anyhow::Result<i32> {
+    let (header, message) = body
+        .split_at_checked(5)
+        .context("truncated health check response")?;
+    if header[0] != 0 {
+        bail!("compressed health check responses are not supported");
+    }
+
+    let mut is = CodedInputStream::from_bytes(message);
+    let mut status = 0;
+    while let Some(tag) = is.read_raw_tag_or_eof()? {
+        let wire_type = WireType::new(tag & 7).context("invalid wire type")?;
+        if tag >> 3 == 1 && wire_type == WireType::Varint {
+            status = is.read_int32()?;
+        } else {
+            is.skip_field(wire_type)?;
+        }
+    }
+    Ok(status)
+}
+
+/// Health of a backend, flipped after a threshold of consecutive checks
+/// with the opposite outcome.
+#[derive(Debug)]
+struct BackendHealth {
+    healthy: bool,
+    consecutive: usize,
+}
+
+impl Default for BackendHealth {
+    /// New backends are used until they fail their checks.
+    fn default() -> Self {
+        BackendHealth {
+            healthy: true,
+            consecutive: 0,
+        }
+    }
+}
+
+impl BackendHealth {
+    /// Returns the new health if it flipped.
+    fn observe(&mut self, success: bool, spec: &HealthCheckSpec) -> Option<bool> {
+        if success == self.healthy {
+            self.consecutive = 0;
+            return None;
+        }
+
+        self.consecutive += 1;
+        let threshold = if success {
+            spec.healthy_threshold
+        } else {
+            spec.unhealthy_threshold
+        };
+        if self.consecutive >= threshold {
+            self.healthy = success;
+            self.consecutive = 0;
+            Some(success)
+        } else {
+            None
+        }
+    }
+}
+
+async fn check_loop(
+    handle_weak: Weak<ServiceBackendsHandle>,
+    check: GrpcHealthCheck,
+    spec: HealthCheckSpec,
+) {
+    let Some(mut backends) = handle_weak.upgrade().map(|h| h.backends.clone()) else {
+        return;
+    };
+    // Static backends never change, and their watch is closed.
+    let mut watching = true;
+    let mut interval = tokio::time::interval(Duration::from_secs(spec.interval_seconds.max(1)));
+    let mut health: BTreeMap<Backend, BackendHealth> = BTreeMap::new();
+
+    loop {
+        let changed = tokio::select! {
+            changed = backends.changed(), if watching => {
+                watching = changed.is_ok();
+                watching
+            }
+            _ = interval.tick() => false,
+        };
+
+        let Some(handle) = handle_weak.upgrade() else {
+            // All references to the handle are gone, stop checking.
+            break;
+        };
+        if changed {
+            handle.update_load_balancers().await;
+        }
+
+        let current = handle.backends.borrow().clone();
+        health.retain(|backend, _| current.contains(backend));
+
+        let results =
+            futures::future::join_all(current.iter().map(|backend| check.check(backend))).await;
+        for (backend, result) in current.iter().zip(results) {
+            let flipped = health
+                .entry(backend.clone())
+                .or_default()
+                .observe(result.is_ok(), &spec);
+            match (flipped, result) {
+                (Some(true), _) => log::info!("upstream {} became healthy", backend.addr),
+                (Some(false), Err(error)) => {
+                    log::warn!("upstream {} became unhealthy: {:#}", backend.addr, error)
+                }
+                _ => continue,
+            }
+            handle.set_available(backend, flipped.unwrap());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use grcache_shared::test::grpc_server::{ok_trailers, MockServer};
+
+    use super::*;
+
+    fn spec() -> HealthCheckSpec {
+        HealthCheckSpec {
+            service: "example.TestService".into(),
+            interval_seconds: 1,
+            timeout_millis: 1000,
+            healthy_threshold: 2,
+            unhealthy_threshold: 1,
+        }
+    }
+
+    #[test]
+    fn flips_health_after_thresholds() {
+        let spec = spec();
+        let mut health = BackendHealth::default();
+        assert_eq!(health.observe(true, &spec), None);
+        assert_eq!(health.observe(false, &spec), Some(false));
+        assert_eq!(health.observe(true, &spec), None);
+        assert_eq!(health.observe(false, &spec), None);
+        assert_eq!(health.observe(true, &spec), None);
+        assert_eq!(health.observe(true, &spec), Some(true));
+    }
+
+    #[tokio::test]
+    async fn skips_unhealthy_backends() {
+        let mut mock_server = MockServer::new().await;
+        for _ in 0..10 {
+            mock_server.expect("grpc.health.v1.Health", "Check", |_parts, body| {
+                assert_eq!(body, encode_request("example.TestService"));
+                // `status: SERVING`
+                (Bytes::from_static(b"\0\0\0\0\x02\x08\x01"), ok_trailers())
+            });
+        }
+
+        // Nothing listens on the port of a dropped listener.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+
+        let (source, _ready_sender, backends_sender) = ServiceBackendsHandle::new_test();
+        let handle = health_checked(source, &spec(), None);
+        backends_sender
+            .send(BTreeSet::from([
+                Backend::new(&mock_server.addr.to_string()).unwrap(),
+                Backend::new(&closed_addr.to_string()).unwrap(),
+            ]))
+            .unwrap();
+
+        let healthy = Backend::new(&mock_server.addr.to_string()).unwrap();
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let selected: BTreeSet<_> = (0..4)
+                .filter_map(|_| handle.load_balancer_round_robin.select(b"", 256))
+                .collect();
+            if selected == BTreeSet::from([healthy.clone()]) {
+                return;
+            }
+        }
+        panic!("unhealthy backend was not skipped");
+    }
+}
@@ -0,0 +1,551 @@
+//! Discovery of upstreams from the `EndpointSlice`s of a Kubernetes
+//! `Service`.
+//!
+//! Unlike DNS, the endpoints are watched, so pods which become ready or
+//! start terminating are picked up as soon as Kubernetes reports them.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    pin::pin,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use futures::{Stream, StreamExt};
+use grcache_shared::resource_change::{resource_changes, ResourceChange};
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use kube::{
+    runtime::{reflector::ObjectRef, watcher, WatchStreamExt as _},
+    Api, Client,
+};
+use pingora::{
+    protocols::l4::socket::SocketAddr, server::ShutdownWatch,
+    services::background::BackgroundService,
+};
+use pingora_load_balancing::{Backend, Extensions};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    watch,
+};
+
+use super::ServiceBackendsHandle;
+
+/// How often a watch loop checks whether its backends are still in use,
+/// when no endpoint changes arrive.
+const UNUSED_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Label set by Kubernetes on the `EndpointSlice`s of a `Service`.
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+
+/// Identifies the backends of a service: namespace, name and port.
+type ServiceKey = (String, String, u16);
+
+/// The discovery service no longer starts watch loops, after
+/// shutdown.
+#[derive(Debug, thiserror::Error)]
+#[error("Kubernetes endpoint discovery service has stopped")]
+pub struct ServiceStopped;
+
+enum Command {
+    StartWatchLoop {
+        key: ServiceKey,
+        ready_send: watch::Sender<bool>,
+        backends_sender: watch::Sender<BTreeSet<Backend>>,
+    },
+}
+
+struct HandleState {
+    service_backends: HashMap<ServiceKey, Weak<ServiceBackendsHandle>>,
+}
+
+struct HandleInner {
+    state: Mutex<HandleState>,
+    service_commands: UnboundedSender<Command>,
+}
+
+#[derive(Clone)]
+pub struct Handle(Arc<HandleInner>);
+
+impl Handle {
+    fn new(service_commands: UnboundedSender<Command>) -> Self {
+        Handle(Arc::new(HandleInner {
+            state: Mutex::new(HandleState {
+                service_backends: HashMap::new(),
+            }),
+            service_commands,
+        }))
+    }
+
+    /// Backends for `port` on the endpoints of the `Service` `name` in
+    /// `namespace`.
+    pub fn backends_for_service(
+        &self,
+        namespace: String,
+        name: String,
+        port: u16,
+    ) -> Result<Arc<ServiceBackendsHandle>, ServiceStopped> {
+        let mut state = self.0.state.lock().unwrap();
+
+        let key = (namespace, name, port);
+
+        if let Some(lb) = state.service_backends.get(&key).and_then(|lb| lb.upgrade()) {
+            Ok(lb)
+        } else {
+            let (ready_send, ready_recv) = watch::channel(false);
+            let (backends_sender, backends_receiver) = watch::channel(BTreeSet::new());
+
+            let data = Arc::new(ServiceBackendsHandle::new(backends_receiver, ready_recv));
+
+            // The watch loop looks the handle up under the lock, so it
+            // is inserted before the command is handled.
+            self.0
+                .service_commands
+                .send(Command::StartWatchLoop {
+                    key: key.clone(),
+                    ready_send,
+                    backends_sender,
+                })
+                .map_err(|_| ServiceStopped)?;
+            state.service_backends.insert(key, Arc::downgrade(&data));
+
+            Ok(data)
+        }
+    }
+}
+
+pub struct Service {
+    handle: Handle,
+    commands: std::sync::Mutex<Option<UnboundedReceiver<Command>>>,
+    zone: Option<String>,
+}
+
+impl Service {
+    /// `zone` is the zone the proxy runs in. When set, topology aware
+    /// routing hints on the endpoints are followed.
+    pub fn new(zone: Option<String>) -> (Service, Handle) {
+        let (commands_sender, commands_receiver) = unbounded_channel();
+        let handle = Handle::new(commands_sender);
+        (
+            Service {
+                handle: handle.clone(),
+                commands: std::sync::Mutex::new(Some(commands_receiver)),
+                zone,
+            },
+            handle,
+        )
+    }
+}
+
+/// The parts of an endpoint relevant for backend selection.
+#[derive(Debug, Clone)]
+struct EndpointState {
+    addresses: Vec<IpAddr>,
+    ready: bool,
+    serving: bool,
+    terminating: bool,
+    /// Zones the endpoint is hinted for, if hints are present.
+    for_zones: Option<Vec<String>>,
+}
+
+fn endpoint_states(slice: &EndpointSlice) -> Vec<EndpointState> {
+    // `FQDN` slices are deprecated and can't be connected to directly.
+    if slice.address_type != "IPv4" && slice.address_type != "IPv6" {
+        return Vec::new();
+    }
+
+    slice
+        .endpoints
+        .iter()
+        .map(|endpoint| {
+            let conditions = endpoint.conditions.clone().unwrap_or_default();
+            // Unknown conditions are to be interpreted as ready.
+            let ready = conditions.ready.unwrap_or(true);
+            EndpointState {
+                addresses: endpoint
+                    .addresses
+                    .iter()
+                    .filter_map(|address| address.parse().ok())
+                    .collect(),
+                ready,
+                serving: conditions.serving.unwrap_or(ready),
+                terminating: conditions.terminating.unwrap_or(false),
+                for_zones: endpoint
+                    .hints
+                    .as_ref()
+                    .and_then(|hints| hints.for_zones.as_ref())
+                    .map(|zones| zones.iter().map(|zone| zone.name.clone()).collect()),
+            }
+        })
+        .collect()
+}
+
+/// Selects the backends to use from all endpoints of a service.
+///
+/// Follows what `kube-proxy` does:
+/// * Ready endpoints are used. If there are none, endpoints which are
+///   terminating but still serving are used, so connections drain
+///   gracefully.
+/// * With `zone` set, if every selected endpoint has zone hints and at
+///   least one is hinted for `zone`, only those are used.
+fn select_backends<'a>(
+    endpoints: impl Iterator<Item = &'a EndpointState> + Clone,
+    zone: Option<&str>,
+    port: u16,
+) -> BTreeSet<Backend> {
+    let mut selected: Vec<_> = endpoints.clone().filter(|e| e.ready).collect();
+    if selected.is_empty() {
+        selected = endpoints.filter(|e| e.serving && e.terminating).collect();
+    }
+
+    if let Some(zone) = zone {
+        let all_hinted = selected.iter().all(|e| e.for_zones.is_some());
+        let in_zone: Vec<_> = selected
+            .iter()
+            .copied()
+            .filter(|e| {
+                e.for_zones
+                    .as_ref()
+                    .is_some_and(|zones| zones.iter().any(|z| z == zone))
+            })
+            .collect();
+        if all_hinted && !in_zone.is_empty() {
+            selected = in_zone;
+        }
+    }
+
+    selected
+        .iter()
+        .flat_map(|e| e.addresses.iter())
+        .map(|addr| Backend {
+            addr: SocketAddr::Inet(std::net::SocketAddr::new(*addr, port)),
+            weight: 1,
+            ext: Extensions::new(),
+        })
+        .collect()
+}
+
+async fn watch_loop(
+    client: Client,
+    handle: Handle,
+    zone: Option<String>,
+    backends_sender: watch::Sender<BTreeSet<Backend>>,
+    ready_send: watch::Sender<bool>,
+    key: ServiceKey,
+    shutdown: ShutdownWatch,
+) {
+    let handle_weak = {
+        let state = handle.0.state.lock().unwrap();
+        state.service_backends.get(&key).unwrap().clone()
+    };
+
+    let api: Api<EndpointSlice> = Api::namespaced(client, &key.0);
+    let watcher_config =
+        watcher::Config::default().labels(&format!("{}={}", SERVICE_NAME_LABEL, key.1));
+    let changes = resource_changes(watcher(api, watcher_config).default_backoff());
+
+    EndpointsWatch {
+        key,
+        zone,
+        handle_weak,
+        backends_sender,
+        ready_send,
+    }
+    .run(changes, shutdown)
+    .await
+}
+
+/// Follows the `EndpointSlice` changes of a service and publishes its
+/// backends.
+struct EndpointsWatch {
+    key: ServiceKey,
+    zone: Option<String>,
+    handle_weak: Weak<ServiceBackendsHandle>,
+    backends_sender: watch::Sender<BTreeSet<Backend>>,
+    ready_send: watch::Sender<bool>,
+}
+
+impl EndpointsWatch {
+    /// Runs until the backends are no longer used, or on shutdown.
+    async fn run(
+        self,
+        changes: impl Stream<Item = Result<ResourceChange<EndpointSlice>, watcher::Error>>,
+        mut shutdown: ShutdownWatch,
+    ) {
+        let (namespace, name, port) = &self.key;
+        log::info!(
+            "started endpoint watch loop for service `{}/{}` port {}",
+            namespace,
+            name,
+            port
+        );
+
+        let mut changes = pin!(changes);
+        let mut slices: HashMap<ObjectRef<EndpointSlice>, Vec<EndpointState>> = HashMap::new();
+        let mut ready = false;
+
+        loop {
+            let change = tokio::select! {
+                _ = shutdown.changed() => break,
+                change = changes.next() => change,
+                _ = tokio::time::sleep(UNUSED_CHECK_INTERVAL) => None,
+            };
+
+            if self.handle_weak.strong_count() == 0 {
+                // All references to lb gone, we stop the watch loop.
+                break;
+            }
+
+            match change {
+                Some(Ok(ResourceChange::Ready)) => ready = true,
+                Some(Ok(ResourceChange::Apply(slice))) => {
+                    slices.insert(ObjectRef::from_obj(&slice), endpoint_states(&slice));
+                }
+                Some(Ok(ResourceChange::Delete(slice_ref))) => {
+                    slices.remove(&slice_ref);
+                }
+                Some(Err(error)) => {
+                    log::error!(
+                        "error \"{}\" while watching endpoints for service `{}/{}`, retrying..",
+                        error,
+                        namespace,
+                        name
+                    );
+                    continue;
+                }
+                None => continue,
+            }
+
+            // Backends are only published once the initial list is
+            // complete, so a partial list never replaces a full one.
+            if !ready {
+                continue;
+            }
+
+            let backends = select_backends(slices.values().flatten(), self.zone.as_deref(), *port);
+            let has_changed = self.backends_sender.send_if_modified(|val| {
+                if val != &backends {
+                    *val = backends;
+                    true
+                } else {
+                    false
+                }
+            });
+
+            if has_changed {
+                log::debug!(
+                    "endpoints for service `{}/{}` changed, {} backends",
+                    namespace,
+                    name,
+                    self.backends_sender.borrow().len()
+                );
+                if let Some(lb) = self.handle_weak.upgrade() {
+                    lb.update_load_balancers().await;
+                }
+            }
+
+            let _ = self.ready_send.send(true);
+        }
+
+        log::info!(
+            "stopped endpoint watch loop for service `{}/{}` port {}",
+            namespace,
+            name,
+            port
+        );
+    }
+}
+
+#[async_trait::async_trait]
+impl BackgroundService for Service {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut commands = self.commands.try_lock().unwrap().take().unwrap();
+
+        let client = Client::try_default()
+            .await
+            .expect("failed to create kubernetes client");
+
+        log::info!("Kubernetes endpoint discovery service started");
+
+        loop {
+            let command = tokio::select! {
+                _ = shutdown.changed() => break,
+                // Unwrap is safe since sender is held from handle, will
+                // never go out of scope since it is referenced from `self`.
+                command = commands.recv() => command.unwrap(),
+            };
+
+            match command {
+                Command::StartWatchLoop {
+                    key,
+                    ready_send,
+                    backends_sender,
+                } => {
+                    tokio::spawn(watch_loop(
+                        client.clone(),
+                        self.handle.clone(),
+                        self.zone.clone(),
+                        backends_sender,
+                        ready_send,
+                        key,
+                        shutdown.clone(),
+                    ));
+                }
+            }
+        }
+
+        log::info!("Kubernetes endpoint discovery service stopped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc;
+    use k8s_openapi::{
+        api::discovery::v1::{Endpoint, EndpointConditions},
+        apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    };
+
+    use super::*;
+
+    fn endpoint(addr: &str, ready: bool, terminating: bool, zones: &[&str]) -> EndpointState {
+        EndpointState {
+            addresses: vec![addr.parse().unwrap()],
+            ready,
+            serving: true,
+            terminating,
+            for_zones: (!zones.is_empty()).then(|| zones.iter().map(|z| z.to_string()).collect()),
+        }
+    }
+
+    fn addrs(backends: BTreeSet<Backend>) -> Vec<String> {
+        backends.iter().map(|b| b.addr.to_string()).collect()
+    }
+
+    #[test]
+    fn selects_ready_endpoints() {
+        let endpoints = [
+            endpoint("10.0.0.1", true, false, &[]),
+            endpoint("10.0.0.2", false, true, &[]),
+        ];
+        assert_eq!(
+            addrs(select_backends(endpoints.iter(), None, 50051)),
+            vec!["10.0.0.1:50051"]
+        );
+
+        // Terminating endpoints are only used while nothing is ready.
+        assert_eq!(
+            addrs(select_backends(endpoints[1..].iter(), None, 50051)),
+            vec!["10.0.0.2:50051"]
+        );
+    }
+
+    #[test]
+    fn follows_zone_hints() {
+        let endpoints = [
+            endpoint("10.0.0.1", true, false, &["a"]),
+            endpoint("10.0.0.2", true, false, &["b"]),
+            endpoint("fd00::3", true, false, &["a"]),
+        ];
+        assert_eq!(
+            addrs(select_backends(endpoints.iter(), Some("a"), 80)),
+            vec!["10.0.0.1:80", "[fd00::3]:80"]
+        );
+        // No endpoints for the zone, hints are ignored.
+        assert_eq!(select_backends(endpoints.iter(), Some("c"), 80).len(), 3);
+
+        // Hints are ignored unless every endpoint has them.
+        let endpoints = [
+            endpoint("10.0.0.1", true, false, &["a"]),
+            endpoint("10.0.0.2", true, false, &[]),
+        ];
+        assert_eq!(select_backends(endpoints.iter(), Some("a"), 80).len(), 2);
+    }
+
+    fn slice(name: &str, endpoints: &[(&str, bool)]) -> EndpointSlice {
+        EndpointSlice {
+            metadata: ObjectMeta {
+                name: Some(name.into()),
+                namespace: Some("default".into()),
+                ..Default::default()
+            },
+            address_type: "IPv4".into(),
+            endpoints: endpoints
+                .iter()
+                .map(|(addr, ready)| Endpoint {
+                    addresses: vec![addr.to_string()],
+                    conditions: Some(EndpointConditions {
+                        ready: Some(*ready),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .collect(),
+            ports: None,
+        }
+    }
+
+    async fn next_backends(backends: &mut watch::Receiver<BTreeSet<Backend>>) -> Vec<String> {
+        tokio::time::timeout(Duration::from_secs(5), backends.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        addrs(backends.borrow_and_update().clone())
+    }
+
+    #[tokio::test]
+    async fn follows_slice_updates_and_removals() {
+        let (ready_send, ready_recv) = watch::channel(false);
+        let (backends_sender, backends_recv) = watch::channel(BTreeSet::new());
+        let mut backends = backends_sender.subscribe();
+        let handle = Arc::new(ServiceBackendsHandle::new(backends_recv, ready_recv));
+        let (shutdown, shutdown_recv) = watch::channel(false);
+        let (changes, changes_recv) = mpsc::unbounded();
+
+        let task = tokio::spawn(
+            EndpointsWatch {
+                key: ("default".into(), "svc".into(), 80),
+                zone: None,
+                handle_weak: Arc::downgrade(&handle),
+                backends_sender,
+                ready_send,
+            }
+            .run(changes_recv, shutdown_recv),
+        );
+
+        let send = |change| changes.unbounded_send(Ok(change)).unwrap();
+
+        // Nothing is published before the initial list is complete.
+        send(ResourceChange::Apply(slice("a", &[("10.0.0.1", true)])));
+        send(ResourceChange::Apply(slice("b", &[("10.0.0.2", true)])));
+        send(ResourceChange::Ready);
+        assert_eq!(
+            next_backends(&mut backends).await,
+            vec!["10.0.0.1:80", "10.0.0.2:80"]
+        );
+
+        // An endpoint becoming unready is dropped.
+        send(ResourceChange::Apply(slice(
+            "a",
+            &[("10.0.0.1", false), ("10.0.0.3", true)],
+        )));
+        assert_eq!(
+            next_backends(&mut backends).await,
+            vec!["10.0.0.2:80", "10.0.0.3:80"]
+        );
+        assert!(*handle.ready.borrow());
+
+        // Removed slices take their endpoints with them.
+        send(ResourceChange::Delete(ObjectRef::from_obj(&slice(
+            "b",
+            &[],
+        ))));
+        assert_eq!(next_backends(&mut backends).await, vec!["10.0.0.3:80"]);
+
+        shutdown.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -9,7 +9,15 @@ use pingora_load_balancing::{
 };
 use tokio::sync::watch;
 
+pub mod balancer;
 pub mod dns;
+pub mod health_check;
+pub mod kubernetes;
+pub mod outlier;
+
+/// Upper bound for `Backend::weight`. The load balancers expand each
+/// backend by its weight, so large weights are expensive.
+pub const MAX_BACKEND_WEIGHT: usize = 100;
 
 /// Handle to actively maintained set of backends for a service
 /// maintained through a service discovery mechanism.
@@ -28,9 +36,62 @@ pub struct ServiceBackendsHandle {
     /// Once it has transitioned into `true`, it will never go back
     /// to false.
     pub ready: watch::Receiver<bool>,
+    /// Handle the backends are taken from, for handles created with
+    /// `derived`. Kept alive so its discovery continues.
+    _source: Option<Arc<ServiceBackendsHandle>>,
 }
 
 impl ServiceBackendsHandle {
+    /// Creates a handle with load balancers over the backends in the
+    /// `backends` watch. `update_load_balancers` must be called after
+    /// the backends change.
+    fn new(backends: watch::Receiver<BTreeSet<Backend>>, ready: watch::Receiver<bool>) -> Self {
+        let discovery = WatchServiceDiscovery::new(backends.clone());
+        ServiceBackendsHandle {
+            backends,
+            load_balancer_round_robin: LoadBalancer::from_backends(
+                discovery.clone().into_backends(),
+            ),
+            load_balancer_consistent: LoadBalancer::from_backends(discovery.into_backends()),
+            ready,
+            _source: None,
+        }
+    }
+
+    /// Creates a handle over the backends of `source`, with load
+    /// balancers of its own. Discovered backends are shared between
+    /// services, this allows per-service health state for them.
+    fn derived(source: Arc<ServiceBackendsHandle>) -> Self {
+        let mut handle = ServiceBackendsHandle::new(source.backends.clone(), source.ready.clone());
+        handle._source = Some(source);
+        handle
+    }
+
+    /// Sets whether `backend` may be selected by the load balancers.
+    /// This is reflected in the health passed to `select_with`.
+    pub fn set_available(&self, backend: &Backend, available: bool) {
+        self.load_balancer_round_robin
+            .backends()
+            .set_enable(backend, available);
+        self.load_balancer_consistent
+            .backends()
+            .set_enable(backend, available);
+    }
+
+    /// Picks up changes to the backends in the load balancers.
+    pub async fn update_load_balancers(&self) {
+        let _ = self.load_balancer_round_robin.update().await;
+        let _ = self.load_balancer_consistent.update().await;
+    }
+
+    /// Creates a handle for a fixed set of backends. The load balancers
+    /// are empty until `update_load_balancers` is called.
+    pub fn new_static(backends: BTreeSet<Backend>) -> Self {
+        let (_backends_sender, backends_receiver) = watch::channel(backends);
+        let (_ready_sender, ready_receiver) = watch::channel(true);
+        ServiceBackendsHandle::new(backends_receiver, ready_receiver)
+    }
+
     pub fn new_test() -> (
         Arc<Self>,
         watch::Sender<bool>,
@@ -38,17 +99,7 @@ impl ServiceBackendsHandle {
     ) {
         let (backend_sender, backend_receiver) = watch::channel(BTreeSet::new());
         let (ready_sender, ready_receiver) = watch::channel(true);
-        let discovery = WatchServiceDiscovery::new(backend_receiver.clone());
-        let handle = ServiceBackendsHandle {
-            backends: backend_receiver,
-            load_balancer_round_robin: LoadBalancer::from_backends(
-                discovery.clone().into_backends(),
-            ),
-            load_balancer_consistent: LoadBalancer::from_backends(
-                discovery.clone().into_backends(),
-            ),
-            ready: ready_receiver,
-        };
+        let handle = ServiceBackendsHandle::new(backend_receiver, ready_receiver);
         (Arc::new(handle), ready_sender, backend_sender)
     }
 }
@@ -0,0 +1,233 @@
+//! Passive outlier detection.
+//!
+//! Backends are ejected from selection after a number of consecutive
+//! failed requests, similar to Envoy outlier detection. Each ejection
+//! of a backend lasts twice as long as the previous one, up to a
+//! maximum, and only a share of the backends can be ejected at once.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use grcache_shared::config::crd::OutlierDetection;
+use pingora_load_balancing::Backend;
+
+#[derive(Debug, Default)]
+struct BackendState {
+    consecutive_failures: u32,
+    /// Ejections in a row, without a quiet period in between.
+    ejections: u32,
+    ejected_until: Option<Instant>,
+}
+
+pub struct OutlierDetector {
+    /// Named in logs and metrics.
+    name: String,
+    config: OutlierDetection,
+    backends: Mutex<HashMap<Backend, BackendState>>,
+}
+
+impl OutlierDetector {
+    pub fn new(name: String, config: OutlierDetection) -> Self {
+        OutlierDetector {
+            name,
+            config,
+            backends: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &OutlierDetection {
+        &self.config
+    }
+
+    /// Whether `backend` is currently ejected and should not be
+    /// selected.
+    pub fn is_ejected(&self, backend: &Backend) -> bool {
+        self.is_ejected_at(backend, Instant::now())
+    }
+
+    /// Records the outcome of a request to `backend`. `backends` are
+    /// all current backends, they bound how many can be ejected. State
+    /// of backends no longer among them is dropped.
+    pub fn record(&self, backend: &Backend, success: bool, backends: &BTreeSet<Backend>) {
+        self.record_at(backend, success, backends, Instant::now());
+    }
+
+    fn is_ejected_at(&self, backend: &Backend, now: Instant) -> bool {
+        self.backends
+            .lock()
+            .unwrap()
+            .get(backend)
+            .and_then(|state| state.ejected_until)
+            .is_some_and(|until| until > now)
+    }
+
+    fn record_at(
+        &self,
+        backend: &Backend,
+        success: bool,
+        current: &BTreeSet<Backend>,
+        now: Instant,
+    ) {
+        let base = Duration::from_secs(self.config.base_ejection_seconds);
+        let max = Duration::from_secs(self.config.max_ejection_seconds);
+
+        let mut backends = self.backends.lock().unwrap();
+
+        if success {
+            // Backends are forgotten once they have been healthy for
+            // the maximum ejection time, which also resets the
+            // ejection time.
+            if let Some(state) = backends.get_mut(backend) {
+                state.consecutive_failures = 0;
+                if state.ejected_until.is_none_or(|until| until + max <= now) {
+                    backends.remove(backend);
+                }
+            }
+            return;
+        }
+
+        // Only failures add state, so pruning here bounds it.
+        backends.retain(|backend, _| current.contains(backend));
+
+        let total_backends = current.len();
+        let ejected = backends
+            .values()
+            .filter(|state| state.ejected_until.is_some_and(|until| until > now))
+            .count();
+        // At least one backend can be ejected, but never all of them.
+        let max_ejected = (total_backends * self.config.max_ejection_percent as usize / 100)
+            .max(1)
+            .min(total_backends.saturating_sub(1));
+
+        let state = backends.entry(backend.clone()).or_default();
+        if state.ejected_until.is_some_and(|until| until > now) {
+            return;
+        }
+        state.consecutive_failures += 1;
+        if state.consecutive_failures < self.config.consecutive_failures || ejected >= max_ejected {
+            return;
+        }
+
+        state.consecutive_failures = 0;
+        state.ejections += 1;
+        let duration = base
+            .saturating_mul(2u32.saturating_pow(state.ejections - 1))
+            .min(max);
+        state.ejected_until = Some(now + duration);
+
+        log::warn!(
+            "ejecting {} upstream {} for {}s",
+            self.name,
+            backend.addr,
+            duration.as_secs()
+        );
+        crate::metrics::record_ejection(&self.name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_detector() -> OutlierDetector {
+        OutlierDetector::new(
+            "test".into(),
+            OutlierDetection {
+                consecutive_failures: 2,
+                base_ejection_seconds: 10,
+                max_ejection_seconds: 25,
+                max_ejection_percent: 50,
+            },
+        )
+    }
+
+    fn backends(count: usize) -> BTreeSet<Backend> {
+        (1..=count)
+            .map(|i| Backend::new(&format!("10.0.0.{}:80", i)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn ejects_with_exponential_times() {
+        let detector = test_detector();
+        let all = backends(4);
+        let backend = all.first().unwrap().clone();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        detector.record_at(&backend, false, &all, at(0));
+        detector.record_at(&backend, true, &all, at(0));
+        detector.record_at(&backend, false, &all, at(0));
+        assert!(!detector.is_ejected_at(&backend, at(0)));
+
+        detector.record_at(&backend, false, &all, at(0));
+        assert!(detector.is_ejected_at(&backend, at(9)));
+        assert!(!detector.is_ejected_at(&backend, at(10)));
+
+        // Ejected again shortly after, for twice as long.
+        detector.record_at(&backend, false, &all, at(10));
+        detector.record_at(&backend, false, &all, at(10));
+        assert!(detector.is_ejected_at(&backend, at(29)));
+        assert!(!detector.is_ejected_at(&backend, at(30)));
+
+        // Up to the maximum.
+        detector.record_at(&backend, false, &all, at(30));
+        detector.record_at(&backend, false, &all, at(30));
+        assert!(detector.is_ejected_at(&backend, at(54)));
+        assert!(!detector.is_ejected_at(&backend, at(55)));
+
+        // Healthy for the maximum ejection time resets it.
+        detector.record_at(&backend, true, &all, at(80));
+        detector.record_at(&backend, false, &all, at(80));
+        detector.record_at(&backend, false, &all, at(80));
+        assert!(detector.is_ejected_at(&backend, at(89)));
+        assert!(!detector.is_ejected_at(&backend, at(90)));
+    }
+
+    #[test]
+    fn limits_ejected_share() {
+        let detector = test_detector();
+        let all = backends(4);
+        let now = Instant::now();
+
+        for backend in &all {
+            detector.record_at(backend, false, &all, now);
+            detector.record_at(backend, false, &all, now);
+        }
+        let ejected = all
+            .iter()
+            .filter(|b| detector.is_ejected_at(b, now))
+            .count();
+        assert_eq!(ejected, 2);
+
+        // The only backend is never ejected.
+        let detector = test_detector();
+        let only = backends(1);
+        let backend = only.first().unwrap();
+        detector.record_at(backend, false, &only, now);
+        detector.record_at(backend, false, &only, now);
+        assert!(!detector.is_ejected_at(backend, now));
+    }
+
+    #[test]
+    fn forgets_removed_backends() {
+        let detector = test_detector();
+        let mut all = backends(4);
+        let now = Instant::now();
+
+        let removed = all.pop_first().unwrap();
+        detector.record_at(&removed, false, &backends(4), now);
+        detector.record_at(&removed, false, &backends(4), now);
+        assert!(detector.is_ejected_at(&removed, now));
+
+        // Ejections of removed backends no longer count against the
+        // share of the remaining ones.
+        let next = all.first().unwrap().clone();
+        detector.record_at(&next, false, &all, now);
+        assert!(!detector.backends.lock().unwrap().contains_key(&removed));
+        assert_eq!(detector.backends.lock().unwrap().len(), 1);
+    }
+}
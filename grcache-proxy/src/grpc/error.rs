@@ -0,0 +1,168 @@
+use http::HeaderMap;
+use pingora::{
+    http::ResponseHeader, protocols::http::ServerSession, BError, ConnectionClosed, ErrorSource,
+    ErrorType, ImmutStr, ReadError, WriteError,
+};
+
+use super::status::GrpcCode;
+
+/// Name of the `ErrorType::CustomCode` used to carry a gRPC status
+/// code through a `pingora::Error`.
+const GRPC_ERROR_NAME: &str = "gRPC status";
+
+/// Creates a proxy error which will be returned to the client as a
+/// gRPC status with the given code and message.
+pub fn grpc_error(code: GrpcCode, message: impl Into<ImmutStr>) -> BError {
+    pingora::Error::explain(ErrorType::CustomCode(GRPC_ERROR_NAME, code as u16), message)
+}
+
+/// Same as `grpc_error`, but with an underlying cause.
+pub fn grpc_error_because(
+    code: GrpcCode,
+    message: impl Into<ImmutStr>,
+    cause: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> BError {
+    pingora::Error::because(
+        ErrorType::CustomCode(GRPC_ERROR_NAME, code as u16),
+        message,
+        cause,
+    )
+}
+
+/// Decides which gRPC status code a proxy error should be reported
+/// to the client as.
+///
+/// Returns `None` when the downstream connection is already dead and
+/// nothing can be sent.
+pub fn code_for_error(error: &pingora::Error) -> Option<GrpcCode> {
+    let code = match error.etype() {
+        ErrorType::CustomCode(GRPC_ERROR_NAME, code) => {
+            GrpcCode::from_u8(*code as u8).unwrap_or(GrpcCode::Unknown)
+        }
+        ErrorType::HTTPStatus(status) => GrpcCode::from_http_status(*status),
+        ErrorType::InvalidHTTPHeader => GrpcCode::InvalidArgument,
+        _ => match error.esource() {
+            ErrorSource::Upstream => GrpcCode::Unavailable,
+            ErrorSource::Downstream => match error.etype() {
+                WriteError | ReadError | ConnectionClosed => return None,
+                _ => GrpcCode::InvalidArgument,
+            },
+            ErrorSource::Internal | ErrorSource::Unset => GrpcCode::Internal,
+        },
+    };
+    Some(code)
+}
+
+/// The message returned to the client in `grpc-message`.
+pub fn message_for_error(error: &pingora::Error) -> String {
+    match &error.context {
+        Some(context) => context.as_str().to_owned(),
+        None => error.etype().as_str().to_owned(),
+    }
+}
+
+/// Percent encodes a `grpc-message` value as required by the spec.
+/// Only printable ASCII except `%` may be sent as is.
+fn encode_grpc_message(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        if (0x20..=0x7e).contains(&byte) && byte != b'%' {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    out
+}
+
+/// Decodes a percent encoded `grpc-message` value.
+/// Invalid escapes are kept as is.
+pub fn decode_grpc_message(message: &[u8]) -> String {
+    let mut out = Vec::with_capacity(message.len());
+    let mut idx = 0;
+    while idx < message.len() {
+        let escaped = message
+            .get(idx + 1..idx + 3)
+            .filter(|_| message[idx] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                idx += 3;
+            }
+            None => {
+                out.push(message[idx]);
+                idx += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Builds the headers for a gRPC "Trailers-Only" response.
+/// The status is carried in the headers, and the stream is expected to
+/// end immediately after.
+pub fn trailers_only_response(
+    code: GrpcCode,
+    message: &str,
+    content_type: &'static str,
+) -> pingora::Result<ResponseHeader> {
+    let mut resp = ResponseHeader::build(200, Some(3))?;
+    resp.insert_header("content-type", content_type)?;
+    resp.insert_header("grpc-status", (code as u8).to_string())?;
+    if !message.is_empty() {
+        resp.insert_header("grpc-message", encode_grpc_message(message))?;
+    }
+    Ok(resp)
+}
+
+/// Responds to the client with a "Trailers-Only" gRPC response,
+/// carrying `metadata` along with the status.
+pub async fn respond_grpc_error(
+    session: &mut ServerSession,
+    code: GrpcCode,
+    message: &str,
+    content_type: &'static str,
+    metadata: &HeaderMap,
+) -> pingora::Result<()> {
+    let mut resp = trailers_only_response(code, message, content_type)?;
+    for (name, value) in metadata {
+        resp.append_header(name.clone(), value.clone())?;
+    }
+    let resp = Box::new(resp);
+    match session {
+        ServerSession::H1(_) => {
+            session.write_response_header(resp).await?;
+            session.finish_body().await
+        }
+        ServerSession::H2(h2) => h2.write_response_header(resp, true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{code_for_error, decode_grpc_message, encode_grpc_message, grpc_error};
+    use crate::grpc::status::GrpcCode;
+
+    #[test]
+    fn grpc_error_roundtrips_code() {
+        let error = grpc_error(GrpcCode::ResourceExhausted, "too large");
+        assert_eq!(code_for_error(&error), Some(GrpcCode::ResourceExhausted));
+    }
+
+    #[test]
+    fn http_status_errors_are_mapped() {
+        let error = pingora::Error::explain(pingora::ErrorType::HTTPStatus(502), "bad gateway");
+        assert_eq!(code_for_error(&error), Some(GrpcCode::Unavailable));
+    }
+
+    #[test]
+    fn grpc_message_is_percent_encoded() {
+        assert_eq!(encode_grpc_message("a b"), "a b");
+        assert_eq!(encode_grpc_message("100%"), "100%25");
+        assert_eq!(encode_grpc_message("ø"), "%C3%B8");
+        assert_eq!(decode_grpc_message(b"100%25 %C3%B8"), "100% ø");
+        assert_eq!(decode_grpc_message(b"bad %zz"), "bad %zz");
+    }
+}
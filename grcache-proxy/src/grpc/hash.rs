@@ -7,6 +7,26 @@ use pingora::http::RequestHeader;
 
 use crate::proxy::Blake2b128;
 
+/// Keeps the entries of different tenants apart. Nothing is hashed
+/// for requests without a tenant.
+pub fn hash_tenant(hasher: &mut Blake2b128, tenant: Option<&str>) {
+    if let Some(tenant) = tenant {
+        hasher.update(b"tenant\0");
+        hasher.update(tenant.len().to_le_bytes());
+        hasher.update(tenant.as_bytes());
+    }
+}
+
+/// Keeps the entries of named upstreams apart from each other and from
+/// the upstream without a name, so canaries do not share entries.
+pub fn hash_upstream(hasher: &mut Blake2b128, upstream: Option<&str>) {
+    if let Some(upstream) = upstream {
+        hasher.update(b"upstream\0");
+        hasher.update(upstream.len().to_le_bytes());
+        hasher.update(upstream.as_bytes());
+    }
+}
+
 pub fn hash_vary(hasher: &mut Blake2b128, vary_set: &BTreeSet<String>, headers: &HeaderMap) {
     let mut vary_headers: Vec<_> = headers.get_all("vary").iter().collect();
     vary_headers.sort();
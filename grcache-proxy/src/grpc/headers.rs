@@ -2,6 +2,7 @@ use std::collections::BTreeSet;
 
 use http::{header::ToStrError, HeaderMap};
 use phf::phf_set;
+use pingora::http::RequestHeader;
 
 /// Takes a HeaderMap with, pulls, cleans and splits `vary` headers,
 /// and outputs a `BTreeSet`.
@@ -11,7 +12,7 @@ pub fn make_vary_headers_set(headers: &HeaderMap) -> Result<BTreeSet<String>, pi
         .iter()
         .map(|v| v.to_str())
         .flat_map(|v| v.map(|i| i.split(",")).into_iter().flatten().map(Ok))
-        .map(|v| v.map(|v| v.trim().to_string()))
+        .map(|v| v.map(|v| v.trim().to_ascii_lowercase()))
         .collect();
 
     vary_set.map_err(|e| {
@@ -23,6 +24,16 @@ pub fn make_vary_headers_set(headers: &HeaderMap) -> Result<BTreeSet<String>, pi
     })
 }
 
+/// Normalizes header names from config into a `BTreeSet`.
+pub fn make_header_names_set<'a>(
+    headers: impl IntoIterator<Item = &'a String>,
+) -> BTreeSet<String> {
+    headers
+        .into_iter()
+        .map(|header| header.trim().to_ascii_lowercase())
+        .collect()
+}
+
 static CORE_HEADERS: phf::Set<&'static str> = phf_set! {
     "host",
     "content-type",
@@ -57,3 +68,66 @@ pub fn find_strip_headers(
         })
         .collect()
 }
+
+/// Rewrites the header of a request translated from another protocol
+/// (gRPC-Web, JSON) into a native gRPC request header for the upstream.
+pub fn native_grpc_request_header(req: &mut RequestHeader) -> pingora::Result<()> {
+    req.insert_header(http::header::CONTENT_TYPE, "application/grpc")?;
+    // Required by gRPC over HTTP/2, used to detect incompatible
+    // proxies.
+    req.insert_header(http::header::TE, "trailers")?;
+    // The body may change size when translated.
+    req.remove_header(&http::header::CONTENT_LENGTH);
+    // gRPC signals end of request with END_STREAM on the last data
+    // frame, never on the headers.
+    req.set_send_end_stream(false);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(headers: &[&str]) -> BTreeSet<String> {
+        headers.iter().map(|h| h.to_string()).collect()
+    }
+
+    #[test]
+    fn classifies_headers() {
+        let mut headers = HeaderMap::new();
+        for name in [
+            "content-type",
+            "te",
+            "x-vary",
+            "x-propagate",
+            "authorization",
+            "cookie",
+        ] {
+            headers.insert(name, "value".parse().unwrap());
+        }
+
+        let mut strip = find_strip_headers(&headers, &set(&["x-vary"]), &set(&["x-propagate"]));
+        strip.sort();
+        assert_eq!(strip, vec!["authorization", "cookie"]);
+    }
+
+    #[test]
+    fn normalizes_header_names() {
+        let headers = [" X-Request-Id".to_string(), "traceparent".to_string()];
+        assert_eq!(
+            make_header_names_set(&headers),
+            set(&["traceparent", "x-request-id"])
+        );
+    }
+
+    #[test]
+    fn splits_vary_headers() {
+        let mut headers = HeaderMap::new();
+        headers.append("vary", "x-a, X-B".parse().unwrap());
+        headers.append("vary", "x-c".parse().unwrap());
+        assert_eq!(
+            make_vary_headers_set(&headers).unwrap(),
+            set(&["x-a", "x-b", "x-c"])
+        );
+    }
+}
@@ -0,0 +1,337 @@
+//! HTTP/JSON front door for gRPC services.
+//!
+//! `POST /package.Service/Method` with a JSON body is transcoded into a
+//! native gRPC request using the method descriptors from the service's
+//! `ServiceSpec`, and the response message is transcoded back into
+//! JSON. Like with gRPC-Web, only the downstream facing side of a
+//! request is translated, so JSON clients share cache entries with
+//! native gRPC clients.
+//!
+//! Only unary methods are supported, and requests must use the gRPC
+//! path. `google.api.http` annotation routes are not supported.
+//!
+//! Errors are returned as `{"code": <grpc code>, "message": "..."}`
+//! with a HTTP status mapped from the gRPC status. When the upstream
+//! fails after it has started sending the response, the HTTP status is
+//! already sent and only the body reflects the error.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::HeaderMap;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    protocols::http::ServerSession,
+};
+use protobuf::reflect::{MessageDescriptor, MethodDescriptor};
+
+use super::{
+    error::{decode_grpc_message, grpc_error, grpc_error_because},
+    status::{GrpcCode, GrpcStatus},
+};
+
+const JSON: &str = "application/json";
+
+/// JSON request bodies are buffered in full before being transcoded.
+const MAX_REQUEST_SIZE: usize = 4 * 1024 * 1024;
+
+/// Size of the gRPC length prefixed message header.
+const FRAME_HEADER_LEN: usize = 5;
+
+pub fn is_json_request(req: &RequestHeader) -> bool {
+    req.headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.to_ascii_lowercase().starts_with(JSON))
+}
+
+/// Per request state for transcoding a JSON exchange.
+#[derive(Default)]
+pub struct JsonCtx {
+    /// Input and output message types of the method.
+    types: Option<(MessageDescriptor, MessageDescriptor)>,
+    request_buffer: BytesMut,
+    response_buffer: BytesMut,
+    /// Error status from a "Trailers-Only" upstream response.
+    header_error: Option<(GrpcCode, String)>,
+    /// Set once the response body has been written.
+    finished: bool,
+}
+
+impl JsonCtx {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the method being called. Must be called before any
+    /// transcoding happens.
+    pub fn set_method(&mut self, method: &MethodDescriptor) -> pingora::Result<()> {
+        let proto = method.proto();
+        if proto.client_streaming() || proto.server_streaming() {
+            return Err(grpc_error(
+                GrpcCode::Unimplemented,
+                "JSON transcoding only supports unary methods",
+            ));
+        }
+        self.types = Some((method.input_type(), method.output_type()));
+        Ok(())
+    }
+
+    pub fn has_method(&self) -> bool {
+        self.types.is_some()
+    }
+
+    fn types(&self) -> pingora::Result<&(MessageDescriptor, MessageDescriptor)> {
+        self.types
+            .as_ref()
+            .ok_or_else(|| grpc_error(GrpcCode::Internal, "JSON request without method"))
+    }
+
+    /// Transcodes a complete JSON request body into a native gRPC
+    /// request body.
+    pub fn transcode_request(&self, json: &[u8]) -> pingora::Result<Bytes> {
+        let json = std::str::from_utf8(json).map_err(|cause| {
+            grpc_error_because(
+                GrpcCode::InvalidArgument,
+                "invalid JSON request body",
+                cause,
+            )
+        })?;
+        // Allow empty bodies for requests without fields.
+        let json = if json.trim().is_empty() { "{}" } else { json };
+
+        let message =
+            protobuf_json_mapping::parse_dyn_from_str(&self.types()?.0, json).map_err(|cause| {
+                grpc_error_because(
+                    GrpcCode::InvalidArgument,
+                    "invalid JSON request body",
+                    cause,
+                )
+            })?;
+        let data = message.write_to_bytes_dyn().map_err(|cause| {
+            grpc_error_because(
+                GrpcCode::Internal,
+                "failed to encode request message",
+                cause,
+            )
+        })?;
+
+        let mut frame = BytesMut::with_capacity(FRAME_HEADER_LEN + data.len());
+        frame.put_u8(0);
+        frame.put_u32(data.len() as u32);
+        frame.put_slice(&data);
+        Ok(frame.freeze())
+    }
+
+    pub fn upstream_request_header(&self, req: &mut RequestHeader) -> pingora::Result<()> {
+        super::headers::native_grpc_request_header(req)
+    }
+
+    /// Buffers the JSON request body, and replaces it with the native
+    /// gRPC body at the end of the stream.
+    pub fn request_body(
+        &mut self,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+    ) -> pingora::Result<()> {
+        if let Some(data) = body.take() {
+            if self.request_buffer.len() + data.len() > MAX_REQUEST_SIZE {
+                return Err(grpc_error(
+                    GrpcCode::ResourceExhausted,
+                    "JSON request body too large",
+                ));
+            }
+            self.request_buffer.put(data);
+        }
+
+        *body = Some(if end_of_stream {
+            self.transcode_request(&self.request_buffer)?
+        } else {
+            Bytes::new()
+        });
+        Ok(())
+    }
+
+    /// Rewrites a native gRPC response header from the upstream (or
+    /// cache) into a JSON response header.
+    pub fn response_header(
+        &mut self,
+        resp: &mut ResponseHeader,
+        downstream_is_h1: bool,
+    ) -> pingora::Result<()> {
+        if resp.status.is_informational() {
+            return Ok(());
+        }
+
+        // A status in the headers means a "Trailers-Only" response,
+        // which is the only case where we can still pick the HTTP
+        // status.
+        if resp.headers.contains_key("grpc-status") {
+            if let Some(error) = status_error(&resp.headers) {
+                resp.set_status(error.0.http_status())?;
+                self.header_error = Some(error);
+            }
+        }
+
+        // The JSON body has a different length than the gRPC body.
+        resp.insert_header(http::header::CONTENT_TYPE, JSON)?;
+        resp.remove_header(&http::header::CONTENT_LENGTH);
+        if downstream_is_h1 {
+            resp.insert_header(http::header::TRANSFER_ENCODING, "chunked")?;
+        }
+        Ok(())
+    }
+
+    /// Buffers the native gRPC response body. The JSON body is written
+    /// once the response is complete.
+    pub fn response_body(&mut self, body: &mut Option<Bytes>, end_of_stream: bool) {
+        if let Some(data) = body.take() {
+            self.response_buffer.put(data);
+        }
+
+        // Cached responses are not followed by trailers, only
+        // successful responses are cached. Buffered chunks are left
+        // empty rather than `None`, which ends cache hit bodies.
+        *body = match (end_of_stream, self.finished) {
+            (false, _) => Some(Bytes::new()),
+            (true, false) => Some(self.finish(None)),
+            (true, true) => None,
+        };
+    }
+
+    /// Writes the JSON body once the upstream trailers arrive.
+    pub fn response_trailers(&mut self, trailers: &HeaderMap) -> Bytes {
+        if self.finished {
+            return Bytes::new();
+        }
+        self.finish(Some(trailers))
+    }
+
+    fn finish(&mut self, trailers: Option<&HeaderMap>) -> Bytes {
+        self.finished = true;
+
+        let error = match trailers {
+            Some(trailers) => status_error(trailers),
+            None => self.header_error.take(),
+        };
+        if let Some((code, message)) = error {
+            return error_body(code, &message);
+        }
+
+        match self.transcode_response() {
+            Ok(body) => body,
+            Err(error) => error_body(GrpcCode::Internal, &error),
+        }
+    }
+
+    fn transcode_response(&mut self) -> Result<Bytes, String> {
+        let output_type = self.types().map_err(|e| e.to_string())?.1.clone();
+
+        let buf = &mut self.response_buffer;
+        if buf.len() < FRAME_HEADER_LEN {
+            return Err("upstream response contained no message".into());
+        }
+        let compressed = buf.get_u8();
+        let len = buf.get_u32() as usize;
+        if compressed != 0 {
+            return Err("compressed upstream responses are not supported".into());
+        }
+        if buf.len() < len {
+            return Err("truncated upstream response message".into());
+        }
+
+        let message = output_type
+            .parse_from_bytes(&buf[..len])
+            .map_err(|e| format!("failed to decode response message: {}", e))?;
+        let json = protobuf_json_mapping::print_to_string(&*message)
+            .map_err(|e| format!("failed to encode response as JSON: {}", e))?;
+        Ok(json.into())
+    }
+}
+
+/// Reads a non-OK status from headers or trailers.
+fn status_error(headers: &HeaderMap) -> Option<(GrpcCode, String)> {
+    let code = match GrpcStatus::parse(headers) {
+        Ok(status) if status.is_ok() => return None,
+        Ok(status) => status.code().unwrap_or(GrpcCode::Unknown),
+        Err(_) => {
+            return Some((
+                GrpcCode::Internal,
+                "invalid gRPC status from upstream".into(),
+            ))
+        }
+    };
+    let message = headers
+        .get("grpc-message")
+        .map(|v| decode_grpc_message(v.as_bytes()))
+        .unwrap_or_default();
+    Some((code, message))
+}
+
+/// JSON body for an error, in the shape of `google.rpc.Status`.
+fn error_body(code: GrpcCode, message: &str) -> Bytes {
+    serde_json::json!({
+        "code": code as u8,
+        "message": message,
+    })
+    .to_string()
+    .into()
+}
+
+/// Responds to a JSON client with an error, with `metadata` as
+/// response headers.
+pub async fn respond_json_error(
+    session: &mut ServerSession,
+    code: GrpcCode,
+    message: &str,
+    metadata: &HeaderMap,
+) -> pingora::Result<()> {
+    let body = error_body(code, message);
+    let mut resp = ResponseHeader::build(code.http_status(), Some(2))?;
+    resp.insert_header(http::header::CONTENT_TYPE, JSON)?;
+    resp.insert_header(http::header::CONTENT_LENGTH, body.len().to_string())?;
+    for (name, value) in metadata {
+        resp.append_header(name.clone(), value.clone())?;
+    }
+    session.write_response_header(Box::new(resp)).await?;
+    session.write_response_body(body, true).await?;
+    session.finish_body().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_header_reframes_body() {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header(http::header::CONTENT_LENGTH, "12")
+            .unwrap();
+        JsonCtx::new().response_header(&mut resp, true).unwrap();
+        assert!(resp.headers.get(http::header::CONTENT_LENGTH).is_none());
+        assert_eq!(resp.headers[http::header::TRANSFER_ENCODING], "chunked");
+        assert_eq!(resp.headers[http::header::CONTENT_TYPE], JSON);
+
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header(http::header::CONTENT_LENGTH, "12")
+            .unwrap();
+        JsonCtx::new().response_header(&mut resp, false).unwrap();
+        assert!(resp.headers.get(http::header::CONTENT_LENGTH).is_none());
+        assert!(resp.headers.get(http::header::TRANSFER_ENCODING).is_none());
+    }
+
+    #[test]
+    fn response_body_ends_once() {
+        let mut json = JsonCtx::new();
+        // Buffered chunks don't end the body.
+        let mut body = Some(Bytes::from_static(b"\0\0\0\0\0"));
+        json.response_body(&mut body, false);
+        assert_eq!(body, Some(Bytes::new()));
+
+        // Without a method the error body is written, then nothing.
+        let mut body = None;
+        json.response_body(&mut body, true);
+        assert!(body.is_some_and(|body| !body.is_empty()));
+        let mut body = None;
+        json.response_body(&mut body, true);
+        assert_eq!(body, None);
+    }
+}
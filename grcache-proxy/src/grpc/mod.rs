@@ -1,3 +1,7 @@
+pub mod error;
 pub mod hash;
 pub mod headers;
+pub mod json;
 pub mod status;
+pub mod timeout;
+pub mod web;
@@ -12,6 +12,122 @@ pub enum ParseError {
     InvalidStatusHeaderInt(#[from] ParseIntError),
 }
 
+/// Status codes as defined by the gRPC protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum GrpcCode {
+    Ok = 0,
+    Cancelled = 1,
+    Unknown = 2,
+    InvalidArgument = 3,
+    DeadlineExceeded = 4,
+    NotFound = 5,
+    AlreadyExists = 6,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    FailedPrecondition = 9,
+    Aborted = 10,
+    OutOfRange = 11,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    DataLoss = 15,
+    Unauthenticated = 16,
+}
+
+impl GrpcCode {
+    pub fn from_u8(code: u8) -> Option<Self> {
+        let code = match code {
+            0 => Self::Ok,
+            1 => Self::Cancelled,
+            2 => Self::Unknown,
+            3 => Self::InvalidArgument,
+            4 => Self::DeadlineExceeded,
+            5 => Self::NotFound,
+            6 => Self::AlreadyExists,
+            7 => Self::PermissionDenied,
+            8 => Self::ResourceExhausted,
+            9 => Self::FailedPrecondition,
+            10 => Self::Aborted,
+            11 => Self::OutOfRange,
+            12 => Self::Unimplemented,
+            13 => Self::Internal,
+            14 => Self::Unavailable,
+            15 => Self::DataLoss,
+            16 => Self::Unauthenticated,
+            _ => return None,
+        };
+        Some(code)
+    }
+
+    /// Parses a code from its name, like `UNAVAILABLE`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        (0..=16)
+            .filter_map(Self::from_u8)
+            .find(|code| code.as_str() == name)
+    }
+
+    /// Maps a HTTP status code to a gRPC status code.
+    /// Follows `doc/http-grpc-status-mapping.md` from the gRPC repo.
+    pub fn from_http_status(status: u16) -> Self {
+        match status {
+            400 => Self::Internal,
+            401 => Self::Unauthenticated,
+            403 => Self::PermissionDenied,
+            404 => Self::Unimplemented,
+            413 => Self::ResourceExhausted,
+            429 | 502 | 503 | 504 => Self::Unavailable,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Maps a gRPC status code to the HTTP status returned to JSON
+    /// clients. Follows the mapping used by `grpc-gateway`.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            Self::Ok => 200,
+            Self::Cancelled => 499,
+            Self::Unknown => 500,
+            Self::InvalidArgument => 400,
+            Self::DeadlineExceeded => 504,
+            Self::NotFound => 404,
+            Self::AlreadyExists => 409,
+            Self::PermissionDenied => 403,
+            Self::ResourceExhausted => 429,
+            Self::FailedPrecondition => 400,
+            Self::Aborted => 409,
+            Self::OutOfRange => 400,
+            Self::Unimplemented => 501,
+            Self::Internal => 500,
+            Self::Unavailable => 503,
+            Self::DataLoss => 500,
+            Self::Unauthenticated => 401,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::Cancelled => "CANCELLED",
+            Self::Unknown => "UNKNOWN",
+            Self::InvalidArgument => "INVALID_ARGUMENT",
+            Self::DeadlineExceeded => "DEADLINE_EXCEEDED",
+            Self::NotFound => "NOT_FOUND",
+            Self::AlreadyExists => "ALREADY_EXISTS",
+            Self::PermissionDenied => "PERMISSION_DENIED",
+            Self::ResourceExhausted => "RESOURCE_EXHAUSTED",
+            Self::FailedPrecondition => "FAILED_PRECONDITION",
+            Self::Aborted => "ABORTED",
+            Self::OutOfRange => "OUT_OF_RANGE",
+            Self::Unimplemented => "UNIMPLEMENTED",
+            Self::Internal => "INTERNAL",
+            Self::Unavailable => "UNAVAILABLE",
+            Self::DataLoss => "DATA_LOSS",
+            Self::Unauthenticated => "UNAUTHENTICATED",
+        }
+    }
+}
+
 pub struct GrpcStatus {
     code: u8,
 }
@@ -20,7 +136,7 @@ impl GrpcStatus {
     pub fn parse(trailers: &HeaderMap) -> Result<Self, ParseError> {
         let status_value_bin = trailers
             .get("grpc-status")
-            .ok_or_else(|| ParseError::NoStatusHeader)?;
+            .ok_or(ParseError::NoStatusHeader)?;
         let status_value_str = std::str::from_utf8(status_value_bin.as_bytes())?;
         let status_code = status_value_str.parse::<u8>()?;
 
@@ -30,4 +146,10 @@ impl GrpcStatus {
     pub fn is_ok(&self) -> bool {
         self.code == 0
     }
+
+    /// Returns the status code, or `None` if the upstream sent a code
+    /// not defined by the gRPC spec.
+    pub fn code(&self) -> Option<GrpcCode> {
+        GrpcCode::from_u8(self.code)
+    }
 }
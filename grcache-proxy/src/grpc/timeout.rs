@@ -0,0 +1,98 @@
+//! The `grpc-timeout` request header.
+//!
+//! See the gRPC over HTTP/2 [spec](https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-HTTP2.md).
+
+use std::time::Duration;
+
+/// Parses a `grpc-timeout` value, a positive integer of at most 8
+/// digits followed by a unit.
+pub fn parse_grpc_timeout(value: &[u8]) -> Option<Duration> {
+    let (digits, unit) = value.split_at_checked(value.len().checked_sub(1)?)?;
+    if digits.is_empty() || digits.len() > 8 || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let amount: u64 = std::str::from_utf8(digits).ok()?.parse().ok()?;
+
+    let timeout = match unit {
+        b"H" => Duration::from_secs(amount * 3600),
+        b"M" => Duration::from_secs(amount * 60),
+        b"S" => Duration::from_secs(amount),
+        b"m" => Duration::from_millis(amount),
+        b"u" => Duration::from_micros(amount),
+        b"n" => Duration::from_nanos(amount),
+        _ => return None,
+    };
+    Some(timeout)
+}
+
+/// Formats a `grpc-timeout` value, in the finest unit which fits in 8
+/// digits.
+pub fn encode_grpc_timeout(timeout: Duration) -> String {
+    const MAX: u128 = 99_999_999;
+    let nanos = timeout.as_nanos();
+    for (unit, nanos_per_unit) in [
+        ("n", 1),
+        ("u", 1_000),
+        ("m", 1_000_000),
+        ("S", 1_000_000_000),
+        ("M", 60_000_000_000),
+    ] {
+        // Rounded up, a timeout must not become zero.
+        let amount = nanos.div_ceil(nanos_per_unit);
+        if amount <= MAX {
+            return format!("{}{}", amount, unit);
+        }
+    }
+    format!("{}H", nanos.div_ceil(3_600_000_000_000).min(MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse_grpc_timeout(b"2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_grpc_timeout(b"3M"), Some(Duration::from_secs(180)));
+        assert_eq!(parse_grpc_timeout(b"10S"), Some(Duration::from_secs(10)));
+        assert_eq!(
+            parse_grpc_timeout(b"250m"),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(
+            parse_grpc_timeout(b"99999999u"),
+            Some(Duration::from_micros(99999999))
+        );
+        assert_eq!(parse_grpc_timeout(b"1n"), Some(Duration::from_nanos(1)));
+
+        for invalid in [
+            &b""[..],
+            b"S",
+            b"10",
+            b"10s",
+            b"-1S",
+            b"123456789S",
+            b"1.5S",
+        ] {
+            assert_eq!(parse_grpc_timeout(invalid), None);
+        }
+    }
+
+    #[test]
+    fn encodes_finest_unit() {
+        assert_eq!(encode_grpc_timeout(Duration::from_nanos(1)), "1n");
+        assert_eq!(encode_grpc_timeout(Duration::from_millis(250)), "250000u");
+        assert_eq!(encode_grpc_timeout(Duration::from_secs(10)), "10000000u");
+        assert_eq!(encode_grpc_timeout(Duration::from_secs(86400)), "86400000m");
+        assert_eq!(
+            encode_grpc_timeout(Duration::from_nanos(100_000_001)),
+            "100001u"
+        );
+        assert_eq!(encode_grpc_timeout(Duration::MAX), "99999999H");
+
+        for timeout in [Duration::from_micros(1234), Duration::from_secs(7200)] {
+            let encoded = encode_grpc_timeout(timeout);
+            assert_eq!(parse_grpc_timeout(encoded.as_bytes()), Some(timeout));
+        }
+    }
+}
@@ -0,0 +1,315 @@
+//! Translation between gRPC-Web and native gRPC.
+//!
+//! See the gRPC-Web [spec](https://github.com/grpc/grpc/blob/master/doc/PROTOCOL-WEB.md).
+//!
+//! Upstreams are always spoken to in native gRPC, and responses are
+//! cached in their native form. Only the downstream facing side of a
+//! request is translated. This means gRPC-Web and native gRPC clients
+//! share cache entries.
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use bytes::{BufMut, Bytes, BytesMut};
+use http::HeaderMap;
+use pingora::http::{RequestHeader, ResponseHeader};
+
+const GRPC_WEB: &str = "application/grpc-web";
+const GRPC_WEB_TEXT: &str = "application/grpc-web-text";
+
+/// Flag set on the frame header of the in-body trailers frame.
+const TRAILERS_FRAME_FLAG: u8 = 0x80;
+
+/// The wire format used by a gRPC-Web client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrpcWebMode {
+    /// `application/grpc-web`, binary framing.
+    Binary,
+    /// `application/grpc-web-text`, base64 encoded framing.
+    Text,
+}
+
+impl GrpcWebMode {
+    /// Detects gRPC-Web from the content type of a request.
+    /// Returns `None` for anything that is not gRPC-Web, including
+    /// native gRPC.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let content_type = content_type.to_ascii_lowercase();
+        if content_type.starts_with(GRPC_WEB_TEXT) {
+            Some(Self::Text)
+        } else if content_type.starts_with(GRPC_WEB) {
+            Some(Self::Binary)
+        } else {
+            None
+        }
+    }
+
+    pub fn from_request(req: &RequestHeader) -> Option<Self> {
+        req.headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(Self::from_content_type)
+    }
+
+    /// Content type used in responses to the client.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Binary => "application/grpc-web+proto",
+            Self::Text => "application/grpc-web-text+proto",
+        }
+    }
+}
+
+/// Per request state for translating a gRPC-Web exchange.
+pub struct GrpcWebCtx {
+    pub mode: GrpcWebMode,
+    request_decoder: TextDecoder,
+    response_encoder: TextEncoder,
+    /// Set once the trailers frame has been written to the response
+    /// body.
+    trailers_sent: bool,
+}
+
+impl GrpcWebCtx {
+    pub fn new(mode: GrpcWebMode) -> Self {
+        GrpcWebCtx {
+            mode,
+            request_decoder: TextDecoder::default(),
+            response_encoder: TextEncoder::default(),
+            trailers_sent: false,
+        }
+    }
+
+    /// Rewrites a gRPC-Web request header into a native gRPC request
+    /// header for the upstream.
+    pub fn upstream_request_header(&self, req: &mut RequestHeader) -> pingora::Result<()> {
+        super::headers::native_grpc_request_header(req)
+    }
+
+    /// Rewrites a native gRPC response header from the upstream (or
+    /// cache) into a gRPC-Web response header.
+    pub fn response_header(
+        &self,
+        resp: &mut ResponseHeader,
+        downstream_is_h1: bool,
+    ) -> pingora::Result<()> {
+        if resp.status.is_informational() {
+            return Ok(());
+        }
+
+        resp.insert_header(http::header::CONTENT_TYPE, self.mode.content_type())?;
+
+        // Trailers are appended to the body, content length no longer
+        // holds.
+        resp.remove_header(&http::header::CONTENT_LENGTH);
+        if downstream_is_h1 {
+            resp.insert_header(http::header::TRANSFER_ENCODING, "chunked")?;
+        }
+        Ok(())
+    }
+
+    /// Decodes a chunk of request body into native gRPC framing.
+    pub fn request_body(
+        &mut self,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+    ) -> pingora::Result<()> {
+        if self.mode == GrpcWebMode::Binary {
+            return Ok(());
+        }
+
+        let data = body.take().unwrap_or_default();
+        let decoded = self
+            .request_decoder
+            .decode(&data, end_of_stream)
+            .map_err(|cause| {
+                super::error::grpc_error_because(
+                    super::status::GrpcCode::InvalidArgument,
+                    "invalid grpc-web-text request body",
+                    cause,
+                )
+            })?;
+        *body = Some(decoded);
+        Ok(())
+    }
+
+    /// Encodes a chunk of native gRPC response body for the client.
+    ///
+    /// `synthesize_ok_trailers` should be set when the response is known
+    /// to be successful but will not be followed by trailers, like
+    /// responses served from cache.
+    pub fn response_body(
+        &mut self,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        synthesize_ok_trailers: bool,
+    ) {
+        if self.trailers_sent {
+            return;
+        }
+
+        let mut data = BytesMut::from(body.take().unwrap_or_default());
+        if end_of_stream && synthesize_ok_trailers {
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", http::HeaderValue::from_static("0"));
+            data.put(encode_trailers_frame(&trailers));
+            self.trailers_sent = true;
+        }
+
+        *body = Some(match self.mode {
+            GrpcWebMode::Binary => data.freeze(),
+            GrpcWebMode::Text => self.response_encoder.encode(&data, end_of_stream),
+        });
+    }
+
+    /// Encodes response trailers into the trailers frame which is sent
+    /// as the last part of the response body.
+    pub fn response_trailers(&mut self, trailers: &HeaderMap) -> Bytes {
+        self.trailers_sent = true;
+        let frame = encode_trailers_frame(trailers);
+        match self.mode {
+            GrpcWebMode::Binary => frame,
+            GrpcWebMode::Text => self.response_encoder.encode(&frame, true),
+        }
+    }
+}
+
+/// Decodes a complete `grpc-web-text` body.
+pub fn decode_text_body(data: &[u8]) -> Result<Bytes, base64::DecodeError> {
+    TextDecoder::default().decode(data, true)
+}
+
+/// Encodes trailers into a gRPC-Web trailers frame.
+///
+/// ```text
+/// | 0x80 | length (u32 BE) | key:value\r\n ... |
+/// ```
+fn encode_trailers_frame(trailers: &HeaderMap) -> Bytes {
+    let mut block = BytesMut::new();
+    for (key, value) in trailers.iter() {
+        block.put_slice(key.as_str().as_bytes());
+        block.put_slice(b":");
+        block.put_slice(value.as_bytes());
+        block.put_slice(b"\r\n");
+    }
+
+    let mut frame = BytesMut::with_capacity(5 + block.len());
+    frame.put_u8(TRAILERS_FRAME_FLAG);
+    frame.put_u32(block.len() as u32);
+    frame.put(block);
+    frame.freeze()
+}
+
+/// Streaming base64 decoder for `grpc-web-text` request bodies.
+///
+/// Clients may send the body as several independently padded base64
+/// segments, so padding is accepted in the middle of the stream.
+#[derive(Default)]
+struct TextDecoder {
+    pending: Vec<u8>,
+}
+
+impl TextDecoder {
+    fn decode(&mut self, data: &[u8], end_of_stream: bool) -> Result<Bytes, base64::DecodeError> {
+        self.pending
+            .extend(data.iter().filter(|b| !b.is_ascii_whitespace()));
+
+        let complete = self.pending.len() - self.pending.len() % 4;
+        if end_of_stream && complete != self.pending.len() {
+            return Err(base64::DecodeError::InvalidLength(self.pending.len()));
+        }
+
+        let mut out = Vec::with_capacity(complete / 4 * 3);
+        let mut segment_start = 0;
+        for quantum_end in (4..=complete).step_by(4) {
+            // A padded quantum terminates a segment.
+            if self.pending[quantum_end - 1] == b'=' || quantum_end == complete {
+                BASE64_STANDARD.decode_vec(&self.pending[segment_start..quantum_end], &mut out)?;
+                segment_start = quantum_end;
+            }
+        }
+
+        self.pending.drain(..complete);
+        Ok(out.into())
+    }
+}
+
+/// Streaming base64 encoder for `grpc-web-text` response bodies.
+///
+/// Only whole 3 byte groups are encoded until the end of the stream,
+/// so that the client sees a single unpadded segment.
+#[derive(Default)]
+struct TextEncoder {
+    pending: Vec<u8>,
+}
+
+impl TextEncoder {
+    fn encode(&mut self, data: &[u8], end_of_stream: bool) -> Bytes {
+        self.pending.extend_from_slice(data);
+
+        let len = if end_of_stream {
+            self.pending.len()
+        } else {
+            self.pending.len() - self.pending.len() % 3
+        };
+
+        let encoded = BASE64_STANDARD.encode(&self.pending[..len]);
+        self.pending.drain(..len);
+        encoded.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{prelude::BASE64_STANDARD, Engine};
+    use http::HeaderMap;
+
+    use super::{decode_text_body, encode_trailers_frame, GrpcWebMode, TextDecoder, TextEncoder};
+
+    #[test]
+    fn detects_mode_from_content_type() {
+        assert_eq!(
+            GrpcWebMode::from_content_type("application/grpc-web+proto"),
+            Some(GrpcWebMode::Binary)
+        );
+        assert_eq!(
+            GrpcWebMode::from_content_type("application/grpc-web-text"),
+            Some(GrpcWebMode::Text)
+        );
+        assert_eq!(GrpcWebMode::from_content_type("application/grpc"), None);
+    }
+
+    #[test]
+    fn text_decoder_handles_split_and_padded_segments() {
+        let first = BASE64_STANDARD.encode(b"hello");
+        let second = BASE64_STANDARD.encode(b" world");
+        let full = format!("{}{}", first, second);
+
+        let mut decoder = TextDecoder::default();
+        let mut out = Vec::new();
+        for chunk in full.as_bytes().chunks(3) {
+            out.extend_from_slice(&decoder.decode(chunk, false).unwrap());
+        }
+        out.extend_from_slice(&decoder.decode(b"", true).unwrap());
+        assert_eq!(out, b"hello world");
+
+        assert!(decode_text_body(b"aGk").is_err());
+    }
+
+    #[test]
+    fn text_encoder_emits_single_segment() {
+        let mut encoder = TextEncoder::default();
+        let mut out = Vec::new();
+        for chunk in b"hello world".chunks(4) {
+            out.extend_from_slice(&encoder.encode(chunk, false));
+        }
+        out.extend_from_slice(&encoder.encode(b"", true));
+        assert_eq!(out, BASE64_STANDARD.encode(b"hello world").as_bytes());
+    }
+
+    #[test]
+    fn trailers_frame_encoding() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        let frame = encode_trailers_frame(&trailers);
+        assert_eq!(&frame[..], b"\x80\x00\x00\x00\x0fgrpc-status:0\r\n");
+    }
+}
@@ -1,7 +1,12 @@
 pub mod cache;
+pub mod config_reload;
 pub mod discovery;
 pub mod grpc;
+pub mod logging;
+pub mod metrics;
 pub mod proxy;
 pub mod service_store;
+pub mod tenant;
 pub mod test_util;
+pub mod tls;
 pub mod tracing;
@@ -0,0 +1,37 @@
+//! Logging through `env_logger`.
+//!
+//! `RUST_LOG` is used as usual. When `logLevel` is set in the config
+//! file it replaces the default level from `RUST_LOG`, and can be
+//! changed by reloading the config. If `logLevel` was unset at startup,
+//! levels more verbose than `RUST_LOG` can't be enabled by a reload.
+
+use std::sync::OnceLock;
+
+use grcache_shared::config::LogLevel;
+use log::LevelFilter;
+
+/// Max level from `RUST_LOG`, used when `logLevel` is unset.
+static ENV_LEVEL: OnceLock<LevelFilter> = OnceLock::new();
+
+pub fn init(level: Option<LogLevel>) {
+    let env_level = env_logger::Builder::from_default_env().build().filter();
+    ENV_LEVEL.set(env_level).expect("logging initialized twice");
+
+    let mut builder = env_logger::Builder::from_default_env();
+    if level.is_some() {
+        // Filtering on the default level happens through
+        // `log::set_max_level` instead.
+        builder.filter_level(LevelFilter::Trace);
+    }
+    builder.init();
+
+    set_level(level);
+}
+
+/// Changes the log level. Does nothing if logging was not set up
+/// through `init`.
+pub fn set_level(level: Option<LogLevel>) {
+    if let Some(env_level) = ENV_LEVEL.get() {
+        log::set_max_level(level.map(Into::into).unwrap_or(*env_level));
+    }
+}
@@ -1,27 +1,41 @@
-use std::{fs::File, io::Read, sync::Arc};
+use std::sync::Arc;
 
 use clap::Parser;
 use grcache_shared::{
-    config::{CacheBackend, ConfigFile},
+    config::{CacheBackend, ConfigFile, ListenerConfig, ServerConfig},
     health::Health,
 };
 use hickory_resolver::TokioAsyncResolver;
-use pingora::{apps::HttpServerOptions, services::background::GenBackgroundService};
-use pingora_core::{prelude::Opt, server::Server};
+use pingora::{
+    apps::HttpServerOptions,
+    listeners::TcpSocketOptions,
+    services::{background::GenBackgroundService, listening::Service},
+};
+use pingora_core::{
+    prelude::Opt,
+    server::{configuration::ServerConf, Server},
+};
+use pingora_proxy::HttpProxy;
 
 pub mod cache;
+pub mod config_reload;
 pub mod discovery;
 pub mod grpc;
+pub mod logging;
+pub mod metrics;
 pub mod proxy;
 pub mod service_store;
+pub mod tenant;
+pub mod tls;
 pub mod tracing;
 
 use cache::{redis_replicas::RedisReplicasCacheBackend, GrcacheStorage};
-use proxy::GrpcProxy;
+use proxy::{hedge::HedgeBudget, GrpcProxy};
 
 #[derive(clap::Parser)]
 struct Args {
-    /// Path to the `grproxy` config file
+    /// Path to the `grproxy` config file. YAML, TOML and JSON are
+    /// supported, picked by file extension.
     config: String,
 
     /// Subcommand to execute
@@ -31,31 +45,50 @@ struct Args {
 
 #[derive(clap::Subcommand)]
 enum Commands {
-    Proxy {},
+    Proxy {
+        /// Take over listening sockets from a running instance through
+        /// the `server.upgradeSock` socket.
+        #[arg(long)]
+        upgrade: bool,
+
+        /// Check the config file and exit.
+        #[arg(long)]
+        test: bool,
+    },
 }
 
 fn main() {
-    env_logger::init();
-
     let args = Args::parse();
 
-    let config: ConfigFile = {
-        let mut config_file = File::open(&args.config).expect("could not open config file");
-        let mut config_buf = Vec::new();
-        config_file.read_to_end(&mut config_buf).unwrap();
-        let config_str = String::from_utf8(config_buf).unwrap();
-        toml::from_str(&config_str).unwrap()
-    };
+    let config = ConfigFile::load(&args.config).unwrap_or_else(|error| {
+        eprintln!("{}: {}", args.config, error);
+        std::process::exit(1);
+    });
+
+    logging::init(config.log_level);
+    let _tracing = config.tracing.as_ref().map(tracing::Tracing::init);
 
-    let opt = Opt::default();
-    //let opt = Opt::parse_args();
+    let Commands::Proxy { upgrade, test } = args.command;
+    let opt = Opt {
+        upgrade,
+        test,
+        ..Default::default()
+    };
     // Daemonization is not supported due to how we create resources before
     // calling `run_forever`.
     assert!(!opt.daemon, "daemonization not supported");
 
-    let mut server = Server::new(Some(opt)).unwrap();
+    let mut server = Server::new_with_opt_and_conf(opt, server_conf(&config.server));
     server.bootstrap();
 
+    // Config file reload
+    let (config_reload_service, config_context) =
+        config_reload::Service::new(args.config.into(), config.clone());
+    server.add_service(GenBackgroundService::new(
+        "Config Reload Service".to_string(),
+        Arc::new(config_reload_service),
+    ));
+
     // Health check subsystem
     let (health, health_root) = Health::new();
 
@@ -64,12 +97,21 @@ fn main() {
     );
 
     // DNS discovery background service
-    let (dns_service, dns_discovery) = discovery::dns::Service::new(dns_resolver.clone());
+    let (dns_service, dns_discovery) =
+        discovery::dns::Service::new(dns_resolver.clone(), config.dns.clone());
     server.add_service(GenBackgroundService::new(
-        format!("DNS Discovery Service"),
+        "DNS Discovery Service".to_string(),
         Arc::new(dns_service),
     ));
 
+    // Kubernetes endpoint discovery background service
+    let (kubernetes_discovery_service, kubernetes_discovery) =
+        discovery::kubernetes::Service::new(config.kubernetes.zone.clone());
+    server.add_service(GenBackgroundService::new(
+        "Kubernetes Endpoint Discovery Service".to_string(),
+        Arc::new(kubernetes_discovery_service),
+    ));
+
     // For now we only support the case where the k8s backend is
     // enabled.
     assert!(
@@ -78,21 +120,26 @@ fn main() {
     );
 
     // Kubernetes config loader
-    let (k8s_config_service, service_config) =
-        service_store::K8SConfigService::new(dns_discovery.clone(), health.add(true));
+    let (k8s_config_service, service_config) = service_store::K8SConfigService::new(
+        dns_discovery.clone(),
+        kubernetes_discovery,
+        health.add(true),
+    );
     server.add_service(GenBackgroundService::new(
-        format!("Kubernetes Config Service"),
+        "Kubernetes Config Service".to_string(),
         Arc::new(k8s_config_service),
     ));
 
     // Initialize cache backend depending on config.
     let cache: Box<dyn GrcacheStorage + Sync + 'static> = match config.cache_backend {
-        CacheBackend::RedisReplicas { hostname, port } => {
-            let redis_discovery = dns_discovery.backends_for_hostname(hostname, port);
-            let (redis_cache_service, redis_cache) =
-                RedisReplicasCacheBackend::new(redis_discovery, health.add(true));
+        CacheBackend::RedisReplicas { .. } => {
+            let (redis_cache_service, redis_cache) = RedisReplicasCacheBackend::new(
+                dns_discovery.clone(),
+                config_context.clone(),
+                health.add(true),
+            );
             server.add_service(GenBackgroundService::new(
-                format!("Redis Connection Pool Service"),
+                "Redis Connection Pool Service".to_string(),
                 Arc::new(redis_cache_service),
             ));
             Box::new(redis_cache)
@@ -101,17 +148,146 @@ fn main() {
     let cache = Box::leak(cache);
 
     // Proxy service
-    let proxy = GrpcProxy::new(service_config, cache);
+    let mut proxy = GrpcProxy::new(service_config, config_context, cache);
+    proxy.json_transcoding = config.proxy.json_transcoding;
+    proxy.hedge_budget = HedgeBudget::new(&config.proxy.hedge_budget);
+    if let Some(tenant_config) = &config.proxy.tenant {
+        let (tenant_resolver, jwks_service) =
+            tenant::TenantResolver::new(tenant_config).expect("failed to set up tenants");
+        server.add_service(GenBackgroundService::new(
+            "JWKS Reload Service".to_string(),
+            Arc::new(jwks_service),
+        ));
+        proxy.tenant = Some(tenant_resolver);
+    }
     let mut proxy = pingora_proxy::http_proxy_service(&server.configuration, proxy);
 
-    let mut http_server_options = HttpServerOptions::default();
-    http_server_options.h2c = true;
-    proxy.app_logic_mut().unwrap().server_options = Some(http_server_options);
-
-    proxy.add_tcp("0.0.0.0:50052");
+    for listener in &config.proxy.listeners {
+        add_listener(&mut server, &mut proxy, listener);
+    }
     server.add_service(proxy);
 
+    if let Some(metrics) = &config.metrics {
+        let mut metrics_service = Service::prometheus_http_service();
+        metrics_service.add_tcp(&metrics.address);
+        server.add_service(metrics_service);
+    }
+
     // Indicate readiness and loop forever
     health_root.ready();
     server.run_forever();
 }
+
+fn server_conf(config: &ServerConfig) -> ServerConf {
+    let mut conf = ServerConf::new().expect("failed to create default server config");
+    if let Some(threads) = config.threads {
+        conf.threads = threads;
+    }
+    if let Some(work_stealing) = config.work_stealing {
+        conf.work_stealing = work_stealing;
+    }
+    if let Some(upgrade_sock) = &config.upgrade_sock {
+        conf.upgrade_sock = upgrade_sock.clone();
+    }
+    if let Some(pid_file) = &config.pid_file {
+        conf.pid_file = pid_file.clone();
+    }
+    conf.grace_period_seconds = config.grace_period_seconds;
+    conf.graceful_shutdown_timeout_seconds = config.graceful_shutdown_timeout_seconds;
+    conf
+}
+
+fn add_listener(
+    server: &mut Server,
+    proxy: &mut Service<HttpProxy<GrpcProxy>>,
+    listener: &ListenerConfig,
+) {
+    if let Some(path) = listener.address.strip_prefix("unix:") {
+        assert!(
+            listener.tls.is_none(),
+            "TLS is not supported on Unix domain socket listeners"
+        );
+        enable_h2c(proxy);
+        proxy.add_uds(path, None);
+        return;
+    }
+
+    let sock_opt = socket_options(listener);
+    match &listener.tls {
+        Some(tls_config) => {
+            // Plaintext connections are not accepted on a TLS listener.
+            let (tls_service, tls_settings) =
+                tls::listener_tls(tls_config).expect("failed to load listener TLS certificates");
+            server.add_service(GenBackgroundService::new(
+                format!("TLS Certificate Reload Service ({})", listener.address),
+                Arc::new(tls_service),
+            ));
+            proxy.add_tls_with_settings(&listener.address, Some(sock_opt), tls_settings);
+        }
+        None => {
+            enable_h2c(proxy);
+            proxy.add_tcp_with_settings(&listener.address, sock_opt);
+        }
+    }
+}
+
+/// Socket options of a TCP listener. pingora binds the sockets itself
+/// and does not expose `SO_REUSEPORT`, so it can not be configured.
+fn socket_options(listener: &ListenerConfig) -> TcpSocketOptions {
+    let mut sock_opt = TcpSocketOptions::default();
+    sock_opt.ipv6_only = listener.ipv6_only;
+    sock_opt
+}
+
+/// Accepts HTTP/2 without TLS. Only affects plaintext listeners, TLS
+/// listeners negotiate the protocol through ALPN.
+fn enable_h2c(proxy: &mut Service<HttpProxy<GrpcProxy>>) {
+    let mut http_server_options = HttpServerOptions::default();
+    http_server_options.h2c = true;
+    proxy.app_logic_mut().unwrap().server_options = Some(http_server_options);
+}
+
+#[cfg(test)]
+mod tests {
+    use grcache_shared::config::{ListenerConfig, ServerConfig};
+
+    use super::{server_conf, socket_options};
+
+    #[test]
+    fn maps_server_options() {
+        let defaults = server_conf(&ServerConfig::default());
+        assert_eq!(defaults.grace_period_seconds, None);
+        assert_eq!(defaults.graceful_shutdown_timeout_seconds, None);
+
+        let conf = server_conf(&ServerConfig {
+            threads: Some(3),
+            work_stealing: Some(false),
+            upgrade_sock: Some("/tmp/upgrade.sock".into()),
+            pid_file: Some("/tmp/grcache.pid".into()),
+            grace_period_seconds: Some(5),
+            graceful_shutdown_timeout_seconds: Some(10),
+        });
+        assert_eq!(conf.threads, 3);
+        assert!(!conf.work_stealing);
+        assert_eq!(conf.upgrade_sock, "/tmp/upgrade.sock");
+        assert_eq!(conf.pid_file, "/tmp/grcache.pid");
+        assert_eq!(conf.grace_period_seconds, Some(5));
+        assert_eq!(conf.graceful_shutdown_timeout_seconds, Some(10));
+    }
+
+    #[test]
+    fn maps_listener_options() {
+        let listener = ListenerConfig {
+            address: "[::]:50052".into(),
+            tls: None,
+            ipv6_only: Some(false),
+        };
+        assert_eq!(socket_options(&listener).ipv6_only, Some(false));
+
+        let listener = ListenerConfig {
+            ipv6_only: None,
+            ..listener
+        };
+        assert_eq!(socket_options(&listener).ipv6_only, None);
+    }
+}
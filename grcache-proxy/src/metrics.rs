@@ -0,0 +1,36 @@
+//! Prometheus metrics, served on the `metrics.address` listener.
+
+use std::sync::LazyLock;
+
+use prometheus::{register_int_counter_vec, IntCounterVec};
+
+static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "grcache_requests_total",
+        "Requests handled by the proxy, by tenant and cache phase.",
+        &["tenant", "cache"]
+    )
+    .unwrap()
+});
+
+static EJECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "grcache_outlier_ejections_total",
+        "Backends ejected by outlier detection, by service or `redis`.",
+        &["target"]
+    )
+    .unwrap()
+});
+
+/// Counts a finished request. `tenant` is empty for requests without
+/// an identified tenant.
+pub fn record_request(tenant: Option<&str>, cache_phase: &str) {
+    REQUESTS
+        .with_label_values(&[tenant.unwrap_or_default(), cache_phase])
+        .inc();
+}
+
+/// Counts a backend ejected by outlier detection.
+pub fn record_ejection(target: &str) {
+    EJECTIONS.with_label_values(&[target]).inc();
+}
@@ -0,0 +1,433 @@
+//! Concurrency limits of services.
+//!
+//! Requests take a permit of their service in `proxy_upstream_filter`,
+//! after a cache miss, so cache hits are never limited. Requests over
+//! the limit wait in a queue for a while before they are rejected.
+//! Each upstream attempt also counts towards the limit of its backend,
+//! and backends at their limit are not selected.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use grcache_shared::config::crd::{AdaptiveConcurrency, ConcurrencyLimit};
+use pingora_load_balancing::Backend;
+use tokio::sync::Notify;
+
+use crate::grpc::{error::grpc_error, status::GrpcCode};
+
+/// Starting limit of adaptive limits without `maxInFlight`.
+const INITIAL_ADAPTIVE_LIMIT: f64 = 20.0;
+
+pub struct ConcurrencyLimiter {
+    max_per_backend: Option<usize>,
+    queue_timeout: Duration,
+    max_queued: usize,
+    spec: ConcurrencyLimit,
+    state: Mutex<LimiterState>,
+    /// Notified when a permit is released.
+    released: Notify,
+}
+
+struct LimiterState {
+    /// Limit of the service. Fractional, so adaptive limits can change
+    /// gradually.
+    limit: f64,
+    in_flight: usize,
+    queued: usize,
+    backends: HashMap<Backend, usize>,
+    /// Long term average latency in seconds, for the gradient limit.
+    average_latency: Option<f64>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(spec: &ConcurrencyLimit) -> anyhow::Result<Self> {
+        validate(spec)?;
+        let max_in_flight = spec.max_in_flight.map(f64::from);
+        let limit = match &spec.adaptive {
+            Some(
+                AdaptiveConcurrency::Aimd {
+                    min_limit,
+                    max_limit,
+                    ..
+                }
+                | AdaptiveConcurrency::Gradient {
+                    min_limit,
+                    max_limit,
+                    ..
+                },
+            ) => max_in_flight
+                .unwrap_or(INITIAL_ADAPTIVE_LIMIT)
+                .clamp(f64::from(*min_limit), f64::from(*max_limit)),
+            None => max_in_flight.unwrap_or(f64::INFINITY),
+        };
+
+        Ok(ConcurrencyLimiter {
+            max_per_backend: spec.max_in_flight_per_upstream.map(|max| max as usize),
+            queue_timeout: Duration::from_millis(spec.queue_timeout_millis),
+            max_queued: spec.max_queued as usize,
+            spec: spec.clone(),
+            state: Mutex::new(LimiterState {
+                limit: limit.max(1.0),
+                in_flight: 0,
+                queued: 0,
+                backends: HashMap::new(),
+                average_latency: None,
+            }),
+            released: Notify::new(),
+        })
+    }
+
+    /// Takes a permit of the service, waiting in the queue until the
+    /// queue timeout or `deadline` if the service is at its limit.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        deadline: Option<Instant>,
+    ) -> pingora::Result<ConcurrencyPermit> {
+        let mut wait_until = Instant::now() + self.queue_timeout;
+        if let Some(deadline) = deadline {
+            wait_until = wait_until.min(deadline);
+        }
+
+        let mut queued = None;
+        loop {
+            // Registered before checking, so releases are not missed.
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            {
+                let mut state = self.state.lock().unwrap();
+                if (state.in_flight as f64) < state.limit.floor() {
+                    state.in_flight += 1;
+                    return Ok(ConcurrencyPermit {
+                        limiter: self.clone(),
+                        start: Instant::now(),
+                    });
+                }
+                if queued.is_none() {
+                    if self.queue_timeout.is_zero() || state.queued >= self.max_queued {
+                        return Err(shed());
+                    }
+                    state.queued += 1;
+                    queued = Some(QueueSlot(self));
+                }
+            }
+
+            if tokio::time::timeout_at(wait_until.into(), released)
+                .await
+                .is_err()
+            {
+                return Err(shed());
+            }
+        }
+    }
+
+    pub fn spec(&self) -> &ConcurrencyLimit {
+        &self.spec
+    }
+
+    /// Whether there is a limit per backend.
+    pub fn limits_backends(&self) -> bool {
+        self.max_per_backend.is_some()
+    }
+
+    /// Whether `backend` is below its limit.
+    pub fn has_capacity(&self, backend: &Backend) -> bool {
+        self.max_per_backend.is_none_or(|max| {
+            let state = self.state.lock().unwrap();
+            state.backends.get(backend).copied().unwrap_or(0) < max
+        })
+    }
+
+    /// Counts a request to `backend` while the permit is alive, unless
+    /// it is at its limit or backends are not limited.
+    pub fn try_start_backend(self: &Arc<Self>, backend: &Backend) -> Option<BackendPermit> {
+        let max = self.max_per_backend?;
+        let mut state = self.state.lock().unwrap();
+        if state.backends.get(backend).copied().unwrap_or(0) >= max {
+            return None;
+        }
+        *state.backends.entry(backend.clone()).or_default() += 1;
+        Some(BackendPermit {
+            limiter: self.clone(),
+            backend: backend.clone(),
+        })
+    }
+
+    /// Adapts the limit to the outcome of a request.
+    fn record(&self, latency: Duration, failed: bool) {
+        let Some(adaptive) = &self.spec.adaptive else {
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        let in_use = state.in_flight as f64 * 2.0 >= state.limit;
+        let limit = state.limit;
+
+        state.limit = match *adaptive {
+            AdaptiveConcurrency::Aimd {
+                min_limit,
+                max_limit,
+                latency_threshold_millis,
+                backoff_ratio,
+            } => {
+                let limit = if failed || latency > Duration::from_millis(latency_threshold_millis) {
+                    limit * backoff_ratio
+                } else if in_use {
+                    limit + 1.0
+                } else {
+                    limit
+                };
+                limit.clamp(f64::from(min_limit), f64::from(max_limit))
+            }
+            AdaptiveConcurrency::Gradient {
+                min_limit,
+                max_limit,
+                tolerance,
+            } => {
+                let limit = if failed {
+                    limit / 2.0
+                } else {
+                    let latency = latency.as_secs_f64().max(1e-6);
+                    let average = match state.average_latency {
+                        Some(average) => average * 0.95 + latency * 0.05,
+                        None => latency,
+                    };
+                    state.average_latency = Some(average);
+
+                    let gradient = (tolerance * average / latency).clamp(0.5, 1.0);
+                    if gradient >= 1.0 && !in_use {
+                        // Not enough requests to tell if more would do.
+                        limit
+                    } else {
+                        let target = limit * gradient + limit.sqrt();
+                        limit * 0.8 + target * 0.2
+                    }
+                };
+                limit.clamp(f64::from(min_limit), f64::from(max_limit))
+            }
+        }
+        .max(1.0);
+    }
+}
+
+fn validate(spec: &ConcurrencyLimit) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        spec.max_in_flight != Some(0),
+        "`maxInFlight` must be at least 1"
+    );
+    anyhow::ensure!(
+        spec.max_in_flight_per_upstream != Some(0),
+        "`maxInFlightPerUpstream` must be at least 1"
+    );
+    match spec.adaptive {
+        Some(AdaptiveConcurrency::Aimd {
+            min_limit,
+            max_limit,
+            backoff_ratio,
+            ..
+        }) => {
+            anyhow::ensure!(
+                min_limit <= max_limit,
+                "`minLimit` {} is above `maxLimit` {}",
+                min_limit,
+                max_limit
+            );
+            anyhow::ensure!(
+                backoff_ratio > 0.0 && backoff_ratio <= 1.0,
+                "`backoffRatio` must be in (0, 1]"
+            );
+        }
+        Some(AdaptiveConcurrency::Gradient {
+            min_limit,
+            max_limit,
+            tolerance,
+        }) => {
+            anyhow::ensure!(
+                min_limit <= max_limit,
+                "`minLimit` {} is above `maxLimit` {}",
+                min_limit,
+                max_limit
+            );
+            anyhow::ensure!(tolerance >= 1.0, "`tolerance` must be at least 1");
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+fn shed() -> Box<pingora::Error> {
+    grpc_error(
+        GrpcCode::ResourceExhausted,
+        "concurrency limit of the service reached",
+    )
+}
+
+/// Counts a request in the queue while alive.
+struct QueueSlot<'a>(&'a ConcurrencyLimiter);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().queued -= 1;
+    }
+}
+
+/// A request in flight to the service.
+pub struct ConcurrencyPermit {
+    limiter: Arc<ConcurrencyLimiter>,
+    start: Instant,
+}
+
+impl ConcurrencyPermit {
+    /// Releases the permit, adapting the limit to the outcome of the
+    /// request.
+    pub fn finish(self, failed: bool) {
+        self.limiter.record(self.start.elapsed(), failed);
+    }
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().in_flight -= 1;
+        self.limiter.released.notify_one();
+    }
+}
+
+/// A request in flight to a backend.
+pub struct BackendPermit {
+    limiter: Arc<ConcurrencyLimiter>,
+    backend: Backend,
+}
+
+impl Drop for BackendPermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        if let Some(count) = state.backends.get_mut(&self.backend) {
+            *count -= 1;
+            if *count == 0 {
+                state.backends.remove(&self.backend);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grpc::error::code_for_error;
+
+    fn limiter(spec: ConcurrencyLimit) -> Arc<ConcurrencyLimiter> {
+        Arc::new(ConcurrencyLimiter::new(&spec).unwrap())
+    }
+
+    fn limit(limiter: &ConcurrencyLimiter) -> f64 {
+        limiter.state.lock().unwrap().limit
+    }
+
+    #[tokio::test]
+    async fn sheds_over_limit() {
+        let limiter = limiter(ConcurrencyLimit {
+            max_in_flight: Some(1),
+            max_queued: 1,
+            ..Default::default()
+        });
+        let permit = limiter.acquire(None).await.unwrap();
+        let error = limiter.acquire(None).await.err().unwrap();
+        assert_eq!(code_for_error(&error), Some(GrpcCode::ResourceExhausted));
+
+        drop(permit);
+        limiter.acquire(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn queues_until_release() {
+        let limiter = limiter(ConcurrencyLimit {
+            max_in_flight: Some(1),
+            queue_timeout_millis: 1000,
+            max_queued: 1,
+            ..Default::default()
+        });
+        let permit = limiter.acquire(None).await.unwrap();
+
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire(None).await.is_ok() }
+        });
+        tokio::task::yield_now().await;
+        // The queue is full.
+        assert!(limiter.acquire(None).await.is_err());
+
+        drop(permit);
+        assert!(waiting.await.unwrap());
+        assert_eq!(limiter.state.lock().unwrap().queued, 0);
+    }
+
+    #[tokio::test]
+    async fn aimd_adapts_to_failures() {
+        let limiter = limiter(ConcurrencyLimit {
+            max_in_flight: Some(10),
+            adaptive: Some(AdaptiveConcurrency::Aimd {
+                min_limit: 2,
+                max_limit: 11,
+                latency_threshold_millis: 1000,
+                backoff_ratio: 0.5,
+            }),
+            ..Default::default()
+        });
+
+        let mut permits = Vec::new();
+        for _ in 0..6 {
+            permits.push(limiter.acquire(None).await.unwrap());
+        }
+        permits.pop().unwrap().finish(false);
+        assert_eq!(limit(&limiter), 11.0);
+        permits.pop().unwrap().finish(true);
+        assert_eq!(limit(&limiter), 5.5);
+        for permit in permits {
+            permit.finish(true);
+        }
+        assert_eq!(limit(&limiter), 2.0);
+    }
+
+    #[test]
+    fn rejects_invalid_specs() {
+        let adaptive = |min_limit, max_limit| ConcurrencyLimit {
+            adaptive: Some(AdaptiveConcurrency::Gradient {
+                min_limit,
+                max_limit,
+                tolerance: 2.0,
+            }),
+            ..Default::default()
+        };
+        assert!(ConcurrencyLimiter::new(&adaptive(5, 5)).is_ok());
+        assert!(ConcurrencyLimiter::new(&adaptive(10, 5)).is_err());
+        assert!(ConcurrencyLimiter::new(&ConcurrencyLimit {
+            max_in_flight: Some(0),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(ConcurrencyLimiter::new(&ConcurrencyLimit {
+            max_in_flight_per_upstream: Some(0),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn limits_backends() {
+        let limiter = limiter(ConcurrencyLimit {
+            max_in_flight_per_upstream: Some(1),
+            ..Default::default()
+        });
+        let backend = Backend::new("127.0.0.1:80").unwrap();
+        assert!(limiter.has_capacity(&backend));
+        let permit = limiter.try_start_backend(&backend);
+        assert!(permit.is_some());
+        assert!(!limiter.has_capacity(&backend));
+        assert!(limiter.try_start_backend(&backend).is_none());
+        drop(permit);
+        assert!(limiter.has_capacity(&backend));
+    }
+}
@@ -0,0 +1,338 @@
+//! Hedged requests.
+//!
+//! Pingora sends each attempt of a request to a single upstream, so
+//! hedged requests are sent from `proxy_upstream_filter`, after a cache
+//! miss, through a connector of our own. The response which wins the
+//! race is passed through the proxy's response filters to the client
+//! and the cache, the other attempts are cancelled by dropping them.
+//!
+//! Responses are buffered until the race is decided. A response body
+//! above `MAX_BUFFERED_BODY` ends the race, and the rest of it is
+//! streamed from its upstream instead.
+
+use std::{
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream::FuturesUnordered, StreamExt};
+use grcache_shared::{config::HedgeBudgetConfig, protos::options::HedgingPolicy as HedgingOptions};
+use http::HeaderMap;
+use pingora::{
+    connectors::http::Connector,
+    http::{RequestHeader, ResponseHeader},
+    prelude::HttpPeer,
+    protocols::http::{client::HttpSession, v2::client::Http2Session},
+    ErrorType,
+};
+
+use crate::grpc::{
+    error::grpc_error,
+    status::{GrpcCode, GrpcStatus},
+};
+
+/// Response bodies are buffered up to this size while attempts race.
+pub const MAX_BUFFERED_BODY: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub struct HedgingPolicy {
+    max_attempts: u32,
+    delay: Duration,
+    non_fatal_codes: Vec<GrpcCode>,
+}
+
+impl HedgingPolicy {
+    /// From the `hedging_policy` method option, where fields left at
+    /// zero take their defaults.
+    pub fn from_method_options(options: &HedgingOptions) -> Self {
+        let mut names = options.non_fatal_status_codes.clone();
+        if names.is_empty() {
+            names.push("UNAVAILABLE".into());
+        }
+        let non_fatal_codes = names
+            .iter()
+            .filter_map(|name| {
+                let code = GrpcCode::from_name(name);
+                if code.is_none() {
+                    log::warn!("unknown gRPC status code `{}` in hedging policy", name);
+                }
+                code
+            })
+            .collect();
+
+        HedgingPolicy {
+            max_attempts: match options.max_attempts {
+                0 => 2,
+                max_attempts => max_attempts,
+            },
+            delay: Duration::from_millis(options.hedging_delay_millis),
+            non_fatal_codes,
+        }
+    }
+
+    /// Whether a response ends the hedged request.
+    fn is_final(&self, response: &HedgedResponse) -> bool {
+        response.rest.is_some()
+            || response
+                .status()
+                .is_none_or(|code| !self.non_fatal_codes.contains(&code))
+    }
+}
+
+/// Limits hedged attempts to a share of the requests to hedged methods,
+/// so that hedging cannot multiply the load of overloaded upstreams.
+#[derive(Debug)]
+pub struct HedgeBudget {
+    percent: u64,
+    min_per_second: u64,
+    window: Mutex<BudgetWindow>,
+}
+
+#[derive(Debug)]
+struct BudgetWindow {
+    start: Instant,
+    requests: u64,
+    hedges: u64,
+}
+
+impl HedgeBudget {
+    pub fn new(config: &HedgeBudgetConfig) -> Self {
+        HedgeBudget {
+            percent: config.percent.into(),
+            min_per_second: config.min_per_second.into(),
+            window: Mutex::new(BudgetWindow {
+                start: Instant::now(),
+                requests: 0,
+                hedges: 0,
+            }),
+        }
+    }
+
+    fn current_window(&self) -> std::sync::MutexGuard<'_, BudgetWindow> {
+        let mut window = self.window.lock().unwrap();
+        if window.start.elapsed() >= Duration::from_secs(1) {
+            *window = BudgetWindow {
+                start: Instant::now(),
+                requests: 0,
+                hedges: 0,
+            };
+        }
+        window
+    }
+
+    pub fn record_request(&self) {
+        self.current_window().requests += 1;
+    }
+
+    /// Takes a hedged attempt from the budget, if any is left.
+    pub fn try_acquire(&self) -> bool {
+        let mut window = self.current_window();
+        let allowed = (window.requests * self.percent / 100).max(self.min_per_second);
+        if window.hedges < allowed {
+            window.hedges += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Default for HedgeBudget {
+    fn default() -> Self {
+        HedgeBudget::new(&HedgeBudgetConfig::default())
+    }
+}
+
+/// An upstream response, complete unless its body is above
+/// `MAX_BUFFERED_BODY`.
+pub struct HedgedResponse {
+    pub header: ResponseHeader,
+    /// The body, or its start when `rest` is set.
+    pub body: Bytes,
+    pub trailers: Option<HeaderMap>,
+    /// Upstream stream of a response too large to buffer, to read the
+    /// rest of the body and the trailers from.
+    pub rest: Option<Box<Http2Session>>,
+}
+
+impl HedgedResponse {
+    /// gRPC status of the response, from the trailers or else the
+    /// headers of a trailers-only response.
+    pub fn status(&self) -> Option<GrpcCode> {
+        let headers = self.trailers.as_ref().unwrap_or(&self.header.headers);
+        GrpcStatus::parse(headers).ok().and_then(|s| s.code())
+    }
+}
+
+/// Sends a request to `peer` and reads the response, up to
+/// `MAX_BUFFERED_BODY` of its body.
+pub async fn send_attempt(
+    connector: &Connector,
+    peer: &HttpPeer,
+    request: RequestHeader,
+    body: Bytes,
+) -> pingora::Result<HedgedResponse> {
+    let (session, _reused) = connector.get_http_session(peer).await?;
+    let HttpSession::H2(mut session) = session else {
+        return Err(pingora::Error::explain(
+            ErrorType::ConnectProxyFailure,
+            "hedged requests need HTTP/2 upstreams",
+        ));
+    };
+    session.read_timeout = peer.options.read_timeout;
+
+    session.write_request_header(Box::new(request), body.is_empty())?;
+    if !body.is_empty() {
+        session.write_request_body(body, true)?;
+    }
+    session.read_response_header().await?;
+
+    let mut response_body = BytesMut::new();
+    while let Some(chunk) = session.read_response_body().await? {
+        response_body.extend_from_slice(&chunk);
+        if response_body.len() > MAX_BUFFERED_BODY {
+            return Ok(HedgedResponse {
+                header: session.response_header().unwrap().clone(),
+                body: response_body.freeze(),
+                trailers: None,
+                rest: Some(Box::new(session)),
+            });
+        }
+    }
+    let trailers = session.read_trailers().await?;
+    let header = session.response_header().unwrap().clone();
+    connector
+        .release_http_session(HttpSession::H2(session), peer, None)
+        .await;
+
+    Ok(HedgedResponse {
+        header,
+        body: response_body.freeze(),
+        trailers,
+        rest: None,
+    })
+}
+
+/// Races attempts from `start` until one gives a final response.
+/// Another attempt is started every hedging delay while the budget
+/// allows, and right away after a non-fatal failure. `start` returns
+/// `None` when there is no upstream left to try.
+pub async fn race<F, Fut>(
+    policy: &HedgingPolicy,
+    budget: &HedgeBudget,
+    mut start: F,
+) -> pingora::Result<HedgedResponse>
+where
+    F: FnMut() -> Option<Fut>,
+    Fut: Future<Output = pingora::Result<HedgedResponse>>,
+{
+    let mut pending = FuturesUnordered::new();
+    pending
+        .push(start().ok_or_else(|| grpc_error(GrpcCode::Unavailable, "no upstream available"))?);
+    let mut attempts = 1;
+    let mut next_hedge = Instant::now() + policy.delay;
+    let mut fallback = None;
+
+    loop {
+        tokio::select! {
+            Some(result) = pending.next() => {
+                match result {
+                    Ok(response) if policy.is_final(&response) => return Ok(response),
+                    result => fallback = Some(result),
+                }
+                next_hedge = Instant::now();
+            }
+            _ = tokio::time::sleep_until(next_hedge.into()), if attempts < policy.max_attempts => {
+                attempts += 1;
+                let attempt = if budget.try_acquire() { start() } else { None };
+                match attempt {
+                    Some(attempt) => pending.push(attempt),
+                    // Out of budget or upstreams, stop hedging.
+                    None => attempts = policy.max_attempts,
+                }
+                next_hedge = Instant::now() + policy.delay;
+            }
+        }
+
+        if pending.is_empty() && attempts >= policy.max_attempts {
+            // Every attempt failed, the last failure is returned.
+            return fallback.unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trailers_only(status: &str) -> HedgedResponse {
+        let mut header = ResponseHeader::build(200, None).unwrap();
+        header.insert_header("grpc-status", status).unwrap();
+        HedgedResponse {
+            header,
+            body: Bytes::new(),
+            trailers: None,
+            rest: None,
+        }
+    }
+
+    #[test]
+    fn budget_allows_share_of_requests() {
+        let budget = HedgeBudget::new(&HedgeBudgetConfig {
+            percent: 10,
+            min_per_second: 1,
+        });
+        assert!(budget.try_acquire());
+        assert!(!budget.try_acquire());
+
+        for _ in 0..30 {
+            budget.record_request();
+        }
+        assert!(budget.try_acquire());
+        assert!(budget.try_acquire());
+        assert!(!budget.try_acquire());
+    }
+
+    #[tokio::test]
+    async fn race_uses_first_final_response() {
+        let policy = HedgingPolicy::from_method_options(&HedgingOptions {
+            max_attempts: 3,
+            hedging_delay_millis: 10,
+            ..Default::default()
+        });
+        let budget = HedgeBudget::default();
+
+        // The first attempt never answers, the second is non-fatal and
+        // the third wins.
+        let mut started = 0;
+        let response = race(&policy, &budget, || {
+            started += 1;
+            let attempt = started;
+            Some(async move {
+                match attempt {
+                    1 => std::future::pending().await,
+                    2 => Ok(trailers_only("14")),
+                    _ => Ok(trailers_only("5")),
+                }
+            })
+        })
+        .await
+        .unwrap();
+        assert_eq!(response.status(), Some(GrpcCode::NotFound));
+        assert_eq!(started, 3);
+
+        // Without upstreams to hedge to, the first attempt is awaited.
+        let response = race(&policy, &budget, {
+            let mut first = true;
+            move || {
+                let attempt = std::mem::take(&mut first);
+                attempt.then_some(async { Ok(trailers_only("14")) })
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(response.status(), Some(GrpcCode::Unavailable));
+    }
+}
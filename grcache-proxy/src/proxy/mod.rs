@@ -1,61 +1,531 @@
 use std::{
     collections::BTreeSet,
-    time::{Duration, SystemTime},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::bail;
 use async_trait::async_trait;
 use blake2::{Blake2b, Digest};
 use bytes::Bytes;
-use grcache_shared::service::MethodSpec;
+use concurrency::{BackendPermit, ConcurrencyPermit};
+use grcache_shared::{
+    config::{
+        context::ConfigContext,
+        crd::{HashOn, RateLimitKey},
+    },
+    field_ref::FieldRef,
+    protos::options::GrcacheMethodOptions,
+    service::MethodSpec,
+};
+use hedge::{HedgeBudget, HedgedResponse, HedgingPolicy};
+use http::HeaderMap;
 use opentelemetry::{
     global::{BoxedSpan, BoxedTracer},
     trace::{noop::NoopTracer, Span as _, SpanKind, Tracer},
     KeyValue,
 };
 use pingora::{
-    cache::{CacheKey, CacheMeta, RespCacheable},
-    http::ResponseHeader,
+    cache::{CacheKey, CacheMeta, CachePhase, NoCacheReason, RespCacheable},
+    connectors::http::Connector,
+    http::{RequestHeader, ResponseHeader},
     prelude::HttpPeer,
-    protocols::ALPN,
+    protocols::http::{HttpTask, ServerSession},
+    BError, ErrorSource,
 };
+use pingora_load_balancing::Backend;
 use pingora_proxy::{ProxyHttp, Session};
+use protobuf::{
+    descriptor::method_options::IdempotencyLevel,
+    reflect::{MethodDescriptor, ReflectValueBox},
+    MessageDyn,
+};
+use rand::Rng;
+use retry::{RetryPolicy, RetryState};
 
 use crate::{
-    cache::GrcacheStorage,
+    cache::{set_lookup_deadline, GrcacheStorage, LookupDeadline},
+    discovery::balancer::InFlight,
     grpc::{
-        hash::{hash_body, hash_vary},
-        headers::{find_strip_headers, make_vary_headers_set},
-        status::GrpcStatus,
+        error::{
+            code_for_error, grpc_error, grpc_error_because, message_for_error, respond_grpc_error,
+        },
+        hash::{hash_body, hash_tenant, hash_upstream, hash_vary},
+        headers::{find_strip_headers, make_header_names_set, make_vary_headers_set},
+        json::{is_json_request, respond_json_error, JsonCtx},
+        status::{GrpcCode, GrpcStatus},
+        timeout::{encode_grpc_timeout, parse_grpc_timeout},
+        web::{decode_text_body, GrpcWebCtx, GrpcWebMode},
     },
-    service_store::{ServiceConfig, ServiceData},
-    tracing::extract_context_from_headers,
+    service_store::{ServiceConfig, ServiceData, ServiceKey},
+    tenant::TenantResolver,
+    tls,
+    tracing::{extract_context_from_headers, propagation_fields},
 };
 
+pub mod concurrency;
+pub mod hedge;
 //mod logic;
+pub mod rate_limit;
+pub mod retry;
+pub mod routing;
 
 pub struct GrpcProxy {
     pub service_config: crate::service_store::ServiceConfig,
+    /// Live config file, for settings which can be reloaded.
+    pub config: ConfigContext,
     pub cache: &'static (dyn GrcacheStorage + Sync),
     pub noop_tracer: BoxedTracer,
     pub tracer: BoxedTracer,
+    /// Accept `application/json` requests and transcode them to and
+    /// from gRPC, see `grpc::json`.
+    pub json_transcoding: bool,
+    /// Partitions the cache by tenant, see `tenant`.
+    pub tenant: Option<TenantResolver>,
+    /// Shared by the hedged requests of all services.
+    pub hedge_budget: HedgeBudget,
+    /// Connects to upstreams for hedged requests.
+    hedge_connector: Connector,
 }
 
 impl GrpcProxy {
-    pub fn new(service_config: ServiceConfig, cache: &'static (dyn GrcacheStorage + Sync)) -> Self {
+    pub fn new(
+        service_config: ServiceConfig,
+        config: ConfigContext,
+        cache: &'static (dyn GrcacheStorage + Sync),
+    ) -> Self {
         GrpcProxy {
             service_config,
+            config,
             cache,
             noop_tracer: BoxedTracer::new(Box::new(NoopTracer::new())),
             tracer: opentelemetry::global::tracer("grcache-proxy"),
+            json_transcoding: false,
+            tenant: None,
+            hedge_budget: HedgeBudget::default(),
+            hedge_connector: Connector::new(None),
+        }
+    }
+
+    /// Headers which are passed to the upstream without being part of
+    /// the cache key. Combines the config file, the `GrcacheService`,
+    /// the method options and the headers of the active trace
+    /// propagators.
+    fn propagation_set(&self, meta: &GrpcMeta) -> BTreeSet<String> {
+        let config = self.config.borrow().config.clone();
+        let method_headers = meta
+            .method_options()
+            .map(|o| &o.propagation_headers[..])
+            .unwrap_or_default();
+        let tracer_headers = propagation_fields();
+        let tenant_headers = self.tenant.iter().flat_map(|t| t.headers());
+
+        make_header_names_set(
+            config
+                .proxy
+                .propagation_headers
+                .iter()
+                .chain(meta.service_data.header_policy.propagation.iter())
+                .chain(method_headers)
+                .chain(&tracer_headers)
+                .chain(tenant_headers),
+        )
+    }
+
+    /// Returns the first request header which makes the request bypass
+    /// the cache. Combines the config file, the `GrcacheService` and the
+    /// method options.
+    fn find_bypass_header<'a>(&self, meta: &GrpcMeta, headers: &'a HeaderMap) -> Option<&'a str> {
+        let config = self.config.borrow().config.clone();
+        let method_headers = meta
+            .method_options()
+            .map(|o| &o.bypass_cache_headers[..])
+            .unwrap_or_default();
+
+        let bypass_set = make_header_names_set(
+            config
+                .proxy
+                .bypass_cache_headers
+                .iter()
+                .chain(meta.service_data.header_policy.bypass_cache.iter())
+                .chain(method_headers),
+        );
+        headers
+            .keys()
+            .map(|name| name.as_str())
+            .find(|name| bypass_set.contains(*name))
+    }
+
+    /// Key of the request for consistent hashing, if the service hashes
+    /// requests. Hashing on a field buffers the request body.
+    async fn hash_key(
+        &self,
+        session: &mut Session,
+        ctx: &RequestCtx,
+    ) -> pingora::Result<Option<Vec<u8>>> {
+        let meta = ctx.grpc_meta.as_ref().unwrap();
+        match meta.service_data.balancer.hash_on() {
+            None => Ok(None),
+            Some(HashOn::Header(name)) => Ok(session
+                .req_header()
+                .headers
+                .get(name.as_str())
+                .map(|value| value.as_bytes().to_vec())),
+            Some(HashOn::Field(path)) => request_field(session, ctx, path).await,
+        }
+    }
+
+    /// Rejects the request if it is over a rate limit of its service,
+    /// with a `retry-after` of the seconds until it would pass.
+    async fn check_rate_limits(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestCtx,
+    ) -> pingora::Result<()> {
+        let meta = ctx.grpc_meta.as_ref().unwrap();
+        let Some(limiter) = meta.service_data.rate_limiter.clone() else {
+            return Ok(());
+        };
+        let service = meta.service_name.clone();
+        let method = meta.method_name.clone();
+
+        for (index, key) in limiter.keys_for(&method) {
+            let key = match key {
+                None => None,
+                Some(RateLimitKey::Header(name)) => session
+                    .req_header()
+                    .headers
+                    .get(name.as_str())
+                    .map(|value| value.as_bytes().to_vec()),
+                Some(RateLimitKey::PeerIp {}) => session
+                    .client_addr()
+                    .and_then(|addr| addr.as_inet())
+                    .map(|addr| addr.ip().to_string().into_bytes()),
+                Some(RateLimitKey::Field(path)) => request_field(session, ctx, path).await?,
+            };
+
+            let Some(wait) = limiter
+                .take(index, &service, key.as_deref(), self.cache)
+                .await
+            else {
+                continue;
+            };
+            let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            ctx.error_metadata
+                .insert("retry-after", retry_after.max(1).into());
+            ctx.error_metadata
+                .insert("grpc-retry-pushback-ms", (wait.as_millis() as u64).into());
+            return Err(grpc_error(
+                GrpcCode::ResourceExhausted,
+                "rate limit of the service reached",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Data of a service, if it has a load balancer.
+    fn service_data(&self, key: &ServiceKey) -> Option<ServiceData> {
+        self.service_config
+            .services
+            .pin()
+            .get(key)
+            .filter(|s| s.load_balancer.is_some())
+            .cloned()
+    }
+
+    /// Routes a request to a named upstream. Requests start on the
+    /// upstream without a name, which declares the routing rules. The
+    /// rules come first, then `requested_upstream` from the upstream
+    /// header, then the traffic splits of the service. Requests stay on
+    /// the upstream without a name otherwise.
+    async fn route(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestCtx,
+        requested_upstream: Option<String>,
+    ) -> pingora::Result<()> {
+        let meta = ctx.grpc_meta.as_ref().unwrap();
+        let service = meta.service_name.clone();
+        let method = meta.method_name.clone();
+
+        let mut upstream = None;
+        if let Some(rules) = meta.service_data.routing_rules.clone() {
+            let message = if rules.needs_message(&method) {
+                // JSON is decoded with the types of the upstream without
+                // a name, before the upstream of the request is known.
+                if let (Some(json), Some(method_spec)) = (ctx.json.as_mut(), meta.method_spec()) {
+                    json.set_method(&method_spec.descriptor)?;
+                }
+                request_message(session, ctx).await?
+            } else {
+                None
+            };
+            if let Some(rule) =
+                rules.route(&method, &session.req_header().headers, message.as_deref())
+            {
+                log::debug!(
+                    "request to {}/{} matched routing rule {}",
+                    service,
+                    method,
+                    rule
+                );
+                ctx.span
+                    .set_attribute(KeyValue::new("routing_rule", rule.name().to_owned()));
+                upstream = Some(rule.upstream().to_owned());
+            }
+        }
+
+        let meta = ctx.grpc_meta.as_ref().unwrap();
+        let (upstream, requested) = match (upstream, requested_upstream) {
+            (Some(upstream), _) => (upstream, false),
+            (None, Some(requested)) => (requested, true),
+            (None, None) if meta.upstream_name.is_some() => return Ok(()),
+            (None, None) => {
+                let split = self
+                    .service_config
+                    .split_upstream(&service, rand::thread_rng().gen_range(0..100));
+                let Some(upstream) = split else {
+                    return Ok(());
+                };
+                (upstream, false)
+            }
+        };
+        if meta.upstream_name.as_ref() == Some(&upstream) {
+            return Ok(());
+        }
+
+        let key = ServiceKey::new(service, Some(upstream));
+        match self.service_data(&key) {
+            Some(service_data) => {
+                let meta = ctx.grpc_meta.as_mut().unwrap();
+                meta.service_data = service_data;
+                meta.upstream_name = key.upstream;
+            }
+            // Only upstreams chosen by clients are errors.
+            None if requested => return Err(unknown_service(&key)),
+            None => log::warn!("routed to unknown service `{}`, ignoring route", key),
+        }
+        Ok(())
+    }
+
+    /// Checks the method against the service the request is sent to,
+    /// and sets up transcoding for its descriptors.
+    fn set_up_service(&self, ctx: &mut RequestCtx) -> pingora::Result<()> {
+        let meta = ctx.grpc_meta.as_ref().unwrap();
+        let (service_name, method) = (&meta.service_name, &meta.method_name);
+        if let Some(service) = meta.service_data.service_spec.as_ref() {
+            // Log a warning IF BOTH:
+            // * The service was not marked as passthrough
+            // * The method map does not contain the method
+            //
+            // We still proxy to upstream, but this is worrying and might
+            // indicate out of date proto descriptors.
+            if !service.passthrough && !service.methods.contains_key(method) {
+                log::warn!(
+                    "Got request for unknown gRPC method `{}` in service `{}`, passing through. Are the proto descriptors up to date?",
+                    method,
+                    service_name
+                )
+            }
+        }
+
+        // Transcoding JSON needs the message types of the method. Routed
+        // requests may keep the types of the upstream without a name.
+        if let Some(json) = ctx.json.as_mut() {
+            match meta.method_spec() {
+                Some(method_spec) => json.set_method(&method_spec.descriptor)?,
+                None if json.has_method() => {}
+                None => {
+                    return Err(grpc_error(
+                        GrpcCode::Unimplemented,
+                        format!(
+                            "no proto descriptor for method `{}` in service `{}`, required for JSON requests",
+                            method, service_name
+                        ),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Hedging policy of the request, if it is hedged. The body of
+    /// hedged requests is buffered to be sent to several upstreams.
+    async fn hedging(
+        &self,
+        session: &mut Session,
+        ctx: &RequestCtx,
+    ) -> pingora::Result<Option<HedgingPolicy>> {
+        let grpc_meta = ctx.grpc_meta.as_ref().unwrap();
+        let Some(policy) = grpc_meta.hedging_policy() else {
+            return Ok(None);
+        };
+        // Streams can't be replayed or buffered.
+        if grpc_meta.method_spec().is_some_and(|m| {
+            let proto = m.descriptor.proto();
+            proto.client_streaming() || proto.server_streaming()
+        }) {
+            log::warn!("hedging policy on streaming method, not hedging");
+            return Ok(None);
+        }
+        // Transcoded bodies are converted by pingora on the way to the
+        // upstream.
+        if ctx.grpc_web.is_some() || ctx.json.is_some() || session.as_http2().is_none() {
+            return Ok(None);
+        }
+
+        session.enable_retry_buffering();
+        while let Some(_bytes) = session.read_request_body().await? {}
+        if session.retry_buffer_truncated() {
+            log::warn!("request body above buffer size, not hedging");
+            return Ok(None);
+        }
+        Ok(Some(policy))
+    }
+
+    /// Races the request over several upstreams, see `hedge`.
+    async fn hedged_request(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestCtx,
+        policy: &HedgingPolicy,
+    ) -> pingora::Result<HedgedResponse> {
+        self.hedge_budget.record_request();
+        let mut request = session.req_header().clone();
+        self.upstream_request_filter(session, &mut request, ctx)
+            .await?;
+        let body = session.get_retry_buffer().unwrap_or_default();
+        let service_data = ctx.grpc_meta.as_ref().unwrap().service_data.clone();
+        let remaining = ctx.remaining_time();
+
+        let race = hedge::race(policy, &self.hedge_budget, || {
+            let (backend, peer, backend_permit) = ctx.next_upstream().ok()?;
+            let in_flight = service_data.balancer.start_request(&backend);
+            let (request, body, service_data) = (request.clone(), body.clone(), &service_data);
+            Some(async move {
+                let _permits = (in_flight, backend_permit);
+                let result = hedge::send_attempt(&self.hedge_connector, &peer, request, body).await;
+                let success = result
+                    .as_ref()
+                    .is_ok_and(|r| r.status() != Some(GrpcCode::Unavailable));
+                record_outcome(service_data, &backend, success);
+                result
+            })
+        });
+        match remaining {
+            Some(remaining) => tokio::time::timeout(remaining, race)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(grpc_error(
+                        GrpcCode::DeadlineExceeded,
+                        "deadline exceeded waiting for hedged attempts",
+                    ))
+                }),
+            None => race.await,
+        }
+    }
+
+    /// Writes the response of a hedged request to the client and the
+    /// cache, through the same filters as proxied responses.
+    async fn respond_hedged(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestCtx,
+        response: HedgedResponse,
+    ) -> pingora::Result<()> {
+        let HedgedResponse {
+            mut header,
+            body,
+            mut trailers,
+            mut rest,
+        } = response;
+        // The race already decided on this response.
+        ctx.retry = RetryState::default();
+
+        self.upstream_response_filter(session, &mut header, ctx);
+        if session.cache.phase() == CachePhase::Miss {
+            match self.response_cache_filter(session, &header, ctx)? {
+                RespCacheable::Cacheable(meta) => {
+                    session.cache.set_cache_meta(meta);
+                    session.cache.set_miss_handler().await?;
+                }
+                RespCacheable::Uncacheable(reason) => session.cache.disable(reason),
+            }
+        }
+        self.response_filter(session, &mut header, ctx).await?;
+        session
+            .write_response_tasks(vec![HttpTask::Header(Box::new(header), false)])
+            .await?;
+
+        let mut chunk = body;
+        loop {
+            let next = match rest.as_mut() {
+                Some(upstream) => upstream.read_response_body().await?,
+                None => None,
+            };
+            if next.is_none() {
+                if let Some(mut upstream) = rest.take() {
+                    trailers = upstream.read_trailers().await?;
+                }
+            }
+            let end = next.is_none() && trailers.is_none();
+
+            let mut body = Some(chunk);
+            self.upstream_response_body_filter(session, &mut body, end, ctx);
+            if session.cache.enabled() {
+                if let Some(miss_handler) = session.cache.miss_handler() {
+                    miss_handler
+                        .write_body(body.clone().unwrap_or_default(), end)
+                        .await?;
+                }
+            }
+            self.response_body_filter(session, &mut body, end, ctx)?;
+            session
+                .write_response_tasks(vec![HttpTask::Body(body, end)])
+                .await?;
+
+            match next {
+                Some(next) => chunk = next,
+                None => break,
+            }
+        }
+
+        if let Some(mut trailers) = trailers {
+            self.upstream_response_trailer_filter(session, &mut trailers, ctx)?;
+            let task = match self
+                .response_trailer_filter(session, &mut trailers, ctx)
+                .await?
+            {
+                Some(body) => HttpTask::Body(Some(body), true),
+                None => HttpTask::Trailer(Some(Box::new(trailers))),
+            };
+            session.write_response_tasks(vec![task]).await?;
+        }
+        if session.cache.enabled() {
+            session.cache.finish_miss_handler().await?;
         }
+        Ok(())
     }
 
     fn new_ctx(&self) -> RequestCtx {
         RequestCtx {
             span: self.noop_tracer.start("request"),
             do_cache: false,
+            tenant: None,
             grpc_meta: None,
+            grpc_web: None,
+            json: None,
+            upstream: None,
+            upstream_status: None,
+            hash_key: None,
+            in_flight: None,
+            retry: RetryState::default(),
+            deadline: None,
+            lookup_deadline: None,
+            hedging: None,
+            concurrency: None,
+            backend_permit: None,
+            error_metadata: HeaderMap::new(),
         }
     }
 }
@@ -80,6 +550,9 @@ fn parse_grpc_path(path: &[u8]) -> Result<(&str, &str), anyhow::Error> {
 
 struct GrpcMeta {
     service_data: ServiceData,
+    service_name: String,
+    /// `upstreamName` of the `GrcacheService` handling the request.
+    upstream_name: Option<String>,
     method_name: String,
     vary_set: BTreeSet<String>,
     //request_message: Option<Box<dyn MessageDyn>>,
@@ -92,12 +565,284 @@ impl GrpcMeta {
             .as_ref()
             .and_then(|s| s.methods.get(&self.method_name))
     }
+
+    fn method_options(&self) -> Option<&GrcacheMethodOptions> {
+        self.method_spec()
+            .and_then(|m| m.cache_spec.as_ref())
+            .map(|c| &c.descriptor)
+    }
+
+    /// Retry policy of the method, or else of the service.
+    fn retry_policy(&self) -> Option<Arc<RetryPolicy>> {
+        self.service_data
+            .method_retry_policies
+            .get(&self.method_name)
+            .or(self.service_data.retry_policy.as_ref())
+            .cloned()
+    }
+
+    /// Hedging policy of the method, if it is hedged.
+    fn hedging_policy(&self) -> Option<HedgingPolicy> {
+        self.method_options()
+            .and_then(|o| o.hedging_policy.as_ref())
+            .map(HedgingPolicy::from_method_options)
+    }
+
+    /// Whether calling the method again has no other effects. Cached
+    /// methods are assumed to be side effect free.
+    fn is_idempotent(&self) -> bool {
+        self.method_spec().is_some_and(|m| {
+            m.descriptor.proto().options.idempotency_level()
+                != IdempotencyLevel::IDEMPOTENCY_UNKNOWN
+                || m.cache_spec
+                    .as_ref()
+                    .is_some_and(|c| c.descriptor.cache_ttl > 0)
+        })
+    }
+}
+
+fn cache_meta(resp: &ResponseHeader) -> CacheMeta {
+    CacheMeta::new(
+        SystemTime::now()
+            .checked_add(Duration::from_secs(60))
+            .unwrap(),
+        SystemTime::now(),
+        // TODO what are these?
+        10,
+        10,
+        resp.clone(),
+    )
+}
+
+/// Records the outcome of a request to `backend` for outlier detection.
+fn record_outcome(service_data: &ServiceData, backend: &Backend, success: bool) {
+    if let (Some(outliers), Some(load_balancer)) =
+        (&service_data.outlier_detector, &service_data.load_balancer)
+    {
+        outliers.record(backend, success, &load_balancer.backends.borrow());
+    }
+}
+
+fn unknown_service(key: &ServiceKey) -> BError {
+    grpc_error(
+        GrpcCode::Unimplemented,
+        format!("unknown service `{}`", key),
+    )
+}
+
+/// The request message, if the method has descriptors. Buffers the
+/// request body.
+async fn request_message(
+    session: &mut Session,
+    ctx: &RequestCtx,
+) -> pingora::Result<Option<Box<dyn MessageDyn>>> {
+    let Some(method_spec) = ctx.grpc_meta.as_ref().unwrap().method_spec() else {
+        return Ok(None);
+    };
+
+    session.enable_retry_buffering();
+    while let Some(_bytes) = session.read_request_body().await? {}
+    if session.retry_buffer_truncated() {
+        log::warn!("request body above buffer size, not decoding request message");
+        return Ok(None);
+    }
+
+    let body = ctx.buffered_grpc_body(session)?;
+    Ok(first_message(&method_spec.descriptor, &body))
+}
+
+/// Value of the field at `path` in the request message, if the method
+/// has descriptors. Buffers the request body.
+async fn request_field(
+    session: &mut Session,
+    ctx: &RequestCtx,
+    path: &str,
+) -> pingora::Result<Option<Vec<u8>>> {
+    let Ok(field) = FieldRef::parse(path);
+    let message = request_message(session, ctx).await?;
+    Ok(message
+        .and_then(|message| field.resolve(&*message))
+        .map(field_bytes))
+}
+
+/// The first message of a gRPC request body.
+fn first_message(method: &MethodDescriptor, body: &[u8]) -> Option<Box<dyn MessageDyn>> {
+    let (header, rest) = body.split_at_checked(5)?;
+    if header[0] != 0 {
+        // Compressed messages are not decoded.
+        return None;
+    }
+    let length = u32::from_be_bytes(header[1..5].try_into().unwrap()) as usize;
+    method
+        .input_type()
+        .parse_from_bytes(rest.get(..length)?)
+        .ok()
+}
+
+/// A field value as bytes. Strings and bytes are taken as is, other
+/// values in their text form.
+fn field_bytes(value: ReflectValueBox) -> Vec<u8> {
+    match value {
+        ReflectValueBox::String(value) => value.into_bytes(),
+        ReflectValueBox::Bytes(value) => value,
+        value => value.as_value_ref().to_string().into_bytes(),
+    }
 }
 
 pub struct RequestCtx {
     span: BoxedSpan,
     do_cache: bool,
+    /// Present when the tenant of the request was identified.
+    tenant: Option<String>,
     grpc_meta: Option<GrpcMeta>,
+    /// Present when the client speaks gRPC-Web.
+    grpc_web: Option<GrpcWebCtx>,
+    /// Present when the client speaks JSON.
+    json: Option<JsonCtx>,
+    /// Backend selected in `upstream_peer`.
+    upstream: Option<Backend>,
+    /// gRPC status of the upstream response, once known.
+    upstream_status: Option<GrpcCode>,
+    /// Key for consistent hashing, if the service hashes requests.
+    hash_key: Option<Vec<u8>>,
+    /// Counts the request towards the selected upstream while alive.
+    in_flight: Option<InFlight>,
+    retry: RetryState,
+    /// From the `grpc-timeout` of the request, capped by the service.
+    deadline: Option<Instant>,
+    /// Makes cache lookups give up at the deadline while alive.
+    lookup_deadline: Option<LookupDeadline>,
+    /// Present when the request is hedged.
+    hedging: Option<HedgingPolicy>,
+    /// Counts the request towards the concurrency limit of the service
+    /// while alive.
+    concurrency: Option<ConcurrencyPermit>,
+    /// Counts the request towards the concurrency limit of the selected
+    /// upstream while alive.
+    backend_permit: Option<BackendPermit>,
+    /// Added to the error response if the proxy rejects the request.
+    error_metadata: HeaderMap,
+}
+
+impl RequestCtx {
+    /// Time left until the deadline of the request, if it has one.
+    fn remaining_time(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    fn deadline_exceeded(&self) -> bool {
+        self.remaining_time()
+            .is_some_and(|remaining| remaining.is_zero())
+    }
+
+    /// Records the outcome of the request to the selected upstream for
+    /// outlier detection. Only the first outcome is recorded.
+    fn record_upstream_outcome(&mut self, success: bool) {
+        let (Some(backend), Some(meta)) = (self.upstream.take(), &self.grpc_meta) else {
+            return;
+        };
+        record_outcome(&meta.service_data, &backend, success);
+    }
+
+    /// Selects the backend of the next upstream attempt, and the peer
+    /// to connect to it with. The backend's permit is taken with it if
+    /// backends are limited.
+    fn next_upstream(&mut self) -> pingora::Result<(Backend, HttpPeer, Option<BackendPermit>)> {
+        let service_data = &self.grpc_meta.as_ref().unwrap().service_data;
+        let handle = service_data.load_balancer.as_ref().unwrap();
+        let outliers = service_data.outlier_detector.as_deref();
+        let limiter = service_data.concurrency_limiter.as_ref();
+        let tried = self.retry.tried();
+        // Backends which reached their limit after being selected.
+        let mut full = Vec::new();
+        let (backend, backend_permit) = loop {
+            let available = |backend: &Backend| {
+                !outliers.is_some_and(|o| o.is_ejected(backend))
+                    && limiter.is_none_or(|l| l.has_capacity(backend))
+                    && !full.contains(backend)
+            };
+            // Retries go to another backend if there is one.
+            let backend = service_data
+                .balancer
+                .select(handle, self.hash_key.as_deref(), |backend| {
+                    available(backend) && !tried.contains(backend)
+                })
+                .or_else(|| {
+                    if tried.is_empty() {
+                        return None;
+                    }
+                    service_data
+                        .balancer
+                        .select(handle, self.hash_key.as_deref(), available)
+                });
+            let Some(backend) = backend else {
+                // Backends at their limit are told apart from no backends.
+                if limiter.is_some_and(|l| l.limits_backends())
+                    && service_data
+                        .balancer
+                        .select(handle, None, |_| true)
+                        .is_some()
+                {
+                    return Err(grpc_error(
+                        GrpcCode::ResourceExhausted,
+                        "all upstreams are at their concurrency limit",
+                    ));
+                }
+                return Err(grpc_error(GrpcCode::Unavailable, "no upstream available"));
+            };
+
+            match limiter.filter(|l| l.limits_backends()) {
+                Some(limiter) => match limiter.try_start_backend(&backend) {
+                    Some(permit) => break (backend, Some(permit)),
+                    None => full.push(backend),
+                },
+                None => break (backend, None),
+            }
+        };
+
+        let inet = backend.addr.as_inet().unwrap();
+        let mut peer = tls::upstream_peer(*inet, service_data.upstream_tls.as_deref());
+        if let Some(remaining) = self.remaining_time() {
+            peer.options.connection_timeout = Some(remaining);
+            peer.options.total_connection_timeout = Some(remaining);
+            peer.options.read_timeout = Some(remaining);
+            peer.options.write_timeout = Some(remaining);
+        }
+        if let Some(timeout) = self.retry.attempt_timeout() {
+            peer.options.read_timeout = Some(timeout);
+        }
+        self.retry.record_backend(backend.clone());
+        Ok((backend, peer, backend_permit))
+    }
+
+    /// The buffered request body as native gRPC, also for clients
+    /// speaking gRPC-Web or JSON.
+    fn buffered_grpc_body(&self, session: &Session) -> pingora::Result<Bytes> {
+        let mut request_body = session.get_retry_buffer().unwrap_or_default();
+
+        if let Some(GrpcWebMode::Text) = self.grpc_web.as_ref().map(|w| w.mode) {
+            request_body = decode_text_body(&request_body).map_err(|cause| {
+                grpc_error_because(
+                    GrpcCode::InvalidArgument,
+                    "invalid grpc-web-text request body",
+                    cause,
+                )
+            })?;
+        }
+        if let Some(json) = self.json.as_ref() {
+            request_body = json.transcode_request(&request_body)?;
+        }
+        Ok(request_body)
+    }
+
+    /// Content type for responses generated by the proxy itself.
+    fn response_content_type(&self) -> &'static str {
+        match &self.grpc_web {
+            Some(web) => web.mode.content_type(),
+            None => "application/grpc",
+        }
+    }
 }
 
 pub(crate) type Blake2b128 = Blake2b<blake2::digest::consts::U16>;
@@ -159,13 +904,20 @@ impl ProxyHttp for GrpcProxy {
     where
         Self::CTX: Send + Sync,
     {
+        // gRPC-Web requests are translated to native gRPC towards the
+        // upstream, see `grpc::web`.
+        if let Some(mode) = GrpcWebMode::from_request(session.req_header()) {
+            ctx.grpc_web = Some(GrpcWebCtx::new(mode));
+            ctx.span
+                .set_attribute(KeyValue::new("grpc_web", format!("{:?}", mode)));
+        } else if self.json_transcoding && is_json_request(session.req_header()) {
+            ctx.json = Some(JsonCtx::new());
+            ctx.span.set_attribute(KeyValue::new("json", true));
+        }
+
         let (service_name, method) =
             parse_grpc_path(session.req_header().raw_path()).map_err(|cause| {
-                pingora::Error::because(
-                    pingora::ErrorType::HTTPStatus(400),
-                    "invalid grpc http path",
-                    cause,
-                )
+                grpc_error_because(GrpcCode::InvalidArgument, "invalid grpc http path", cause)
             })?;
 
         ctx.span
@@ -173,42 +925,99 @@ impl ProxyHttp for GrpcProxy {
         ctx.span
             .set_attribute(KeyValue::new("grpc_method", method.to_owned()));
 
-        let service_spec = {
-            let services_map = self.service_config.services.pin();
-
-            services_map
-                .get(service_name)
-                // Service is only present if we have a LB
-                .filter(|s| s.load_balancer.is_some())
-                .ok_or_else(|| {
-                    pingora::Error::explain(pingora::ErrorType::HTTPStatus(404), "unknown service")
-                })?
-                .clone()
-        };
+        let upstream_header = self.config.borrow().config.proxy.upstream_header.clone();
+        let upstream_name = session
+            .req_header()
+            .headers
+            .get(upstream_header.as_str())
+            .map(|value| {
+                value.to_str().map(str::to_owned).map_err(|cause| {
+                    grpc_error_because(
+                        GrpcCode::InvalidArgument,
+                        "invalid upstream name header",
+                        cause,
+                    )
+                })
+            })
+            .transpose()?;
+        // The upstream header is applied by `route`, after the routing
+        // rules of the upstream without a name. Services with only named
+        // upstreams start on the one of the header.
+        let (service_key, service_data, requested_upstream) =
+            match self.service_data(&ServiceKey::new(service_name, None)) {
+                Some(service_data) => (
+                    ServiceKey::new(service_name, None),
+                    service_data,
+                    upstream_name,
+                ),
+                None => {
+                    let service_key = ServiceKey::new(service_name, upstream_name);
+                    let service_data = self
+                        .service_data(&service_key)
+                        .ok_or_else(|| unknown_service(&service_key))?;
+                    (service_key, service_data, None)
+                }
+            };
 
-        if let Some(service) = service_spec.service_spec.as_ref() {
-            // Log a warning IF BOTH:
-            // * The service was not marked as passthrough
-            // * The method map does not contain the method
-            //
-            // We still proxy to upstream, but this is worrying and might
-            // indicate out of date proto descriptors.
-            if !service.passthrough && !service.methods.contains_key(method) {
-                log::warn!(
-                    "Got request for unknown gRPC method `{}` in service `{}`, passing through. Are the proto descriptors up to date?",
-                    method,
-                    service_name
-                )
+        ctx.grpc_meta = Some(GrpcMeta {
+            service_data,
+            service_name: service_name.into(),
+            upstream_name: service_key.upstream,
+            method_name: method.into(),
+            vary_set: BTreeSet::new(),
+        });
+
+        let mut missing_tenant = false;
+        if let Some(tenant) = self.tenant.as_ref() {
+            let ssl_digest = session
+                .digest()
+                .and_then(|d| d.ssl_digest.as_ref())
+                .map(|d| &**d);
+            ctx.tenant = tenant.resolve(&session.req_header().headers, ssl_digest);
+            missing_tenant = tenant.required() && ctx.tenant.is_none();
+            if let Some(tenant) = ctx.tenant.as_ref() {
+                ctx.span
+                    .set_attribute(KeyValue::new("tenant", tenant.clone()));
             }
         }
 
-        let vary_set = make_vary_headers_set(&session.req_header().headers)?;
+        self.route(session, ctx, requested_upstream).await?;
+        self.set_up_service(ctx)?;
+        // The upstream name is part of the cache key, and not passed on.
+        session
+            .req_header_mut()
+            .remove_header(upstream_header.as_str());
+        let meta = ctx.grpc_meta.as_mut().unwrap();
+        if let Some(upstream_name) = &meta.upstream_name {
+            ctx.span
+                .set_attribute(KeyValue::new("upstream_name", upstream_name.clone()));
+        }
 
-        ctx.grpc_meta = Some(GrpcMeta {
-            service_data: service_spec.clone(),
-            method_name: method.into(),
-            vary_set,
-        });
+        // The client `vary` header can only add to the vary headers
+        // declared for the service and method.
+        meta.vary_set = make_vary_headers_set(&session.req_header().headers)?;
+        let method_vary = meta
+            .method_options()
+            .map(|o| make_header_names_set(&o.vary_headers))
+            .unwrap_or_default();
+        meta.vary_set.extend(method_vary);
+        meta.vary_set
+            .extend(meta.service_data.header_policy.vary.iter().cloned());
+        let bypass_header = self
+            .find_bypass_header(meta, &session.req_header().headers)
+            .map(str::to_owned);
+        let timeout = session
+            .req_header()
+            .headers
+            .get("grpc-timeout")
+            .and_then(|value| parse_grpc_timeout(value.as_bytes()))
+            .into_iter()
+            .chain(meta.service_data.max_timeout)
+            .min();
+        ctx.deadline = timeout.map(|timeout| Instant::now() + timeout);
+        ctx.retry = RetryState::new(meta.retry_policy(), meta.is_idempotent(), ctx.deadline);
+
+        self.check_rate_limits(session, ctx).await?;
 
         if let Some((_method_spec, cache_spec)) = ctx
             .grpc_meta
@@ -217,6 +1026,7 @@ impl ProxyHttp for GrpcProxy {
             .method_spec()
             .and_then(|m| m.cache_spec.as_ref().map(|c| (m, c)))
             .filter(|(_m, c)| c.descriptor.cache_ttl > 0)
+            .filter(|_| bypass_header.is_none() && !missing_tenant)
         {
             ctx.do_cache = true;
             log::info!(
@@ -229,19 +1039,19 @@ impl ProxyHttp for GrpcProxy {
 
             if session.retry_buffer_truncated() {
                 log::error!("Request body above buffer size!");
-                return Err(pingora::Error::explain(
-                    pingora::ErrorType::HTTPStatus(413),
+                return Err(grpc_error(
+                    GrpcCode::ResourceExhausted,
                     "request body size above buffer size for cache",
                 ));
             }
 
             // Remove request headers which are not allowed.
             // We do this to protect against cache leaks.
+            let meta = ctx.grpc_meta.as_ref().unwrap();
             let strip_headers = find_strip_headers(
                 &session.req_header().headers,
-                &ctx.grpc_meta.as_ref().unwrap().vary_set,
-                // TODO from config
-                &BTreeSet::new(),
+                &meta.vary_set,
+                &self.propagation_set(meta),
             );
             for header in strip_headers.iter() {
                 session.req_header_mut().remove_header(header);
@@ -260,6 +1070,12 @@ impl ProxyHttp for GrpcProxy {
             //        log::error!("error while parsing message for caching! {}", error);
             //    }
             //}
+        } else if let Some(header) = bypass_header {
+            log::info!("cache bypassed for request with header `{}`", header);
+            ctx.span
+                .set_attribute(KeyValue::new("cache_bypass_header", header));
+        } else if missing_tenant {
+            log::info!("cache bypassed for request without tenant");
         } else {
             log::info!("cache not enabled for request");
         }
@@ -267,6 +1083,9 @@ impl ProxyHttp for GrpcProxy {
         ctx.span
             .set_attribute(KeyValue::new("cache_enabled", ctx.do_cache));
 
+        ctx.hash_key = self.hash_key(session, ctx).await?;
+        ctx.hedging = self.hedging(session, ctx).await?;
+
         Ok(false)
     }
 
@@ -294,22 +1113,29 @@ impl ProxyHttp for GrpcProxy {
         let meta = ctx.grpc_meta.as_ref().unwrap();
 
         let req_header = session.req_header();
-        let request_body = session.get_retry_buffer().unwrap();
+        // Hash the decoded message so that gRPC-Web and JSON clients
+        // share cache entries with everyone else.
+        let request_body = ctx.buffered_grpc_body(session)?;
 
         let mut hasher = Blake2b128::new();
+        hash_tenant(&mut hasher, ctx.tenant.as_deref());
+        hash_upstream(&mut hasher, meta.upstream_name.as_deref());
         hash_vary(&mut hasher, &meta.vary_set, &req_header.headers);
-        hash_body(&mut hasher, &req_header, &request_body);
+        hash_body(&mut hasher, req_header, &request_body);
         let key_hash = hasher.finalize();
 
         // Ultimately the only thing that matters here is that
         // how we construct the key here matches what the cache
         // backends expect.
         // For now the cache backends expect:
-        // * A namespace. Empty for now.
-        // * A primary bin override, used as primary cache key.
-        let mut cache_key = CacheKey::new("", "", "");
+        // * A namespace. The tenant, empty without tenants.
+        // * A primary bin override, used as primary cache key. The
+        //   tenant and upstream name are hashed into it as well.
+        let mut cache_key = CacheKey::new(ctx.tenant.as_deref().unwrap_or_default(), "", "");
         cache_key.set_primary_bin_override(key_hash.into());
-        Ok(CacheKey::default(req_header))
+        // Cache lookups give up at the deadline of the request.
+        ctx.lookup_deadline = ctx.deadline.map(set_lookup_deadline);
+        Ok(cache_key)
     }
 
     async fn cache_hit_filter(
@@ -337,7 +1163,130 @@ impl ProxyHttp for GrpcProxy {
         let age_sec = meta.age().as_secs();
         let hit = (age_sec as i64) < (cache_spec.descriptor.cache_ttl as i64);
         log::info!("found cache entry (age: {}s) (hit: {})", age_sec, hit);
-        Ok(hit)
+        // Entries past the TTL of the method are force expired.
+        Ok(!hit)
+    }
+
+    async fn request_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if let Some(web) = ctx.grpc_web.as_mut() {
+            web.request_body(body, end_of_stream)?;
+        }
+        if let Some(json) = ctx.json.as_mut() {
+            json.request_body(body, end_of_stream)?;
+        }
+        Ok(())
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        upstream_request: &mut RequestHeader,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        if let Some(web) = ctx.grpc_web.as_ref() {
+            web.upstream_request_header(upstream_request)?;
+        }
+        if let Some(json) = ctx.json.as_ref() {
+            json.upstream_request_header(upstream_request)?;
+        }
+        // The upstream gets the time left after the cache lookup and
+        // any previous attempts.
+        if let Some(remaining) = ctx.remaining_time() {
+            upstream_request.insert_header("grpc-timeout", encode_grpc_timeout(remaining))?;
+        }
+        Ok(())
+    }
+
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        // Nothing was sent to the client yet, so a trailers-only
+        // response can still be retried.
+        if let Some(code) = ctx.upstream_status {
+            if ctx.retry.is_retryable(code)
+                && ctx.retry.can_retry(true)
+                && !session.retry_buffer_truncated()
+            {
+                ctx.record_upstream_outcome(code != GrpcCode::Unavailable);
+                let mut e = grpc_error(code, format!("upstream responded with {}", code.as_str()));
+                e.set_retry(true);
+                return Err(e);
+            }
+        }
+
+        let downstream_is_h1 = session.as_http2().is_none();
+        if let Some(web) = ctx.grpc_web.as_ref() {
+            web.response_header(upstream_response, downstream_is_h1)?;
+        }
+        if let Some(json) = ctx.json.as_mut() {
+            json.response_header(upstream_response, downstream_is_h1)?;
+        }
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<Option<Duration>>
+    where
+        Self::CTX: Send + Sync,
+    {
+        // Trailers are not stored in cache, only successful responses
+        // are cached.
+        let served_from_cache = session.cache.enabled() && !session.cache.upstream_used();
+        if let Some(web) = ctx.grpc_web.as_mut() {
+            web.response_body(body, end_of_stream, served_from_cache);
+        } else if let Some(json) = ctx.json.as_mut() {
+            json.response_body(body, end_of_stream);
+        } else if served_from_cache && end_of_stream {
+            if let ServerSession::H2(h2) = session.as_mut() {
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", http::HeaderValue::from_static("0"));
+                h2.write_trailers(trailers)?;
+            }
+        }
+        Ok(None)
+    }
+
+    async fn response_trailer_filter(
+        &self,
+        _session: &mut Session,
+        upstream_trailers: &mut http::HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<Option<Bytes>>
+    where
+        Self::CTX: Send + Sync,
+    {
+        // gRPC-Web sends trailers as the last frame of the body, and
+        // JSON responses are written once the status is known.
+        if let Some(web) = ctx.grpc_web.as_mut() {
+            return Ok(Some(web.response_trailers(upstream_trailers)));
+        }
+        if let Some(json) = ctx.json.as_mut() {
+            return Ok(Some(json.response_trailers(upstream_trailers)));
+        }
+        Ok(None)
     }
 
     fn upstream_response_body_filter(
@@ -364,7 +1313,7 @@ impl ProxyHttp for GrpcProxy {
         &self,
         session: &mut Session,
         upstream_trailers: &mut http::HeaderMap,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> pingora::Result<()> {
         match GrpcStatus::parse(upstream_trailers) {
             Err(error) => {
@@ -377,6 +1326,7 @@ impl ProxyHttp for GrpcProxy {
                 Ok(())
             }
             Ok(status) => {
+                ctx.upstream_status = status.code();
                 if !status.is_ok() {
                     session.cache.disable(pingora::cache::NoCacheReason::Custom(
                         "non-cachable trailers",
@@ -387,24 +1337,53 @@ impl ProxyHttp for GrpcProxy {
         }
     }
 
+    fn upstream_response_filter(
+        &self,
+        _session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) {
+        // Trailers-only responses carry the status in the headers.
+        if let Ok(status) = GrpcStatus::parse(&upstream_response.headers) {
+            ctx.upstream_status = status.code();
+        }
+    }
+
     fn response_cache_filter(
         &self,
         _session: &Session,
         resp: &ResponseHeader,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> pingora::Result<RespCacheable> {
+        // Errors sent as trailers-only responses.
+        if ctx.upstream_status.is_some_and(|code| code != GrpcCode::Ok) {
+            return Ok(RespCacheable::Uncacheable(NoCacheReason::OriginNotCache));
+        }
+
         // Even through we return cachable here, it doesn't mean we can
         // actually cache. Trailers also need to be checked for errors.
-        Ok(RespCacheable::Cacheable(CacheMeta::new(
-            SystemTime::now()
-                .checked_add(Duration::from_secs(60))
-                .unwrap(),
-            SystemTime::now(),
-            // TODO what are these?
-            10,
-            10,
-            resp.clone(),
-        )))
+        Ok(RespCacheable::Cacheable(cache_meta(resp)))
+    }
+
+    async fn proxy_upstream_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<bool>
+    where
+        Self::CTX: Send + Sync,
+    {
+        let service_data = &ctx.grpc_meta.as_ref().unwrap().service_data;
+        if let Some(limiter) = &service_data.concurrency_limiter {
+            ctx.concurrency = Some(limiter.acquire(ctx.deadline).await?);
+        }
+
+        let Some(policy) = ctx.hedging.take() else {
+            return Ok(true);
+        };
+        let response = self.hedged_request(session, ctx, &policy).await?;
+        self.respond_hedged(session, ctx, response).await?;
+        Ok(false)
     }
 
     async fn upstream_peer(
@@ -412,46 +1391,149 @@ impl ProxyHttp for GrpcProxy {
         _session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<Box<HttpPeer>> {
-        let backend = ctx
-            .grpc_meta
-            .as_ref()
-            .unwrap()
-            .service_data
-            .load_balancer
-            .as_ref()
-            .unwrap()
-            .load_balancer_round_robin
-            // Hash doesn't matter for now.
-            .select(b"", 256);
-
-        if let Some(backend) = backend {
-            let inet = backend.addr.as_inet().unwrap();
-
-            let mut peer = HttpPeer::new(
-                inet,
-                false,
-                // TODO set SNI properly? not terribly relevant for non tls grpc
-                "".into(),
-            );
-            peer.options.alpn = ALPN::H2;
-            //peer.options.verify_cert = false;
+        ctx.retry.start_attempt().await;
+        ctx.upstream_status = None;
+        if ctx.deadline_exceeded() {
+            return Err(grpc_error(
+                GrpcCode::DeadlineExceeded,
+                "deadline exceeded before reaching the upstream",
+            ));
+        }
 
-            ctx.span
-                .set_attribute(KeyValue::new("upstream", inet.to_string()));
+        let (backend, peer, backend_permit) = ctx.next_upstream()?;
+        let service_data = &ctx.grpc_meta.as_ref().unwrap().service_data;
+        ctx.span.set_attribute(KeyValue::new(
+            "upstream",
+            backend.addr.as_inet().unwrap().to_string(),
+        ));
+        ctx.in_flight = service_data.balancer.start_request(&backend);
+        ctx.backend_permit = backend_permit;
+        ctx.upstream = Some(backend);
+
+        Ok(Box::new(peer))
+    }
+
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        // The connection may be retried with another backend, so the
+        // failure is recorded right away.
+        ctx.record_upstream_outcome(false);
+        if ctx.retry.has_policy() {
+            e.set_retry(ctx.retry.can_retry(false));
+        }
+        e
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<pingora::Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<pingora::Error> {
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        // Pingora retries errors on reused connections which the
+        // upstream may have closed before getting the request.
+        let can_resend = !session.as_ref().retry_buffer_truncated();
+        e.retry.decide_reuse(client_reused && can_resend);
+        if !ctx.retry.has_policy() {
+            return e;
+        }
+
+        // Retryable statuses are already decided in `response_filter`.
+        if e.esource() == &ErrorSource::Upstream {
+            ctx.record_upstream_outcome(false);
+            let retry = (e.retry() && ctx.retry.can_retry(false))
+                || (ctx.retry.can_retry(true)
+                    && can_resend
+                    && session.response_written().is_none());
+            e.set_retry(retry);
+        }
+        e
+    }
 
-            Ok(Box::new(peer))
+    // Errors generated by the proxy itself are returned to the client
+    // as gRPC "Trailers-Only" responses, gRPC clients do not handle
+    // plain HTTP error statuses well.
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &pingora::Error,
+        ctx: &mut Self::CTX,
+    ) -> u16
+    where
+        Self::CTX: Send + Sync,
+    {
+        let Some(mut code) = code_for_error(e) else {
+            // Downstream connection is dead, nothing to send.
+            return 0;
+        };
+        // Upstream timeouts are from the deadline once it has passed.
+        if e.esource() == &ErrorSource::Upstream && ctx.deadline_exceeded() {
+            code = GrpcCode::DeadlineExceeded;
+        }
+
+        if session.response_written().is_some() {
+            // Too late to send a status in headers, the upstream
+            // response has already started.
+            return 0;
+        }
+
+        let message = message_for_error(e);
+        log::debug!(
+            "responding with gRPC status {} ({})",
+            code.as_str(),
+            message
+        );
+
+        let result = if ctx.json.is_some() {
+            respond_json_error(session.as_mut(), code, &message, &ctx.error_metadata).await
+        } else {
+            let content_type = ctx.response_content_type();
+            respond_grpc_error(
+                session.as_mut(),
+                code,
+                &message,
+                content_type,
+                &ctx.error_metadata,
+            )
+            .await
+        };
+        if let Err(error) = result {
+            log::error!(
+                "failed to send gRPC error response to downstream: {}",
+                error
+            );
+        }
+
+        if ctx.json.is_some() {
+            code.http_status()
         } else {
-            Err(pingora::Error::explain(
-                pingora::ErrorType::HTTPStatus(502),
-                "no upstream available",
-            ))
+            200
         }
     }
 
-    async fn logging(&self, _session: &mut Session, e: Option<&pingora::Error>, ctx: &mut Self::CTX)
+    async fn logging(&self, session: &mut Session, e: Option<&pingora::Error>, ctx: &mut Self::CTX)
     where
         Self::CTX: Send + Sync,
     {
+        crate::metrics::record_request(ctx.tenant.as_deref(), session.cache.phase().as_str());
+
+        // Connection errors and timeouts have the upstream as their
+        // source.
+        let failed = e.is_some_and(|e| e.esource() == &ErrorSource::Upstream)
+            || ctx.upstream_status == Some(GrpcCode::Unavailable);
+        ctx.record_upstream_outcome(!failed);
+        if let Some(permit) = ctx.concurrency.take() {
+            permit.finish(failed || ctx.upstream_status == Some(GrpcCode::ResourceExhausted));
+        }
+
         if let Some(error) = e {
             ctx.span.record_error(error);
             ctx.span.set_status(opentelemetry::trace::Status::Error {
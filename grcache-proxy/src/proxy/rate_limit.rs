@@ -0,0 +1,342 @@
+//! Rate limits of services.
+//!
+//! Requests are checked in `request_filter`, before the cache lookup,
+//! so cache hits are limited too. Each limit keeps a token bucket per
+//! service and key in the proxy, or in the cache backend for limits
+//! shared by the whole fleet. Limits are validated against the
+//! descriptors of the service when they are loaded, invalid limits are
+//! skipped.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use grcache_shared::{
+    config::crd::{RateLimit, RateLimitKey},
+    field_ref::FieldRef,
+    service::ServiceSpec,
+};
+
+use crate::cache::GrcacheStorage;
+
+/// Local buckets of a limit evict the least recently used ones past
+/// this size.
+const MAX_LOCAL_BUCKETS: usize = 10_000;
+
+/// Buckets evicted at once, so new keys do not scan all the buckets
+/// one by one.
+const EVICTED_BUCKETS: usize = MAX_LOCAL_BUCKETS / 10;
+
+pub struct RateLimiter {
+    specs: Vec<RateLimit>,
+    limits: Vec<Limit>,
+}
+
+struct Limit {
+    /// Position of the limit in the spec, part of its bucket keys.
+    index: usize,
+    spec: RateLimit,
+    /// Tokens per second.
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<Vec<u8>, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Limit {
+    fn build(index: usize, spec: &RateLimit, service: &ServiceSpec) -> anyhow::Result<Self> {
+        if spec.requests_per_second == 0 {
+            bail!("`requestsPerSecond` must be at least 1");
+        }
+        if spec.burst == Some(0) {
+            bail!("`burst` must be at least 1");
+        }
+        for method in &spec.methods {
+            if !service.passthrough && !service.methods.contains_key(method) {
+                bail!("unknown method `{}`", method);
+            }
+        }
+
+        // The field must resolve in the input of every method the limit
+        // applies to.
+        if let Some(RateLimitKey::Field(field)) = &spec.key {
+            if service.passthrough {
+                bail!("field `{}` needs the descriptors of the service", field);
+            }
+            let Ok(field_ref) = FieldRef::parse(field);
+            for (method_name, method) in &service.methods {
+                if !applies_to(spec, method_name) {
+                    continue;
+                }
+                field_ref
+                    .validate(&method.descriptor.input_type())
+                    .map_err(|error| {
+                        anyhow!("field `{}` of method `{}`: {}", field, method_name, error)
+                    })?;
+            }
+        }
+
+        Ok(Limit {
+            index,
+            spec: spec.clone(),
+            rate: f64::from(spec.requests_per_second),
+            burst: f64::from(spec.burst.unwrap_or(spec.requests_per_second)),
+            buckets: Default::default(),
+        })
+    }
+
+    /// Takes a token from the local bucket of `key`, or returns how long
+    /// until there is one.
+    fn take_local(&self, key: &[u8]) -> Option<Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_LOCAL_BUCKETS && !buckets.contains_key(key) {
+            // Keys come from clients, so the number of buckets is capped.
+            // The least recently used buckets are the most likely to be
+            // full, which is the same as missing.
+            let mut updated: Vec<_> = buckets.values().map(|bucket| bucket.updated).collect();
+            let (_, &mut last_evicted, _) = updated.select_nth_unstable(EVICTED_BUCKETS);
+            buckets.retain(|_, bucket| bucket.updated > last_evicted);
+        }
+
+        let bucket = buckets.entry(key.to_vec()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refilled).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+
+fn applies_to(spec: &RateLimit, method: &str) -> bool {
+    spec.methods.is_empty() || spec.methods.iter().any(|m| m == method)
+}
+
+impl RateLimiter {
+    /// Builds the limits declared for `service`. Invalid limits are
+    /// passed to `invalid_limit` and skipped.
+    pub fn build(
+        specs: &[RateLimit],
+        service: &ServiceSpec,
+        mut invalid_limit: impl FnMut(usize, anyhow::Error),
+    ) -> Self {
+        let limits = specs
+            .iter()
+            .enumerate()
+            .filter_map(|(index, spec)| {
+                Limit::build(index, spec, service)
+                    .map_err(|error| invalid_limit(index, error))
+                    .ok()
+            })
+            .collect();
+        RateLimiter {
+            specs: specs.to_vec(),
+            limits,
+        }
+    }
+
+    /// The limits the limiter was built from, invalid ones included.
+    pub fn specs(&self) -> &[RateLimit] {
+        &self.specs
+    }
+
+    /// Indexes and keys of the limits which apply to `method`.
+    pub fn keys_for<'a>(
+        &'a self,
+        method: &'a str,
+    ) -> impl Iterator<Item = (usize, Option<&'a RateLimitKey>)> + 'a {
+        self.limits
+            .iter()
+            .enumerate()
+            .filter(move |(_, limit)| applies_to(&limit.spec, method))
+            .map(|(index, limit)| (index, limit.spec.key.as_ref()))
+    }
+
+    /// Takes a token from the bucket of `key` for the limit at `index`,
+    /// or returns how long until there is one. Fleet-wide limits fall
+    /// back to the local bucket when the cache backend fails.
+    pub async fn take(
+        &self,
+        index: usize,
+        service: &str,
+        key: Option<&[u8]>,
+        storage: &(dyn GrcacheStorage + Sync),
+    ) -> Option<Duration> {
+        let limit = &self.limits[index];
+        let mut bucket_key = format!("{}:{}:", service, limit.index).into_bytes();
+        bucket_key.extend_from_slice(key.unwrap_or_default());
+
+        if limit.spec.fleet_wide {
+            let shared_key = [b"grcache:ratelimit:".as_slice(), &bucket_key].concat();
+            let result = storage
+                .take_shared_token(&shared_key, limit.rate as u32, limit.burst as u32)
+                .await;
+            match result {
+                Ok(wait) => return wait,
+                Err(error) => {
+                    log::warn!(
+                        "failed to check fleet-wide rate limit of service {}, using local limit: {:#}",
+                        service,
+                        error
+                    );
+                }
+            }
+        }
+        limit.take_local(&bucket_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use grcache_shared::{
+        config::crd::DescriptorSetSource,
+        service::{
+            descriptor_set::{self, DummyPanicContext},
+            qualified_service::QualifiedService,
+        },
+    };
+    use pingora::cache::Storage;
+
+    use super::*;
+
+    /// Storage without shared buckets.
+    struct NoStorage;
+
+    impl GrcacheStorage for NoStorage {
+        fn as_storage(&self) -> &(dyn Storage + Sync) {
+            unimplemented!()
+        }
+    }
+
+    fn limiter(specs: &[RateLimit]) -> RateLimiter {
+        let service =
+            ServiceSpec::build_passthrough(&QualifiedService::parse("package.Service").unwrap());
+        RateLimiter::build(specs, &service, |index, error| {
+            panic!("limit {} invalid: {}", index, error)
+        })
+    }
+
+    fn limit(requests_per_second: u32, burst: u32) -> RateLimit {
+        RateLimit {
+            methods: Vec::new(),
+            key: None,
+            requests_per_second,
+            burst: Some(burst),
+            fleet_wide: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_each_key() {
+        let limiter = limiter(&[limit(1, 2)]);
+        let storage = NoStorage;
+        assert!(limiter.take(0, "svc", Some(b"a"), &storage).await.is_none());
+        assert!(limiter.take(0, "svc", Some(b"a"), &storage).await.is_none());
+        let wait = limiter.take(0, "svc", Some(b"a"), &storage).await.unwrap();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+
+        assert!(limiter.take(0, "svc", Some(b"b"), &storage).await.is_none());
+        assert!(limiter
+            .take(0, "other", Some(b"a"), &storage)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn fleet_wide_falls_back_to_local() {
+        let limiter = limiter(&[RateLimit {
+            fleet_wide: true,
+            ..limit(1, 1)
+        }]);
+        let storage = NoStorage;
+        assert!(limiter.take(0, "svc", None, &storage).await.is_none());
+        assert!(limiter.take(0, "svc", None, &storage).await.is_some());
+    }
+
+    #[test]
+    fn applies_to_methods() {
+        let limiter = limiter(&[
+            limit(1, 1),
+            RateLimit {
+                methods: vec!["GetData".into()],
+                key: Some(RateLimitKey::PeerIp {}),
+                ..limit(1, 1)
+            },
+        ]);
+        let keys = limiter.keys_for("GetData").collect::<Vec<_>>();
+        assert_eq!(keys, vec![(0, None), (1, Some(&RateLimitKey::PeerIp {}))]);
+        assert_eq!(limiter.keys_for("Other").count(), 1);
+    }
+
+    #[test]
+    fn caps_local_buckets() {
+        let limiter = limiter(&[limit(1, 1)]);
+        let limit = &limiter.limits[0];
+        for key in 0..=MAX_LOCAL_BUCKETS {
+            assert!(limit.take_local(key.to_string().as_bytes()).is_none());
+        }
+        assert!(limit.buckets.lock().unwrap().len() <= MAX_LOCAL_BUCKETS);
+        // Recent buckets are kept.
+        let last_key = MAX_LOCAL_BUCKETS.to_string();
+        assert!(limit.take_local(last_key.as_bytes()).is_some());
+    }
+
+    #[tokio::test]
+    async fn skips_invalid_limits() {
+        let descriptor_set = descriptor_set::from_source(
+            &DummyPanicContext,
+            &DescriptorSetSource::File {
+                path: "tests/data/proto_descriptors.binpb".into(),
+            },
+        )
+        .await
+        .unwrap();
+        let name = QualifiedService::parse("example.TestService").unwrap();
+        let service = ServiceSpec::build(&descriptor_set, &name).unwrap().0;
+        let field_limit = |field: &str| RateLimit {
+            key: Some(RateLimitKey::Field(field.into())),
+            ..limit(1, 1)
+        };
+
+        let mut invalid = Vec::new();
+        let limiter = RateLimiter::build(
+            &[
+                limit(0, 1),
+                limit(1, 0),
+                field_limit("id"),
+                field_limit("missing"),
+                RateLimit {
+                    methods: vec!["Unknown".into()],
+                    ..limit(1, 1)
+                },
+                limit(1, 1),
+            ],
+            &service,
+            |index, _| invalid.push(index),
+        );
+        assert_eq!(invalid, vec![0, 1, 3, 4]);
+        assert_eq!(limiter.keys_for("GetData").count(), 2);
+        assert_eq!(limiter.specs().len(), 6);
+
+        // Bucket keys keep the position of the limit in the spec.
+        let storage = NoStorage;
+        assert!(limiter.take(1, "svc", None, &storage).await.is_none());
+        let buckets = limiter.limits[1].buckets.lock().unwrap();
+        assert!(buckets.contains_key(b"svc:5:".as_slice()));
+    }
+}
@@ -0,0 +1,250 @@
+//! Retries of failed upstream requests.
+//!
+//! Pingora calls `upstream_peer` again for errors marked as retryable.
+//! Errors are marked in `fail_to_connect` and `error_while_proxy`, and
+//! trailers-only responses with a retryable status are turned into
+//! retryable errors in `response_filter`, before anything is sent to
+//! the client. The request body is resent from the retry buffer.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use grcache_shared::{
+    config::crd::RetryPolicy as RetryPolicySpec, protos::options::RetryPolicy as RetryOptions,
+    service::ServiceSpec,
+};
+use pingora_load_balancing::Backend;
+use rand::Rng;
+
+use crate::grpc::status::GrpcCode;
+
+#[derive(Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    retryable_codes: Vec<GrpcCode>,
+    per_try_timeout: Option<Duration>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retry_non_idempotent: bool,
+}
+
+impl RetryPolicy {
+    pub fn from_spec(spec: &RetryPolicySpec) -> Self {
+        let retryable_codes = spec
+            .retryable_status_codes
+            .iter()
+            .filter_map(|name| {
+                let code = GrpcCode::from_name(name);
+                if code.is_none() {
+                    log::warn!("unknown gRPC status code `{}` in retry policy", name);
+                }
+                code
+            })
+            .collect();
+
+        RetryPolicy {
+            max_attempts: spec.max_attempts.max(1),
+            retryable_codes,
+            per_try_timeout: spec.per_try_timeout_millis.map(Duration::from_millis),
+            initial_backoff: Duration::from_millis(spec.initial_backoff_millis),
+            max_backoff: Duration::from_millis(spec.max_backoff_millis),
+            retry_non_idempotent: spec.retry_non_idempotent,
+        }
+    }
+
+    /// From the `retry_policy` method option, where fields left at zero
+    /// take their defaults.
+    pub fn from_method_options(options: &RetryOptions) -> Self {
+        let defaults = RetryPolicySpec::default();
+        let or_default = |value: u64, default: u64| if value == 0 { default } else { value };
+
+        RetryPolicy::from_spec(&RetryPolicySpec {
+            max_attempts: match options.max_attempts {
+                0 => defaults.max_attempts,
+                max_attempts => max_attempts,
+            },
+            retryable_status_codes: if options.retryable_status_codes.is_empty() {
+                defaults.retryable_status_codes
+            } else {
+                options.retryable_status_codes.clone()
+            },
+            per_try_timeout_millis: Some(options.per_try_timeout_millis).filter(|t| *t > 0),
+            initial_backoff_millis: or_default(
+                options.initial_backoff_millis,
+                defaults.initial_backoff_millis,
+            ),
+            max_backoff_millis: or_default(options.max_backoff_millis, defaults.max_backoff_millis),
+            retry_non_idempotent: options.retry_non_idempotent,
+        })
+    }
+
+    /// Random backoff before retry number `retry`, from 1. Up to the
+    /// initial backoff doubled for every retry, as in gRPC clients.
+    fn backoff(&self, retry: u32) -> Duration {
+        let ceiling = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+            .min(self.max_backoff);
+        ceiling.mul_f64(rand::thread_rng().gen())
+    }
+}
+
+/// Retry policies of the methods of `service` which declare one in
+/// their options.
+pub fn method_retry_policies(service: &ServiceSpec) -> HashMap<String, Arc<RetryPolicy>> {
+    service
+        .methods
+        .iter()
+        .filter_map(|(name, method)| {
+            let options = method
+                .cache_spec
+                .as_ref()?
+                .descriptor
+                .retry_policy
+                .as_ref()?;
+            Some((
+                name.clone(),
+                Arc::new(RetryPolicy::from_method_options(options)),
+            ))
+        })
+        .collect()
+}
+
+/// Retry state of a request.
+#[derive(Debug, Default)]
+pub struct RetryState {
+    policy: Option<Arc<RetryPolicy>>,
+    /// Whether requests which may have reached the upstream can be
+    /// retried.
+    idempotent: bool,
+    /// From the `grpc-timeout` of the request.
+    deadline: Option<Instant>,
+    attempts: u32,
+    /// Backends of previous attempts, avoided in later ones.
+    tried: Vec<Backend>,
+}
+
+impl RetryState {
+    pub fn new(
+        policy: Option<Arc<RetryPolicy>>,
+        idempotent: bool,
+        deadline: Option<Instant>,
+    ) -> Self {
+        let idempotent = idempotent || policy.as_ref().is_some_and(|p| p.retry_non_idempotent);
+        RetryState {
+            policy,
+            idempotent,
+            deadline,
+            attempts: 0,
+            tried: Vec::new(),
+        }
+    }
+
+    pub fn has_policy(&self) -> bool {
+        self.policy.is_some()
+    }
+
+    /// Starts an attempt, waiting for the backoff if it is a retry.
+    pub async fn start_attempt(&mut self) {
+        self.attempts += 1;
+        let Some(policy) = self.policy.as_ref().filter(|_| self.attempts > 1) else {
+            return;
+        };
+        let mut backoff = policy.backoff(self.attempts - 1);
+        if let Some(deadline) = self.deadline {
+            backoff = backoff.min(deadline.saturating_duration_since(Instant::now()));
+        }
+        tokio::time::sleep(backoff).await;
+    }
+
+    pub fn tried(&self) -> &[Backend] {
+        &self.tried
+    }
+
+    pub fn record_backend(&mut self, backend: Backend) {
+        self.tried.push(backend);
+    }
+
+    /// How long to wait for the upstream response in the current
+    /// attempt, bounded by the deadline of the request.
+    pub fn attempt_timeout(&self) -> Option<Duration> {
+        let per_try = self.policy.as_ref().and_then(|p| p.per_try_timeout)?;
+        Some(match self.deadline {
+            Some(deadline) => per_try.min(deadline.saturating_duration_since(Instant::now())),
+            None => per_try,
+        })
+    }
+
+    /// Whether a failed attempt can be retried. `sent` is whether the
+    /// request may have reached the upstream.
+    pub fn can_retry(&self, sent: bool) -> bool {
+        let Some(policy) = &self.policy else {
+            return false;
+        };
+        (!sent || self.idempotent)
+            && self.attempts < policy.max_attempts
+            && self
+                .deadline
+                .is_none_or(|deadline| Instant::now() < deadline)
+    }
+
+    /// Whether a response with `code` should be retried.
+    pub fn is_retryable(&self, code: GrpcCode) -> bool {
+        self.policy
+            .as_ref()
+            .is_some_and(|p| p.retryable_codes.contains(&code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> Arc<RetryPolicy> {
+        Arc::new(RetryPolicy::from_spec(&RetryPolicySpec {
+            max_attempts: 2,
+            ..Default::default()
+        }))
+    }
+
+    #[test]
+    fn limits_retries() {
+        let mut state = RetryState::new(Some(policy()), false, None);
+        state.attempts = 1;
+        assert!(state.is_retryable(GrpcCode::Unavailable));
+        assert!(!state.is_retryable(GrpcCode::Internal));
+        // Not idempotent, only requests which were never sent.
+        assert!(state.can_retry(false));
+        assert!(!state.can_retry(true));
+
+        state.attempts = 2;
+        assert!(!state.can_retry(false));
+
+        let mut state = RetryState::new(Some(policy()), true, None);
+        state.attempts = 1;
+        assert!(state.can_retry(true));
+
+        let past = Instant::now() - Duration::from_secs(1);
+        let mut state = RetryState::new(Some(policy()), true, Some(past));
+        state.attempts = 1;
+        assert!(!state.can_retry(false));
+
+        assert!(!RetryState::default().can_retry(false));
+    }
+
+    #[test]
+    fn method_options_take_defaults() {
+        let policy = RetryPolicy::from_method_options(&RetryOptions {
+            max_attempts: 5,
+            ..Default::default()
+        });
+        assert_eq!(policy.max_attempts, 5);
+        assert_eq!(policy.retryable_codes, [GrpcCode::Unavailable]);
+        assert_eq!(policy.per_try_timeout, None);
+        assert_eq!(policy.initial_backoff, Duration::from_millis(25));
+        assert!(policy.backoff(10) <= Duration::from_millis(250));
+    }
+}
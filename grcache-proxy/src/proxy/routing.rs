@@ -0,0 +1,418 @@
+//! Content-based routing rules.
+//!
+//! Requests are matched against the rules of the `GrcacheService`
+//! without an upstream name in `request_filter`, and the first matching
+//! rule selects a named upstream, over the upstream header of the
+//! request. Rules are
+//! validated against the descriptors of the service when they are
+//! loaded, invalid rules are skipped.
+
+use std::fmt;
+
+use anyhow::{anyhow, bail, Context};
+use grcache_shared::{
+    config::crd::{RoutingRule, ValueMatch},
+    field_ref::FieldRef,
+    service::ServiceSpec,
+};
+use http::{HeaderMap, HeaderName};
+use protobuf::MessageDyn;
+use regex::Regex;
+
+use super::field_bytes;
+
+pub struct RoutingRules {
+    rules: Vec<Rule>,
+}
+
+pub struct Rule {
+    name: String,
+    methods: Vec<String>,
+    headers: Vec<(HeaderName, Option<Matcher>)>,
+    fields: Vec<(String, FieldRef, Matcher)>,
+    upstream: String,
+}
+
+enum Matcher {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn new(spec: &ValueMatch) -> anyhow::Result<Self> {
+        Ok(match spec {
+            ValueMatch::Exact(value) => Matcher::Exact(value.clone()),
+            ValueMatch::Prefix(prefix) => Matcher::Prefix(prefix.clone()),
+            ValueMatch::Regex(regex) => Matcher::Regex(
+                Regex::new(&format!("^(?:{})$", regex))
+                    .with_context(|| format!("invalid regex `{}`", regex))?,
+            ),
+        })
+    }
+
+    fn matches(&self, value: &[u8]) -> bool {
+        match self {
+            Matcher::Exact(expected) => value == expected.as_bytes(),
+            Matcher::Prefix(prefix) => value.starts_with(prefix.as_bytes()),
+            Matcher::Regex(regex) => std::str::from_utf8(value).is_ok_and(|v| regex.is_match(v)),
+        }
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::Exact(value) => write!(f, "= {:?}", value),
+            Matcher::Prefix(prefix) => write!(f, "starts with {:?}", prefix),
+            Matcher::Regex(regex) => write!(f, "matches {:?}", regex.as_str()),
+        }
+    }
+}
+
+impl Rule {
+    fn build(index: usize, spec: &RoutingRule, service: &ServiceSpec) -> anyhow::Result<Self> {
+        let name = spec.name.clone().unwrap_or_else(|| index.to_string());
+
+        let headers = spec
+            .headers
+            .iter()
+            .map(|header| {
+                let name = HeaderName::try_from(&header.name)
+                    .with_context(|| format!("invalid header name `{}`", header.name))?;
+                let matcher = header.value.as_ref().map(Matcher::new).transpose()?;
+                Ok((name, matcher))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        // Fields must resolve in the input of every method the rule
+        // applies to.
+        if !spec.fields.is_empty() && service.passthrough {
+            bail!("field conditions need the descriptors of the service");
+        }
+        for method in &spec.methods {
+            if !service.passthrough && !service.methods.contains_key(method) {
+                bail!("unknown method `{}`", method);
+            }
+        }
+        let fields = spec
+            .fields
+            .iter()
+            .map(|field| {
+                let Ok(field_ref) = FieldRef::parse(&field.field);
+                for (method_name, method) in &service.methods {
+                    if !spec.methods.is_empty() && !spec.methods.contains(method_name) {
+                        continue;
+                    }
+                    field_ref
+                        .validate(&method.descriptor.input_type())
+                        .map_err(|error| {
+                            anyhow!(
+                                "field `{}` of method `{}`: {}",
+                                field.field,
+                                method_name,
+                                error
+                            )
+                        })?;
+                }
+                Ok((field.field.clone(), field_ref, Matcher::new(&field.value)?))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Rule {
+            name,
+            methods: spec.methods.clone(),
+            headers,
+            fields,
+            upstream: spec.upstream_name.clone(),
+        })
+    }
+
+    fn applies_to(&self, method: &str) -> bool {
+        self.methods.is_empty() || self.methods.iter().any(|m| m == method)
+    }
+
+    fn matches(&self, headers: &HeaderMap, message: Option<&dyn MessageDyn>) -> bool {
+        let headers_match = self.headers.iter().all(|(name, matcher)| {
+            headers.get(name).is_some_and(|value| {
+                matcher
+                    .as_ref()
+                    .is_none_or(|matcher| matcher.matches(value.as_bytes()))
+            })
+        });
+        headers_match
+            && self.fields.iter().all(|(_, field, matcher)| {
+                message
+                    .and_then(|message| field.resolve(message))
+                    .is_some_and(|value| matcher.matches(&field_bytes(value)))
+            })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn upstream(&self) -> &str {
+        &self.upstream
+    }
+}
+
+/// Explains the conditions of the rule, for debug logs.
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` (", self.name)?;
+        let mut conditions = Vec::new();
+        if !self.methods.is_empty() {
+            conditions.push(format!("method in {:?}", self.methods));
+        }
+        for (name, matcher) in &self.headers {
+            conditions.push(match matcher {
+                Some(matcher) => format!("header `{}` {}", name, matcher),
+                None => format!("header `{}` present", name),
+            });
+        }
+        for (path, _, matcher) in &self.fields {
+            conditions.push(format!("field `{}` {}", path, matcher));
+        }
+        if conditions.is_empty() {
+            conditions.push("any request".into());
+        }
+        write!(
+            f,
+            "{}) -> upstream `{}`",
+            conditions.join(", "),
+            self.upstream
+        )
+    }
+}
+
+impl RoutingRules {
+    /// Builds the rules declared for `service`. Invalid rules are
+    /// passed to `invalid_rule` and skipped.
+    pub fn build(
+        specs: &[RoutingRule],
+        service: &ServiceSpec,
+        mut invalid_rule: impl FnMut(usize, anyhow::Error),
+    ) -> Self {
+        let rules = specs
+            .iter()
+            .enumerate()
+            .filter_map(|(index, spec)| {
+                Rule::build(index, spec, service)
+                    .map_err(|error| invalid_rule(index, error))
+                    .ok()
+            })
+            .collect();
+        RoutingRules { rules }
+    }
+
+    /// Whether a rule for `method` needs the request message.
+    pub fn needs_message(&self, method: &str) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.applies_to(method) && !rule.fields.is_empty())
+    }
+
+    /// The first rule matching a request. `message` is only needed
+    /// when `needs_message` is true.
+    pub fn route(
+        &self,
+        method: &str,
+        headers: &HeaderMap,
+        message: Option<&dyn MessageDyn>,
+    ) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .find(|rule| rule.applies_to(method) && rule.matches(headers, message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use grcache_shared::{
+        config::crd::{DescriptorSetSource, FieldMatch, HeaderMatch},
+        service::{
+            descriptor_set::{self, DummyPanicContext},
+            qualified_service::QualifiedService,
+        },
+    };
+
+    use super::*;
+
+    async fn test_service() -> ServiceSpec {
+        let descriptor_set = descriptor_set::from_source(
+            &DummyPanicContext,
+            &DescriptorSetSource::File {
+                path: "tests/data/proto_descriptors.binpb".into(),
+            },
+        )
+        .await
+        .unwrap();
+        let name = QualifiedService::parse("example.TestService").unwrap();
+        ServiceSpec::build(&descriptor_set, &name).unwrap().0
+    }
+
+    fn field_rule(name: &str, field: &str, value: ValueMatch) -> RoutingRule {
+        RoutingRule {
+            fields: vec![FieldMatch {
+                field: field.into(),
+                value,
+            }],
+            ..rule(name, Vec::new(), "upstream")
+        }
+    }
+
+    fn rule(name: &str, headers: Vec<HeaderMatch>, upstream: &str) -> RoutingRule {
+        RoutingRule {
+            name: Some(name.into()),
+            methods: Vec::new(),
+            headers,
+            fields: Vec::new(),
+            upstream_name: upstream.into(),
+        }
+    }
+
+    fn header(name: &str, value: Option<ValueMatch>) -> HeaderMatch {
+        HeaderMatch {
+            name: name.into(),
+            value,
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let service =
+            ServiceSpec::build_passthrough(&QualifiedService::parse("package.Service").unwrap());
+        let rules = RoutingRules::build(
+            &[
+                rule(
+                    "beta",
+                    vec![header(
+                        "x-tenant",
+                        Some(ValueMatch::Regex("beta-.*".into())),
+                    )],
+                    "beta",
+                ),
+                rule("debug", vec![header("x-debug", None)], "debug"),
+                rule("tenants", vec![header("x-tenant", None)], "tenants"),
+            ],
+            &service,
+            |index, error| panic!("rule {} invalid: {}", index, error),
+        );
+
+        let route = |headers: &[(&'static str, &'static str)]| {
+            let headers: HeaderMap = headers
+                .iter()
+                .map(|(name, value)| (HeaderName::from_static(name), value.parse().unwrap()))
+                .collect();
+            rules
+                .route("Method", &headers, None)
+                .map(|rule| rule.name().to_owned())
+        };
+        assert_eq!(
+            route(&[("x-tenant", "beta-1"), ("x-debug", "1")]).as_deref(),
+            Some("beta")
+        );
+        assert_eq!(
+            route(&[("x-tenant", "prod"), ("x-debug", "1")]).as_deref(),
+            Some("debug")
+        );
+        assert_eq!(route(&[("x-tenant", "prod")]).as_deref(), Some("tenants"));
+        assert_eq!(route(&[]), None);
+    }
+
+    #[test]
+    fn field_rules_need_descriptors() {
+        let service =
+            ServiceSpec::build_passthrough(&QualifiedService::parse("package.Service").unwrap());
+        let mut invalid = Vec::new();
+        let rules = RoutingRules::build(
+            &[RoutingRule {
+                fields: vec![FieldMatch {
+                    field: "id".into(),
+                    value: ValueMatch::Exact("1".into()),
+                }],
+                ..rule("field", Vec::new(), "upstream")
+            }],
+            &service,
+            |index, _error| invalid.push(index),
+        );
+        assert_eq!(invalid, vec![0]);
+        assert!(rules.rules.is_empty());
+    }
+
+    #[tokio::test]
+    async fn field_rules_match_request_messages() {
+        let service = test_service().await;
+        let rules = RoutingRules::build(
+            &[field_rule("beta", "id", ValueMatch::Prefix("beta-".into()))],
+            &service,
+            |index, error| panic!("rule {} invalid: {}", index, error),
+        );
+        assert!(rules.needs_message("GetData"));
+
+        let input_type = service.methods["GetData"].descriptor.input_type();
+        let route = |message: &[u8]| {
+            let message = input_type.parse_from_bytes(message).unwrap();
+            rules
+                .route("GetData", &HeaderMap::new(), Some(&*message))
+                .map(|rule| rule.name().to_owned())
+        };
+        // `id: "beta-1"` and `id: "a"`.
+        assert_eq!(route(b"\x0a\x06beta-1").as_deref(), Some("beta"));
+        assert_eq!(route(b"\x0a\x01a"), None);
+        assert!(rules.route("GetData", &HeaderMap::new(), None).is_none());
+    }
+
+    #[tokio::test]
+    async fn rules_apply_to_their_methods() {
+        let service = test_service().await;
+        let rules = RoutingRules::build(
+            &[RoutingRule {
+                methods: vec!["GetDataHedged".into()],
+                ..rule("hedged", Vec::new(), "upstream")
+            }],
+            &service,
+            |index, error| panic!("rule {} invalid: {}", index, error),
+        );
+        let headers = HeaderMap::new();
+        assert!(rules.route("GetDataHedged", &headers, None).is_some());
+        assert!(rules.route("GetData", &headers, None).is_none());
+
+        // Unknown methods and fields outside the scoped methods' inputs
+        // are invalid.
+        let mut invalid = Vec::new();
+        RoutingRules::build(
+            &[
+                RoutingRule {
+                    methods: vec!["Missing".into()],
+                    ..rule("missing", Vec::new(), "upstream")
+                },
+                RoutingRule {
+                    methods: vec!["GetData".into()],
+                    ..field_rule("field", "missing", ValueMatch::Exact("1".into()))
+                },
+            ],
+            &service,
+            |index, _error| invalid.push(index),
+        );
+        assert_eq!(invalid, vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn invalid_regexes_are_skipped() {
+        let service = test_service().await;
+        let mut invalid = Vec::new();
+        let rules = RoutingRules::build(
+            &[
+                field_rule("invalid", "id", ValueMatch::Regex("beta-(".into())),
+                field_rule("valid", "id", ValueMatch::Regex("beta-[0-9]+".into())),
+            ],
+            &service,
+            |index, _error| invalid.push(index),
+        );
+        assert_eq!(invalid, vec![0]);
+        assert_eq!(rules.rules.len(), 1);
+        assert_eq!(rules.rules[0].name(), "valid");
+    }
+}
@@ -1,15 +1,25 @@
-use futures::StreamExt;
+use futures::{future, FutureExt, StreamExt};
 use papaya::Compute;
-use pingora::{server::ShutdownWatch, services::background::BackgroundService};
+use pingora::{
+    protocols::l4::socket::SocketAddr, server::ShutdownWatch,
+    services::background::BackgroundService,
+};
+use pingora_load_balancing::{Backend, Extensions};
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
     pin::pin,
     sync::Arc,
+    time::Duration,
 };
 use tokio::{select, sync::watch};
 
 use grcache_shared::{
-    config::crd::GrcacheService,
+    config::crd::{
+        ConcurrencyLimit, GrcacheService, GrcacheServiceSpec, HashOn, LoadBalancing,
+        LoadBalancingPolicy, RateLimit, RoutingRule, StaticBackend, Upstream,
+    },
+    field_ref::FieldRef,
     health::HealthEndpoint,
     resource_change::{resource_changes, ResourceChange},
     service::{
@@ -23,7 +33,17 @@ use kube::{
     Api, Client,
 };
 
-use crate::discovery::{self, ServiceBackendsHandle};
+use crate::{
+    discovery::{self, balancer::Balancer, outlier::OutlierDetector, ServiceBackendsHandle},
+    grpc::headers::make_header_names_set,
+    proxy::{
+        concurrency::ConcurrencyLimiter,
+        rate_limit::RateLimiter,
+        retry::{method_retry_policies, RetryPolicy},
+        routing::RoutingRules,
+    },
+    tls::UpstreamTls,
+};
 
 #[derive(Clone)]
 pub struct ServiceData {
@@ -31,17 +51,137 @@ pub struct ServiceData {
     pub generation: usize,
     pub service_spec: Option<Arc<ServiceSpec>>,
     pub load_balancer: Option<Arc<ServiceBackendsHandle>>,
+    /// Plaintext is used towards upstreams if `None`.
+    pub upstream_tls: Option<Arc<UpstreamTls>>,
+    /// Request header handling declared on the `GrcacheService`.
+    pub header_policy: Arc<HeaderPolicy>,
+    /// Ejects failing upstreams, if enabled on the `GrcacheService`.
+    pub outlier_detector: Option<Arc<OutlierDetector>>,
+    /// Selects upstreams with the policy of the `GrcacheService`.
+    pub balancer: Arc<Balancer>,
+    /// Retries of the `GrcacheService`, if enabled. Methods may
+    /// declare their own.
+    pub retry_policy: Option<Arc<RetryPolicy>>,
+    /// Retries declared by methods with the `retry_policy` option, by
+    /// method name.
+    pub method_retry_policies: Arc<HashMap<String, Arc<RetryPolicy>>>,
+    /// Upper bound on the deadline of requests.
+    pub max_timeout: Option<Duration>,
+    /// Limits requests in flight to the upstreams, if enabled on the
+    /// `GrcacheService`.
+    pub concurrency_limiter: Option<Arc<ConcurrencyLimiter>>,
+    /// Limits the rate of requests, if the `GrcacheService` declares
+    /// rate limits.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Routes requests to named upstreams, if the `GrcacheService`
+    /// declares routing rules.
+    pub routing_rules: Option<Arc<RoutingRules>>,
+}
+
+/// Request header handling declared on a `GrcacheService`, with
+/// normalized header names.
+#[derive(Debug, Default)]
+pub struct HeaderPolicy {
+    /// Passed to the upstream, not part of the cache key.
+    pub propagation: BTreeSet<String>,
+    /// Always part of the cache key.
+    pub vary: BTreeSet<String>,
+    /// Caching is skipped when any of these are present.
+    pub bypass_cache: BTreeSet<String>,
+}
+
+impl HeaderPolicy {
+    pub fn from_spec(spec: &GrcacheServiceSpec) -> Self {
+        HeaderPolicy {
+            propagation: make_header_names_set(&spec.propagation_headers),
+            vary: make_header_names_set(&spec.vary_headers),
+            bypass_cache: make_header_names_set(&spec.bypass_cache_headers),
+        }
+    }
+}
+
+/// A gRPC service, and the `upstreamName` of the `GrcacheService`
+/// implementing it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ServiceKey {
+    pub service: String,
+    pub upstream: Option<String>,
+}
+
+impl ServiceKey {
+    pub fn new(service: impl Into<String>, upstream: Option<String>) -> Self {
+        ServiceKey {
+            service: service.into(),
+            upstream,
+        }
+    }
+
+    fn of_spec(spec: &GrcacheServiceSpec) -> Self {
+        ServiceKey::new(spec.service_name.clone(), spec.upstream_name.clone())
+    }
+}
+
+/// The `GrcacheService` of `service` without an upstream name.
+impl From<&str> for ServiceKey {
+    fn from(service: &str) -> Self {
+        ServiceKey::new(service, None)
+    }
+}
+
+impl fmt::Display for ServiceKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.upstream {
+            Some(upstream) => write!(f, "{} (upstream {})", self.service, upstream),
+            None => f.write_str(&self.service),
+        }
+    }
+}
+
+/// Share of the requests to a service sent to a named upstream.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrafficSplit {
+    pub upstream: String,
+    pub percent: u32,
 }
 
 pub struct ServiceConfigInner {
     pub ready: watch::Receiver<bool>,
-    pub services: papaya::HashMap<String, ServiceData>,
+    pub services: papaya::HashMap<ServiceKey, ServiceData>,
+    /// Traffic splits to named upstreams, by gRPC service.
+    pub traffic_splits: papaya::HashMap<String, Arc<[TrafficSplit]>>,
 }
 
 impl ServiceConfigInner {
+    pub fn new(ready: watch::Receiver<bool>) -> Self {
+        ServiceConfigInner {
+            ready,
+            services: papaya::HashMap::new(),
+            traffic_splits: papaya::HashMap::new(),
+        }
+    }
+
+    /// Named upstream for a request to `service` without an upstream
+    /// name, from the traffic splits of the service. `roll` is uniform
+    /// in `0..100`. Upstreams which are not loaded are skipped.
+    pub fn split_upstream(&self, service: &str, roll: u32) -> Option<String> {
+        let splits = self.traffic_splits.pin().get(service)?.clone();
+        let mut total = 0;
+        let split = splits.iter().find(|split| {
+            total += split.percent;
+            roll < total
+        })?;
+
+        let key = ServiceKey::new(service, Some(split.upstream.clone()));
+        self.services
+            .pin()
+            .get(&key)
+            .is_some_and(|data| data.load_balancer.is_some())
+            .then(|| split.upstream.clone())
+    }
+
     /// Swap in new spec IF we are not already on a newer generation.
     /// This prevents races.
-    fn update_service_if_newer(&self, service_name: String, data: ServiceData) {
+    fn update_service_if_newer(&self, service_name: ServiceKey, data: ServiceData) {
         let services_map = self.services.pin();
         let result: Compute<_, _, ()> = services_map.compute(service_name.clone(), |kv| {
             if let Some((_key, value)) = kv {
@@ -113,6 +253,7 @@ pub type ServiceConfig = Arc<ServiceConfigInner>;
 
 pub struct K8SConfigService {
     dns_service_handle: discovery::dns::Handle,
+    kubernetes_discovery: discovery::kubernetes::Handle,
     ready_sender: watch::Sender<bool>,
     config: ServiceConfig,
     health: HealthEndpoint,
@@ -121,19 +262,18 @@ pub struct K8SConfigService {
 impl K8SConfigService {
     pub fn new(
         dns_service_handle: discovery::dns::Handle,
+        kubernetes_discovery: discovery::kubernetes::Handle,
         mut health: HealthEndpoint,
     ) -> (Self, ServiceConfig) {
         health.name("kubernetes GrcacheService loader");
 
         let (ready_sender, ready_receiver) = watch::channel(false);
 
-        let config = Arc::new(ServiceConfigInner {
-            ready: ready_receiver,
-            services: papaya::HashMap::new(),
-        });
+        let config = Arc::new(ServiceConfigInner::new(ready_receiver));
 
         let service = Self {
             dns_service_handle,
+            kubernetes_discovery,
             ready_sender,
             config: config.clone(),
             health,
@@ -147,16 +287,19 @@ struct ServiceConfigState {
     spec_generation: usize,
     config: ServiceConfig,
     raw_services: HashMap<ObjectRef<GrcacheService>, GrcacheService>,
-    ref_by_grpc_service: BTreeMap<String, HashSet<ObjectRef<GrcacheService>>>,
+    ref_by_grpc_service: BTreeMap<ServiceKey, HashSet<ObjectRef<GrcacheService>>>,
     dns_service_handle: discovery::dns::Handle,
+    kubernetes_discovery: discovery::kubernetes::Handle,
+    k8s_client: Client,
 }
 
 impl ServiceConfigState {
     fn remove(&mut self, object_ref: &ObjectRef<GrcacheService>) {
         if let Some(previous) = self.remove_noupdate(object_ref) {
             //for name in previous.spec.service_names.iter() {
-            let name = &previous.spec.service_name;
-            self.update_service(name);
+            let key = ServiceKey::of_spec(&previous.spec);
+            self.update_service(&key);
+            self.update_traffic_splits(&key.service);
         }
     }
 
@@ -168,9 +311,9 @@ impl ServiceConfigState {
 
         if let Some(previous) = &previous {
             //for name in previous.spec.service_names.iter() {
-            let name = &previous.spec.service_name;
+            let key = ServiceKey::of_spec(&previous.spec);
             self.ref_by_grpc_service
-                .get_mut(name)
+                .get_mut(&key)
                 .unwrap()
                 .remove(object_ref);
         }
@@ -186,17 +329,55 @@ impl ServiceConfigState {
         self.raw_services.insert(object_ref.clone(), object.clone());
 
         //for grpc_service in object.spec.service_names.iter() {
-        let grpc_service = &object.spec.service_name;
+        let key = ServiceKey::of_spec(&object.spec);
         self.ref_by_grpc_service
-            .entry(grpc_service.to_owned())
+            .entry(key.clone())
             .or_default()
             .insert(object_ref.clone());
 
-        self.update_service(&grpc_service);
+        self.update_service(&key);
+        self.update_traffic_splits(&key.service);
+    }
+
+    /// Collects the traffic splits of the named upstreams of
+    /// `grpc_service`.
+    fn update_traffic_splits(&mut self, grpc_service: &str) {
+        let start = ServiceKey::new(grpc_service, None);
+        let splits: Vec<_> = self
+            .ref_by_grpc_service
+            .range(start..)
+            .take_while(|(key, _)| key.service == grpc_service)
+            .filter_map(|(key, objects)| {
+                let upstream = key.upstream.clone()?;
+                let object_ref = objects.iter().min_by_key(|v| (&v.namespace, &v.name))?;
+                let percent = self.raw_services[object_ref].spec.traffic_percent?;
+                Some(TrafficSplit { upstream, percent })
+            })
+            .collect();
+
+        let splits = valid_traffic_splits(grpc_service, splits);
+        for object in self.raw_services.values() {
+            if object.spec.service_name == grpc_service
+                && object.spec.upstream_name.is_none()
+                && object.spec.traffic_percent.is_some()
+            {
+                log::warn!(
+                    "`trafficPercent` of gRPC service {} ignored without `upstreamName`",
+                    grpc_service
+                );
+            }
+        }
+
+        let traffic_splits = self.config.traffic_splits.pin();
+        if splits.is_empty() {
+            traffic_splits.remove(grpc_service);
+        } else {
+            traffic_splits.insert(grpc_service.to_owned(), splits.into());
+        }
     }
 
-    fn update_service(&mut self, grpc_service: &str) {
-        let objects = self.ref_by_grpc_service.get(grpc_service).unwrap();
+    fn update_service(&mut self, key: &ServiceKey) {
+        let objects = self.ref_by_grpc_service.get(key).unwrap();
 
         if objects.is_empty() {
             let generation = self.spec_generation;
@@ -204,15 +385,25 @@ impl ServiceConfigState {
 
             let services = self.config.services.pin();
             services.insert(
-                grpc_service.to_string(),
+                key.clone(),
                 ServiceData {
                     generation,
                     service_spec: None,
                     load_balancer: None,
+                    upstream_tls: None,
+                    header_policy: Default::default(),
+                    outlier_detector: None,
+                    balancer: Default::default(),
+                    retry_policy: None,
+                    method_retry_policies: Default::default(),
+                    max_timeout: None,
+                    concurrency_limiter: None,
+                    rate_limiter: None,
+                    routing_rules: None,
                 },
             );
 
-            log::info!("unloaded spec for gRPC service: {}", grpc_service);
+            log::info!("unloaded spec for gRPC service: {}", key);
         } else {
             // When we have duplicate objects, we select an object deterministically
             // so that `grcache` replicas behave identically.
@@ -227,8 +418,8 @@ impl ServiceConfigState {
             // error. We behave gracefully but complain.
             if objects.len() > 1 {
                 log::error!(
-                    "Duplicate service resource for gRPC service {}! Make sure each gRPC service only has a single `GrcacheService` object. Selected: {} Objects: {:?}",
-                    grpc_service,
+                    "Duplicate service resource for gRPC service {}! Make sure each gRPC service only has a single `GrcacheService` object per upstream name. Selected: {} Objects: {:?}",
+                    key,
                     object_ref.name,
                     objects.iter().map(|v| v.name.clone()).collect::<Vec<_>>()
                 );
@@ -244,24 +435,194 @@ impl ServiceConfigState {
             //for service_name in object.spec.service_names.iter() {
             let service_name = &object.spec.service_name;
             // TODO error
-            let service = QualifiedService::parse(&service_name).unwrap();
+            let service = QualifiedService::parse(service_name).unwrap();
             services.push((service_name.to_owned(), service));
 
-            let load_balancer = match &object.spec.upstream {
-                grcache_shared::config::crd::Upstream::Dns { url, port } => {
+            let namespace = object_ref
+                .namespace
+                .clone()
+                .unwrap_or_else(|| self.k8s_client.default_namespace().to_owned());
+
+            let (load_balancer, hostname) = match &object.spec.upstream {
+                Upstream::Dns {
+                    url,
+                    port,
+                    address_family,
+                } => {
                     // Since we create the new handle before we remove
                     // the old entry from the service map, this only
                     // has the cost of reference counting for the case
                     // where the hostname is identical.
-                    self.dns_service_handle
-                        .backends_for_hostname(url.clone(), *port)
+                    let load_balancer = match self.dns_service_handle.backends_for_hostname(
+                        url.clone(),
+                        *port,
+                        *address_family,
+                    ) {
+                        Ok(load_balancer) => load_balancer,
+                        Err(error) => {
+                            log::warn!("not loading gRPC service {}: {}", key, error);
+                            return;
+                        }
+                    };
+                    (future::ready(load_balancer).boxed(), url.clone())
+                }
+                Upstream::DnsSrv {
+                    name,
+                    address_family,
+                } => {
+                    let load_balancer = match self
+                        .dns_service_handle
+                        .backends_for_srv(name.clone(), *address_family)
+                    {
+                        Ok(load_balancer) => load_balancer,
+                        Err(error) => {
+                            log::warn!("not loading gRPC service {}: {}", key, error);
+                            return;
+                        }
+                    };
+                    (
+                        future::ready(load_balancer).boxed(),
+                        srv_domain(name).to_owned(),
+                    )
+                }
+                Upstream::Static { backends } => {
+                    let hostname = backends
+                        .first()
+                        .and_then(|b| split_host_port(&b.address))
+                        .map(|(host, _port)| host.to_owned())
+                        .unwrap_or_default();
+                    // Hostnames are resolved once, when the service is
+                    // loaded below.
+                    let backends = backends.clone();
+                    let load_balancer = async move {
+                        Arc::new(ServiceBackendsHandle::new_static(
+                            static_backends(&backends).await,
+                        ))
+                    }
+                    .boxed();
+                    (load_balancer, hostname)
+                }
+                Upstream::KubernetesService {
+                    name,
+                    namespace: service_namespace,
+                    port,
+                } => {
+                    let service_namespace = service_namespace.as_ref().unwrap_or(&namespace);
+                    let load_balancer = match self.kubernetes_discovery.backends_for_service(
+                        service_namespace.clone(),
+                        name.clone(),
+                        *port,
+                    ) {
+                        Ok(load_balancer) => load_balancer,
+                        Err(error) => {
+                            log::warn!("not loading gRPC service {}: {}", key, error);
+                            return;
+                        }
+                    };
+                    // Name of the service as resolved through cluster
+                    // DNS, used as the default SNI.
+                    (
+                        future::ready(load_balancer).boxed(),
+                        format!("{}.{}.svc", name, service_namespace),
+                    )
                 }
             };
 
-            if let Some(source) = object.spec.descriptor_set_source.clone() {
-                let config = self.config.clone();
-
-                tokio::spawn(async move {
+            let upstream_tls_spec = object.spec.upstream_tls.clone();
+            let health_check = object.spec.health_check.clone();
+            // Ejections survive reconciles which keep the settings.
+            let outlier_detector = object.spec.outlier_detection.clone().map(|config| {
+                self.config
+                    .services
+                    .pin()
+                    .get(key)
+                    .and_then(|data| data.outlier_detector.clone())
+                    .filter(|detector| detector.config() == &config)
+                    .unwrap_or_else(|| {
+                        Arc::new(OutlierDetector::new(service_name.to_owned(), config))
+                    })
+            });
+            let load_balancing = &object.spec.load_balancing;
+            if matches!(
+                load_balancing.policy,
+                LoadBalancingPolicy::Ketama | LoadBalancingPolicy::Maglev
+            ) && load_balancing.hash_on.is_none()
+            {
+                log::warn!(
+                    "no `hashOn` for {:?} load balancing of service {}, using round robin",
+                    load_balancing.policy,
+                    service_name
+                );
+            }
+            let load_balancing = load_balancing.clone();
+            let retry_policy = object
+                .spec
+                .retry_policy
+                .as_ref()
+                .map(|spec| Arc::new(RetryPolicy::from_spec(spec)));
+            let max_timeout = object.spec.max_timeout_millis.map(Duration::from_millis);
+            // Permits in flight and adaptive limits survive reconciles
+            // which keep the settings.
+            let concurrency_limiter = object.spec.concurrency_limit.as_ref().and_then(|spec| {
+                self.config
+                    .services
+                    .pin()
+                    .get(key)
+                    .and_then(|data| data.concurrency_limiter.clone())
+                    .filter(|limiter| limiter.spec() == spec)
+                    .or_else(|| concurrency_limiter(spec, service_name))
+            });
+            let rate_limits = object.spec.rate_limits.clone();
+            let upstream_name = key.upstream.clone();
+            let mut routing_specs = object.spec.routing_rules.clone();
+            if upstream_name.is_some() && !routing_specs.is_empty() {
+                log::warn!(
+                    "routing rules of gRPC service {} ignored with `upstreamName`",
+                    key
+                );
+                routing_specs.clear();
+            }
+            let k8s_client = self.k8s_client.clone();
+            let descriptor_set_source = object.spec.descriptor_set_source.clone();
+            let header_policy = Arc::new(HeaderPolicy::from_spec(&object.spec));
+            let config = self.config.clone();
+
+            tokio::spawn(async move {
+                let upstream_tls = match &upstream_tls_spec {
+                    Some(spec) => {
+                        match UpstreamTls::load(&k8s_client, &namespace, spec, &hostname).await {
+                            Ok(upstream_tls) => Some(Arc::new(upstream_tls)),
+                            Err(error) => {
+                                // Never fall back to plaintext, keep the
+                                // previous spec for the service.
+                                log::error!(
+                                    "failed to load upstream TLS settings for {:?}: {:#}",
+                                    services.iter().map(|(name, _)| name).collect::<Vec<_>>(),
+                                    error
+                                );
+                                return;
+                            }
+                        }
+                    }
+                    None => None,
+                };
+
+                let load_balancer = load_balancer.await;
+                let load_balancer = match &health_check {
+                    Some(spec) => discovery::health_check::health_checked(
+                        load_balancer,
+                        spec,
+                        upstream_tls.clone(),
+                    ),
+                    None => load_balancer,
+                };
+
+                // Static backends are only loaded into the load
+                // balancers here. Discovered ones are kept up to date
+                // by their discovery loop, or the health check loop.
+                load_balancer.update_load_balancers().await;
+
+                if let Some(source) = descriptor_set_source {
                     // TODO error
                     let descriptor_set = descriptor_set::from_source(&DummyPanicContext, &source)
                         .await
@@ -270,39 +631,233 @@ impl ServiceConfigState {
                     for (service_name, service) in services.iter() {
                         // TODO error
                         let (spec, _validation_errors) =
-                            ServiceSpec::build(&descriptor_set, &service).unwrap();
+                            ServiceSpec::build(&descriptor_set, service).unwrap();
                         let spec = Arc::new(spec);
+                        let key = ServiceKey::new(service_name, upstream_name.clone());
+                        let current_rate_limiter = config
+                            .services
+                            .pin()
+                            .get(&key)
+                            .and_then(|data| data.rate_limiter.clone());
 
                         let service_data = ServiceData {
                             generation,
                             service_spec: Some(spec.clone()),
                             load_balancer: Some(load_balancer.clone()),
+                            upstream_tls: upstream_tls.clone(),
+                            header_policy: header_policy.clone(),
+                            outlier_detector: outlier_detector.clone(),
+                            balancer: balancer(&load_balancing, &spec),
+                            retry_policy: retry_policy.clone(),
+                            method_retry_policies: Arc::new(method_retry_policies(&spec)),
+                            max_timeout,
+                            concurrency_limiter: concurrency_limiter.clone(),
+                            rate_limiter: rate_limiter(&rate_limits, &spec, current_rate_limiter),
+                            routing_rules: routing_rules(&routing_specs, &spec),
                         };
 
-                        config.update_service_if_newer(service_name.to_owned(), service_data);
+                        config.update_service_if_newer(key, service_data);
                     }
+                } else {
+                    for (service_name, service) in services.iter() {
+                        let spec = ServiceSpec::build_passthrough(service);
+                        let spec = Arc::new(spec);
+                        let key = ServiceKey::new(service_name, upstream_name.clone());
+                        let current_rate_limiter = config
+                            .services
+                            .pin()
+                            .get(&key)
+                            .and_then(|data| data.rate_limiter.clone());
 
-                    // TODO mark as ready
-                });
-            } else {
-                for (_service_name, service) in services.iter() {
-                    let spec = ServiceSpec::build_passthrough(service);
-                    let spec = Arc::new(spec);
-
-                    let service_data = ServiceData {
-                        generation,
-                        service_spec: Some(spec.clone()),
-                        load_balancer: Some(load_balancer.clone()),
-                    };
+                        let service_data = ServiceData {
+                            generation,
+                            service_spec: Some(spec.clone()),
+                            load_balancer: Some(load_balancer.clone()),
+                            upstream_tls: upstream_tls.clone(),
+                            header_policy: header_policy.clone(),
+                            outlier_detector: outlier_detector.clone(),
+                            balancer: balancer(&load_balancing, &spec),
+                            retry_policy: retry_policy.clone(),
+                            method_retry_policies: Arc::new(method_retry_policies(&spec)),
+                            max_timeout,
+                            concurrency_limiter: concurrency_limiter.clone(),
+                            rate_limiter: rate_limiter(&rate_limits, &spec, current_rate_limiter),
+                            routing_rules: routing_rules(&routing_specs, &spec),
+                        };
 
-                    self.config
-                        .update_service_if_newer(service_name.to_owned(), service_data);
+                        config.update_service_if_newer(key, service_data);
+                    }
                 }
 
                 // TODO mark as ready
-            }
+            });
+        }
+    }
+}
+
+/// Skips splits above 100%, and all splits of a service when they add
+/// up to more than 100%. Invalid splits are logged.
+fn valid_traffic_splits(grpc_service: &str, mut splits: Vec<TrafficSplit>) -> Vec<TrafficSplit> {
+    splits.retain(|split| {
+        if split.percent > 100 {
+            log::error!(
+                "invalid `trafficPercent` {} of upstream {} of gRPC service {}, above 100",
+                split.percent,
+                split.upstream,
+                grpc_service
+            );
         }
+        split.percent <= 100
+    });
+
+    let total: u32 = splits.iter().map(|split| split.percent).sum();
+    if total > 100 {
+        log::error!(
+            "traffic splits of gRPC service {} add up to {}%, above 100%, ignoring them",
+            grpc_service,
+            total
+        );
+        splits.clear();
+    }
+    splits
+}
+
+/// Builds the routing rules of a service without an upstream name.
+/// Invalid rules are logged and skipped.
+fn routing_rules(specs: &[RoutingRule], service: &ServiceSpec) -> Option<Arc<RoutingRules>> {
+    if specs.is_empty() {
+        return None;
+    }
+    let rules = RoutingRules::build(specs, service, |index, error| {
+        log::error!(
+            "invalid routing rule {} of gRPC service {}: {:#}",
+            index,
+            service.name,
+            error
+        );
+    });
+    Some(Arc::new(rules))
+}
+
+/// Builds the rate limiter of a service, or keeps `current` when the
+/// limits did not change so its buckets survive reconciles. Invalid
+/// limits are logged and skipped.
+fn rate_limiter(
+    specs: &[RateLimit],
+    service: &ServiceSpec,
+    current: Option<Arc<RateLimiter>>,
+) -> Option<Arc<RateLimiter>> {
+    if specs.is_empty() {
+        return None;
+    }
+    if let Some(current) = current.filter(|limiter| limiter.specs() == specs) {
+        return Some(current);
+    }
+    let limiter = RateLimiter::build(specs, service, |index, error| {
+        log::error!(
+            "invalid rate limit {} of gRPC service {}: {:#}",
+            index,
+            service.name,
+            error
+        );
+    });
+    Some(Arc::new(limiter))
+}
+
+/// Builds the concurrency limiter of a service. An invalid limit is
+/// logged and skipped, the service is then not limited.
+fn concurrency_limiter(
+    spec: &ConcurrencyLimit,
+    service_name: &str,
+) -> Option<Arc<ConcurrencyLimiter>> {
+    match ConcurrencyLimiter::new(spec) {
+        Ok(limiter) => Some(Arc::new(limiter)),
+        Err(error) => {
+            log::error!(
+                "invalid `concurrencyLimit` of gRPC service {}: {:#}",
+                service_name,
+                error
+            );
+            None
+        }
+    }
+}
+
+/// Builds the balancer of a service. An invalid `hashOn` field is
+/// logged and skipped, requests are then not hashed.
+fn balancer(spec: &LoadBalancing, service: &ServiceSpec) -> Arc<Balancer> {
+    let mut spec = spec.clone();
+    if let Some(HashOn::Field(field)) = &spec.hash_on {
+        if let Err(error) = validate_hash_field(field, service) {
+            log::error!(
+                "invalid `hashOn` field of gRPC service {}: {:#}",
+                service.name,
+                error
+            );
+            spec.hash_on = None;
+        }
+    }
+    Arc::new(Balancer::new(&spec))
+}
+
+/// The hashed field must resolve in the input of every method.
+fn validate_hash_field(field: &str, service: &ServiceSpec) -> anyhow::Result<()> {
+    if service.passthrough {
+        anyhow::bail!("field `{}` needs the descriptors of the service", field);
+    }
+    let Ok(field_ref) = FieldRef::parse(field);
+    for (method_name, method) in &service.methods {
+        field_ref
+            .validate(&method.descriptor.input_type())
+            .map_err(|error| {
+                anyhow::anyhow!("field `{}` of method `{}`: {}", field, method_name, error)
+            })?;
     }
+    Ok(())
+}
+
+/// Resolves the backends of a `static` upstream, every address of a
+/// hostname becomes a backend. Invalid entries are logged and skipped.
+async fn static_backends(backends: &[StaticBackend]) -> BTreeSet<Backend> {
+    let mut resolved = BTreeSet::new();
+    for backend in backends {
+        let addrs = match tokio::net::lookup_host(&backend.address).await {
+            Ok(addrs) => addrs,
+            Err(error) => {
+                log::error!(
+                    "invalid static upstream address `{}`: {}",
+                    backend.address,
+                    error
+                );
+                continue;
+            }
+        };
+        resolved.extend(addrs.map(|addr| Backend {
+            addr: SocketAddr::Inet(addr),
+            weight: backend.weight.clamp(1, discovery::MAX_BACKEND_WEIGHT),
+            ext: Extensions::new(),
+        }));
+    }
+    resolved
+}
+
+/// Splits `host:port`, removing the brackets around IPv6 addresses.
+fn split_host_port(address: &str) -> Option<(&str, u16)> {
+    let (host, port) = address.rsplit_once(':')?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    Some((host, port.parse().ok()?))
+}
+
+/// The domain of SRV records, without the `_service._proto` labels.
+fn srv_domain(name: &str) -> &str {
+    let mut domain = name;
+    while let Some(rest) = domain.strip_prefix('_').and_then(|d| d.split_once('.')) {
+        domain = rest.1;
+    }
+    domain
 }
 
 #[async_trait::async_trait]
@@ -328,6 +883,8 @@ impl BackgroundService for K8SConfigService {
             raw_services: HashMap::new(),
             ref_by_grpc_service: BTreeMap::new(),
             dns_service_handle: self.dns_service_handle.clone(),
+            kubernetes_discovery: self.kubernetes_discovery.clone(),
+            k8s_client,
         };
 
         log::info!("loading initial service specs..");
@@ -365,3 +922,99 @@ impl BackgroundService for K8SConfigService {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use grcache_shared::config::crd::DescriptorSetSource;
+
+    use super::*;
+
+    fn backend(address: &str, weight: usize) -> StaticBackend {
+        StaticBackend {
+            address: address.into(),
+            weight,
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_static_backends() {
+        let backends = static_backends(&[
+            backend("10.0.0.1:50051", 1),
+            backend("[fd00::1]:50051", 500),
+            backend("localhost:8080", 2),
+            backend("10.0.0.2", 1),
+            backend("missing-port:", 1),
+        ])
+        .await;
+
+        let weights: BTreeMap<String, usize> = backends
+            .iter()
+            .map(|b| (b.addr.to_string(), b.weight))
+            .collect();
+        assert_eq!(weights["10.0.0.1:50051"], 1);
+        assert_eq!(weights["[fd00::1]:50051"], discovery::MAX_BACKEND_WEIGHT);
+        assert_eq!(weights["127.0.0.1:8080"], 2);
+        assert!(weights
+            .keys()
+            .all(|addr| !addr.starts_with("10.0.0.2") && !addr.ends_with(':')));
+    }
+
+    #[tokio::test]
+    async fn skips_invalid_hash_fields() {
+        let descriptor_set = descriptor_set::from_source(
+            &DummyPanicContext,
+            &DescriptorSetSource::File {
+                path: "tests/data/proto_descriptors.binpb".into(),
+            },
+        )
+        .await
+        .unwrap();
+        let name = QualifiedService::parse("example.TestService").unwrap();
+        let (service, _validation_errors) = ServiceSpec::build(&descriptor_set, &name).unwrap();
+        let passthrough = ServiceSpec::build_passthrough(&name);
+
+        let hash_on = |field: &str, service: &ServiceSpec| {
+            let spec = LoadBalancing {
+                policy: LoadBalancingPolicy::Ketama,
+                hash_on: Some(HashOn::Field(field.into())),
+            };
+            balancer(&spec, service).hash_on().cloned()
+        };
+        assert_eq!(hash_on("id", &service), Some(HashOn::Field("id".into())));
+        assert_eq!(hash_on("missing", &service), None);
+        assert_eq!(hash_on("id.value", &service), None);
+        assert_eq!(hash_on("id", &passthrough), None);
+    }
+
+    #[test]
+    fn skips_invalid_traffic_splits() {
+        let splits = |percents: &[u32]| {
+            let splits = percents
+                .iter()
+                .enumerate()
+                .map(|(index, &percent)| TrafficSplit {
+                    upstream: format!("upstream-{}", index),
+                    percent,
+                })
+                .collect();
+            valid_traffic_splits("example.TestService", splits)
+                .iter()
+                .map(|split| split.percent)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(splits(&[10, 90]), vec![10, 90]);
+        assert_eq!(splits(&[101, 20]), vec![20]);
+        assert!(splits(&[60, 50]).is_empty());
+    }
+
+    #[test]
+    fn splits_host_and_port() {
+        assert_eq!(split_host_port("10.0.0.1:80"), Some(("10.0.0.1", 80)));
+        assert_eq!(split_host_port("[fd00::1]:80"), Some(("fd00::1", 80)));
+        assert_eq!(
+            split_host_port("backend.internal:80"),
+            Some(("backend.internal", 80))
+        );
+        assert_eq!(split_host_port("backend.internal"), None);
+    }
+}
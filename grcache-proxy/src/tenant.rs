@@ -0,0 +1,371 @@
+//! Cache partitioning by tenant.
+//!
+//! The tenant of a request is read from the sources in `proxy.tenant`
+//! and becomes part of the cache key. JWKS files used to verify tokens
+//! are reloaded by a background service when they change, so signing
+//! keys can be rotated without a restart.
+
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use grcache_shared::config::{TenantConfig, TenantSource};
+use http::HeaderMap;
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm},
+    Algorithm, DecodingKey, Validation,
+};
+use pingora::{
+    protocols::tls::SslDigest, server::ShutdownWatch, services::background::BackgroundService,
+};
+
+/// How often the JWKS files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+type SharedJwks = Arc<RwLock<Arc<JwkSet>>>;
+
+enum Source {
+    Header(String),
+    JwtClaim {
+        header: String,
+        claim: String,
+        validation: Box<Validation>,
+        jwks: SharedJwks,
+    },
+    ClientCertOrganization,
+}
+
+/// Identifies the tenant of requests.
+pub struct TenantResolver {
+    sources: Vec<Source>,
+    required: bool,
+}
+
+impl TenantResolver {
+    /// Creates the resolver for `config`, along with the background
+    /// service which reloads JWKS files.
+    ///
+    /// Fails if a JWKS file can not be loaded initially.
+    pub fn new(config: &TenantConfig) -> anyhow::Result<(TenantResolver, Service)> {
+        let mut jwks_files = Vec::new();
+        let sources = config
+            .sources
+            .iter()
+            .map(|source| {
+                Ok(match source {
+                    TenantSource::Header { name } => Source::Header(name.to_ascii_lowercase()),
+                    TenantSource::JwtClaim {
+                        header,
+                        claim,
+                        jwks_path,
+                        issuer,
+                        audience,
+                    } => {
+                        let jwks = Arc::new(RwLock::new(Arc::new(load_jwks(jwks_path)?)));
+                        jwks_files.push((jwks_path.clone(), jwks.clone()));
+
+                        let mut validation = Validation::default();
+                        validation.validate_aud = audience.is_some();
+                        if let Some(audience) = audience {
+                            validation.set_audience(&[audience]);
+                        }
+                        if let Some(issuer) = issuer {
+                            validation.set_issuer(&[issuer]);
+                        }
+
+                        Source::JwtClaim {
+                            header: header.to_ascii_lowercase(),
+                            claim: claim.clone(),
+                            validation: Box::new(validation),
+                            jwks,
+                        }
+                    }
+                    TenantSource::ClientCertOrganization {} => Source::ClientCertOrganization,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        let resolver = TenantResolver {
+            sources,
+            required: config.required,
+        };
+        Ok((resolver, Service { jwks_files }))
+    }
+
+    /// Whether requests without a tenant must bypass the cache.
+    pub fn required(&self) -> bool {
+        self.required
+    }
+
+    /// Headers the tenant is read from. These are passed to the
+    /// upstream, and only affect the cache key through the tenant.
+    pub fn headers(&self) -> impl Iterator<Item = &String> {
+        self.sources.iter().filter_map(|source| match source {
+            Source::Header(name) => Some(name),
+            Source::JwtClaim { header, .. } => Some(header),
+            Source::ClientCertOrganization => None,
+        })
+    }
+
+    /// Returns the tenant from the first source which identifies it.
+    pub fn resolve(&self, headers: &HeaderMap, ssl_digest: Option<&SslDigest>) -> Option<String> {
+        self.sources.iter().find_map(|source| match source {
+            Source::Header(name) => header_value(headers, name).map(str::to_owned),
+            Source::JwtClaim {
+                header,
+                claim,
+                validation,
+                jwks,
+            } => {
+                let value = header_value(headers, header)?;
+                let token = value
+                    .get(..7)
+                    .filter(|prefix| prefix.eq_ignore_ascii_case("bearer "))
+                    .map_or(value, |_| value[7..].trim_start());
+                let jwks = jwks.read().unwrap().clone();
+
+                verify_claim(token, claim, validation, &jwks)
+                    .inspect_err(|error| log::debug!("invalid token in `{}`: {}", header, error))
+                    .ok()
+                    .flatten()
+            }
+            Source::ClientCertOrganization => ssl_digest
+                .and_then(|digest| digest.organization.clone())
+                .filter(|organization| !organization.is_empty()),
+        })
+    }
+}
+
+fn header_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+/// Verifies `token` against the keys in `jwks` and returns the value of
+/// `claim`, if it is a string or number.
+fn verify_claim(
+    token: &str,
+    claim: &str,
+    validation: &Validation,
+    jwks: &JwkSet,
+) -> anyhow::Result<Option<String>> {
+    let header = jsonwebtoken::decode_header(token)?;
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None => jwks.keys.first(),
+    }
+    .context("no matching key in JWKS")?;
+    let key = DecodingKey::from_jwk(jwk)?;
+
+    // The token header is not verified yet, the key decides which
+    // algorithms are acceptable.
+    let algorithms = key_algorithms(jwk);
+    anyhow::ensure!(
+        algorithms.contains(&header.alg),
+        "algorithm {:?} is not allowed for the key",
+        header.alg
+    );
+    let mut validation = validation.clone();
+    validation.algorithms = algorithms;
+    let claims = jsonwebtoken::decode::<serde_json::Value>(token, &key, &validation)?.claims;
+
+    Ok(match claims.get(claim) {
+        Some(serde_json::Value::String(value)) => Some(value.clone()),
+        Some(serde_json::Value::Number(value)) => Some(value.to_string()),
+        _ => None,
+    })
+}
+
+/// Signature algorithms tokens verified with `jwk` may use: its `alg`
+/// if set, every algorithm of its key type otherwise.
+fn key_algorithms(jwk: &Jwk) -> Vec<Algorithm> {
+    if let Some(algorithm) = jwk.common.key_algorithm {
+        return match algorithm {
+            KeyAlgorithm::HS256 => vec![Algorithm::HS256],
+            KeyAlgorithm::HS384 => vec![Algorithm::HS384],
+            KeyAlgorithm::HS512 => vec![Algorithm::HS512],
+            KeyAlgorithm::ES256 => vec![Algorithm::ES256],
+            KeyAlgorithm::ES384 => vec![Algorithm::ES384],
+            KeyAlgorithm::RS256 => vec![Algorithm::RS256],
+            KeyAlgorithm::RS384 => vec![Algorithm::RS384],
+            KeyAlgorithm::RS512 => vec![Algorithm::RS512],
+            KeyAlgorithm::PS256 => vec![Algorithm::PS256],
+            KeyAlgorithm::PS384 => vec![Algorithm::PS384],
+            KeyAlgorithm::PS512 => vec![Algorithm::PS512],
+            KeyAlgorithm::EdDSA => vec![Algorithm::EdDSA],
+            // Encryption keys don't verify signatures.
+            KeyAlgorithm::RSA1_5 | KeyAlgorithm::RSA_OAEP | KeyAlgorithm::RSA_OAEP_256 => {
+                Vec::new()
+            }
+        };
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::OctetKey(_) => {
+            vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512]
+        }
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            _ => Vec::new(),
+        },
+        AlgorithmParameters::OctetKeyPair(params) => match params.curve {
+            EllipticCurve::Ed25519 => vec![Algorithm::EdDSA],
+            _ => Vec::new(),
+        },
+    }
+}
+
+fn load_jwks(path: &str) -> anyhow::Result<JwkSet> {
+    let file =
+        std::fs::read(path).with_context(|| format!("failed to read JWKS file `{}`", path))?;
+    serde_json::from_slice(&file).with_context(|| format!("invalid JWKS file `{}`", path))
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+pub struct Service {
+    jwks_files: Vec<(String, SharedJwks)>,
+}
+
+#[async_trait]
+impl BackgroundService for Service {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut last_modified: Vec<_> = self
+            .jwks_files
+            .iter()
+            .map(|(path, _)| modified(path))
+            .collect();
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(RELOAD_INTERVAL) => {}
+            }
+
+            for ((path, jwks), last_modified) in self.jwks_files.iter().zip(&mut last_modified) {
+                let modified = modified(path);
+                if modified == *last_modified {
+                    continue;
+                }
+                match load_jwks(path) {
+                    Ok(loaded) => {
+                        log::info!("reloaded JWKS file `{}`", path);
+                        *jwks.write().unwrap() = Arc::new(loaded);
+                        *last_modified = modified;
+                    }
+                    // Keep the previous keys and retry on the next tick.
+                    Err(error) => log::error!("failed to reload JWKS file: {:#}", error),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{EncodingKey, Header};
+
+    use super::*;
+
+    const JWKS: &str = r#"{"keys": [{"kty": "oct", "kid": "a", "k": "c2VjcmV0"}]}"#;
+
+    fn resolver(sources: Vec<Source>) -> TenantResolver {
+        TenantResolver {
+            sources,
+            required: false,
+        }
+    }
+
+    fn token(secret: &[u8], tenant: &str) -> String {
+        token_with(Algorithm::HS256, secret, tenant)
+    }
+
+    fn token_with(alg: Algorithm, secret: &[u8], tenant: &str) -> String {
+        let header = Header {
+            kid: Some("a".into()),
+            alg,
+            ..Default::default()
+        };
+        let claims = serde_json::json!({ "tenant": tenant, "exp": u32::MAX });
+        jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    #[test]
+    fn resolves_from_first_matching_source() {
+        let jwks = Arc::new(RwLock::new(Arc::new(serde_json::from_str(JWKS).unwrap())));
+        let resolver = resolver(vec![
+            Source::JwtClaim {
+                header: "authorization".into(),
+                claim: "tenant".into(),
+                validation: Default::default(),
+                jwks,
+            },
+            Source::Header("x-tenant".into()),
+        ]);
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(resolver.resolve(&headers, None), None);
+
+        headers.insert("x-tenant", "from-header".parse().unwrap());
+        assert_eq!(
+            resolver.resolve(&headers, None).as_deref(),
+            Some("from-header")
+        );
+
+        let bearer = format!("Bearer {}", token(b"secret", "from-token"));
+        headers.insert("authorization", bearer.parse().unwrap());
+        assert_eq!(
+            resolver.resolve(&headers, None).as_deref(),
+            Some("from-token")
+        );
+
+        // Tokens signed with other keys fall through to the next source.
+        let bearer = format!("Bearer {}", token(b"other", "from-token"));
+        headers.insert("authorization", bearer.parse().unwrap());
+        assert_eq!(
+            resolver.resolve(&headers, None).as_deref(),
+            Some("from-header")
+        );
+    }
+
+    #[test]
+    fn key_decides_algorithm() {
+        let jwks: JwkSet = serde_json::from_str(
+            r#"{"keys": [{"kty": "oct", "kid": "a", "alg": "HS512", "k": "c2VjcmV0"}]}"#,
+        )
+        .unwrap();
+        let validation = Validation::default();
+
+        let token = token_with(Algorithm::HS512, b"secret", "tenant");
+        let tenant = verify_claim(&token, "tenant", &validation, &jwks).unwrap();
+        assert_eq!(tenant.as_deref(), Some("tenant"));
+
+        // The algorithm in the token header can't override the key.
+        let token = token_with(Algorithm::HS256, b"secret", "tenant");
+        assert!(verify_claim(&token, "tenant", &validation, &jwks).is_err());
+
+        // Keys without `alg` allow the algorithms of their type only.
+        let jwks: JwkSet = serde_json::from_str(JWKS).unwrap();
+        assert_eq!(
+            key_algorithms(&jwks.keys[0]),
+            vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512]
+        );
+        let token = token_with(Algorithm::HS384, b"secret", "tenant");
+        assert!(verify_claim(&token, "tenant", &validation, &jwks).is_ok());
+    }
+}
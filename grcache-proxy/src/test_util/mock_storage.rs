@@ -1,9 +1,12 @@
-use std::any::Any;
+use std::{
+    any::Any,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use async_trait::async_trait;
 use pingora::cache::{
     key::CompactCacheKey, storage::HandleMiss, trace::SpanHandle, CacheKey, CacheMeta, HitHandler,
-    MissHandler, PurgeType, Storage,
+    MemCache, MissHandler, PurgeType, Storage,
 };
 
 use crate::cache::GrcacheStorage;
@@ -74,3 +77,80 @@ impl Storage for MockStorage {
         self
     }
 }
+
+/// In-memory storage which serves hits, counting lookups which hit and
+/// entries written.
+pub struct MemoryStorage {
+    cache: MemCache,
+    hits: AtomicUsize,
+    writes: AtomicUsize,
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        MemoryStorage {
+            cache: MemCache::new(),
+            hits: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl MemoryStorage {
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn writes(&self) -> usize {
+        self.writes.load(Ordering::Relaxed)
+    }
+}
+
+impl GrcacheStorage for MemoryStorage {
+    fn as_storage(&self) -> &(dyn Storage + Sync) {
+        self
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn lookup(
+        &'static self,
+        key: &CacheKey,
+        trace: &SpanHandle,
+    ) -> pingora::Result<Option<(CacheMeta, HitHandler)>> {
+        let hit = self.cache.lookup(key, trace).await?;
+        if hit.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(hit)
+    }
+    async fn get_miss_handler(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        trace: &SpanHandle,
+    ) -> pingora::Result<MissHandler> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.cache.get_miss_handler(key, meta, trace).await
+    }
+    async fn purge(
+        &'static self,
+        key: &CompactCacheKey,
+        purge_type: PurgeType,
+        trace: &SpanHandle,
+    ) -> pingora::Result<bool> {
+        self.cache.purge(key, purge_type, trace).await
+    }
+    async fn update_meta(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        trace: &SpanHandle,
+    ) -> pingora::Result<bool> {
+        self.cache.update_meta(key, meta, trace).await
+    }
+    fn as_any(&self) -> &(dyn Any + Send + Sync + 'static) {
+        self
+    }
+}
@@ -1,24 +1,30 @@
 use std::{collections::BTreeSet, net::SocketAddr, sync::Arc};
 
 use grcache_shared::{
-    config::crd::DescriptorSetSource,
+    config::{context::ConfigContextData, crd::DescriptorSetSource, ConfigFile, TenantConfig},
     service::{
         descriptor_set::{self, DummyPanicContext},
         qualified_service::QualifiedService,
         ServiceSpec,
     },
 };
+use mock_storage::MemoryStorage;
 use pingora_load_balancing::Backend;
-use proxy::{proxy_server, ProxyServerTestContext};
+use proxy::{proxy_server, proxy_server_with_storage, ProxyServerTestContext};
 use tokio::sync::watch;
 
-use crate::{discovery::ServiceBackendsHandle, service_store::ServiceData};
+use crate::{
+    discovery::ServiceBackendsHandle,
+    service_store::{ServiceData, ServiceKey, TrafficSplit},
+    tenant::TenantResolver,
+};
 
 pub mod mock_storage;
 pub mod proxy;
 
 pub struct ProxyTest {
     proxy_ctx: ProxyServerTestContext,
+    storage: Option<&'static MemoryStorage>,
 }
 
 impl ProxyTest {
@@ -27,25 +33,75 @@ impl ProxyTest {
 
         ProxyTest {
             proxy_ctx: server_test_ctx,
+            storage: None,
+        }
+    }
+
+    /// Starts the proxy with an in-memory cache which serves hits, see
+    /// `storage`.
+    pub async fn with_memory_cache() -> Self {
+        let storage: &'static MemoryStorage = Box::leak(Default::default());
+        let server_test_ctx = proxy_server_with_storage(storage, |_proxy| {}).await;
+
+        ProxyTest {
+            proxy_ctx: server_test_ctx,
+            storage: Some(storage),
+        }
+    }
+
+    /// Starts the proxy with an in-memory cache partitioned by tenant.
+    pub async fn with_tenant(config: &TenantConfig) -> Self {
+        let (tenant_resolver, _jwks_service) = TenantResolver::new(config).unwrap();
+        let storage: &'static MemoryStorage = Box::leak(Default::default());
+        let server_test_ctx =
+            proxy_server_with_storage(storage, |proxy| proxy.tenant = Some(tenant_resolver)).await;
+
+        ProxyTest {
+            proxy_ctx: server_test_ctx,
+            storage: Some(storage),
         }
     }
 
+    /// The in-memory cache of a proxy started with `with_memory_cache`
+    /// or `with_tenant`.
+    pub fn storage(&self) -> &'static MemoryStorage {
+        self.storage
+            .expect("proxy started without an in-memory cache")
+    }
+
     pub fn addr(&self) -> SocketAddr {
         self.proxy_ctx.listener_addr
     }
 
     pub fn add_service_passthrough(&mut self, service_name: &str) -> BackendsTest {
+        self.add_upstream_passthrough(ServiceKey::from(service_name))
+    }
+
+    /// Adds a service without descriptors under the `upstreamName` of
+    /// `key`.
+    pub fn add_upstream_passthrough(&mut self, key: ServiceKey) -> BackendsTest {
+        let service_name = key.service.clone();
         let (backends_handle, ready_sender, backends_sender) = ServiceBackendsHandle::new_test();
         ready_sender.send(true).unwrap();
 
         self.proxy_ctx.service_config.services.pin().insert(
-            service_name.into(),
+            key,
             ServiceData {
                 generation: 10,
                 service_spec: Some(Arc::new(ServiceSpec::build_passthrough(
-                    &QualifiedService::parse(service_name).unwrap(),
+                    &QualifiedService::parse(&service_name).unwrap(),
                 ))),
                 load_balancer: Some(backends_handle.clone()),
+                upstream_tls: None,
+                header_policy: Default::default(),
+                outlier_detector: None,
+                balancer: Default::default(),
+                retry_policy: None,
+                method_retry_policies: Default::default(),
+                max_timeout: None,
+                concurrency_limiter: None,
+                rate_limiter: None,
+                routing_rules: None,
             },
         );
 
@@ -83,6 +139,16 @@ impl ProxyTest {
                 generation: 10,
                 service_spec: Some(Arc::new(service_spec)),
                 load_balancer: Some(backends_handle.clone()),
+                upstream_tls: None,
+                header_policy: Default::default(),
+                outlier_detector: None,
+                balancer: Default::default(),
+                retry_policy: None,
+                method_retry_policies: Default::default(),
+                max_timeout: None,
+                concurrency_limiter: None,
+                rate_limiter: None,
+                routing_rules: None,
             },
         );
 
@@ -92,6 +158,44 @@ impl ProxyTest {
         }
     }
 
+    /// Changes the config file of the proxy, like a reload would.
+    pub fn update_config(&self, update: impl FnOnce(&mut ConfigFile)) {
+        let mut config = (*self.proxy_ctx.config.borrow().config).clone();
+        update(&mut config);
+        self.proxy_ctx
+            .config
+            .send_replace(ConfigContextData::new(config));
+    }
+
+    /// Changes the data of a service added through `add_service*`,
+    /// like a reconcile of its `GrcacheService` would.
+    pub fn update_service(&self, service_name: &str, update: impl FnOnce(&mut ServiceData)) {
+        let services = self.proxy_ctx.service_config.services.pin();
+        let mut service_data = services
+            .get(&ServiceKey::from(service_name))
+            .unwrap()
+            .clone();
+        update(&mut service_data);
+        services.insert(service_name.into(), service_data);
+    }
+
+    /// Sends `percent` of the requests to a service without an
+    /// upstream name to each named upstream.
+    pub fn set_traffic_splits(&self, service_name: &str, splits: &[(&str, u32)]) {
+        let splits: Vec<_> = splits
+            .iter()
+            .map(|(upstream, percent)| TrafficSplit {
+                upstream: (*upstream).into(),
+                percent: *percent,
+            })
+            .collect();
+        self.proxy_ctx
+            .service_config
+            .traffic_splits
+            .pin()
+            .insert(service_name.into(), splits.into());
+    }
+
     pub async fn shutdown(self) {
         self.proxy_ctx.shutdown().await;
     }
@@ -114,8 +218,14 @@ impl BackendsTest {
     }
 
     pub async fn set_single_backend_addr(&mut self, addr: SocketAddr) {
-        let mut backends = BTreeSet::new();
-        backends.insert(Backend::new(&format!("{}", addr)).unwrap());
+        self.set_backend_addrs(&[addr]).await;
+    }
+
+    pub async fn set_backend_addrs(&mut self, addrs: &[SocketAddr]) {
+        let backends = addrs
+            .iter()
+            .map(|addr| Backend::new(&format!("{}", addr)).unwrap())
+            .collect();
         self.set_backends(backends).await;
     }
 }
@@ -1,18 +1,20 @@
 use std::{net::SocketAddr, os::fd::AsRawFd, sync::Arc};
 
+use grcache_shared::config::{context::ConfigContextData, loader::ConfigFormat, ConfigFile};
 use pingora::{
     apps::HttpServerOptions,
     server::{configuration::ServerConf, Fds},
     services::Service as _,
 };
 use pingora_proxy::http_proxy_service;
+use std::net::TcpListener;
 use tokio::{
-    net::TcpListener,
     sync::{watch, Mutex},
     task::JoinHandle,
 };
 
 use crate::{
+    cache::GrcacheStorage,
     proxy::GrpcProxy,
     service_store::{ServiceConfig, ServiceConfigInner},
 };
@@ -24,6 +26,8 @@ pub struct ProxyServerTestContext {
     pub shutdown: watch::Sender<bool>,
     pub proxy_handle: JoinHandle<()>,
     pub service_config: ServiceConfig,
+    /// Replaces the config file, like a reload would.
+    pub config: watch::Sender<ConfigContextData>,
     pub listener_addr: SocketAddr,
 }
 
@@ -35,16 +39,29 @@ impl ProxyServerTestContext {
 }
 
 pub async fn proxy_server() -> ProxyServerTestContext {
+    proxy_server_with(|_proxy| {}).await
+}
+
+/// Like `proxy_server`, with `setup` applied to the proxy before it is
+/// started.
+pub async fn proxy_server_with(setup: impl FnOnce(&mut GrpcProxy)) -> ProxyServerTestContext {
+    proxy_server_with_storage(Box::leak(Box::new(MockStorage {})), setup).await
+}
+
+/// Like `proxy_server_with`, caching in `cache`.
+pub async fn proxy_server_with_storage(
+    cache: &'static (dyn GrcacheStorage + Sync),
+    setup: impl FnOnce(&mut GrpcProxy),
+) -> ProxyServerTestContext {
     let (s0, r0) = watch::channel(true);
 
-    let service_config = Arc::new(ServiceConfigInner {
-        ready: r0,
-        services: papaya::HashMap::new(),
-    });
+    let service_config = Arc::new(ServiceConfigInner::new(r0));
 
-    let cache = Box::leak(Box::new(MockStorage {}));
+    let (config, config_context) = watch::channel(ConfigContextData::new(test_config()));
 
-    let proxy = GrpcProxy::new(service_config.clone(), cache);
+    let mut proxy = GrpcProxy::new(service_config.clone(), config_context, cache);
+    proxy.json_transcoding = true;
+    setup(&mut proxy);
 
     let conf = ServerConf::default();
     let mut http_proxy = http_proxy_service(&Arc::new(conf), proxy);
@@ -56,7 +73,10 @@ pub async fn proxy_server() -> ProxyServerTestContext {
     http_proxy.add_tcp("127.0.0.1:12345");
 
     // Create listener with dynamic port and insert into fds.
-    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    // The listener is bound through `std` so that it is not registered with
+    // the tokio reactor before pingora takes over the fd.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
     let listener_addr = listener.local_addr().unwrap();
     let mut fds = Fds::new();
     fds.add("127.0.0.1:12345".into(), listener.as_raw_fd());
@@ -75,6 +95,21 @@ pub async fn proxy_server() -> ProxyServerTestContext {
         shutdown: s,
         proxy_handle,
         service_config,
+        config,
         listener_addr,
     }
 }
+
+/// Minimal config file for the proxy under test.
+pub fn test_config() -> ConfigFile {
+    let source = r#"
+kubernetes:
+  enable: true
+cacheBackend:
+  redisReplicas:
+    hostname: redis
+proxy:
+  propagationHeaders: []
+"#;
+    ConfigFile::parse(source, ConfigFormat::Yaml).unwrap()
+}
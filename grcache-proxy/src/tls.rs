@@ -0,0 +1,489 @@
+//! TLS for the proxy listener and for upstream connections.
+//!
+//! Listener certificates are provided to the TLS handshake from a
+//! snapshot which is reloaded by a background service when the files
+//! on disk change. Rotated certificates, like the ones written by
+//! cert-manager into a mounted secret, are picked up without a restart.
+//!
+//! Upstream TLS settings come from the `GrcacheService`, with
+//! certificates read from Kubernetes secrets.
+
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use grcache_shared::config::{crd::UpstreamTls as UpstreamTlsSpec, ListenerTlsConfig};
+use k8s_openapi::api::core::v1::Secret;
+use kube::{Api, Client};
+use pingora::{
+    listeners::{tls::TlsSettings, TlsAccept},
+    prelude::HttpPeer,
+    protocols::{tls::TlsRef, ALPN},
+    server::ShutdownWatch,
+    services::background::BackgroundService,
+    tls::{
+        ext,
+        pkey::{PKey, Private},
+        ssl::SslVerifyMode,
+        x509::{
+            store::{X509Store, X509StoreBuilder},
+            X509,
+        },
+    },
+    utils::tls::CertKey,
+};
+
+/// How often the certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Certificates and keys as loaded from disk.
+struct TlsMaterial {
+    cert: X509,
+    chain: Vec<X509>,
+    key: PKey<Private>,
+    client_ca: Option<X509Store>,
+}
+
+impl TlsMaterial {
+    fn load(config: &ListenerTlsConfig) -> anyhow::Result<Self> {
+        let cert_pem = std::fs::read(&config.cert_path)
+            .with_context(|| format!("failed to read cert file `{}`", config.cert_path))?;
+        let mut certs = X509::stack_from_pem(&cert_pem)
+            .with_context(|| format!("invalid cert file `{}`", config.cert_path))?
+            .into_iter();
+        let cert = certs
+            .next()
+            .with_context(|| format!("no certificate in cert file `{}`", config.cert_path))?;
+
+        let key_pem = std::fs::read(&config.key_path)
+            .with_context(|| format!("failed to read key file `{}`", config.key_path))?;
+        let key = PKey::private_key_from_pem(&key_pem)
+            .with_context(|| format!("invalid key file `{}`", config.key_path))?;
+
+        let client_ca = config
+            .client_ca_path
+            .as_ref()
+            .map(|path| {
+                let ca_pem = std::fs::read(path)
+                    .with_context(|| format!("failed to read client CA file `{}`", path))?;
+                let mut store = X509StoreBuilder::new()?;
+                for ca in X509::stack_from_pem(&ca_pem)
+                    .with_context(|| format!("invalid client CA file `{}`", path))?
+                {
+                    store.add_cert(ca)?;
+                }
+                anyhow::Ok(store.build())
+            })
+            .transpose()?;
+
+        Ok(TlsMaterial {
+            cert,
+            chain: certs.collect(),
+            key,
+            client_ca,
+        })
+    }
+
+    /// Latest modification time of the files the material was loaded
+    /// from. Follows symlinks, which is how Kubernetes swaps mounted
+    /// secrets.
+    fn modified(config: &ListenerTlsConfig) -> Option<SystemTime> {
+        std::iter::once(&config.cert_path)
+            .chain(std::iter::once(&config.key_path))
+            .chain(config.client_ca_path.iter())
+            .filter_map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .max()
+    }
+}
+
+type SharedMaterial = Arc<RwLock<Arc<TlsMaterial>>>;
+
+struct CertificateCallback {
+    material: SharedMaterial,
+}
+
+#[async_trait]
+impl TlsAccept for CertificateCallback {
+    async fn certificate_callback(&self, ssl: &mut TlsRef) {
+        let material = self.material.read().unwrap().clone();
+
+        let result = ext::ssl_use_certificate(ssl, &material.cert)
+            .and_then(|_| ext::ssl_use_private_key(ssl, &material.key))
+            .and_then(|_| {
+                material
+                    .chain
+                    .iter()
+                    .try_for_each(|cert| ext::ssl_add_chain_cert(ssl, cert))
+            })
+            .and_then(|_| match &material.client_ca {
+                Some(store) => ext::ssl_set_verify_cert_store(ssl, store),
+                None => Ok(()),
+            });
+
+        if let Err(error) = result {
+            // The handshake fails without a certificate.
+            log::error!("failed to set certificate for TLS handshake: {}", error);
+        }
+    }
+}
+
+/// Creates the `TlsSettings` for the proxy listener, along with the
+/// background service which reloads certificates.
+///
+/// Fails if the certificates can not be loaded initially.
+pub fn listener_tls(config: &ListenerTlsConfig) -> anyhow::Result<(Service, TlsSettings)> {
+    let material: SharedMaterial = Arc::new(RwLock::new(Arc::new(TlsMaterial::load(config)?)));
+
+    let callback = CertificateCallback {
+        material: material.clone(),
+    };
+    let mut settings = TlsSettings::with_callbacks(Box::new(callback))?;
+
+    // Prefer h2 for gRPC, keep HTTP/1.1 for gRPC-Web and JSON clients.
+    settings.enable_h2();
+
+    if config.client_ca_path.is_some() {
+        let mut mode = SslVerifyMode::PEER;
+        if !config.client_cert_optional {
+            mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+        }
+        settings.set_verify(mode);
+    }
+
+    let service = Service {
+        config: config.clone(),
+        material,
+    };
+
+    Ok((service, settings))
+}
+
+pub struct Service {
+    config: ListenerTlsConfig,
+    material: SharedMaterial,
+}
+
+impl Service {
+    /// Reloads the material if the files changed since `last_modified`.
+    /// Returns the modification time of the material in use.
+    fn reload_if_changed(&self, last_modified: Option<SystemTime>) -> Option<SystemTime> {
+        let modified = TlsMaterial::modified(&self.config);
+        if modified == last_modified {
+            return last_modified;
+        }
+
+        match TlsMaterial::load(&self.config) {
+            Ok(material) => {
+                log::info!("reloaded TLS certificates");
+                *self.material.write().unwrap() = Arc::new(material);
+                modified
+            }
+            Err(error) => {
+                // Files may be observed mid-update, keep serving the
+                // previous certificates and retry on the next tick.
+                log::error!("failed to reload TLS certificates: {:#}", error);
+                last_modified
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for Service {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut last_modified = TlsMaterial::modified(&self.config);
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = tokio::time::sleep(RELOAD_INTERVAL) => {}
+            }
+            last_modified = self.reload_if_changed(last_modified);
+        }
+    }
+}
+
+/// Resolved TLS settings for connecting to the upstreams of a
+/// service.
+pub struct UpstreamTls {
+    pub sni: String,
+    pub verify_cert: bool,
+    pub verify_hostname: bool,
+    /// CA certificates to verify the upstream with, system roots if
+    /// `None`.
+    pub ca: Option<Arc<Box<[X509]>>>,
+    /// Client certificate for mTLS.
+    pub client_cert_key: Option<Arc<CertKey>>,
+}
+
+impl UpstreamTls {
+    /// Resolves the TLS settings of a `GrcacheService`, reading
+    /// referenced secrets from `namespace`.
+    ///
+    /// `default_sni` is used when the spec does not override it,
+    /// normally the hostname the upstream was discovered through.
+    pub async fn load(
+        client: &Client,
+        namespace: &str,
+        spec: &UpstreamTlsSpec,
+        default_sni: &str,
+    ) -> anyhow::Result<Self> {
+        let secrets: Api<Secret> = Api::namespaced(client.clone(), namespace);
+
+        let ca = match &spec.ca_secret {
+            Some(name) => {
+                let secret = secrets
+                    .get(name)
+                    .await
+                    .with_context(|| format!("failed to get CA secret `{}`", name))?;
+                let ca_pem = secret_key(&secret, name, "ca.crt")?;
+                let certs = X509::stack_from_pem(ca_pem)
+                    .with_context(|| format!("invalid `ca.crt` in secret `{}`", name))?;
+                Some(Arc::new(certs.into_boxed_slice()))
+            }
+            None => None,
+        };
+
+        let client_cert_key = match &spec.client_cert_secret {
+            Some(name) => {
+                let secret = secrets
+                    .get(name)
+                    .await
+                    .with_context(|| format!("failed to get client cert secret `{}`", name))?;
+                let certs = X509::stack_from_pem(secret_key(&secret, name, "tls.crt")?)
+                    .with_context(|| format!("invalid `tls.crt` in secret `{}`", name))?;
+                anyhow::ensure!(!certs.is_empty(), "no certificate in secret `{}`", name);
+                let key = PKey::private_key_from_pem(secret_key(&secret, name, "tls.key")?)
+                    .with_context(|| format!("invalid `tls.key` in secret `{}`", name))?;
+                Some(Arc::new(CertKey::new(certs, key)))
+            }
+            None => None,
+        };
+
+        Ok(UpstreamTls {
+            sni: spec.sni.clone().unwrap_or_else(|| default_sni.to_owned()),
+            verify_cert: spec.verify_cert,
+            verify_hostname: spec.verify_hostname,
+            ca,
+            client_cert_key,
+        })
+    }
+
+    /// Applies the settings to a peer created with TLS enabled.
+    pub fn apply(&self, peer: &mut HttpPeer) {
+        peer.client_cert_key = self.client_cert_key.clone();
+        peer.options.verify_cert = self.verify_cert;
+        peer.options.verify_hostname = self.verify_hostname;
+        peer.options.ca = self.ca.clone();
+    }
+}
+
+/// Creates an HTTP/2 peer for an upstream, with plaintext if
+/// `upstream_tls` is `None`.
+pub fn upstream_peer(addr: std::net::SocketAddr, upstream_tls: Option<&UpstreamTls>) -> HttpPeer {
+    let mut peer = match upstream_tls {
+        Some(upstream_tls) => {
+            let mut peer = HttpPeer::new(addr, true, upstream_tls.sni.clone());
+            upstream_tls.apply(&mut peer);
+            peer
+        }
+        // SNI is irrelevant for plaintext.
+        None => HttpPeer::new(addr, false, "".into()),
+    };
+    peer.options.alpn = ALPN::H2;
+    peer
+}
+
+fn secret_key<'a>(secret: &'a Secret, name: &str, key: &str) -> anyhow::Result<&'a [u8]> {
+    secret
+        .data
+        .as_ref()
+        .and_then(|data| data.get(key))
+        .map(|value| value.0.as_slice())
+        .with_context(|| format!("secret `{}` has no `{}` key", name, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use grcache_shared::config::{crd::UpstreamTls as UpstreamTlsSpec, ListenerTlsConfig};
+    use kube::Client;
+    use openssl::{
+        asn1::Asn1Time,
+        hash::MessageDigest,
+        pkey::PKey,
+        x509::{X509NameBuilder, X509},
+    };
+    use pingora::{
+        protocols::ALPN,
+        upstreams::peer::{Peer, Scheme},
+        utils::tls::CertKey,
+    };
+
+    use super::{listener_tls, upstream_peer, TlsMaterial, UpstreamTls};
+
+    fn self_signed(common_name: &str) -> (Vec<u8>, Vec<u8>) {
+        let key = PKey::generate_ed25519().unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", common_name).unwrap();
+        let name = name.build();
+
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        // Ed25519 signatures do not use a separate digest.
+        cert.sign(&key, MessageDigest::null()).unwrap();
+
+        (
+            cert.build().to_pem().unwrap(),
+            key.private_key_to_pem_pkcs8().unwrap(),
+        )
+    }
+
+    fn common_name_of(cert: &X509) -> String {
+        let entry = cert.subject_name().entries().next().unwrap();
+        entry.data().as_utf8().unwrap().to_string()
+    }
+
+    fn write_files(dir: &Path, common_name: &str) -> ListenerTlsConfig {
+        let (cert, key) = self_signed(common_name);
+        std::fs::write(dir.join("tls.crt"), &cert).unwrap();
+        std::fs::write(dir.join("tls.key"), &key).unwrap();
+        std::fs::write(dir.join("ca.crt"), &cert).unwrap();
+        ListenerTlsConfig {
+            cert_path: dir.join("tls.crt").to_str().unwrap().into(),
+            key_path: dir.join("tls.key").to_str().unwrap().into(),
+            client_ca_path: Some(dir.join("ca.crt").to_str().unwrap().into()),
+            client_cert_optional: false,
+        }
+    }
+
+    fn common_name(material: &TlsMaterial) -> String {
+        common_name_of(&material.cert)
+    }
+
+    #[test]
+    fn reloads_changed_certificates() {
+        let dir = std::env::temp_dir().join(format!("grcache-tls-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let config = write_files(&dir, "first");
+        let (service, _settings) = listener_tls(&config).unwrap();
+        let material = service.material.read().unwrap().clone();
+        assert_eq!(common_name(&material), "first");
+        assert!(material.client_ca.is_some());
+
+        // Unchanged files are not reloaded.
+        let modified = TlsMaterial::modified(&config);
+        assert_eq!(service.reload_if_changed(modified), modified);
+        assert!(Arc::ptr_eq(
+            &material,
+            &service.material.read().unwrap().clone()
+        ));
+
+        // Broken files keep the previous certificates.
+        std::fs::write(&config.key_path, b"garbage").unwrap();
+        service.reload_if_changed(None);
+        assert_eq!(common_name(&service.material.read().unwrap()), "first");
+
+        write_files(&dir, "second");
+        service.reload_if_changed(None);
+        assert_eq!(common_name(&service.material.read().unwrap()), "second");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn upstream_tls() -> UpstreamTls {
+        UpstreamTls {
+            sni: "upstream.example".into(),
+            verify_cert: true,
+            verify_hostname: true,
+            ca: None,
+            client_cert_key: None,
+        }
+    }
+
+    #[test]
+    fn plaintext_peer() {
+        let peer = upstream_peer("127.0.0.1:50051".parse().unwrap(), None);
+        assert_eq!(peer.scheme, Scheme::HTTP);
+        assert!(peer.sni.is_empty());
+        assert!(matches!(peer.options.alpn, ALPN::H2));
+        assert!(peer.client_cert_key.is_none());
+    }
+
+    #[test]
+    fn tls_peer_with_system_roots() {
+        let peer = upstream_peer("127.0.0.1:50051".parse().unwrap(), Some(&upstream_tls()));
+        assert_eq!(peer.scheme, Scheme::HTTPS);
+        assert_eq!(peer.sni, "upstream.example");
+        assert!(matches!(peer.options.alpn, ALPN::H2));
+        assert!(peer.options.verify_cert);
+        assert!(peer.options.verify_hostname);
+        assert!(peer.options.ca.is_none());
+        assert!(peer.client_cert_key.is_none());
+    }
+
+    #[test]
+    fn tls_peer_with_ca_and_client_cert() {
+        let (cert, key) = self_signed("client");
+        let cert = X509::from_pem(&cert).unwrap();
+        let key = PKey::private_key_from_pem(&key).unwrap();
+        let tls = UpstreamTls {
+            verify_hostname: false,
+            ca: Some(Arc::new(vec![cert.clone()].into_boxed_slice())),
+            client_cert_key: Some(Arc::new(CertKey::new(vec![cert], key))),
+            ..upstream_tls()
+        };
+
+        let peer = upstream_peer("127.0.0.1:50051".parse().unwrap(), Some(&tls));
+        assert_eq!(peer.scheme, Scheme::HTTPS);
+        assert!(peer.options.verify_cert);
+        assert!(!peer.options.verify_hostname);
+        assert_eq!(peer.get_ca().unwrap().len(), 1);
+        assert_eq!(
+            common_name_of(peer.get_client_cert_key().unwrap().leaf()),
+            "client"
+        );
+    }
+
+    #[tokio::test]
+    async fn sni_defaults_to_upstream_hostname() {
+        // Nothing is requested from the cluster without secrets.
+        let config = kube::Config::new("http://127.0.0.1:1".parse().unwrap());
+        let client = Client::try_from(config).unwrap();
+        let spec = UpstreamTlsSpec {
+            sni: None,
+            ca_secret: None,
+            client_cert_secret: None,
+            verify_cert: false,
+            verify_hostname: true,
+        };
+
+        let tls = UpstreamTls::load(&client, "default", &spec, "backend.svc")
+            .await
+            .unwrap();
+        assert_eq!(tls.sni, "backend.svc");
+        let peer = upstream_peer("127.0.0.1:50051".parse().unwrap(), Some(&tls));
+        assert_eq!(peer.sni, "backend.svc");
+        assert!(!peer.options.verify_cert);
+
+        let spec = UpstreamTlsSpec {
+            sni: Some("override.example".into()),
+            ..spec
+        };
+        let tls = UpstreamTls::load(&client, "default", &spec, "backend.svc")
+            .await
+            .unwrap();
+        assert_eq!(tls.sni, "override.example");
+    }
+}
@@ -1,7 +1,7 @@
 use std::sync::OnceLock;
 
 use grcache_shared::config::TracingConfig;
-use http::{HeaderMap, Request};
+use http::HeaderMap;
 use opentelemetry::{
     global,
     propagation::{TextMapCompositePropagator, TextMapPropagator},
@@ -29,14 +29,7 @@ pub struct Tracing {
 
 impl Tracing {
     pub fn init(config: &TracingConfig) -> Self {
-        let mut propagators: Vec<Box<dyn TextMapPropagator + Send + Sync + 'static>> = Vec::new();
-        if config.opentelemetry_propagator {
-            propagators.push(Box::new(TraceContextPropagator::new()));
-        }
-        if config.datadog_propagator {
-            propagators.push(Box::new(DatadogPropagator::new()));
-        }
-        global::set_text_map_propagator(TextMapCompositePropagator::new(propagators));
+        set_propagators(config);
 
         let exporter = SpanExporter::builder()
             .with_http()
@@ -56,6 +49,24 @@ impl Tracing {
     }
 }
 
+/// Sets the global propagators from the config. Can be called again
+/// when the config is reloaded.
+pub fn set_propagators(config: &TracingConfig) {
+    let mut propagators: Vec<Box<dyn TextMapPropagator + Send + Sync + 'static>> = Vec::new();
+    if config.opentelemetry_propagator {
+        propagators.push(Box::new(TraceContextPropagator::new()));
+    }
+    if config.datadog_propagator {
+        propagators.push(Box::new(DatadogPropagator::new()));
+    }
+    global::set_text_map_propagator(TextMapCompositePropagator::new(propagators));
+}
+
+/// Headers used by the active propagators.
+pub fn propagation_fields() -> Vec<String> {
+    global::get_text_map_propagator(|propagator| propagator.fields().map(str::to_string).collect())
+}
+
 pub fn extract_context_from_headers(headers: &HeaderMap) -> Context {
     global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
 }
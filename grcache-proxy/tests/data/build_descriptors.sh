@@ -2,3 +2,7 @@
 set -xe
 
 buf build -o proto_descriptors.binpb --exclude-source-info
+
+# Without `buf`, the same descriptors can be built with `protoc` against
+# the local `grcache` options:
+# protoc -I . -I ../../../proto --include_imports --retain_options -o proto_descriptors.binpb test_service.proto
@@ -1,17 +1,46 @@
-use grcache_shared::test::{grpc_client::grpc_request, grpc_server::MockServer};
-use http::{HeaderMap, StatusCode};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use grcache_proxy::test_util::ProxyTest;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use grcache_shared::{
+    config::{
+        crd::{
+            ConcurrencyLimit, FieldMatch, HashOn, HeaderMatch, LoadBalancing, LoadBalancingPolicy,
+            OutlierDetection, RateLimit, RateLimitKey, RetryPolicy, RoutingRule, ValueMatch,
+        },
+        TenantConfig, TenantSource,
+    },
+    test::{
+        grpc_client::{grpc_request, grpc_request_with_headers, grpc_status},
+        grpc_server::{ok_trailers, MockServer},
+    },
+};
+
+use grcache_proxy::{
+    discovery::{balancer::Balancer, outlier::OutlierDetector},
+    grpc::timeout::parse_grpc_timeout,
+    proxy::{
+        concurrency::ConcurrencyLimiter, rate_limit::RateLimiter, retry, routing::RoutingRules,
+    },
+    service_store::{HeaderPolicy, ServiceKey},
+    test_util::ProxyTest,
+};
+use http::HeaderMap;
 
 #[tokio::test]
 async fn request_without_service_match() {
     let proxy_test = ProxyTest::new().await;
 
-    // Unknown service and method returns not found (404).
-    let (head, _body) = grpc_request(&proxy_test.addr(), "package.Service", "Method", b"")
-        .await
-        .into_parts();
-    assert!(head.status == StatusCode::NOT_FOUND);
+    // Unknown service and method returns UNIMPLEMENTED (12) as a
+    // trailers-only response.
+    let response = grpc_request(&proxy_test.addr(), "package.Service", "Method", b"").await;
+    assert!(response.status().is_success());
+    assert_eq!(grpc_status(response).await.as_deref(), Some("12"));
 
     proxy_test.shutdown().await;
 }
@@ -21,11 +50,10 @@ async fn request_with_no_backends() {
     let mut proxy_test = ProxyTest::new().await;
     let _backends_test = proxy_test.add_service_passthrough("package.Service");
 
-    // No backends available returns bad gateway (502)
-    let (head, _body) = grpc_request(&proxy_test.addr(), "package.Service", "Method", b"")
-        .await
-        .into_parts();
-    assert!(head.status == StatusCode::BAD_GATEWAY);
+    // No backends available returns UNAVAILABLE (14)
+    let response = grpc_request(&proxy_test.addr(), "package.Service", "Method", b"").await;
+    assert!(response.status().is_success());
+    assert_eq!(grpc_status(response).await.as_deref(), Some("14"));
 
     proxy_test.shutdown().await;
 }
@@ -35,7 +63,7 @@ async fn request_with_passthrough_backend() {
     let mut mock_server = MockServer::new().await;
     mock_server.expect("package.Service", "Method", |_parts, body| {
         assert!(&*body == b"\0\0\0\0\0");
-        (bytes::Bytes::from_static(b"\0\0\0\0\0"), HeaderMap::new())
+        (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
     });
 
     let mut proxy_test = ProxyTest::new().await;
@@ -44,10 +72,9 @@ async fn request_with_passthrough_backend() {
         .set_single_backend_addr(mock_server.addr)
         .await;
 
-    let (head, _body) = grpc_request(&proxy_test.addr(), "package.Service", "Method", b"")
-        .await
-        .into_parts();
-    assert!(head.status.is_success());
+    let response = grpc_request(&proxy_test.addr(), "package.Service", "Method", b"").await;
+    assert!(response.status().is_success());
+    assert_eq!(grpc_status(response).await.as_deref(), Some("0"));
 
     proxy_test.shutdown().await;
     mock_server.finish();
@@ -55,17 +82,925 @@ async fn request_with_passthrough_backend() {
 
 #[tokio::test]
 async fn request_with_service_full_backend() {
-    env_logger::builder()
+    let _ = env_logger::builder()
         .filter_level(log::LevelFilter::Info)
-        .init();
+        .try_init();
 
     let mut mock_server = MockServer::new().await;
     mock_server.expect("example.TestService", "GetData", |_parts, body| {
         assert!(&*body == b"\0\0\0\0\0");
-        (bytes::Bytes::from_static(b"\0\0\0\0\0"), HeaderMap::new())
+        (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+    });
+
+    let mut proxy_test = ProxyTest::new().await;
+    let mut backends_test = proxy_test
+        .add_service("example.TestService", "tests/data/proto_descriptors.binpb")
+        .await;
+    backends_test
+        .set_single_backend_addr(mock_server.addr)
+        .await;
+
+    let response = grpc_request(&proxy_test.addr(), "example.TestService", "GetData", b"").await;
+    assert!(response.status().is_success());
+    assert_eq!(grpc_status(response).await.as_deref(), Some("0"));
+
+    proxy_test.shutdown().await;
+    mock_server.finish();
+}
+
+#[tokio::test]
+async fn request_with_outlier_ejection() {
+    let mut failing_server = MockServer::new().await;
+    failing_server.expect("package.Service", "Method", |_parts, _body| {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "14".parse().unwrap());
+        (bytes::Bytes::new(), trailers)
+    });
+    let mut mock_server = MockServer::new().await;
+    for _ in 0..3 {
+        mock_server.expect("package.Service", "Method", |_parts, _body| {
+            (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+        });
+    }
+
+    let mut proxy_test = ProxyTest::new().await;
+    let mut backends_test = proxy_test.add_service_passthrough("package.Service");
+    backends_test
+        .set_backend_addrs(&[failing_server.addr, mock_server.addr])
+        .await;
+    proxy_test.update_service("package.Service", |service| {
+        let config = OutlierDetection {
+            consecutive_failures: 1,
+            base_ejection_seconds: 60,
+            max_ejection_seconds: 60,
+            max_ejection_percent: 50,
+        };
+        service.outlier_detector = Some(Arc::new(OutlierDetector::new(
+            "package.Service".into(),
+            config,
+        )));
+    });
+
+    // The failing backend is ejected after its first response, and the
+    // remaining requests go to the other one.
+    let mut statuses = Vec::new();
+    for _ in 0..4 {
+        let response = grpc_request(&proxy_test.addr(), "package.Service", "Method", b"").await;
+        statuses.push(grpc_status(response).await.unwrap());
+    }
+    statuses.sort();
+    assert_eq!(statuses, ["0", "0", "0", "14"]);
+
+    proxy_test.shutdown().await;
+    failing_server.finish();
+    mock_server.finish();
+}
+
+#[tokio::test]
+async fn request_with_retries() {
+    let mut failing_server = MockServer::new().await;
+    let mut mock_server = MockServer::new().await;
+    for _ in 0..3 {
+        failing_server.expect_status("package.Service", "Method", "14");
+        mock_server.expect("package.Service", "Method", |_parts, body| {
+            assert_eq!(&*body, b"\0\0\0\0\x01x");
+            (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+        });
+    }
+
+    let mut proxy_test = ProxyTest::new().await;
+    let mut backends_test = proxy_test.add_service_passthrough("package.Service");
+    backends_test
+        .set_backend_addrs(&[failing_server.addr, mock_server.addr])
+        .await;
+    let policy = RetryPolicy {
+        max_attempts: 2,
+        initial_backoff_millis: 1,
+        ..Default::default()
+    };
+
+    let send_requests = async || {
+        let mut statuses = Vec::new();
+        for _ in 0..2 {
+            let response =
+                grpc_request(&proxy_test.addr(), "package.Service", "Method", b"x").await;
+            statuses.push(grpc_status(response).await.unwrap());
+        }
+        statuses.sort();
+        statuses
+    };
+
+    // Methods without descriptors are not known to be idempotent.
+    let set_retry_policy = |spec: &RetryPolicy| {
+        proxy_test.update_service("package.Service", |service| {
+            service.retry_policy = Some(Arc::new(retry::RetryPolicy::from_spec(spec)));
+        });
+    };
+    set_retry_policy(&policy);
+    assert_eq!(send_requests().await, ["0", "14"]);
+
+    set_retry_policy(&RetryPolicy {
+        retry_non_idempotent: true,
+        ..policy
+    });
+    assert_eq!(send_requests().await, ["0", "0"]);
+
+    proxy_test.shutdown().await;
+    failing_server.finish();
+    mock_server.finish();
+}
+
+#[tokio::test]
+async fn request_with_deadline() {
+    let mut mock_server = MockServer::new().await;
+    mock_server.expect("package.Service", "Method", |parts, _body| {
+        // Capped by the service, less the time spent in the proxy.
+        let timeout = parts.headers["grpc-timeout"].as_bytes();
+        let timeout = parse_grpc_timeout(timeout).unwrap();
+        assert!(timeout <= Duration::from_secs(5));
+        (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+    });
+
+    let mut proxy_test = ProxyTest::new().await;
+    let mut backends_test = proxy_test.add_service_passthrough("package.Service");
+    backends_test.set_backend_addrs(&[mock_server.addr]).await;
+    proxy_test.update_service("package.Service", |service| {
+        service.max_timeout = Some(Duration::from_secs(5));
+    });
+
+    let response = grpc_request_with_headers(
+        &proxy_test.addr(),
+        "package.Service",
+        "Method",
+        &[("grpc-timeout", "60S")],
+        b"",
+    )
+    .await;
+    assert_eq!(grpc_status(response).await.as_deref(), Some("0"));
+
+    // The deadline passes before reaching the upstream, DEADLINE_EXCEEDED
+    // (4) is returned without calling it.
+    let response = grpc_request_with_headers(
+        &proxy_test.addr(),
+        "package.Service",
+        "Method",
+        &[("grpc-timeout", "1n")],
+        b"",
+    )
+    .await;
+    assert_eq!(grpc_status(response).await.as_deref(), Some("4"));
+
+    proxy_test.shutdown().await;
+    mock_server.finish();
+}
+
+#[tokio::test]
+async fn request_with_hedging() {
+    let mut slow_server = MockServer::new().await;
+    let mut fast_server = MockServer::new().await;
+    for _ in 0..2 {
+        slow_server.expect_delayed(
+            "example.TestService",
+            "GetDataHedged",
+            Duration::from_secs(2),
+            |_parts, _body| (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers()),
+        );
+        fast_server.expect("example.TestService", "GetDataHedged", |_parts, _body| {
+            (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+        });
+    }
+
+    let mut proxy_test = ProxyTest::new().await;
+    let mut backends_test = proxy_test
+        .add_service("example.TestService", "tests/data/proto_descriptors.binpb")
+        .await;
+    backends_test
+        .set_backend_addrs(&[slow_server.addr, fast_server.addr])
+        .await;
+
+    // Round robin sends one of the requests to the slow server first,
+    // which is hedged to the fast server after 50ms.
+    for message in [&b"\x0a\x01a"[..], b"\x0a\x01b"] {
+        let start = std::time::Instant::now();
+        let response = grpc_request(
+            &proxy_test.addr(),
+            "example.TestService",
+            "GetDataHedged",
+            message,
+        )
+        .await;
+        assert_eq!(grpc_status(response).await.as_deref(), Some("0"));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    proxy_test.shutdown().await;
+    slow_server.finish();
+    fast_server.finish();
+}
+
+#[tokio::test]
+async fn request_with_hedging_large_response() {
+    // Above the hedging buffer, the response is streamed.
+    let mut message = vec![0; 2 * 1024 * 1024];
+    message[..5].copy_from_slice(&[0, 0, 0x20, 0, 0]);
+    let mut mock_server = MockServer::new().await;
+    mock_server.expect("example.TestService", "GetDataHedged", {
+        let message = bytes::Bytes::from(message.clone());
+        move |_parts, _body| (message, ok_trailers())
+    });
+
+    let mut proxy_test = ProxyTest::new().await;
+    let mut backends_test = proxy_test
+        .add_service("example.TestService", "tests/data/proto_descriptors.binpb")
+        .await;
+    backends_test
+        .set_single_backend_addr(mock_server.addr)
+        .await;
+
+    let response = grpc_request(
+        &proxy_test.addr(),
+        "example.TestService",
+        "GetDataHedged",
+        b"\x0a\x01a",
+    )
+    .await;
+    let mut body = response.into_body();
+    let mut received = Vec::new();
+    while let Some(data) = body.data().await {
+        let data = data.unwrap();
+        body.flow_control().release_capacity(data.len()).unwrap();
+        received.extend_from_slice(&data);
+    }
+    assert!(received == message);
+    let trailers = body.trailers().await.unwrap().unwrap();
+    assert_eq!(trailers["grpc-status"], "0");
+
+    proxy_test.shutdown().await;
+    mock_server.finish();
+}
+
+#[tokio::test]
+async fn request_with_concurrency_limit() {
+    let mut mock_server = MockServer::new().await;
+    mock_server.expect_delayed(
+        "package.Service",
+        "Method",
+        Duration::from_millis(500),
+        |_parts, _body| (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers()),
+    );
+    mock_server.expect("package.Service", "Method", |_parts, _body| {
+        (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+    });
+
+    let mut proxy_test = ProxyTest::new().await;
+    let mut backends_test = proxy_test.add_service_passthrough("package.Service");
+    backends_test.set_backend_addrs(&[mock_server.addr]).await;
+    proxy_test.update_service("package.Service", |service| {
+        let spec = ConcurrencyLimit {
+            max_in_flight: Some(1),
+            ..Default::default()
+        };
+        service.concurrency_limiter = Some(Arc::new(ConcurrencyLimiter::new(&spec).unwrap()));
+    });
+
+    // The second request is shed with RESOURCE_EXHAUSTED (8) while the
+    // first one is in flight.
+    let addr = proxy_test.addr();
+    let slow_request = async {
+        let response = grpc_request(&addr, "package.Service", "Method", b"").await;
+        grpc_status(response).await.unwrap()
+    };
+    let shed_request = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let response = grpc_request(&addr, "package.Service", "Method", b"").await;
+        grpc_status(response).await.unwrap()
+    };
+    assert_eq!(
+        tokio::join!(slow_request, shed_request),
+        ("0".to_owned(), "8".to_owned())
+    );
+
+    // Finished requests release their permit.
+    let response = grpc_request(&addr, "package.Service", "Method", b"").await;
+    assert_eq!(grpc_status(response).await.as_deref(), Some("0"));
+
+    proxy_test.shutdown().await;
+    mock_server.finish();
+}
+
+#[tokio::test]
+async fn request_with_rate_limit() {
+    let mut mock_server = MockServer::new().await;
+    for _ in 0..2 {
+        mock_server.expect("package.Service", "Method", |_parts, _body| {
+            (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+        });
+    }
+
+    let mut proxy_test = ProxyTest::new().await;
+    let mut backends_test = proxy_test.add_service_passthrough("package.Service");
+    backends_test.set_backend_addrs(&[mock_server.addr]).await;
+    proxy_test.update_service("package.Service", |service| {
+        let specs = [RateLimit {
+            methods: Vec::new(),
+            key: Some(RateLimitKey::Header("x-api-key".into())),
+            requests_per_second: 1,
+            burst: Some(1),
+            fleet_wide: false,
+        }];
+        let limiter = RateLimiter::build(
+            &specs,
+            service.service_spec.as_ref().unwrap(),
+            |index, error| panic!("invalid rate limit {}: {:#}", index, error),
+        );
+        service.rate_limiter = Some(Arc::new(limiter));
+    });
+
+    let addr = proxy_test.addr();
+    let request = |key| async move {
+        grpc_request_with_headers(
+            &addr,
+            "package.Service",
+            "Method",
+            &[("x-api-key", key)],
+            b"",
+        )
+        .await
+    };
+    let response = request("a").await;
+    assert_eq!(grpc_status(response).await.as_deref(), Some("0"));
+
+    // The second request with the same key is over the limit, and
+    // rejected with RESOURCE_EXHAUSTED (8) and a retry-after.
+    let response = request("a").await;
+    assert_eq!(response.headers()["retry-after"], "1");
+    assert!(response.headers().contains_key("grpc-retry-pushback-ms"));
+    assert_eq!(grpc_status(response).await.as_deref(), Some("8"));
+
+    // Other keys have their own bucket.
+    let response = request("b").await;
+    assert_eq!(grpc_status(response).await.as_deref(), Some("0"));
+
+    proxy_test.shutdown().await;
+    mock_server.finish();
+}
+
+#[tokio::test]
+async fn request_with_upstream_name() {
+    let mut main_server = MockServer::new().await;
+    main_server.expect("package.Service", "Method", |_parts, _body| {
+        (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+    });
+    let mut canary_server = MockServer::new().await;
+    let canary_requests = Arc::new(AtomicUsize::new(0));
+    for _ in 0..2 {
+        let canary_requests = canary_requests.clone();
+        canary_server.expect("package.Service", "Method", move |parts, _body| {
+            // The upstream header is not passed on.
+            assert!(!parts.headers.contains_key("grcache-upstream"));
+            canary_requests.fetch_add(1, Ordering::Relaxed);
+            (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+        });
+    }
+
+    let mut proxy_test = ProxyTest::new().await;
+    let mut main_backends = proxy_test.add_service_passthrough("package.Service");
+    main_backends.set_backend_addrs(&[main_server.addr]).await;
+    let mut canary_backends = proxy_test
+        .add_upstream_passthrough(ServiceKey::new("package.Service", Some("canary".into())));
+    canary_backends
+        .set_backend_addrs(&[canary_server.addr])
+        .await;
+
+    let addr = proxy_test.addr();
+    let request = |upstream: Option<&'static str>| async move {
+        let headers: Vec<_> = upstream
+            .map(|name| ("grcache-upstream", name))
+            .into_iter()
+            .collect();
+        let response =
+            grpc_request_with_headers(&addr, "package.Service", "Method", &headers, b"").await;
+        grpc_status(response).await.unwrap()
+    };
+
+    // The upstream header selects the upstream, requests without it
+    // go to the upstream without a name.
+    assert_eq!(request(Some("canary")).await, "0");
+    assert_eq!(canary_requests.load(Ordering::Relaxed), 1);
+    assert_eq!(request(None).await, "0");
+    // Unknown upstream names are UNIMPLEMENTED (12).
+    assert_eq!(request(Some("other")).await, "12");
+
+    // Traffic splits send requests without the header to the canary.
+    proxy_test.set_traffic_splits("package.Service", &[("canary", 100)]);
+    assert_eq!(request(None).await, "0");
+    assert_eq!(canary_requests.load(Ordering::Relaxed), 2);
+
+    proxy_test.shutdown().await;
+    main_server.finish();
+    canary_server.finish();
+}
+
+#[tokio::test]
+async fn request_with_routing_rules() {
+    let mut main_server = MockServer::new().await;
+    main_server.expect("example.TestService", "GetData", |_parts, _body| {
+        (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+    });
+    let mut beta_server = MockServer::new().await;
+    for _ in 0..5 {
+        beta_server.expect("example.TestService", "GetData", |_parts, _body| {
+            (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+        });
+    }
+    // Never reached, rules come before the upstream header.
+    let canary_server = MockServer::new().await;
+
+    let mut proxy_test = ProxyTest::new().await;
+    let mut main_backends = proxy_test
+        .add_service("example.TestService", "tests/data/proto_descriptors.binpb")
+        .await;
+    main_backends.set_backend_addrs(&[main_server.addr]).await;
+    let mut beta_backends = proxy_test
+        .add_upstream_passthrough(ServiceKey::new("example.TestService", Some("beta".into())));
+    beta_backends.set_backend_addrs(&[beta_server.addr]).await;
+    let mut canary_backends = proxy_test.add_upstream_passthrough(ServiceKey::new(
+        "example.TestService",
+        Some("canary".into()),
+    ));
+    canary_backends
+        .set_backend_addrs(&[canary_server.addr])
+        .await;
+    proxy_test.update_service("example.TestService", |service| {
+        let specs = [
+            RoutingRule {
+                name: Some("beta-ids".into()),
+                methods: vec!["GetData".into()],
+                headers: Vec::new(),
+                fields: vec![FieldMatch {
+                    field: "id".into(),
+                    value: ValueMatch::Prefix("beta-".into()),
+                }],
+                upstream_name: "beta".into(),
+            },
+            RoutingRule {
+                name: Some("beta-header".into()),
+                methods: Vec::new(),
+                headers: vec![HeaderMatch {
+                    name: "x-beta".into(),
+                    value: None,
+                }],
+                fields: Vec::new(),
+                upstream_name: "beta".into(),
+            },
+        ];
+        let rules = RoutingRules::build(
+            &specs,
+            service.service_spec.as_ref().unwrap(),
+            |index, error| panic!("invalid routing rule {}: {:#}", index, error),
+        );
+        service.routing_rules = Some(Arc::new(rules));
+    });
+
+    // The cache is bypassed so that every request reaches an upstream.
+    let addr = proxy_test.addr();
+    let request = |headers: &'static [(&'static str, &'static str)], message: &'static [u8]| async move {
+        let headers = [&[("x-method-bypass", "1")], headers].concat();
+        let response =
+            grpc_request_with_headers(&addr, "example.TestService", "GetData", &headers, message)
+                .await;
+        grpc_status(response).await.unwrap()
+    };
+    // `id: "beta-1"`
+    assert_eq!(request(&[], b"\x0a\x06beta-1").await, "0");
+    assert_eq!(request(&[("x-beta", "1")], b"\x0a\x01a").await, "0");
+    assert_eq!(
+        request(
+            &[("x-beta", "1"), ("grcache-upstream", "canary")],
+            b"\x0a\x01a"
+        )
+        .await,
+        "0"
+    );
+    // Matching no rule, the request goes to the upstream without a name.
+    assert_eq!(request(&[], b"\x0a\x01a").await, "0");
+    // Unknown upstream names only fail requests matching no rule.
+    assert_eq!(
+        request(
+            &[("x-beta", "1"), ("grcache-upstream", "unknown")],
+            b"\x0a\x01a"
+        )
+        .await,
+        "0"
+    );
+    assert_eq!(
+        request(&[("grcache-upstream", "unknown")], b"\x0a\x01a").await,
+        "12"
+    );
+
+    // JSON is routed with the descriptors of the upstream without a
+    // name, and transcoded for an upstream without them.
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://{}/example.TestService/GetData",
+            proxy_test.addr()
+        ))
+        .header("content-type", "application/json")
+        .header("x-method-bypass", "1")
+        .body(r#"{"id": "beta-2"}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+    assert_eq!(body, serde_json::json!({}));
+
+    proxy_test.shutdown().await;
+    main_server.finish();
+    beta_server.finish();
+    canary_server.finish();
+}
+
+#[tokio::test]
+async fn request_with_field_hash_load_balancing() {
+    let mut mock_servers = Vec::new();
+    let mut counts = Vec::new();
+    for _ in 0..2 {
+        let mut mock_server = MockServer::new().await;
+        let count = Arc::new(AtomicUsize::new(0));
+        for _ in 0..4 {
+            let count = count.clone();
+            mock_server.expect("example.TestService", "GetData", move |_parts, _body| {
+                count.fetch_add(1, Ordering::Relaxed);
+                (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+            });
+        }
+        mock_servers.push(mock_server);
+        counts.push(count);
+    }
+
+    let mut proxy_test = ProxyTest::new().await;
+    let mut backends_test = proxy_test
+        .add_service("example.TestService", "tests/data/proto_descriptors.binpb")
+        .await;
+    backends_test
+        .set_backend_addrs(&[mock_servers[0].addr, mock_servers[1].addr])
+        .await;
+    proxy_test.update_service("example.TestService", |service| {
+        let spec = LoadBalancing {
+            policy: LoadBalancingPolicy::Maglev,
+            hash_on: Some(HashOn::Field("id".into())),
+        };
+        service.balancer = Arc::new(Balancer::new(&spec));
+    });
+
+    // Requests for the same `id` go to the same upstream. The cache is
+    // bypassed so that they all reach it.
+    for _ in 0..4 {
+        let response = grpc_request_with_headers(
+            &proxy_test.addr(),
+            "example.TestService",
+            "GetData",
+            &[("x-method-bypass", "1")],
+            // `id: "abc"`
+            b"\x0a\x03abc",
+        )
+        .await;
+        assert_eq!(grpc_status(response).await.as_deref(), Some("0"));
+    }
+    let mut counts: Vec<_> = counts.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+    counts.sort();
+    assert_eq!(counts, [0, 4]);
+
+    proxy_test.shutdown().await;
+    for mock_server in mock_servers {
+        mock_server.finish();
+    }
+}
+
+#[tokio::test]
+async fn cached_request_propagation_headers() {
+    let mut mock_server = MockServer::new().await;
+    mock_server.expect("example.TestService", "GetData", |parts, _body| {
+        let headers = &parts.headers;
+        // From the config file, service and method options.
+        assert!(headers["x-global-header"] == "global");
+        assert!(headers["x-service-header"] == "service");
+        assert!(headers["x-method-header"] == "method");
+        // Vary headers are part of the cache key and kept.
+        assert!(headers["x-vary-header"] == "vary");
+        // Anything else could leak into the cache.
+        assert!(!headers.contains_key("x-other-header"));
+        (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+    });
+
+    let mut proxy_test = ProxyTest::new().await;
+    let mut backends_test = proxy_test
+        .add_service("example.TestService", "tests/data/proto_descriptors.binpb")
+        .await;
+    backends_test
+        .set_single_backend_addr(mock_server.addr)
+        .await;
+    proxy_test.update_service("example.TestService", |service| {
+        service.header_policy = Arc::new(HeaderPolicy {
+            propagation: ["x-service-header".into()].into(),
+            ..Default::default()
+        });
+    });
+    proxy_test.update_config(|config| {
+        config
+            .proxy
+            .propagation_headers
+            .insert("X-Global-Header".into());
+    });
+
+    let response = grpc_request_with_headers(
+        &proxy_test.addr(),
+        "example.TestService",
+        "GetData",
+        &[
+            ("x-global-header", "global"),
+            ("x-service-header", "service"),
+            ("x-method-header", "method"),
+            ("vary", "x-vary-header"),
+            ("x-vary-header", "vary"),
+            ("x-other-header", "other"),
+        ],
+        b"",
+    )
+    .await;
+    assert!(response.status().is_success());
+    assert_eq!(grpc_status(response).await.as_deref(), Some("0"));
+
+    proxy_test.shutdown().await;
+    mock_server.finish();
+}
+
+#[tokio::test]
+async fn cached_request_declared_vary_headers() {
+    let mut mock_server = MockServer::new().await;
+    mock_server.expect("example.TestService", "GetData", |parts, _body| {
+        let headers = &parts.headers;
+        // Declared on the service and method, without a client `vary`.
+        assert!(headers["x-service-vary"] == "service");
+        assert!(headers["x-method-vary"] == "method");
+        assert!(!headers.contains_key("x-other-header"));
+        (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+    });
+
+    let mut proxy_test = ProxyTest::new().await;
+    let mut backends_test = proxy_test
+        .add_service("example.TestService", "tests/data/proto_descriptors.binpb")
+        .await;
+    backends_test
+        .set_single_backend_addr(mock_server.addr)
+        .await;
+    proxy_test.update_service("example.TestService", |service| {
+        service.header_policy = Arc::new(HeaderPolicy {
+            vary: ["x-service-vary".into()].into(),
+            ..Default::default()
+        });
+    });
+
+    let response = grpc_request_with_headers(
+        &proxy_test.addr(),
+        "example.TestService",
+        "GetData",
+        &[
+            ("x-service-vary", "service"),
+            ("x-method-vary", "method"),
+            ("x-other-header", "other"),
+        ],
+        b"",
+    )
+    .await;
+    assert!(response.status().is_success());
+    assert_eq!(grpc_status(response).await.as_deref(), Some("0"));
+
+    proxy_test.shutdown().await;
+    mock_server.finish();
+}
+
+#[tokio::test]
+async fn cached_request_vary_values_partition_cache() {
+    let mut mock_server = MockServer::new().await;
+    for expected in ["a", "b"] {
+        mock_server.expect("example.TestService", "GetData", move |parts, _body| {
+            assert!(parts.headers["x-service-vary"] == expected);
+            (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+        });
+    }
+
+    let mut proxy_test = ProxyTest::with_memory_cache().await;
+    let mut backends_test = proxy_test
+        .add_service("example.TestService", "tests/data/proto_descriptors.binpb")
+        .await;
+    backends_test
+        .set_single_backend_addr(mock_server.addr)
+        .await;
+    proxy_test.update_service("example.TestService", |service| {
+        service.header_policy = Arc::new(HeaderPolicy {
+            vary: ["x-service-vary".into()].into(),
+            ..Default::default()
+        });
+    });
+
+    // Each vary value gets its own entry, the repeated one is a hit.
+    for value in ["a", "b", "a"] {
+        let response = grpc_request_with_headers(
+            &proxy_test.addr(),
+            "example.TestService",
+            "GetData",
+            &[("x-service-vary", value)],
+            b"",
+        )
+        .await;
+        assert!(response.status().is_success());
+        assert_eq!(grpc_status(response).await.as_deref(), Some("0"));
+    }
+    assert_eq!(proxy_test.storage().writes(), 2);
+    assert_eq!(proxy_test.storage().hits(), 1);
+
+    proxy_test.shutdown().await;
+    mock_server.finish();
+}
+
+#[tokio::test]
+async fn cached_request_bypass_headers() {
+    let mut mock_server = MockServer::new().await;
+    for _ in 0..2 {
+        mock_server.expect("example.TestService", "GetData", |parts, _body| {
+            // Not cached, so nothing is stripped.
+            assert!(parts.headers["x-other-header"] == "other");
+            (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+        });
+    }
+
+    let mut proxy_test = ProxyTest::new().await;
+    let mut backends_test = proxy_test
+        .add_service("example.TestService", "tests/data/proto_descriptors.binpb")
+        .await;
+    backends_test
+        .set_single_backend_addr(mock_server.addr)
+        .await;
+    proxy_test.update_config(|config| {
+        config
+            .proxy
+            .bypass_cache_headers
+            .insert("authorization".into());
+    });
+
+    for bypass_header in ["authorization", "x-method-bypass"] {
+        let response = grpc_request_with_headers(
+            &proxy_test.addr(),
+            "example.TestService",
+            "GetData",
+            &[(bypass_header, "secret"), ("x-other-header", "other")],
+            b"",
+        )
+        .await;
+        assert!(response.status().is_success());
+        assert_eq!(grpc_status(response).await.as_deref(), Some("0"));
+    }
+
+    proxy_test.shutdown().await;
+    mock_server.finish();
+}
+
+#[tokio::test]
+async fn cached_request_tenant_partitioning() {
+    let mut mock_server = MockServer::new().await;
+    for expected in ["tenant-a", "tenant-b"] {
+        mock_server.expect("example.TestService", "GetData", move |parts, _body| {
+            // The tenant header reaches the upstream, other headers don't.
+            assert!(parts.headers["x-tenant"] == expected);
+            assert!(!parts.headers.contains_key("x-other-header"));
+            (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+        });
+    }
+    mock_server.expect("example.TestService", "GetData", |parts, _body| {
+        // Without a tenant the cache is bypassed, nothing is stripped.
+        assert!(parts.headers["x-other-header"] == "other");
+        (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
+    });
+
+    let mut proxy_test = ProxyTest::with_tenant(&TenantConfig {
+        sources: vec![TenantSource::Header {
+            name: "x-tenant".into(),
+        }],
+        required: true,
+    })
+    .await;
+    let mut backends_test = proxy_test
+        .add_service("example.TestService", "tests/data/proto_descriptors.binpb")
+        .await;
+    backends_test
+        .set_single_backend_addr(mock_server.addr)
+        .await;
+
+    // The same request from another tenant is a miss, from the same
+    // tenant a hit.
+    let requests: [&[(&str, &str)]; 4] = [
+        &[("x-tenant", "tenant-a"), ("x-other-header", "other")],
+        &[("x-tenant", "tenant-b"), ("x-other-header", "other")],
+        &[("x-tenant", "tenant-a"), ("x-other-header", "other")],
+        &[("x-other-header", "other")],
+    ];
+    for headers in requests {
+        let response = grpc_request_with_headers(
+            &proxy_test.addr(),
+            "example.TestService",
+            "GetData",
+            headers,
+            b"",
+        )
+        .await;
+        assert!(response.status().is_success());
+        assert_eq!(grpc_status(response).await.as_deref(), Some("0"));
+    }
+    assert_eq!(proxy_test.storage().writes(), 2);
+    assert_eq!(proxy_test.storage().hits(), 1);
+
+    proxy_test.shutdown().await;
+    mock_server.finish();
+}
+
+async fn grpc_web_passthrough_request(
+    content_type: &str,
+    body: &'static [u8],
+) -> (String, Vec<u8>) {
+    let mut mock_server = MockServer::new().await;
+    mock_server.expect("package.Service", "Method", |parts, body| {
+        assert!(parts.headers["content-type"] == "application/grpc");
+        assert!(&*body == b"\0\0\0\0\0");
+        (bytes::Bytes::from_static(b"\0\0\0\0\0"), ok_trailers())
     });
 
     let mut proxy_test = ProxyTest::new().await;
+    let mut backends_test = proxy_test.add_service_passthrough("package.Service");
+    backends_test
+        .set_single_backend_addr(mock_server.addr)
+        .await;
+
+    // gRPC-Web clients speak HTTP/1.1
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://{}/package.Service/Method",
+            proxy_test.addr()
+        ))
+        .header("content-type", content_type)
+        .body(body)
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_success());
+    let response_content_type = response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+    let response_body = response.bytes().await.unwrap().to_vec();
+
+    proxy_test.shutdown().await;
+    mock_server.finish();
+
+    (response_content_type, response_body)
+}
+
+#[tokio::test]
+async fn grpc_web_request_with_passthrough_backend() {
+    let (content_type, body) =
+        grpc_web_passthrough_request("application/grpc-web+proto", b"\0\0\0\0\0").await;
+
+    assert_eq!(content_type, "application/grpc-web+proto");
+    // Message followed by the in-body trailers frame.
+    assert_eq!(body, b"\0\0\0\0\0\x80\0\0\0\x0fgrpc-status:0\r\n");
+}
+
+#[tokio::test]
+async fn grpc_web_text_request_with_passthrough_backend() {
+    let (content_type, body) =
+        grpc_web_passthrough_request("application/grpc-web-text", b"AAAAAAA=").await;
+
+    assert_eq!(content_type, "application/grpc-web-text+proto");
+    // base64 of the message followed by the in-body trailers frame.
+    assert_eq!(body, b"AAAAAACAAAAAD2dycGMtc3RhdHVzOjANCg==");
+}
+
+#[tokio::test]
+async fn grpc_web_cached_request() {
+    let mut mock_server = MockServer::new().await;
+    mock_server.expect("example.TestService", "GetData", |parts, body| {
+        assert!(parts.headers["content-type"] == "application/grpc");
+        // GetDataRequest { id: "a" }
+        assert!(&*body == b"\0\0\0\0\x03\x0a\x01a");
+        // GetDataResponse { data: "xyz" }
+        (
+            bytes::Bytes::from_static(b"\0\0\0\0\x05\x0a\x03xyz"),
+            ok_trailers(),
+        )
+    });
+
+    let mut proxy_test = ProxyTest::with_memory_cache().await;
     let mut backends_test = proxy_test
         .add_service("example.TestService", "tests/data/proto_descriptors.binpb")
         .await;
@@ -73,14 +1008,164 @@ async fn request_with_service_full_backend() {
         .set_single_backend_addr(mock_server.addr)
         .await;
 
-    let (head, _body) = grpc_request(&proxy_test.addr(), "example.TestService", "GetData", b"")
+    let addr = proxy_test.addr();
+    let request = |content_type: &'static str, body: &'static [u8]| async move {
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/example.TestService/GetData", addr))
+            .header("content-type", content_type)
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        response.bytes().await.unwrap().to_vec()
+    };
+
+    // Message followed by the in-body trailers frame, also when served
+    // from cache.
+    let expected = b"\0\0\0\0\x05\x0a\x03xyz\x80\0\0\0\x0fgrpc-status:0\r\n";
+    for _ in 0..2 {
+        let body = request("application/grpc-web+proto", b"\0\0\0\0\x03\x0a\x01a").await;
+        assert_eq!(body, expected);
+    }
+    // gRPC-Web-text shares the entry, the trailers frame is encoded
+    // with the message.
+    for _ in 0..2 {
+        let body = request("application/grpc-web-text", b"AAAAAAMKAWE=").await;
+        assert_eq!(BASE64_STANDARD.decode(body).unwrap(), expected);
+    }
+    assert_eq!(proxy_test.storage().writes(), 1);
+    assert_eq!(proxy_test.storage().hits(), 3);
+
+    proxy_test.shutdown().await;
+    mock_server.finish();
+}
+
+#[tokio::test]
+async fn json_request_with_service_full_backend() {
+    let mut mock_server = MockServer::new().await;
+    mock_server.expect("example.TestService", "GetData", |parts, body| {
+        assert!(parts.headers["content-type"] == "application/grpc");
+        // GetDataRequest { id: "abc" }
+        assert!(&*body == b"\0\0\0\0\x05\x0a\x03abc");
+        // GetDataResponse { data: "xyz" }
+        (
+            bytes::Bytes::from_static(b"\0\0\0\0\x05\x0a\x03xyz"),
+            ok_trailers(),
+        )
+    });
+
+    let mut proxy_test = ProxyTest::new().await;
+    let mut backends_test = proxy_test
+        .add_service("example.TestService", "tests/data/proto_descriptors.binpb")
+        .await;
+    backends_test
+        .set_single_backend_addr(mock_server.addr)
+        .await;
+
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://{}/example.TestService/GetData",
+            proxy_test.addr()
+        ))
+        .header("content-type", "application/json")
+        .body(r#"{"id": "abc"}"#)
+        .send()
         .await
-        .into_parts();
-    println!("{:?}", head);
-    assert!(head.status.is_success());
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    let body: serde_json::Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+    assert_eq!(body, serde_json::json!({ "data": "xyz" }));
+
+    proxy_test.shutdown().await;
+    mock_server.finish();
+}
+
+#[tokio::test]
+async fn json_cached_request() {
+    let mut mock_server = MockServer::new().await;
+    mock_server.expect("example.TestService", "GetData", |_parts, body| {
+        // GetDataRequest { id: "abc" }
+        assert!(&*body == b"\0\0\0\0\x05\x0a\x03abc");
+        // GetDataResponse { data: "xyz" }
+        (
+            bytes::Bytes::from_static(b"\0\0\0\0\x05\x0a\x03xyz"),
+            ok_trailers(),
+        )
+    });
+
+    let mut proxy_test = ProxyTest::with_memory_cache().await;
+    let mut backends_test = proxy_test
+        .add_service("example.TestService", "tests/data/proto_descriptors.binpb")
+        .await;
+    backends_test
+        .set_single_backend_addr(mock_server.addr)
+        .await;
+
+    // The second request is a hit, transcoded to JSON.
+    for _ in 0..2 {
+        let response = reqwest::Client::new()
+            .post(format!(
+                "http://{}/example.TestService/GetData",
+                proxy_test.addr()
+            ))
+            .header("content-type", "application/json")
+            .body(r#"{"id": "abc"}"#)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        let body: serde_json::Value =
+            serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(body, serde_json::json!({ "data": "xyz" }));
+    }
+
+    // Native clients get the same entry in its gRPC encoding.
+    let response = grpc_request(
+        &proxy_test.addr(),
+        "example.TestService",
+        "GetData",
+        b"\x0a\x03abc",
+    )
+    .await;
+    let mut body = response.into_body();
+    let mut received = Vec::new();
+    while let Some(data) = body.data().await {
+        received.extend_from_slice(&data.unwrap());
+    }
+    assert_eq!(received, b"\0\0\0\0\x05\x0a\x03xyz");
+    let trailers = body.trailers().await.unwrap().unwrap();
+    assert_eq!(trailers["grpc-status"], "0");
+
+    assert_eq!(proxy_test.storage().writes(), 1);
+    assert_eq!(proxy_test.storage().hits(), 2);
 
     proxy_test.shutdown().await;
     mock_server.finish();
+}
+
+#[tokio::test]
+async fn json_request_without_descriptors() {
+    let mut proxy_test = ProxyTest::new().await;
+    let _backends_test = proxy_test.add_service_passthrough("package.Service");
 
-    todo!();
+    // Transcoding needs descriptors, returns UNIMPLEMENTED (12) as
+    // HTTP 501.
+    let response = reqwest::Client::new()
+        .post(format!(
+            "http://{}/package.Service/Method",
+            proxy_test.addr()
+        ))
+        .header("content-type", "application/json")
+        .body("{}")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 501);
+    let body: serde_json::Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+    assert_eq!(body["code"], 12);
+
+    proxy_test.shutdown().await;
 }
@@ -1,5 +1,140 @@
+use std::{collections::BTreeSet, sync::Arc};
+
+use serde_json::Value;
 use tokio::sync::watch;
 
+use super::ConfigFile;
+
+/// Live view of the config file. Updated whenever the config file is
+/// reloaded with only reloadable changes.
 pub type ConfigContext = watch::Receiver<ConfigContextData>;
 
-pub struct ConfigContextData {}
+#[derive(Debug, Clone)]
+pub struct ConfigContextData {
+    pub config: Arc<ConfigFile>,
+}
+
+impl ConfigContextData {
+    pub fn new(config: ConfigFile) -> Self {
+        ConfigContextData {
+            config: Arc::new(config),
+        }
+    }
+}
+
+/// JSON pointers of the settings which are applied without a restart.
+/// Any other change requires a restart.
+const RELOADABLE: &[&str] = &[
+    "/logLevel",
+    "/proxy/propagationHeaders",
+    "/proxy/bypassCacheHeaders",
+    "/proxy/upstreamHeader",
+    "/tracing/datadogPropagator",
+    "/tracing/opentelemetryPropagator",
+    "/cacheBackend/redisReplicas",
+];
+
+impl ConfigFile {
+    /// Returns the settings which differ in `new` and can only be
+    /// applied by restarting the proxy.
+    ///
+    /// Reloadable settings are:
+    /// * `logLevel`
+    /// * `proxy.propagationHeaders`, `proxy.bypassCacheHeaders` and
+    ///   `proxy.upstreamHeader`
+    /// * The propagators in `tracing`
+    /// * The parameters of the `cacheBackend`
+    pub fn restart_required_changes(&self, new: &ConfigFile) -> Vec<String> {
+        let old = serde_json::to_value(self).unwrap();
+        let new = serde_json::to_value(new).unwrap();
+        let mut changes = Vec::new();
+        diff(&old, &new, &mut String::new(), &mut changes);
+        changes
+    }
+}
+
+/// Collects the settings below `pointer` which differ. Objects are
+/// only descended into when they contain reloadable settings, changes
+/// are reported at that level otherwise.
+fn diff(old: &Value, new: &Value, pointer: &mut String, changes: &mut Vec<String>) {
+    if RELOADABLE.contains(&pointer.as_str()) {
+        return;
+    }
+    let prefix = format!("{}/", pointer);
+    let has_reloadable = RELOADABLE.iter().any(|r| r.starts_with(&prefix));
+
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) if has_reloadable => {
+            let keys: BTreeSet<_> = old.keys().chain(new.keys()).collect();
+            for key in keys {
+                let len = pointer.len();
+                pointer.push('/');
+                pointer.push_str(key);
+                let missing = Value::Null;
+                diff(
+                    old.get(key).unwrap_or(&missing),
+                    new.get(key).unwrap_or(&missing),
+                    pointer,
+                    changes,
+                );
+                pointer.truncate(len);
+            }
+        }
+        _ if old != new => changes.push(pointer[1..].replace('/', ".")),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{loader::ConfigFormat, ConfigFile};
+
+    fn parse(extra: &str) -> ConfigFile {
+        let source = format!(
+            "kubernetes:\n  enable: true\ncacheBackend:\n  redisReplicas:\n    hostname: redis\nproxy:\n  propagationHeaders: []\n{}",
+            extra
+        );
+        ConfigFile::parse(&source, ConfigFormat::Yaml).unwrap()
+    }
+
+    #[test]
+    fn detects_restart_required_changes() {
+        let old = parse("");
+
+        let reloadable =
+            parse("  jsonTranscoding: false\nlogLevel: debug\n").restart_required_changes(&old);
+        assert!(reloadable.is_empty());
+
+        let mut new = parse("  jsonTranscoding: true\nserver:\n  threads: 2\n");
+        assert_eq!(
+            old.restart_required_changes(&new),
+            vec!["proxy.jsonTranscoding", "server"]
+        );
+
+        new = parse("  listeners: []\n");
+        assert_eq!(old.restart_required_changes(&new), vec!["proxy.listeners"]);
+
+        // Settings are restart-required unless listed as reloadable.
+        new = parse("  tenant:\n    sources: []\ndns:\n  staleSeconds: 1\n");
+        assert_eq!(
+            old.restart_required_changes(&new),
+            vec!["dns", "proxy.tenant"]
+        );
+
+        new = parse("  hedgeBudget:\n    percent: 50\n");
+        assert_eq!(
+            old.restart_required_changes(&new),
+            vec!["proxy.hedgeBudget"]
+        );
+
+        new = parse(
+            "  upstreamHeader: x-upstream\ntracing:\n  datadogPropagator: true\n  opentelemetryPropagator: false\n  exporter:\n    otlp: {}\n",
+        );
+        let mut reloaded = parse(
+            "tracing:\n  datadogPropagator: false\n  opentelemetryPropagator: true\n  exporter:\n    otlp: {}\n",
+        );
+        assert!(reloaded.restart_required_changes(&new).is_empty());
+        reloaded.tracing = None;
+        assert_eq!(reloaded.restart_required_changes(&new), vec!["tracing"]);
+    }
+}
@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{collections::BTreeSet, path::PathBuf};
 
 use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube_derive::CustomResource;
@@ -13,6 +13,10 @@ fn default_grpc_port() -> u16 {
     50051
 }
 
+fn default_true() -> bool {
+    true
+}
+
 #[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
 #[kube(
     group = "hansihe.com",
@@ -36,12 +40,83 @@ pub struct GrcacheServiceSpec {
     /// If more than one `GrcacheService` is declared for each
     /// permutation of (service_name, upstream_name), validation
     /// errors will occur.
+    /// Cache entries are kept apart per upstream name.
     /// For 99% of cases this should not be set.
     pub upstream_name: Option<String>,
 
+    /// Percentage of the requests without an upstream name which are
+    /// sent to this upstream instead of the one without an upstream
+    /// name, for canaries. Requires `upstreamName`. At most 100, and
+    /// the splits of a service must not add up to more than 100.
+    pub traffic_percent: Option<u32>,
+
+    /// Routes requests to named upstreams by their metadata and
+    /// message, over the upstream header. The first matching rule is
+    /// used, requests matching no rule follow their upstream header or
+    /// else the traffic splits. Only used on the `GrcacheService`
+    /// without `upstreamName`.
+    #[serde(default)]
+    pub routing_rules: Vec<RoutingRule>,
+
     /// Declares how upstreams are resolved for this service.
     pub upstream: Upstream,
 
+    /// TLS settings for connections to the upstream. If unset,
+    /// plaintext HTTP/2 is used.
+    pub upstream_tls: Option<UpstreamTls>,
+
+    /// Active health checks of the upstreams, with the gRPC health
+    /// checking protocol. If unset, all discovered upstreams are used.
+    pub health_check: Option<HealthCheck>,
+
+    /// Ejects upstreams which fail requests from selection for a
+    /// while.
+    pub outlier_detection: Option<OutlierDetection>,
+
+    /// How an upstream is selected for each request. Defaults to
+    /// round robin in proportion to the upstream weights.
+    #[serde(default)]
+    pub load_balancing: LoadBalancing,
+
+    /// Retries of failed upstream requests. Methods can declare their
+    /// own policy with the `retry_policy` method option. If unset, only
+    /// connection failures are retried.
+    pub retry_policy: Option<RetryPolicy>,
+
+    /// Upper bound on the `grpc-timeout` of requests to this service, in
+    /// milliseconds. Requests without a `grpc-timeout` get this timeout.
+    pub max_timeout_millis: Option<u64>,
+
+    /// Limits the requests in flight to the upstreams of this service.
+    /// Requests over the limit are queued, then rejected with
+    /// `RESOURCE_EXHAUSTED`. Cache hits are never limited.
+    pub concurrency_limit: Option<ConcurrencyLimit>,
+
+    /// Token bucket rate limits of requests to this service. Requests
+    /// must pass every limit which applies to their method, and are
+    /// rejected with `RESOURCE_EXHAUSTED` otherwise. Unlike concurrency
+    /// limits, cache hits are limited too.
+    #[serde(default)]
+    pub rate_limits: Vec<RateLimit>,
+
+    /// Request headers which are passed to the upstream for every
+    /// method of this service, but are not part of the cache key.
+    /// Extends `proxy.propagationHeaders` from the `grcache` config.
+    #[serde(default)]
+    pub propagation_headers: BTreeSet<String>,
+
+    /// Request headers which are always part of the cache key for
+    /// every method of this service. Headers in the `vary` request
+    /// header can only add to these.
+    #[serde(default)]
+    pub vary_headers: BTreeSet<String>,
+
+    /// If any of these request headers are present, requests to this
+    /// service are passed through to the upstream without caching.
+    /// Extends `proxy.bypassCacheHeaders` from the `grcache` config.
+    #[serde(default)]
+    pub bypass_cache_headers: BTreeSet<String>,
+
     /// The name(s) of the gRPC service(s) provided by the upstream.
     /// This should match the name of the gRPC service specified in your
     /// proto file, including the full package path.
@@ -81,7 +156,492 @@ pub enum Upstream {
         url: String,
         #[serde(default = "default_grpc_port")]
         port: u16,
+        #[serde(default)]
+        address_family: AddressFamily,
+    },
+    /// DNS SRV records will be used to resolve the upstreams, as
+    /// registered by e.g. Consul.
+    ///
+    /// Each target is connected to on the port from its record. Only
+    /// targets with the lowest priority value are used, weighted by the
+    /// weights of their records. Records are refreshed based on DNS
+    /// TTLs.
+    DnsSrv {
+        /// Name of the SRV records, like `_grpc._tcp.auth.service.consul`.
+        name: String,
+        /// Address family of the targets.
+        #[serde(default)]
+        address_family: AddressFamily,
+    },
+    /// A fixed list of upstreams.
+    Static { backends: Vec<StaticBackend> },
+    /// The endpoints of a Kubernetes `Service` will be watched through
+    /// its `EndpointSlice`s.
+    ///
+    /// Changes to pods are picked up as soon as Kubernetes reports them,
+    /// without waiting for DNS TTLs. Only ready endpoints are used,
+    /// falling back to terminating endpoints which are still serving.
+    /// Topology aware routing hints are followed when `kubernetes.zone`
+    /// is set in the `grcache` config.
+    ///
+    /// Requires `grcache-proxy` to be allowed to list and watch
+    /// `EndpointSlice`s in `namespace`.
+    KubernetesService {
+        /// Name of the `Service`.
+        name: String,
+        /// Namespace of the `Service`. Defaults to the namespace of the
+        /// `GrcacheService`.
+        namespace: Option<String>,
+        /// Port on the endpoints, which is the `targetPort` of the
+        /// `Service`.
+        #[serde(default = "default_grpc_port")]
+        port: u16,
+    },
+}
+
+/// Which addresses are looked up for DNS upstreams.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum AddressFamily {
+    /// IPv4 addresses from A records.
+    #[default]
+    V4,
+    /// IPv6 addresses from AAAA records.
+    V6,
+    /// Both IPv4 and IPv6 addresses.
+    Both,
+    /// IPv6 addresses if there are any, IPv4 addresses otherwise.
+    PreferV6,
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StaticBackend {
+    /// `host:port`, with IPv6 addresses in brackets (`[fd00::1]:50051`).
+    /// Hostnames are resolved once when the service is loaded, and
+    /// every address becomes a backend. Use a `dns` upstream to follow
+    /// DNS changes.
+    pub address: String,
+
+    /// Share of requests sent to this backend, relative to the others.
+    /// From 1 to 100.
+    #[serde(default = "default_weight")]
+    pub weight: usize,
+}
+
+fn default_health_check_interval_seconds() -> u64 {
+    10
+}
+
+fn default_health_check_timeout_millis() -> u64 {
+    1000
+}
+
+fn default_healthy_threshold() -> usize {
+    2
+}
+
+fn default_unhealthy_threshold() -> usize {
+    3
+}
+
+/// Each upstream is checked by calling `grpc.health.v1.Health/Check`.
+/// Upstreams are unhealthy while they don't respond with `SERVING`,
+/// and are not selected for requests.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthCheck {
+    /// Service name sent in the `HealthCheckRequest`. The default,
+    /// empty name checks the health of the server as a whole.
+    #[serde(default)]
+    pub service: String,
+
+    /// Seconds between checks of each upstream.
+    #[serde(default = "default_health_check_interval_seconds")]
+    pub interval_seconds: u64,
+
+    /// Milliseconds to wait for the response to a check, including
+    /// connecting.
+    #[serde(default = "default_health_check_timeout_millis")]
+    pub timeout_millis: u64,
+
+    /// Consecutive successful checks for an unhealthy upstream to
+    /// become healthy.
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: usize,
+
+    /// Consecutive failed checks for a healthy upstream to become
+    /// unhealthy.
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: usize,
+}
+
+fn default_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_base_ejection_seconds() -> u64 {
+    30
+}
+
+fn default_max_ejection_seconds() -> u64 {
+    300
+}
+
+fn default_max_ejection_percent() -> u32 {
+    10
+}
+
+/// Backends are ejected after consecutive failed requests. For
+/// upstreams, failures are connection errors, timeouts and
+/// `UNAVAILABLE` statuses. For `redis`, failed commands.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OutlierDetection {
+    /// Consecutive failed requests before a backend is ejected.
+    #[serde(default = "default_consecutive_failures")]
+    pub consecutive_failures: u32,
+
+    /// Seconds a backend is ejected for the first time. Every
+    /// following ejection lasts twice as long as the previous one.
+    #[serde(default = "default_base_ejection_seconds")]
+    pub base_ejection_seconds: u64,
+
+    /// Upper bound on the ejection time. A backend which is not
+    /// ejected for this long starts over at `baseEjectionSeconds`.
+    #[serde(default = "default_max_ejection_seconds")]
+    pub max_ejection_seconds: u64,
+
+    /// Maximum percentage of the backends which can be ejected at
+    /// once. One backend can always be ejected, but never all of them.
+    #[serde(default = "default_max_ejection_percent")]
+    pub max_ejection_percent: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct LoadBalancing {
+    #[serde(default)]
+    pub policy: LoadBalancingPolicy,
+
+    /// What requests are hashed on, for the `ketama` and `maglev`
+    /// policies. Requests without it are sent round robin.
+    pub hash_on: Option<HashOn>,
+}
+
+/// Only healthy upstreams which are not ejected are selected, whatever
+/// the policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum LoadBalancingPolicy {
+    /// Upstreams take turns, ignoring their weights.
+    RoundRobin,
+    /// Upstreams take turns in proportion to their weights.
+    #[default]
+    WeightedRoundRobin,
+    /// Upstreams are picked at random, in proportion to their weights.
+    Random,
+    /// The upstream with the fewest requests in flight from this
+    /// proxy.
+    LeastRequests,
+    /// The upstream with fewer requests in flight out of two picked at
+    /// random.
+    PowerOfTwoChoices,
+    /// Consistent hashing on a ring, in proportion to the upstream
+    /// weights. Most keys stay on their upstream when upstreams are
+    /// added or removed.
+    Ketama,
+    /// Consistent hashing with a Maglev lookup table, which spreads
+    /// keys more evenly than `ketama`.
+    Maglev,
+}
+
+/// Key of a request for consistent hashing.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum HashOn {
+    /// Value of a request header.
+    Header(String),
+    /// Value of a field of the request message, as a dotted path of
+    /// singular fields (`user.id`). Requires the descriptors of the
+    /// service, and buffers the request body. Must exist in the request
+    /// of every method the limit applies to.
+    Field(String),
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_retryable_status_codes() -> Vec<String> {
+    vec!["UNAVAILABLE".into()]
+}
+
+fn default_initial_backoff_millis() -> u64 {
+    25
+}
+
+fn default_max_backoff_millis() -> u64 {
+    250
+}
+
+/// Connection failures are retried for all methods, as the request
+/// never reached the upstream. Other failures are only retried for
+/// idempotent methods, and only until the upstream starts its
+/// response. Each retry goes to another upstream if there is one, and
+/// retries stop when the `grpc-timeout` of the request runs out.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RetryPolicy {
+    /// Attempts per request, including the first one.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+
+    /// gRPC status codes which are retried, by name (`UNAVAILABLE`).
+    /// Only trailers-only responses can be retried.
+    #[serde(default = "default_retryable_status_codes")]
+    pub retryable_status_codes: Vec<String>,
+
+    /// Milliseconds to wait for the upstream response in each attempt.
+    /// Timed out attempts are retried.
+    pub per_try_timeout_millis: Option<u64>,
+
+    /// Backoff before the first retry, with jitter. Doubled for every
+    /// following retry, up to `maxBackoffMillis`.
+    #[serde(default = "default_initial_backoff_millis")]
+    pub initial_backoff_millis: u64,
+
+    #[serde(default = "default_max_backoff_millis")]
+    pub max_backoff_millis: u64,
+
+    /// Retry requests which may have reached the upstream even though
+    /// the method is not idempotent. Methods are idempotent when they
+    /// are cached, or declare an `idempotency_level`.
+    #[serde(default)]
+    pub retry_non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: default_max_attempts(),
+            retryable_status_codes: default_retryable_status_codes(),
+            per_try_timeout_millis: None,
+            initial_backoff_millis: default_initial_backoff_millis(),
+            max_backoff_millis: default_max_backoff_millis(),
+            retry_non_idempotent: false,
+        }
+    }
+}
+
+fn default_max_queued() -> u32 {
+    100
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConcurrencyLimit {
+    /// Requests in flight to all upstreams of the service. With
+    /// `adaptive`, the starting limit, 20 by default.
+    pub max_in_flight: Option<u32>,
+
+    /// Requests in flight to each upstream. Upstreams at the limit are
+    /// not selected.
+    pub max_in_flight_per_upstream: Option<u32>,
+
+    /// Adjusts the service limit to the latency and failures of the
+    /// upstreams.
+    pub adaptive: Option<AdaptiveConcurrency>,
+
+    /// Milliseconds a request over the limit waits for another request
+    /// to finish. Zero rejects requests over the limit right away.
+    #[serde(default)]
+    pub queue_timeout_millis: u64,
+
+    /// Requests which can wait at once, the others are rejected.
+    #[serde(default = "default_max_queued")]
+    pub max_queued: u32,
+}
+
+fn default_min_limit() -> u32 {
+    1
+}
+
+fn default_max_limit() -> u32 {
+    1000
+}
+
+fn default_backoff_ratio() -> f64 {
+    0.9
+}
+
+fn default_tolerance() -> f64 {
+    2.0
+}
+
+/// Failed requests are timeouts, connection errors and `UNAVAILABLE`
+/// or `RESOURCE_EXHAUSTED` statuses.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum AdaptiveConcurrency {
+    /// Additive increase, multiplicative decrease. The limit grows by
+    /// one while it is in use, and shrinks by `backoffRatio` on failed
+    /// requests or responses slower than `latencyThresholdMillis`.
+    #[serde(rename_all = "camelCase")]
+    Aimd {
+        #[serde(default = "default_min_limit")]
+        min_limit: u32,
+        #[serde(default = "default_max_limit")]
+        max_limit: u32,
+        latency_threshold_millis: u64,
+        #[serde(default = "default_backoff_ratio")]
+        backoff_ratio: f64,
     },
+    /// Follows the ratio of the long term average latency to the
+    /// latency of each response. The limit shrinks when responses get
+    /// slower than `tolerance` times the average, and halves on failed
+    /// requests.
+    #[serde(rename_all = "camelCase")]
+    Gradient {
+        #[serde(default = "default_min_limit")]
+        min_limit: u32,
+        #[serde(default = "default_max_limit")]
+        max_limit: u32,
+        #[serde(default = "default_tolerance")]
+        tolerance: f64,
+    },
+}
+
+/// Each key of a limit has a bucket of `burst` tokens, refilled at
+/// `requestsPerSecond`. Requests take a token from their bucket.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimit {
+    /// Methods the limit applies to, by name (`GetData`). Empty applies
+    /// the limit to every method, with a bucket shared by all of them.
+    #[serde(default)]
+    pub methods: Vec<String>,
+
+    /// Requests are limited separately for each value of the key.
+    /// Requests without the key share a bucket. If unset, all requests
+    /// share a bucket.
+    pub key: Option<RateLimitKey>,
+
+    /// At least 1.
+    pub requests_per_second: u32,
+
+    /// Size of the buckets, at least 1. Defaults to
+    /// `requestsPerSecond`.
+    pub burst: Option<u32>,
+
+    /// Share the buckets between all proxies through the cache backend,
+    /// instead of limiting each proxy on its own. Falls back to local
+    /// buckets when the cache backend is unavailable.
+    #[serde(default)]
+    pub fleet_wide: bool,
+}
+
+/// Key of a request for rate limiting.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum RateLimitKey {
+    /// Value of a request header.
+    Header(String),
+    /// IP address of the client, as `peerIp: {}`.
+    PeerIp {},
+    /// Value of a field of the request message, as a dotted path of
+    /// singular fields (`user.id`). Requires the descriptors of the
+    /// service, and buffers the request body. Must exist in the request
+    /// of every method the limit applies to.
+    Field(String),
+}
+
+/// Requests match a rule when they match all of its conditions.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RoutingRule {
+    /// Name of the rule in logs. Defaults to its position in the list.
+    pub name: Option<String>,
+
+    /// Methods the rule applies to, by name (`GetData`). Empty applies
+    /// the rule to every method.
+    #[serde(default)]
+    pub methods: Vec<String>,
+
+    #[serde(default)]
+    pub headers: Vec<HeaderMatch>,
+
+    /// Conditions on fields of the request message. Requires the
+    /// descriptors of the service, and buffers the request body.
+    #[serde(default)]
+    pub fields: Vec<FieldMatch>,
+
+    /// `upstreamName` of the `GrcacheService` matching requests are
+    /// sent to.
+    pub upstream_name: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct HeaderMatch {
+    pub name: String,
+
+    /// If unset, the header only has to be present.
+    pub value: Option<ValueMatch>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldMatch {
+    /// Dotted path of singular fields (`user.id`). Unset fields have
+    /// their default value.
+    pub field: String,
+
+    /// Non-string values are matched in their text form (`42`,
+    /// `true`, enum names).
+    pub value: ValueMatch,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ValueMatch {
+    Exact(String),
+    Prefix(String),
+    /// Regular expression which must match the whole value.
+    Regex(String),
+}
+
+/// Secrets are referenced by name in the namespace of the
+/// `GrcacheService`, and are read when the `GrcacheService` is
+/// applied.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamTls {
+    /// Server name sent as SNI, and which the upstream certificate is
+    /// verified against. Defaults to the DNS hostname of the upstream.
+    pub sni: Option<String>,
+
+    /// Secret containing a PEM CA bundle under the `ca.crt` key, used
+    /// to verify upstream certificates. If unset, the system roots are
+    /// used.
+    pub ca_secret: Option<String>,
+
+    /// `kubernetes.io/tls` Secret with the client certificate
+    /// presented to the upstream (mTLS), under the `tls.crt` and
+    /// `tls.key` keys.
+    pub client_cert_secret: Option<String>,
+
+    /// Verify the upstream certificate chain.
+    #[serde(default = "default_true")]
+    pub verify_cert: bool,
+
+    /// Verify that the upstream certificate matches `sni`.
+    #[serde(default = "default_true")]
+    pub verify_hostname: bool,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
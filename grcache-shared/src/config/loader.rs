@@ -0,0 +1,582 @@
+//! Loading of the `ConfigFile`.
+//!
+//! The format of the file is picked from its extension, `.yaml`/`.yml`,
+//! `.toml` or `.json`. In string values of the parsed document, `${VAR}`
+//! is replaced with the value of the environment variable `VAR`,
+//! `${VAR:-default}` falls back to `default` when `VAR` is unset, and
+//! `$${` is a literal `${`. Comments, keys and non-string values are
+//! left alone.
+//!
+//! The parsed document is validated against the JSON schema generated
+//! from `ConfigFile`, and every violation, including unknown keys, is
+//! reported with the line it was found at.
+
+use std::{collections::HashMap, fmt, ops::Range, path::Path};
+
+use serde_json::Value;
+use yaml_rust2::{
+    parser::{Event, Parser},
+    Yaml, YamlLoader,
+};
+
+use super::ConfigFile;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "yaml" | "yml" => Some(Self::Yaml),
+            "toml" => Some(Self::Toml),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read config file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("unknown config file extension, expected one of `yaml`, `yml`, `toml` or `json`")]
+    UnknownFormat,
+    #[error("failed to parse config file: {0}")]
+    Parse(String),
+    #[error("invalid config file:{}", FormatIssues(.0))]
+    Invalid(Vec<ConfigIssue>),
+}
+
+/// A single schema violation in a config file.
+#[derive(Debug)]
+pub struct ConfigIssue {
+    /// 1-based line the violation was found at, if it could be located.
+    pub line: Option<usize>,
+    /// JSON pointer to the offending value.
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        let path = if self.path.is_empty() {
+            "/"
+        } else {
+            &self.path
+        };
+        write!(f, "{}: {}", path, self.message)
+    }
+}
+
+struct FormatIssues<'a>(&'a [ConfigIssue]);
+
+impl fmt::Display for FormatIssues<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for issue in self.0 {
+            write!(f, "\n  {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+impl ConfigFile {
+    /// Reads and validates the config file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let format = ConfigFormat::from_path(path).ok_or(ConfigError::UnknownFormat)?;
+        let source = std::fs::read_to_string(path)?;
+        Self::parse(&source, format)
+    }
+
+    /// Parses and validates a config file, interpolating environment
+    /// variables from the process environment.
+    pub fn parse(source: &str, format: ConfigFormat) -> Result<Self, ConfigError> {
+        let mut value: Value = match format {
+            ConfigFormat::Yaml => parse_yaml(source)?,
+            ConfigFormat::Toml => {
+                toml::from_str(source).map_err(|e| ConfigError::Parse(e.to_string()))?
+            }
+            ConfigFormat::Json => {
+                serde_json::from_str(source).map_err(|e| ConfigError::Parse(e.to_string()))?
+            }
+        };
+
+        let mut issues = Vec::new();
+        interpolate_strings(
+            &mut value,
+            &mut Vec::new(),
+            &|name| std::env::var(name).ok(),
+            &mut issues,
+        );
+        if issues.is_empty() {
+            issues = validate(&value);
+        }
+        let locate = |issues: Vec<(Vec<String>, String)>| {
+            let locator = Locator::new(source, format);
+            ConfigError::Invalid(
+                issues
+                    .into_iter()
+                    .map(|(path, message)| ConfigIssue {
+                        line: locator.line(&path),
+                        path: to_pointer(&path),
+                        message,
+                    })
+                    .collect(),
+            )
+        };
+        if !issues.is_empty() {
+            return Err(locate(issues));
+        }
+
+        // The schema is generated from the same types, anything it
+        // doesn't catch (like integer overflow) is reported without a
+        // line.
+        let config: ConfigFile = serde_json::from_value(value).map_err(|e| {
+            ConfigError::Invalid(vec![ConfigIssue {
+                line: None,
+                path: String::new(),
+                message: e.to_string(),
+            }])
+        })?;
+
+        let issues = check(&config);
+        if !issues.is_empty() {
+            return Err(locate(issues));
+        }
+        Ok(config)
+    }
+}
+
+/// Checks constraints between values, which the schema can't express.
+fn check(config: &ConfigFile) -> Vec<(Vec<String>, String)> {
+    let mut issues = Vec::new();
+    if config.dns.min_refresh_seconds > config.dns.max_refresh_seconds {
+        issues.push((
+            vec!["dns".into(), "minRefreshSeconds".into()],
+            "must not be larger than `maxRefreshSeconds`".into(),
+        ));
+    }
+    issues
+}
+
+/// JSON schema of the config file.
+pub fn schema() -> Value {
+    serde_json::to_value(schemars::schema_for!(ConfigFile)).unwrap()
+}
+
+/// Validates a parsed config against the schema. Returns the path and
+/// message of every violation.
+fn validate(value: &Value) -> Vec<(Vec<String>, String)> {
+    let validator = jsonschema::validator_for(&schema()).expect("config schema is invalid");
+    validator
+        .iter_errors(value)
+        .map(|error| {
+            let mut path = from_pointer(error.instance_path.as_str());
+            let message = match &error.kind {
+                // Point at the unknown key itself.
+                jsonschema::error::ValidationErrorKind::AdditionalProperties { unexpected }
+                    if unexpected.len() == 1 =>
+                {
+                    path.push(unexpected[0].clone());
+                    "unknown key".to_string()
+                }
+                jsonschema::error::ValidationErrorKind::AdditionalProperties { unexpected } => {
+                    format!("unknown keys `{}`", unexpected.join("`, `"))
+                }
+                _ => error.to_string(),
+            };
+            (path, message)
+        })
+        .collect()
+}
+
+/// Parses a YAML document into the same shape as the other formats.
+fn parse_yaml(source: &str) -> Result<Value, ConfigError> {
+    let documents =
+        YamlLoader::load_from_str(source).map_err(|e| ConfigError::Parse(e.to_string()))?;
+    documents.first().map_or(Ok(Value::Null), yaml_to_json)
+}
+
+fn yaml_to_json(yaml: &Yaml) -> Result<Value, ConfigError> {
+    Ok(match yaml {
+        Yaml::Null => Value::Null,
+        Yaml::Boolean(value) => Value::Bool(*value),
+        Yaml::Integer(value) => Value::from(*value),
+        Yaml::Real(value) => value
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number)
+            .ok_or_else(|| ConfigError::Parse(format!("invalid number `{}`", value)))?,
+        Yaml::String(value) => Value::String(value.clone()),
+        Yaml::Array(items) => {
+            Value::Array(items.iter().map(yaml_to_json).collect::<Result<_, _>>()?)
+        }
+        Yaml::Hash(entries) => Value::Object(
+            entries
+                .iter()
+                .map(|(key, value)| {
+                    let key = match key {
+                        Yaml::String(key) | Yaml::Real(key) => key.clone(),
+                        Yaml::Integer(key) => key.to_string(),
+                        Yaml::Boolean(key) => key.to_string(),
+                        _ => return Err(ConfigError::Parse(format!("unsupported key {:?}", key))),
+                    };
+                    Ok((key, yaml_to_json(value)?))
+                })
+                .collect::<Result<_, _>>()?,
+        ),
+        Yaml::Alias(_) | Yaml::BadValue => {
+            return Err(ConfigError::Parse("unsupported YAML value".to_string()))
+        }
+    })
+}
+
+/// Interpolates every string value below `value`, recording the path
+/// and message of values which can not be interpolated.
+fn interpolate_strings(
+    value: &mut Value,
+    path: &mut Vec<String>,
+    lookup: &impl Fn(&str) -> Option<String>,
+    issues: &mut Vec<(Vec<String>, String)>,
+) {
+    match value {
+        Value::String(string) => match interpolate(string, lookup) {
+            Ok(interpolated) => *string = interpolated,
+            Err(message) => issues.push((path.clone(), message)),
+        },
+        Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                path.push(index.to_string());
+                interpolate_strings(item, path, lookup, issues);
+                path.pop();
+            }
+        }
+        Value::Object(entries) => {
+            for (key, item) in entries.iter_mut() {
+                path.push(key.clone());
+                interpolate_strings(item, path, lookup, issues);
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Replaces `${VAR}` and `${VAR:-default}` with values from `lookup`.
+fn interpolate(value: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        // `$${` escapes the interpolation.
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start]);
+            out.push('{');
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+
+        let expr = &rest[start + 2..];
+        let end = expr.find('}').ok_or("unterminated `${`")?;
+        let (name, default) = match expr[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&expr[..end], None),
+        };
+
+        let value = lookup(name)
+            .or_else(|| default.map(str::to_string))
+            .ok_or_else(|| format!("environment variable `{}` is not set", name))?;
+        out.push_str(&value);
+        rest = &expr[end + 1..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn line_at(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count() + 1
+}
+
+fn from_pointer(pointer: &str) -> Vec<String> {
+    pointer
+        .split('/')
+        .skip(1)
+        .map(|segment| segment.replace("~1", "/").replace("~0", "~"))
+        .collect()
+}
+
+fn to_pointer(path: &[String]) -> String {
+    path.iter()
+        .map(|segment| format!("/{}", segment.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+/// Finds the line of a value in the source of a config file.
+enum Locator {
+    /// Lines of every node, for YAML and JSON (which is valid YAML).
+    Yaml(HashMap<Vec<String>, usize>),
+    Toml(String, Option<toml_edit::ImDocument<String>>),
+}
+
+impl Locator {
+    fn new(source: &str, format: ConfigFormat) -> Self {
+        match format {
+            ConfigFormat::Yaml | ConfigFormat::Json => {
+                let mut lines = HashMap::new();
+                yaml_lines(source, &mut lines);
+                Self::Yaml(lines)
+            }
+            ConfigFormat::Toml => Self::Toml(
+                source.to_string(),
+                toml_edit::ImDocument::parse(source.to_string()).ok(),
+            ),
+        }
+    }
+
+    /// Line of the value at `path`, or of its closest parent that
+    /// exists in the source.
+    fn line(&self, path: &[String]) -> Option<usize> {
+        (0..=path.len()).rev().find_map(|len| match self {
+            Self::Yaml(lines) => lines.get(&path[..len]).copied(),
+            Self::Toml(source, document) => {
+                let span = toml_span(document.as_ref()?.as_item(), &path[..len])?;
+                Some(line_at(source, span.start))
+            }
+        })
+    }
+}
+
+fn yaml_lines(source: &str, lines: &mut HashMap<Vec<String>, usize>) -> Option<()> {
+    let mut parser = Parser::new_from_str(source);
+    loop {
+        match parser.next_token().ok()?.0 {
+            Event::DocumentStart => break,
+            Event::StreamEnd => return None,
+            _ => {}
+        }
+    }
+    yaml_node_lines(&mut parser, &mut Vec::new(), lines)
+}
+
+/// Records the line of the next node and all of its children.
+fn yaml_node_lines(
+    parser: &mut Parser<std::str::Chars>,
+    path: &mut Vec<String>,
+    lines: &mut HashMap<Vec<String>, usize>,
+) -> Option<()> {
+    let (event, marker) = parser.next_token().ok()?;
+    // Map entries are recorded at the line of their key.
+    lines.entry(path.clone()).or_insert(marker.line());
+
+    match event {
+        Event::MappingStart(..) => loop {
+            let (key, marker) = parser.next_token().ok()?;
+            let Event::Scalar(key, ..) = key else {
+                // Only string keys can be located.
+                return (key == Event::MappingEnd).then_some(());
+            };
+            path.push(key);
+            lines.insert(path.clone(), marker.line());
+            yaml_node_lines(parser, path, lines)?;
+            path.pop();
+        },
+        Event::SequenceStart(..) => {
+            for index in 0.. {
+                if parser.peek().ok()?.0 == Event::SequenceEnd {
+                    parser.next_token().ok()?;
+                    break;
+                }
+                path.push(index.to_string());
+                yaml_node_lines(parser, path, lines)?;
+                path.pop();
+            }
+            Some(())
+        }
+        _ => Some(()),
+    }
+}
+
+fn toml_span(item: &toml_edit::Item, path: &[String]) -> Option<Range<usize>> {
+    let Some((segment, rest)) = path.split_first() else {
+        return item.span();
+    };
+
+    if let Some(table) = item.as_table_like() {
+        let (key, child) = table.get_key_value(segment)?;
+        return match rest.is_empty() {
+            true => key.span(),
+            false => toml_span(child, rest),
+        };
+    }
+
+    let index: usize = segment.parse().ok()?;
+    let child = match item {
+        toml_edit::Item::ArrayOfTables(tables) => {
+            toml_edit::Item::Table(tables.get(index)?.clone())
+        }
+        toml_edit::Item::Value(toml_edit::Value::Array(array)) => {
+            toml_edit::Item::Value(array.get(index)?.clone())
+        }
+        _ => return None,
+    };
+    toml_span(&child, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = "\
+kubernetes:
+  enable: true
+cacheBackend:
+  redisReplicas:
+    hostname: redis
+proxy:
+  propagationHeaders: []
+  listeners:
+    - address: \"[::]:50052\"
+      ipv6Only: nope
+";
+
+    #[test]
+    fn interpolates_variables() {
+        let lookup = |name: &str| (name == "HOST").then(|| "redis".to_string());
+
+        let out = interpolate("${HOST}:${PORT:-6379} $${HOST}", lookup).unwrap();
+        assert_eq!(out, "redis:6379 ${HOST}");
+
+        let err = interpolate("${PORT}", lookup).unwrap_err();
+        assert_eq!(err, "environment variable `PORT` is not set");
+    }
+
+    #[test]
+    fn interpolates_only_string_values() {
+        let source = "\
+# Not interpolated: ${UNSET_IN_COMMENT}
+kubernetes:
+  enable: true
+cacheBackend:
+  redisReplicas:
+    hostname: ${GRCACHE_TEST_UNSET_HOST:-redis}
+proxy:
+  propagationHeaders: [\"$${literal}\"]
+";
+        let config = ConfigFile::parse(source, ConfigFormat::Yaml).unwrap();
+        let crate::config::CacheBackend::RedisReplicas { hostname, .. } = config.cache_backend;
+        assert_eq!(hostname, "redis");
+        assert!(config.proxy.propagation_headers.contains("${literal}"));
+
+        let source = source.replace(":-redis", "");
+        let ConfigError::Invalid(issues) =
+            ConfigFile::parse(&source, ConfigFormat::Yaml).unwrap_err()
+        else {
+            panic!("expected validation error");
+        };
+        assert_eq!(
+            issues[0].to_string(),
+            "line 6: /cacheBackend/redisReplicas/hostname: \
+             environment variable `GRCACHE_TEST_UNSET_HOST` is not set"
+        );
+    }
+
+    #[test]
+    fn parses_all_formats() {
+        let toml = r#"
+[kubernetes]
+enable = true
+
+[cacheBackend.redisReplicas]
+hostname = "redis"
+
+[proxy]
+propagationHeaders = []
+"#;
+        let json = r#"{
+  "kubernetes": { "enable": true },
+  "cacheBackend": { "redisReplicas": { "hostname": "redis" } },
+  "proxy": { "propagationHeaders": [] }
+}"#;
+        let yaml = YAML.replace("nope", "true");
+
+        for (source, format) in [
+            (toml, ConfigFormat::Toml),
+            (json, ConfigFormat::Json),
+            (&yaml, ConfigFormat::Yaml),
+        ] {
+            let config = ConfigFile::parse(source, format).unwrap();
+            assert!(config.kubernetes.enable);
+        }
+    }
+
+    #[test]
+    fn parses_example_config() {
+        let source = include_str!("../../../grcache_config.yaml");
+        ConfigFile::parse(source, ConfigFormat::Yaml).unwrap();
+    }
+
+    #[test]
+    fn reports_issues_with_lines() {
+        let source = YAML.replace("propagationHeaders", "propagationHeader");
+        let ConfigError::Invalid(issues) =
+            ConfigFile::parse(&source, ConfigFormat::Yaml).unwrap_err()
+        else {
+            panic!("expected validation error");
+        };
+        let issues: Vec<_> = issues.iter().map(ToString::to_string).collect();
+
+        assert!(
+            issues.contains(
+                &"line 6: /proxy: \"propagationHeaders\" is a required property".to_string()
+            ),
+            "{:?}",
+            issues
+        );
+        assert!(
+            issues.contains(&"line 7: /proxy/propagationHeader: unknown key".to_string()),
+            "{:?}",
+            issues
+        );
+        assert!(
+            issues
+                .iter()
+                .any(|i| i.starts_with("line 10: /proxy/listeners/0/ipv6Only:")),
+            "{:?}",
+            issues
+        );
+    }
+
+    #[test]
+    fn reports_inconsistent_values() {
+        let source = YAML.replace("nope", "true")
+            + "dns:\n  minRefreshSeconds: 60\n  maxRefreshSeconds: 30\n";
+        let ConfigError::Invalid(issues) =
+            ConfigFile::parse(&source, ConfigFormat::Yaml).unwrap_err()
+        else {
+            panic!("expected validation error");
+        };
+        assert_eq!(
+            issues[0].to_string(),
+            "line 12: /dns/minRefreshSeconds: must not be larger than `maxRefreshSeconds`"
+        );
+    }
+
+    #[test]
+    fn locates_toml_values() {
+        let source = "[proxy]\npropagationHeaders = []\n\n[[proxy.listeners]]\naddress = 1\n";
+        let locator = Locator::new(source, ConfigFormat::Toml);
+        let path = from_pointer("/proxy/listeners/0/address");
+        assert_eq!(locator.line(&path), Some(5));
+        assert_eq!(locator.line(&from_pointer("/proxy/missing")), Some(1));
+    }
+}
@@ -3,11 +3,14 @@ use std::collections::BTreeSet;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use crd::{AddressFamily, OutlierDetection};
+
 pub mod context;
 pub mod crd;
+pub mod loader;
 
-#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct ConfigFile {
     /// Configuration for kubernetes integration.
     pub kubernetes: KubernetesConfig,
@@ -18,10 +21,123 @@ pub struct ConfigFile {
     pub proxy: ProxyConfig,
 
     pub tracing: Option<TracingConfig>,
+
+    /// Options passed through to the underlying pingora server.
+    #[serde(default)]
+    pub server: ServerConfig,
+
+    /// Overrides the default log level from `RUST_LOG`. Module
+    /// specific directives in `RUST_LOG` still apply.
+    pub log_level: Option<LogLevel>,
+
+    /// Serves Prometheus metrics over HTTP.
+    pub metrics: Option<MetricsConfig>,
+
+    /// DNS service discovery, for `dns` and `dnsSrv` upstreams and the
+    /// `redisReplicas` cache backend.
+    #[serde(default)]
+    pub dns: DnsConfig,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+fn default_dns_min_refresh_seconds() -> u64 {
+    5
+}
+
+fn default_dns_max_refresh_seconds() -> u64 {
+    300
+}
+
+fn default_dns_stale_seconds() -> u64 {
+    600
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DnsConfig {
+    /// Minimum seconds between lookups of a name, for records with
+    /// short or zero TTLs. At most `maxRefreshSeconds`.
+    #[serde(default = "default_dns_min_refresh_seconds")]
+    pub min_refresh_seconds: u64,
+
+    /// Maximum seconds between lookups of a name, for records with
+    /// long TTLs.
+    #[serde(default = "default_dns_max_refresh_seconds")]
+    pub max_refresh_seconds: u64,
+
+    /// Seconds the last resolved backends are kept while lookups fail.
+    /// After that the backends are removed until a lookup succeeds.
+    #[serde(default = "default_dns_stale_seconds")]
+    pub stale_seconds: u64,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            min_refresh_seconds: default_dns_min_refresh_seconds(),
+            max_refresh_seconds: default_dns_max_refresh_seconds(),
+            stale_seconds: default_dns_stale_seconds(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// `host:port` the metrics endpoint listens on.
+    pub address: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Number of worker threads for the proxy service.
+    pub threads: Option<usize>,
+
+    /// Allow work stealing between worker threads.
+    pub work_stealing: Option<bool>,
+
+    /// Path of the Unix socket used to hand over listening sockets to
+    /// a new process during a graceful upgrade (`--upgrade`).
+    pub upgrade_sock: Option<String>,
+
+    /// Path of the pid file.
+    pub pid_file: Option<String>,
+
+    /// Seconds to keep accepting connections after receiving a
+    /// graceful shutdown signal.
+    pub grace_period_seconds: Option<u64>,
+
+    /// Seconds to wait for services to finish before exiting on
+    /// graceful shutdown.
+    pub graceful_shutdown_timeout_seconds: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct TracingConfig {
     /// Imports and exports trace information from
     /// datadog headers.
@@ -34,14 +150,14 @@ pub struct TracingConfig {
     pub exporter: TracingExporterConfig,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub enum TracingExporterConfig {
     Otlp {},
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct ProxyConfig {
     /// HTTP headers for gRPC requests are put into 3 categories:
     /// * Vary headers. These are considered as part of the cache
@@ -55,6 +171,179 @@ pub struct ProxyConfig {
     /// Telemetry headers do not need to be specified in this map
     /// if telemetry propagation is enabled.
     pub propagation_headers: BTreeSet<String>,
+
+    /// If any of these request headers are present, the request is
+    /// passed through to the upstream without caching. Useful for
+    /// headers like `authorization` that must never share cache
+    /// entries.
+    #[serde(default)]
+    pub bypass_cache_headers: BTreeSet<String>,
+
+    /// If true, `application/json` requests to
+    /// `/package.Service/Method` are transcoded to gRPC using the
+    /// service's proto descriptors, and responses are transcoded back
+    /// into JSON. Only unary methods are supported.
+    #[serde(default)]
+    pub json_transcoding: bool,
+
+    /// Addresses the proxy accepts connections on.
+    /// Defaults to a single plaintext listener on `0.0.0.0:50052`.
+    #[serde(default = "default_listeners")]
+    pub listeners: Vec<ListenerConfig>,
+
+    /// Partitions the cache by tenant. When set, the tenant of each
+    /// request is part of the cache key, so identical requests from
+    /// different tenants never share cache entries.
+    pub tenant: Option<TenantConfig>,
+
+    /// Limits the hedged attempts sent for methods with a
+    /// `hedging_policy`, across all services. Changes require a
+    /// restart.
+    #[serde(default)]
+    pub hedge_budget: HedgeBudgetConfig,
+
+    /// Request header selecting the `GrcacheService` with the same
+    /// `upstreamName` for the request. Routing rules take precedence
+    /// over it, and it is not passed to the upstream.
+    #[serde(default = "default_upstream_header")]
+    pub upstream_header: String,
+}
+
+fn default_upstream_header() -> String {
+    "grcache-upstream".into()
+}
+
+fn default_hedge_budget_percent() -> u32 {
+    10
+}
+
+fn default_hedge_budget_min_per_second() -> u32 {
+    10
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct HedgeBudgetConfig {
+    /// Hedged attempts allowed per second, as a percentage of the
+    /// requests to hedged methods in the same second.
+    #[serde(default = "default_hedge_budget_percent")]
+    pub percent: u32,
+
+    /// Hedged attempts allowed per second regardless of `percent`, so
+    /// that hedging works at low request rates.
+    #[serde(default = "default_hedge_budget_min_per_second")]
+    pub min_per_second: u32,
+}
+
+impl Default for HedgeBudgetConfig {
+    fn default() -> Self {
+        HedgeBudgetConfig {
+            percent: default_hedge_budget_percent(),
+            min_per_second: default_hedge_budget_min_per_second(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct TenantConfig {
+    /// Where the tenant is read from. Sources are tried in order, the
+    /// first one which identifies the tenant is used.
+    pub sources: Vec<TenantSource>,
+
+    /// If true, requests without an identified tenant are passed
+    /// through to the upstream without caching. Otherwise they share
+    /// a cache partition of their own.
+    #[serde(default)]
+    pub required: bool,
+}
+
+fn default_jwt_header() -> String {
+    "authorization".into()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub enum TenantSource {
+    /// The value of a request header.
+    Header {
+        /// Name of the header.
+        name: String,
+    },
+    /// A claim of a JWT, verified against the keys of a local JWKS
+    /// file. Requests with invalid or expired tokens are not
+    /// identified by this source.
+    JwtClaim {
+        /// Header carrying the token, with an optional `Bearer `
+        /// prefix.
+        #[serde(default = "default_jwt_header")]
+        header: String,
+
+        /// Name of the claim holding the tenant. String and number
+        /// claims are supported.
+        claim: String,
+
+        /// Path to a JWKS file with the keys tokens are signed with.
+        /// Reloaded when the file changes.
+        jwks_path: String,
+
+        /// If set, tokens must have this `iss` claim.
+        issuer: Option<String>,
+
+        /// If set, tokens must have this `aud` claim.
+        audience: Option<String>,
+    },
+    /// The organization (`O`) in the subject of the verified client
+    /// certificate, for listeners with `tls.clientCaPath` set.
+    ClientCertOrganization {},
+}
+
+fn default_listeners() -> Vec<ListenerConfig> {
+    vec![ListenerConfig {
+        address: "0.0.0.0:50052".into(),
+        tls: None,
+        ipv6_only: None,
+    }]
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// `host:port` for TCP, with IPv6 addresses in brackets
+    /// (`[::]:50052`), or `unix:/path/to/socket` for a Unix domain
+    /// socket.
+    pub address: String,
+
+    /// TLS for this listener. When absent, the listener accepts
+    /// plaintext HTTP/2 (h2c). Not supported for Unix domain sockets.
+    pub tls: Option<ListenerTlsConfig>,
+
+    /// Sets `IPV6_V6ONLY` on the socket. When binding to `[::]`, most
+    /// systems accept IPv4 connections as well unless this is set.
+    /// `SO_REUSEPORT` is not configurable, pingora binds the socket.
+    pub ipv6_only: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ListenerTlsConfig {
+    /// Path to a PEM file with the certificate chain, leaf first.
+    /// Reloaded when the file changes.
+    pub cert_path: String,
+
+    /// Path to a PEM file with the private key for the certificate.
+    /// Reloaded when the file changes.
+    pub key_path: String,
+
+    /// Path to a PEM file with CA certificates used to verify client
+    /// certificates. When set, clients are asked for a certificate
+    /// (mTLS). Reloaded when the file changes.
+    pub client_ca_path: Option<String>,
+
+    /// If true, clients which do not present a certificate are still
+    /// accepted. Presented certificates are always verified.
+    #[serde(default)]
+    pub client_cert_optional: bool,
 }
 
 //#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
@@ -80,8 +369,8 @@ fn default_redis_port() -> u16 {
     6379
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub enum CacheBackend {
     RedisReplicas {
         /// The hostname used to discover Redis instances used
@@ -92,11 +381,17 @@ pub enum CacheBackend {
         hostname: String,
         #[serde(default = "default_redis_port")]
         port: u16,
+        /// Address family of the `redis` instances.
+        #[serde(default)]
+        address_family: AddressFamily,
+        /// Ejects `redis` instances which fail commands, their keys
+        /// are treated as cache misses meanwhile.
+        outlier_detection: Option<OutlierDetection>,
     },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct KubernetesConfig {
     /// If true, will enable kubernetes integration.
     /// This will:
@@ -106,6 +401,11 @@ pub struct KubernetesConfig {
     ///   `GrcacheModel` CRD.
     /// * Disallow inline declarations of the two above.
     pub enable: bool,
+
+    /// Zone the proxy runs in, usually the `topology.kubernetes.io/zone`
+    /// label of its node. When set, topology aware routing hints are
+    /// followed for `kubernetesService` upstreams.
+    pub zone: Option<String>,
     // /// This setting makes it possible to use multiple grcache
     // /// clusters within a single k8s namespace. grcache instances
     // /// will only pick up k8s resources with this `clusterName`
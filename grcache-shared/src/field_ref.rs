@@ -1,4 +1,7 @@
-use protobuf::reflect::MessageDescriptor;
+use protobuf::{
+    reflect::{MessageDescriptor, ReflectValueBox, ReflectValueRef, RuntimeFieldType, RuntimeType},
+    MessageDyn,
+};
 
 #[derive(Debug)]
 pub enum PathComponent {
@@ -13,6 +16,16 @@ pub struct FieldRef {
 #[derive(Debug, thiserror::Error)]
 pub enum ParseError {}
 
+#[derive(Debug, thiserror::Error)]
+pub enum ValidateError {
+    #[error("no field `{field}` in message `{message}`")]
+    FieldNotFound { field: String, message: String },
+    #[error("field `{field}` is not a singular field")]
+    NotSingular { field: String },
+    #[error("field `{field}` is not a message, but the path continues")]
+    NotMessage { field: String },
+}
+
 impl FieldRef {
     pub fn parse(string: &str) -> Result<Self, ParseError> {
         let components: Vec<_> = string
@@ -23,7 +36,83 @@ impl FieldRef {
         Ok(FieldRef { components })
     }
 
-    pub fn validate(&self, _descriptor: &MessageDescriptor) {
-        // TODO
+    /// Checks that the path matches singular fields of messages of
+    /// type `descriptor`, so that `resolve` can find a value.
+    pub fn validate(&self, descriptor: &MessageDescriptor) -> Result<(), ValidateError> {
+        let mut message = descriptor.clone();
+        let mut components = self.components.iter().peekable();
+        while let Some(PathComponent::Field(name)) = components.next() {
+            let field =
+                message
+                    .field_by_name(name)
+                    .ok_or_else(|| ValidateError::FieldNotFound {
+                        field: name.clone(),
+                        message: message.full_name().to_owned(),
+                    })?;
+            let RuntimeFieldType::Singular(field_type) = field.runtime_field_type() else {
+                return Err(ValidateError::NotSingular {
+                    field: name.clone(),
+                });
+            };
+            if components.peek().is_some() {
+                let RuntimeType::Message(inner) = field_type else {
+                    return Err(ValidateError::NotMessage {
+                        field: name.clone(),
+                    });
+                };
+                message = inner;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the value at this path in `message`. Unset fields at the
+    /// end of the path have their default value, `None` is returned
+    /// when a message along the path is unset or the path doesn't
+    /// match singular fields of the message.
+    pub fn resolve(&self, message: &dyn MessageDyn) -> Option<ReflectValueBox> {
+        resolve_in(&self.components, message)
+    }
+}
+
+fn resolve_in(components: &[PathComponent], message: &dyn MessageDyn) -> Option<ReflectValueBox> {
+    let (PathComponent::Field(name), rest) = components.split_first()?;
+    let field = message.descriptor_dyn().field_by_name(name)?;
+    if !field.is_singular() {
+        return None;
+    }
+
+    if rest.is_empty() {
+        return Some(field.get_singular_field_or_default(message).to_box());
+    }
+    match field.get_singular(message)? {
+        ReflectValueRef::Message(inner) => resolve_in(rest, &*inner),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use protobuf::{well_known_types::duration::Duration, MessageFull};
+
+    use super::{FieldRef, ValidateError};
+
+    #[test]
+    fn validates_against_descriptor() {
+        let descriptor = Duration::descriptor();
+        let Ok(field) = FieldRef::parse("seconds");
+        assert!(field.validate(&descriptor).is_ok());
+
+        let Ok(field) = FieldRef::parse("minutes");
+        assert!(matches!(
+            field.validate(&descriptor),
+            Err(ValidateError::FieldNotFound { .. })
+        ));
+
+        let Ok(field) = FieldRef::parse("seconds.value");
+        assert!(matches!(
+            field.validate(&descriptor),
+            Err(ValidateError::NotMessage { .. })
+        ));
     }
 }
@@ -78,7 +78,7 @@ pub async fn from_source(
 
             buf
         }
-        DescriptorSetSource::Inline { data } => BASE64_STANDARD.decode(&data)?,
+        DescriptorSetSource::Inline { data } => BASE64_STANDARD.decode(data)?,
     };
 
     let descriptor_set = protobuf::descriptor::FileDescriptorSet::parse_from_bytes(&buf)?;
@@ -10,6 +10,17 @@ pub async fn grpc_request(
     service: &str,
     method: &str,
     message: &[u8],
+) -> Response<RecvStream> {
+    grpc_request_with_headers(addr, service, method, &[], message).await
+}
+
+/// Like `grpc_request`, with additional request headers.
+pub async fn grpc_request_with_headers(
+    addr: &SocketAddr,
+    service: &str,
+    method: &str,
+    headers: &[(&str, &str)],
+    message: &[u8],
 ) -> Response<RecvStream> {
     let tcp = TcpStream::connect(addr).await.unwrap();
     let (h2, connection) = client::handshake(tcp).await.unwrap();
@@ -18,13 +29,15 @@ pub async fn grpc_request(
     });
 
     let mut h2 = h2.ready().await.unwrap();
-    let request = Request::builder()
+    let mut request = Request::builder()
         .method(Method::POST)
         .uri(format!("/{}/{}", service, method))
         .header("content-type", "application/grpc")
-        .header("host", "localhost")
-        .body(())
-        .unwrap();
+        .header("host", "localhost");
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let request = request.body(()).unwrap();
     let (response, mut send_stream) = h2.send_request(request, false).unwrap();
 
     let mut data = bytes::BytesMut::new();
@@ -35,3 +48,22 @@ pub async fn grpc_request(
 
     response.await.unwrap()
 }
+
+/// Reads the `grpc-status` of a response. This is taken from the headers
+/// for "Trailers-Only" responses, otherwise the body is drained and it
+/// is taken from the trailers.
+pub async fn grpc_status(response: Response<RecvStream>) -> Option<String> {
+    let (head, mut body) = response.into_parts();
+
+    if let Some(status) = head.headers.get("grpc-status") {
+        return Some(status.to_str().unwrap().to_owned());
+    }
+
+    while let Some(data) = body.data().await {
+        data.unwrap();
+    }
+    let trailers = body.trailers().await.unwrap()?;
+    trailers
+        .get("grpc-status")
+        .map(|status| status.to_str().unwrap().to_owned())
+}
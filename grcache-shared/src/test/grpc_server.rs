@@ -1,6 +1,7 @@
 use std::{
     net::SocketAddr,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use bytes::{BufMut, Bytes, BytesMut};
@@ -21,11 +22,28 @@ pub struct MockServer {
 
 pub struct State {
     got_extra_requests: bool,
-    expects: Vec<(String, String, Box<HandleFn>)>,
+    expects: Vec<Expect>,
+}
+
+struct Expect {
+    service: String,
+    method: String,
+    handler: Box<HandleFn>,
+    /// Send the trailers in the response headers, without a body.
+    trailers_only: bool,
+    /// Wait before responding.
+    delay: Option<Duration>,
 }
 
 pub type HandleFn = dyn FnOnce(Parts, Bytes) -> (Bytes, HeaderMap) + Send;
 
+/// Trailers for a successful gRPC response.
+pub fn ok_trailers() -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", "0".parse().unwrap());
+    trailers
+}
+
 impl MockServer {
     pub async fn new() -> Self {
         let state = State {
@@ -58,11 +76,44 @@ impl MockServer {
         method: &str,
         handle: impl FnOnce(Parts, Bytes) -> (Bytes, HeaderMap) + Send + 'static,
     ) {
-        self.state
-            .lock()
-            .unwrap()
-            .expects
-            .push((service.into(), method.into(), Box::new(handle)));
+        self.state.lock().unwrap().expects.push(Expect {
+            service: service.into(),
+            method: method.into(),
+            handler: Box::new(handle),
+            trailers_only: false,
+            delay: None,
+        });
+    }
+
+    /// Like `expect`, responding after `delay`.
+    pub fn expect_delayed(
+        &mut self,
+        service: &str,
+        method: &str,
+        delay: Duration,
+        handle: impl FnOnce(Parts, Bytes) -> (Bytes, HeaderMap) + Send + 'static,
+    ) {
+        self.state.lock().unwrap().expects.push(Expect {
+            service: service.into(),
+            method: method.into(),
+            handler: Box::new(handle),
+            trailers_only: false,
+            delay: Some(delay),
+        });
+    }
+
+    /// Expects a request which is answered with a "Trailers-Only"
+    /// response with the given status code.
+    pub fn expect_status(&mut self, service: &str, method: &str, status: &str) {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", status.parse().unwrap());
+        self.state.lock().unwrap().expects.push(Expect {
+            service: service.into(),
+            method: method.into(),
+            handler: Box::new(move |_parts, _body| (Bytes::new(), trailers)),
+            trailers_only: true,
+            delay: None,
+        });
     }
 
     pub fn finish(self) {
@@ -102,32 +153,37 @@ async fn dummy_handle_request(
 
     let (parts, _) = request.into_parts();
 
-    {
+    let expect = {
         let mut guard = state.lock().unwrap();
         if guard.expects.is_empty() {
             respond.send_reset(Reason::REFUSED_STREAM);
             guard.got_extra_requests = true;
-        } else {
-            let (service, method, handler) = guard.expects.remove(0);
-            // TODO send back to main
-            assert!(parts.uri.path() == &format!("/{}/{}", service, method));
-
-            let (resp_body, resp_parts) = handler(parts, full_body.freeze());
-
-            let response = http::Response::new(());
-            let mut send = respond.send_response(response, false).unwrap();
-            send.send_data(resp_body, false).unwrap();
-            send.send_trailers(resp_parts).unwrap();
+            return Ok(());
         }
+        guard.expects.remove(0)
+    };
+    // TODO send back to main
+    assert!(parts.uri.path() == format!("/{}/{}", expect.service, expect.method));
+
+    let (resp_body, resp_parts) = (expect.handler)(parts, full_body.freeze());
+    if let Some(delay) = expect.delay {
+        tokio::time::sleep(delay).await;
     }
 
-    //let (resp_bytes, resp_trailers) =
-
-    let response = http::Response::new(());
-    let mut send = respond.send_response(response, false).unwrap();
-    send.send_data(Bytes::from_static(b"\0\0\0\0\0"), false)
-        .unwrap();
-    send.send_trailers(HeaderMap::new()).unwrap();
+    // Sending fails when the client has cancelled the request.
+    if expect.trailers_only {
+        let mut response = http::Response::new(());
+        *response.headers_mut() = resp_parts;
+        response
+            .headers_mut()
+            .insert("content-type", "application/grpc".parse().unwrap());
+        respond.send_response(response, true)?;
+    } else {
+        let response = http::Response::new(());
+        let mut send = respond.send_response(response, false)?;
+        send.send_data(resp_body, false)?;
+        send.send_trailers(resp_parts)?;
+    }
 
     Ok(())
 }